@@ -0,0 +1,5 @@
+CREATE TABLE prngs (
+    id INTEGER NOT NULL PRIMARY KEY,
+    state INTEGER NOT NULL,
+    inc INTEGER NOT NULL
+)
\ No newline at end of file
@@ -0,0 +1 @@
+DROP TABLE prngs
\ No newline at end of file
@@ -0,0 +1 @@
+DROP TABLE plays
\ No newline at end of file
@@ -0,0 +1,13 @@
+CREATE TABLE plays (
+	id INTEGER NOT NULL PRIMARY KEY,
+	created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	username TEXT NOT NULL,
+	track_id INTEGER NOT NULL,
+	started_at DATETIME NOT NULL,
+	duration INTEGER NOT NULL,
+	client TEXT,
+	FOREIGN KEY(username) REFERENCES users(username) ON UPDATE CASCADE ON DELETE CASCADE,
+	FOREIGN KEY(track_id) REFERENCES tracks(id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+CREATE INDEX plays_track_id ON plays(track_id);
+CREATE INDEX plays_username_started_at ON plays(username, started_at);
@@ -0,0 +1,2 @@
+DROP TRIGGER plays_daily_after_insert_on_plays;
+DROP TABLE plays_daily;
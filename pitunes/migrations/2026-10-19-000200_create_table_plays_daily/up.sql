@@ -0,0 +1,22 @@
+CREATE TABLE plays_daily (
+	username TEXT NOT NULL,
+	day DATE NOT NULL,
+	track_id INTEGER NOT NULL,
+	play_count INTEGER NOT NULL,
+	duration INTEGER NOT NULL,
+	PRIMARY KEY(username, day, track_id),
+	FOREIGN KEY(username) REFERENCES users(username) ON UPDATE CASCADE ON DELETE CASCADE,
+	FOREIGN KEY(track_id) REFERENCES tracks(id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+INSERT INTO plays_daily (username, day, track_id, play_count, duration)
+	SELECT username, date(started_at), track_id, COUNT(*), SUM(duration)
+	FROM plays
+	GROUP BY username, date(started_at), track_id;
+CREATE TRIGGER plays_daily_after_insert_on_plays AFTER INSERT ON plays
+BEGIN
+	INSERT INTO plays_daily (username, day, track_id, play_count, duration)
+	VALUES (NEW.username, date(NEW.started_at), NEW.track_id, 1, NEW.duration)
+	ON CONFLICT(username, day, track_id) DO UPDATE SET
+		play_count = play_count + 1,
+		duration = duration + excluded.duration;
+END;
@@ -0,0 +1,17 @@
+CREATE TABLE stars (
+	username TEXT NOT NULL,
+	entity_kind INTEGER NOT NULL,
+	entity_id INTEGER NOT NULL,
+	created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	PRIMARY KEY(username, entity_kind, entity_id),
+	FOREIGN KEY(username) REFERENCES users(username) ON UPDATE CASCADE ON DELETE CASCADE
+);
+CREATE TABLE ratings (
+	username TEXT NOT NULL,
+	track_id INTEGER NOT NULL,
+	created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	rating INTEGER NOT NULL CHECK(rating BETWEEN 1 AND 5),
+	PRIMARY KEY(username, track_id),
+	FOREIGN KEY(username) REFERENCES users(username) ON UPDATE CASCADE ON DELETE CASCADE,
+	FOREIGN KEY(track_id) REFERENCES tracks(id) ON UPDATE CASCADE ON DELETE CASCADE
+);
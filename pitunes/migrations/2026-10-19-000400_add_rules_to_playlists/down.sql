@@ -0,0 +1,8 @@
+CREATE TABLE playlists_without_rules (
+	id INTEGER NOT NULL PRIMARY KEY,
+	created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	name TEXT NOT NULL
+);
+INSERT INTO playlists_without_rules (id, created_at, name) SELECT id, created_at, name FROM playlists;
+DROP TABLE playlists;
+ALTER TABLE playlists_without_rules RENAME TO playlists;
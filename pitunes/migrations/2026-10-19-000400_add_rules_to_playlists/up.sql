@@ -0,0 +1 @@
+ALTER TABLE playlists ADD COLUMN rules TEXT
\ No newline at end of file
@@ -0,0 +1,2 @@
+DROP TABLE queues_tracks;
+DROP TABLE queues;
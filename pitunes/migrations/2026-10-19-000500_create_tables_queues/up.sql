@@ -0,0 +1,18 @@
+CREATE TABLE queues (
+	username TEXT NOT NULL PRIMARY KEY,
+	updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	current_index INTEGER NOT NULL DEFAULT 0,
+	position INTEGER NOT NULL DEFAULT 0,
+	shuffle BOOLEAN NOT NULL DEFAULT 0,
+	repeat_mode TEXT NOT NULL DEFAULT 'OFF' CHECK(repeat_mode IN ('OFF', 'ALL', 'ONE')),
+	FOREIGN KEY(username) REFERENCES users(username) ON UPDATE CASCADE ON DELETE CASCADE
+);
+CREATE TABLE queues_tracks (
+	id INTEGER NOT NULL PRIMARY KEY,
+	username TEXT NOT NULL,
+	track_id INTEGER NOT NULL,
+	position INTEGER NOT NULL,
+	FOREIGN KEY(username) REFERENCES queues(username) ON UPDATE CASCADE ON DELETE CASCADE,
+	FOREIGN KEY(track_id) REFERENCES tracks(id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+CREATE INDEX queues_tracks_username_position ON queues_tracks(username, position);
@@ -0,0 +1,7 @@
+CREATE TABLE users_without_subsonic_password (
+	username TEXT NOT NULL PRIMARY KEY,
+	password BLOB NOT NULL
+);
+INSERT INTO users_without_subsonic_password (username, password) SELECT username, password FROM users;
+DROP TABLE users;
+ALTER TABLE users_without_subsonic_password RENAME TO users;
@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN subsonic_password TEXT
\ No newline at end of file
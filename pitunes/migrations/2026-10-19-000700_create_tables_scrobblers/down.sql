@@ -0,0 +1,2 @@
+DROP TABLE scrobbles;
+DROP TABLE scrobblers;
@@ -0,0 +1,27 @@
+CREATE TABLE scrobblers (
+	username TEXT NOT NULL,
+	service TEXT NOT NULL CHECK(service IN ('LISTENBRAINZ', 'LASTFM')),
+	url TEXT NOT NULL,
+	token TEXT NOT NULL,
+	api_key TEXT,
+	api_secret TEXT,
+	last_error TEXT,
+	PRIMARY KEY(username, service),
+	FOREIGN KEY(username) REFERENCES users(username) ON UPDATE CASCADE ON DELETE CASCADE
+);
+CREATE TABLE scrobbles (
+	id INTEGER NOT NULL PRIMARY KEY,
+	created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	username TEXT NOT NULL,
+	service TEXT NOT NULL,
+	now_playing BOOLEAN NOT NULL,
+	started_at DATETIME NOT NULL,
+	artist TEXT NOT NULL,
+	track TEXT NOT NULL,
+	album TEXT,
+	duration INTEGER NOT NULL,
+	attempts INTEGER NOT NULL DEFAULT 0,
+	next_attempt_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	FOREIGN KEY(username, service) REFERENCES scrobblers(username, service) ON UPDATE CASCADE ON DELETE CASCADE
+);
+CREATE INDEX scrobbles_next_attempt_at ON scrobbles(next_attempt_at);
@@ -0,0 +1,5 @@
+DROP TRIGGER tracks_stars;
+DROP TRIGGER playlists_stars;
+DROP TRIGGER genres_stars;
+DROP TRIGGER artists_stars;
+DROP TRIGGER albums_stars;
@@ -0,0 +1,21 @@
+DELETE FROM stars WHERE
+	(entity_kind = 1 AND entity_id NOT IN (SELECT id FROM albums)) OR
+	(entity_kind = 2 AND entity_id NOT IN (SELECT id FROM artists)) OR
+	(entity_kind = 3 AND entity_id NOT IN (SELECT id FROM genres)) OR
+	(entity_kind = 4 AND entity_id NOT IN (SELECT id FROM playlists)) OR
+	(entity_kind = 5 AND entity_id NOT IN (SELECT id FROM tracks));
+CREATE TRIGGER albums_stars AFTER DELETE ON albums BEGIN
+	DELETE FROM stars WHERE entity_kind = 1 AND entity_id = OLD.id;
+END;
+CREATE TRIGGER artists_stars AFTER DELETE ON artists BEGIN
+	DELETE FROM stars WHERE entity_kind = 2 AND entity_id = OLD.id;
+END;
+CREATE TRIGGER genres_stars AFTER DELETE ON genres BEGIN
+	DELETE FROM stars WHERE entity_kind = 3 AND entity_id = OLD.id;
+END;
+CREATE TRIGGER playlists_stars AFTER DELETE ON playlists BEGIN
+	DELETE FROM stars WHERE entity_kind = 4 AND entity_id = OLD.id;
+END;
+CREATE TRIGGER tracks_stars AFTER DELETE ON tracks BEGIN
+	DELETE FROM stars WHERE entity_kind = 5 AND entity_id = OLD.id;
+END;
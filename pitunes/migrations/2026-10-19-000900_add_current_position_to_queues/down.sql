@@ -0,0 +1,17 @@
+CREATE TABLE queues_with_current_index (
+	username TEXT NOT NULL PRIMARY KEY,
+	updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	current_index INTEGER NOT NULL DEFAULT 0,
+	position INTEGER NOT NULL DEFAULT 0,
+	shuffle BOOLEAN NOT NULL DEFAULT 0,
+	repeat_mode TEXT NOT NULL DEFAULT 'OFF' CHECK(repeat_mode IN ('OFF', 'ALL', 'ONE')),
+	FOREIGN KEY(username) REFERENCES users(username) ON UPDATE CASCADE ON DELETE CASCADE
+);
+INSERT INTO queues_with_current_index (username, updated_at, current_index, position, shuffle, repeat_mode)
+	SELECT username, updated_at, (
+		SELECT COUNT(*) FROM queues_tracks
+		WHERE queues_tracks.username = queues.username
+		AND queues_tracks.position < queues.current_position
+	), position, shuffle, repeat_mode FROM queues;
+DROP TABLE queues;
+ALTER TABLE queues_with_current_index RENAME TO queues;
@@ -0,0 +1,19 @@
+CREATE TABLE queues_with_current_position (
+	username TEXT NOT NULL PRIMARY KEY,
+	updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	current_position INTEGER NOT NULL DEFAULT 0,
+	position INTEGER NOT NULL DEFAULT 0,
+	shuffle BOOLEAN NOT NULL DEFAULT 0,
+	repeat_mode TEXT NOT NULL DEFAULT 'OFF' CHECK(repeat_mode IN ('OFF', 'ALL', 'ONE')),
+	FOREIGN KEY(username) REFERENCES users(username) ON UPDATE CASCADE ON DELETE CASCADE
+);
+INSERT INTO queues_with_current_position (username, updated_at, current_position, position, shuffle, repeat_mode)
+	SELECT username, updated_at, COALESCE((
+		SELECT current.position FROM queues_tracks AS current
+		WHERE current.username = queues.username AND (
+			SELECT COUNT(*) FROM queues_tracks AS previous
+			WHERE previous.username = current.username AND previous.position < current.position
+		) = queues.current_index
+	), 0), position, shuffle, repeat_mode FROM queues;
+DROP TABLE queues;
+ALTER TABLE queues_with_current_position RENAME TO queues;
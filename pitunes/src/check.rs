@@ -0,0 +1,158 @@
+use std::{collections::HashSet, convert::TryInto, path::Path};
+
+use anyhow::Result;
+use diesel::prelude::*;
+
+use crate::{
+    external_id::{EntityKind, ExternalId},
+    models::{Issue, IssueKind, Track},
+    schema::tracks,
+    tracks_service,
+};
+
+// Durations are stored in milliseconds, tags and decoders may disagree by a few frames
+const DURATION_TOLERANCE: i32 = 1000;
+
+// Decoding every file takes long, so it can be skipped to only look for missing and empty files
+pub fn check(
+    conn: &SqliteConnection,
+    tracks_dir: &Path,
+    repair: bool,
+    decode: bool,
+) -> Result<Vec<Issue>> {
+    let mut issues = Vec::new();
+    let mut filepaths = HashSet::new();
+    for track in tracks::table.load::<Track>(conn)? {
+        let filepath = tracks_service::track_filepath(tracks_dir, track.id);
+        filepaths.insert(filepath.clone());
+        let path = filepath.to_string_lossy().into_owned();
+        let track_id = Some(ExternalId::new(EntityKind::Track, track.id).0);
+        let metadata = match std::fs::metadata(&filepath) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                let repaired =
+                    repair && diesel::delete(tracks::table.find(track.id)).execute(conn)? == 1;
+                issues.push(Issue {
+                    kind: IssueKind::MissingFile,
+                    track_id,
+                    path,
+                    repaired,
+                });
+                continue;
+            }
+        };
+        if metadata.len() == 0 {
+            let repaired = repair
+                && diesel::delete(tracks::table.find(track.id)).execute(conn)? == 1
+                && std::fs::remove_file(&filepath).is_ok();
+            issues.push(Issue {
+                kind: IssueKind::EmptyFile,
+                track_id,
+                path,
+                repaired,
+            });
+            continue;
+        }
+        if !decode {
+            continue;
+        }
+        match mp3_duration::from_path(&filepath) {
+            Ok(duration) => {
+                let duration: i32 = duration.as_millis().try_into()?;
+                if (duration - track.duration).abs() > DURATION_TOLERANCE {
+                    let repaired = repair
+                        && diesel::update(tracks::table.find(track.id))
+                            .set(tracks::duration.eq(duration))
+                            .execute(conn)?
+                            == 1;
+                    issues.push(Issue {
+                        kind: IssueKind::DurationMismatch,
+                        track_id,
+                        path,
+                        repaired,
+                    });
+                }
+            }
+            Err(_) => issues.push(Issue {
+                kind: IssueKind::UndecodableAudio,
+                track_id,
+                path,
+                repaired: false,
+            }),
+        }
+    }
+    for entry in std::fs::read_dir(tracks_dir)? {
+        let filepath = entry?.path();
+        // uploads in progress and files being deleted don't have track file names
+        if !is_track_filepath(tracks_dir, &filepath) {
+            continue;
+        }
+        if !filepaths.contains(&filepath) {
+            let repaired = repair && std::fs::remove_file(&filepath).is_ok();
+            issues.push(Issue {
+                kind: IssueKind::OrphanFile,
+                track_id: None,
+                path: filepath.to_string_lossy().into_owned(),
+                repaired,
+            });
+        }
+    }
+    Ok(issues)
+}
+
+fn is_track_filepath(tracks_dir: &Path, filepath: &Path) -> bool {
+    let file_stem = match filepath
+        .file_stem()
+        .and_then(|file_stem| file_stem.to_str())
+    {
+        Some(file_stem) => file_stem,
+        None => return false,
+    };
+    match ExternalId(juniper::ID::from(String::from(file_stem))).decode_any() {
+        Ok((None, id)) => tracks_service::track_filepath(tracks_dir, id) == filepath,
+        _ => false,
+    }
+}
+
+#[test]
+fn it_checks_the_library() {
+    use diesel::connection::SimpleConnection;
+
+    use crate::db;
+
+    let dir = tempfile::tempdir().unwrap();
+    let pool = db::establish_connection(dir.path().join("pitunes.db").to_str().unwrap());
+    let conn = pool.get().unwrap();
+    let tracks_dir = dir.path().join("tracks");
+    std::fs::create_dir(&tracks_dir).unwrap();
+    conn.batch_execute(
+        "INSERT INTO tracks (id, name, duration) VALUES (1, 'Missing', 1000), (2, 'Empty', 1000);",
+    )
+    .unwrap();
+    std::fs::write(tracks_service::track_filepath(&tracks_dir, 2), b"").unwrap();
+    let orphan = tracks_service::track_filepath(&tracks_dir, 3);
+    std::fs::write(&orphan, b"ID3").unwrap();
+    let upload = tracks_dir.join(".uploadAbC123");
+    std::fs::write(&upload, b"ID3").unwrap();
+    let deleted = tracks_service::track_filepath(&tracks_dir, 4).with_extension("deleted");
+    std::fs::write(&deleted, b"ID3").unwrap();
+
+    let kinds = |issues: Vec<Issue>| -> Vec<String> {
+        issues
+            .into_iter()
+            .map(|issue| format!("{:?} {}", issue.kind, issue.repaired))
+            .collect()
+    };
+    assert_eq!(
+        kinds(check(&conn, &tracks_dir, false, true).unwrap()),
+        vec!["MissingFile false", "EmptyFile false", "OrphanFile false"]
+    );
+    assert_eq!(
+        kinds(check(&conn, &tracks_dir, true, true).unwrap()),
+        vec!["MissingFile true", "EmptyFile true", "OrphanFile true"]
+    );
+    assert!(check(&conn, &tracks_dir, false, true).unwrap().is_empty());
+    assert!(!orphan.exists());
+    assert!(upload.exists());
+    assert!(deleted.exists());
+}
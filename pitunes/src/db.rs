@@ -1,23 +1,46 @@
 use diesel::{
+    connection::SimpleConnection,
     prelude::*,
-    r2d2::{ConnectionManager, Pool, PoolError},
+    r2d2::{self, ConnectionManager, CustomizeConnection, Pool, PoolError},
     sqlite::SqliteConnection,
 };
 
-use crate::prng;
-
 embed_migrations!();
 
 pub type SqlitePool = Pool<ConnectionManager<SqliteConnection>>;
 
+// SQLite only enforces foreign keys, including their ON DELETE actions, when asked to on each
+// connection. Connections wait for each other instead of failing right away, e.g. while the
+// player saves the queue during a request.
+#[derive(Debug)]
+struct Pragmas;
+
+impl CustomizeConnection<SqliteConnection, r2d2::Error> for Pragmas {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), r2d2::Error> {
+        conn.batch_execute("PRAGMA foreign_keys = ON; PRAGMA busy_timeout = 5000;")
+            .map_err(r2d2::Error::QueryError)
+    }
+}
+
+// Matches the value anywhere with its wildcards escaped by backslashes, which requires
+// `ESCAPE '\'`
+pub fn like_pattern(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
 fn init_pool(database_url: &str) -> Result<SqlitePool, PoolError> {
     let manager = ConnectionManager::<SqliteConnection>::new(database_url);
-    Pool::builder().build(manager)
+    Pool::builder()
+        .connection_customizer(Box::new(Pragmas))
+        .build(manager)
 }
 
 pub fn establish_connection(database_url: &str) -> SqlitePool {
     let conn = SqliteConnection::establish(database_url).unwrap();
     embedded_migrations::run(&conn).unwrap();
-    prng::insert_prng_if_not_exists(&conn).unwrap();
     init_pool(database_url).unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
 }
@@ -0,0 +1,226 @@
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use futures::channel::mpsc::UnboundedSender;
+use getrandom::getrandom;
+use serde_json::{json, Value};
+
+use crate::external_id::{EntityKind, ExternalId};
+
+// Messages are pushed to a connection through its outbox
+pub type Outbox = UnboundedSender<Value>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Play,
+    Pause,
+    Next,
+    Previous,
+    Seek(f64),
+    Volume(f64),
+    SetQueue {
+        track_ids: Vec<String>,
+        index: usize,
+    },
+}
+
+impl TryFrom<&Value> for Command {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &Value) -> Result<Self> {
+        let number = |key: &str| {
+            value[key]
+                .as_f64()
+                .ok_or_else(|| anyhow!("Missing {}", key))
+        };
+        Ok(match value["action"].as_str() {
+            Some("play") => Command::Play,
+            Some("pause") => Command::Pause,
+            Some("next") => Command::Next,
+            Some("previous") => Command::Previous,
+            Some("seek") => Command::Seek(number("position")?.max(0.0)),
+            Some("volume") => Command::Volume(number("volume")?.clamp(0.0, 1.0)),
+            Some("setQueue") => {
+                let track_ids = value["trackIds"]
+                    .as_array()
+                    .ok_or_else(|| anyhow!("Missing trackIds"))?
+                    .iter()
+                    .map(|id| {
+                        let id = id.as_str().ok_or_else(|| anyhow!("Invalid track id"))?;
+                        ExternalId(juniper::ID::from(String::from(id)))
+                            .decode(EntityKind::Track)?;
+                        Ok(String::from(id))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let index = value["index"].as_u64().unwrap_or(0) as usize;
+                if index > 0 && index >= track_ids.len() {
+                    return Err(anyhow!("Index out of range"));
+                }
+                Command::SetQueue { track_ids, index }
+            }
+            _ => return Err(anyhow!("Unknown action")),
+        })
+    }
+}
+
+impl Command {
+    pub fn to_json(&self) -> Value {
+        match self {
+            Command::Play => json!({ "action": "play" }),
+            Command::Pause => json!({ "action": "pause" }),
+            Command::Next => json!({ "action": "next" }),
+            Command::Previous => json!({ "action": "previous" }),
+            Command::Seek(position) => json!({ "action": "seek", "position": position }),
+            Command::Volume(volume) => json!({ "action": "volume", "volume": volume }),
+            Command::SetQueue { track_ids, index } => {
+                json!({ "action": "setQueue", "trackIds": track_ids, "index": index })
+            }
+        }
+    }
+}
+
+struct Device {
+    name: String,
+    username: String,
+    state: Value,
+    outbox: Outbox,
+    observers: Vec<Outbox>,
+}
+
+impl Device {
+    fn notify(&mut self, message: Value) {
+        self.observers
+            .retain(|observer| observer.unbounded_send(message.clone()).is_ok());
+    }
+}
+
+// Playback devices are only visible to and controllable by the user who registered them
+#[derive(Clone, Default)]
+pub struct Devices {
+    devices: Arc<Mutex<HashMap<String, Device>>>,
+}
+
+impl Devices {
+    pub fn register(&self, username: &str, name: &str, outbox: Outbox) -> Result<String> {
+        let mut buf = [0u8; 16];
+        getrandom(&mut buf)?;
+        let id: String = buf.iter().map(|b| format!("{:02x}", b)).collect();
+        self.devices.lock().unwrap().insert(
+            id.clone(),
+            Device {
+                name: String::from(name),
+                username: String::from(username),
+                state: Value::Null,
+                outbox,
+                observers: Vec::new(),
+            },
+        );
+        Ok(id)
+    }
+
+    pub fn unregister(&self, id: &str) {
+        if let Some(mut device) = self.devices.lock().unwrap().remove(id) {
+            device.notify(json!({ "type": "deviceRemoved", "deviceId": id }));
+        }
+    }
+
+    pub fn list(&self, username: &str) -> Vec<Value> {
+        let devices = self.devices.lock().unwrap();
+        let mut list: Vec<Value> = devices
+            .iter()
+            .filter(|(_, device)| device.username == username)
+            .map(|(id, device)| json!({ "id": id, "name": device.name, "state": device.state }))
+            .collect();
+        list.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+        list
+    }
+
+    fn with_device<F, T>(&self, username: &str, id: &str, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Device) -> T,
+    {
+        match self.devices.lock().unwrap().get_mut(id) {
+            Some(device) if device.username == username => Ok(f(device)),
+            _ => Err(anyhow!("Unknown device")),
+        }
+    }
+
+    // Observers receive the current state right away and every update afterwards
+    pub fn observe(&self, username: &str, id: &str, outbox: Outbox) -> Result<()> {
+        self.with_device(username, id, |device| {
+            let _ = outbox.unbounded_send(json!({
+                "type": "state",
+                "deviceId": id,
+                "state": device.state,
+            }));
+            device.observers.push(outbox);
+        })
+    }
+
+    pub fn unobserve(&self, username: &str, id: &str, outbox: &Outbox) -> Result<()> {
+        self.with_device(username, id, |device| {
+            device
+                .observers
+                .retain(|observer| !observer.same_receiver(outbox))
+        })
+    }
+
+    pub fn command(&self, username: &str, id: &str, command: &Command) -> Result<()> {
+        self.with_device(username, id, |device| {
+            device
+                .outbox
+                .unbounded_send(json!({ "type": "command", "command": command.to_json() }))
+                .map_err(|_| anyhow!("Device is gone"))
+        })?
+    }
+
+    pub fn update_state(&self, username: &str, id: &str, state: Value) -> Result<()> {
+        self.with_device(username, id, |device| {
+            device.state = state.clone();
+            device.notify(json!({ "type": "state", "deviceId": id, "state": state }));
+        })
+    }
+}
+
+#[test]
+fn it_brokers_commands_and_state() {
+    use futures::channel::mpsc::unbounded;
+
+    let devices = Devices::default();
+    let (desktop, mut desktop_inbox) = unbounded();
+    let (phone, mut phone_inbox) = unbounded();
+    let id = devices.register("alice", "Desktop", desktop).unwrap();
+    assert_eq!(devices.list("alice").len(), 1);
+    assert!(devices.list("bob").is_empty());
+    assert!(devices.command("bob", &id, &Command::Play).is_err());
+
+    devices.command("alice", &id, &Command::Seek(42.0)).unwrap();
+    assert_eq!(
+        desktop_inbox.try_next().unwrap().unwrap(),
+        json!({ "type": "command", "command": { "action": "seek", "position": 42.0 } })
+    );
+
+    devices.observe("alice", &id, phone).unwrap();
+    assert_eq!(
+        phone_inbox.try_next().unwrap().unwrap()["state"],
+        Value::Null
+    );
+    devices
+        .update_state("alice", &id, json!({ "playing": true }))
+        .unwrap();
+    assert_eq!(
+        phone_inbox.try_next().unwrap().unwrap()["state"],
+        json!({ "playing": true })
+    );
+
+    devices.unregister(&id);
+    assert_eq!(
+        phone_inbox.try_next().unwrap().unwrap(),
+        json!({ "type": "deviceRemoved", "deviceId": id })
+    );
+    assert!(devices.list("alice").is_empty());
+}
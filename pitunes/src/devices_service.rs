@@ -0,0 +1,143 @@
+use std::{convert::TryFrom, time::Duration};
+
+use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+use serde_json::{json, Value};
+
+use crate::{
+    devices::{Command, Devices, Outbox},
+    graphql_schema::RequestContext,
+};
+
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+// A connection can register itself as a playback device, remote other devices of the same user, or both
+struct DeviceSocket {
+    devices: Devices,
+    username: String,
+    outbox: Outbox,
+    inbox: Option<UnboundedReceiver<Value>>,
+    device_id: Option<String>,
+}
+
+impl DeviceSocket {
+    fn handle_message(&mut self, message: &Value) -> anyhow::Result<Option<Value>> {
+        let device_id = || {
+            message["deviceId"]
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("Missing deviceId"))
+        };
+        Ok(match message["type"].as_str() {
+            Some("register") => {
+                if self.device_id.is_some() {
+                    Err(anyhow::anyhow!("Already registered"))?
+                }
+                let name = message["name"].as_str().unwrap_or("Unnamed device");
+                let id = self
+                    .devices
+                    .register(&self.username, name, self.outbox.clone())?;
+                self.device_id = Some(id.clone());
+                Some(json!({ "type": "registered", "deviceId": id }))
+            }
+            Some("state") => {
+                let id = self
+                    .device_id
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("Not registered"))?;
+                self.devices
+                    .update_state(&self.username, id, message["state"].clone())?;
+                None
+            }
+            Some("devices") => {
+                Some(json!({ "type": "devices", "devices": self.devices.list(&self.username) }))
+            }
+            Some("observe") => {
+                self.devices
+                    .observe(&self.username, device_id()?, self.outbox.clone())?;
+                None
+            }
+            Some("unobserve") => {
+                self.devices
+                    .unobserve(&self.username, device_id()?, &self.outbox)?;
+                None
+            }
+            Some("command") => {
+                let command = Command::try_from(&message["command"])?;
+                self.devices
+                    .command(&self.username, device_id()?, &command)?;
+                None
+            }
+            _ => Err(anyhow::anyhow!("Unknown message"))?,
+        })
+    }
+}
+
+impl Actor for DeviceSocket {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(inbox) = self.inbox.take() {
+            ctx.add_stream(inbox);
+        }
+        ctx.run_interval(PING_INTERVAL, |_, ctx| ctx.ping(b""));
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        if let Some(id) = self.device_id.take() {
+            self.devices.unregister(&id);
+        }
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for DeviceSocket {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let text = match msg {
+            Ok(ws::Message::Text(text)) => text,
+            Ok(ws::Message::Ping(bytes)) => return ctx.pong(&bytes),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                return ctx.stop();
+            }
+            Ok(_) => return,
+            Err(_) => return ctx.stop(),
+        };
+        let reply = serde_json::from_str(&text)
+            .map_err(anyhow::Error::from)
+            .and_then(|message| self.handle_message(&message));
+        match reply {
+            Ok(Some(reply)) => ctx.text(reply.to_string()),
+            Ok(None) => {}
+            Err(e) => ctx.text(json!({ "type": "error", "message": e.to_string() }).to_string()),
+        }
+    }
+}
+
+impl StreamHandler<Value> for DeviceSocket {
+    fn handle(&mut self, message: Value, ctx: &mut Self::Context) {
+        ctx.text(message.to_string());
+    }
+}
+
+#[get("/devices")]
+async fn devices(
+    ctx: web::Data<RequestContext>,
+    credentials: BasicAuth,
+    req: HttpRequest,
+    stream: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let (outbox, inbox) = unbounded();
+    ws::start(
+        DeviceSocket {
+            devices: ctx.devices.clone(),
+            username: String::from(credentials.user_id().as_ref()),
+            outbox,
+            inbox: Some(inbox),
+            device_id: None,
+        },
+        &req,
+        stream,
+    )
+}
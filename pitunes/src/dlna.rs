@@ -0,0 +1,651 @@
+use std::{
+    fmt::{self, Write as _},
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    path::Path,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use futures::StreamExt;
+use md5::{Digest, Md5};
+
+use crate::{
+    events::{Event, Events},
+    xml::{escape, unescape},
+};
+
+pub const DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:MediaServer:1";
+pub const CONTENT_DIRECTORY: &str = "urn:schemas-upnp-org:service:ContentDirectory:1";
+pub const CONNECTION_MANAGER: &str = "urn:schemas-upnp-org:service:ConnectionManager:1";
+
+// Tracks can be streamed and seeked by byte ranges
+pub const CONTENT_FEATURES: &str =
+    "DLNA.ORG_PN=MP3;DLNA.ORG_OP=01;DLNA.ORG_FLAGS=01700000000000000000000000000000";
+
+const SSDP_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_PORT: u16 = 1900;
+// Announcements are repeated well before they expire
+const MAX_AGE: u64 = 1800;
+
+#[derive(Clone)]
+pub struct Device {
+    pub uuid: String,
+    pub port: u16,
+    // bumped whenever the library changes so that control points refresh their views
+    pub update_id: Arc<AtomicU32>,
+}
+
+impl Device {
+    // The uuid has to stay the same across restarts, so it is derived from the library location
+    pub fn new(tracks_dir: &Path, port: u16) -> Device {
+        let digest = Md5::digest(format!("{}:{}", tracks_dir.display(), port).as_bytes());
+        let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        Device {
+            uuid: format!(
+                "{}-{}-{}-{}-{}",
+                &hex[..8],
+                &hex[8..12],
+                &hex[12..16],
+                &hex[16..20],
+                &hex[20..]
+            ),
+            port,
+            update_id: Arc::new(AtomicU32::new(1)),
+        }
+    }
+
+    pub fn watch(&self, events: &Events) {
+        let mut events = events.subscribe();
+        let update_id = self.update_id.clone();
+        thread::spawn(move || {
+            while let Some(event) = futures::executor::block_on(events.next()) {
+                match event {
+                    Event::TrackAdded(_)
+                    | Event::TrackUpdated(_)
+                    | Event::TrackDeleted(_)
+                    | Event::PlaylistChanged(_)
+                    | Event::PlaylistDeleted(_) => {
+                        update_id.fetch_add(1, Ordering::SeqCst);
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    pub fn update_id(&self) -> u32 {
+        self.update_id.load(Ordering::SeqCst)
+    }
+
+    // Pairs of notification type and unique service name
+    fn notification_types(&self) -> Vec<(String, String)> {
+        let udn = format!("uuid:{}", self.uuid);
+        let mut types = vec![
+            (
+                String::from("upnp:rootdevice"),
+                format!("{}::upnp:rootdevice", udn),
+            ),
+            (udn.clone(), udn.clone()),
+        ];
+        for nt in [DEVICE_TYPE, CONTENT_DIRECTORY, CONNECTION_MANAGER].iter() {
+            types.push((String::from(*nt), format!("{}::{}", udn, nt)));
+        }
+        types
+    }
+
+    fn location(&self, ip: IpAddr) -> String {
+        format!("http://{}:{}/description.xml", ip, self.port)
+    }
+
+    pub fn description(&self) -> String {
+        let mut xml = String::from(r#"<?xml version="1.0" encoding="utf-8"?>"#);
+        xml.push_str(r#"<root xmlns="urn:schemas-upnp-org:device-1-0" xmlns:dlna="urn:schemas-dlna-org:device-1-0">"#);
+        xml.push_str("<specVersion><major>1</major><minor>0</minor></specVersion><device>");
+        let _ = write!(xml, "<deviceType>{}</deviceType>", DEVICE_TYPE);
+        xml.push_str("<friendlyName>pitunes</friendlyName>");
+        xml.push_str("<manufacturer>pitunes</manufacturer>");
+        xml.push_str("<modelName>pitunes</modelName>");
+        let _ = write!(
+            xml,
+            "<modelNumber>{}</modelNumber>",
+            env!("CARGO_PKG_VERSION")
+        );
+        let _ = write!(xml, "<UDN>uuid:{}</UDN>", self.uuid);
+        xml.push_str("<dlna:X_DLNADOC>DMS-1.50</dlna:X_DLNADOC><serviceList>");
+        for (service_type, name) in [
+            (CONTENT_DIRECTORY, "ContentDirectory"),
+            (CONNECTION_MANAGER, "ConnectionManager"),
+        ]
+        .iter()
+        {
+            let _ = write!(
+                xml,
+                "<service><serviceType>{}</serviceType><serviceId>urn:upnp-org:serviceId:{}</serviceId><SCPDURL>/{}.xml</SCPDURL><controlURL>/control/{}</controlURL><eventSubURL/></service>",
+                service_type, name, name, name
+            );
+        }
+        xml.push_str("</serviceList></device></root>");
+        xml
+    }
+}
+
+// Arguments are (name, direction, related state variable) and state variables are
+// (name, data type, allowed values)
+type ScpdAction<'a> = (&'a str, &'a [(&'a str, &'a str, &'a str)]);
+type ScpdVariable<'a> = (&'a str, &'a str, &'a [&'a str]);
+
+fn scpd(actions: &[ScpdAction], variables: &[ScpdVariable]) -> String {
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="utf-8"?>"#);
+    xml.push_str(r#"<scpd xmlns="urn:schemas-upnp-org:service-1-0">"#);
+    xml.push_str("<specVersion><major>1</major><minor>0</minor></specVersion><actionList>");
+    for (name, arguments) in actions {
+        let _ = write!(xml, "<action><name>{}</name><argumentList>", name);
+        for (argument, direction, variable) in arguments.iter() {
+            let _ = write!(
+                xml,
+                "<argument><name>{}</name><direction>{}</direction><relatedStateVariable>{}</relatedStateVariable></argument>",
+                argument, direction, variable
+            );
+        }
+        xml.push_str("</argumentList></action>");
+    }
+    xml.push_str("</actionList><serviceStateTable>");
+    for (name, data_type, allowed_values) in variables {
+        // eventing isn't supported, control points learn about changes from the UpdateID of
+        // Browse responses instead
+        let _ = write!(
+            xml,
+            r#"<stateVariable sendEvents="no"><name>{}</name><dataType>{}</dataType>"#,
+            name, data_type
+        );
+        if !allowed_values.is_empty() {
+            xml.push_str("<allowedValueList>");
+            for allowed_value in allowed_values.iter() {
+                let _ = write!(xml, "<allowedValue>{}</allowedValue>", allowed_value);
+            }
+            xml.push_str("</allowedValueList>");
+        }
+        xml.push_str("</stateVariable>");
+    }
+    xml.push_str("</serviceStateTable></scpd>");
+    xml
+}
+
+pub fn content_directory_scpd() -> String {
+    scpd(
+        &[
+            (
+                "GetSearchCapabilities",
+                &[("SearchCaps", "out", "SearchCapabilities")],
+            ),
+            (
+                "GetSortCapabilities",
+                &[("SortCaps", "out", "SortCapabilities")],
+            ),
+            ("GetSystemUpdateID", &[("Id", "out", "SystemUpdateID")]),
+            (
+                "Browse",
+                &[
+                    ("ObjectID", "in", "A_ARG_TYPE_ObjectID"),
+                    ("BrowseFlag", "in", "A_ARG_TYPE_BrowseFlag"),
+                    ("Filter", "in", "A_ARG_TYPE_Filter"),
+                    ("StartingIndex", "in", "A_ARG_TYPE_Index"),
+                    ("RequestedCount", "in", "A_ARG_TYPE_Count"),
+                    ("SortCriteria", "in", "A_ARG_TYPE_SortCriteria"),
+                    ("Result", "out", "A_ARG_TYPE_Result"),
+                    ("NumberReturned", "out", "A_ARG_TYPE_Count"),
+                    ("TotalMatches", "out", "A_ARG_TYPE_Count"),
+                    ("UpdateID", "out", "A_ARG_TYPE_UpdateID"),
+                ],
+            ),
+        ],
+        &[
+            ("SearchCapabilities", "string", &[]),
+            ("SortCapabilities", "string", &[]),
+            ("SystemUpdateID", "ui4", &[]),
+            ("A_ARG_TYPE_ObjectID", "string", &[]),
+            (
+                "A_ARG_TYPE_BrowseFlag",
+                "string",
+                &["BrowseMetadata", "BrowseDirectChildren"],
+            ),
+            ("A_ARG_TYPE_Filter", "string", &[]),
+            ("A_ARG_TYPE_Index", "ui4", &[]),
+            ("A_ARG_TYPE_Count", "ui4", &[]),
+            ("A_ARG_TYPE_SortCriteria", "string", &[]),
+            ("A_ARG_TYPE_Result", "string", &[]),
+            ("A_ARG_TYPE_UpdateID", "ui4", &[]),
+        ],
+    )
+}
+
+pub fn connection_manager_scpd() -> String {
+    scpd(
+        &[
+            (
+                "GetProtocolInfo",
+                &[
+                    ("Source", "out", "SourceProtocolInfo"),
+                    ("Sink", "out", "SinkProtocolInfo"),
+                ],
+            ),
+            (
+                "GetCurrentConnectionIDs",
+                &[("ConnectionIDs", "out", "CurrentConnectionIDs")],
+            ),
+            (
+                "GetCurrentConnectionInfo",
+                &[
+                    ("ConnectionID", "in", "A_ARG_TYPE_ConnectionID"),
+                    ("RcsID", "out", "A_ARG_TYPE_RcsID"),
+                    ("AVTransportID", "out", "A_ARG_TYPE_AVTransportID"),
+                    ("ProtocolInfo", "out", "A_ARG_TYPE_ProtocolInfo"),
+                    (
+                        "PeerConnectionManager",
+                        "out",
+                        "A_ARG_TYPE_ConnectionManager",
+                    ),
+                    ("PeerConnectionID", "out", "A_ARG_TYPE_ConnectionID"),
+                    ("Direction", "out", "A_ARG_TYPE_Direction"),
+                    ("Status", "out", "A_ARG_TYPE_ConnectionStatus"),
+                ],
+            ),
+        ],
+        &[
+            ("SourceProtocolInfo", "string", &[]),
+            ("SinkProtocolInfo", "string", &[]),
+            ("CurrentConnectionIDs", "string", &[]),
+            ("A_ARG_TYPE_ConnectionID", "i4", &[]),
+            ("A_ARG_TYPE_RcsID", "i4", &[]),
+            ("A_ARG_TYPE_AVTransportID", "i4", &[]),
+            ("A_ARG_TYPE_ProtocolInfo", "string", &[]),
+            ("A_ARG_TYPE_ConnectionManager", "string", &[]),
+            ("A_ARG_TYPE_Direction", "string", &["Input", "Output"]),
+            (
+                "A_ARG_TYPE_ConnectionStatus",
+                "string",
+                &[
+                    "OK",
+                    "ContentFormatMismatch",
+                    "InsufficientBandwidth",
+                    "UnreliableChannel",
+                    "Unknown",
+                ],
+            ),
+        ],
+    )
+}
+
+pub fn protocol_info() -> String {
+    format!("http-get:*:audio/mpeg:{}", CONTENT_FEATURES)
+}
+
+#[derive(Debug)]
+pub struct UpnpError {
+    pub code: i32,
+    pub description: String,
+}
+
+impl UpnpError {
+    pub fn new(code: i32, description: &str) -> UpnpError {
+        UpnpError {
+            code,
+            description: String::from(description),
+        }
+    }
+
+    pub fn invalid_args() -> UpnpError {
+        UpnpError::new(402, "Invalid Args")
+    }
+
+    pub fn no_such_object() -> UpnpError {
+        UpnpError::new(701, "No such object")
+    }
+}
+
+impl fmt::Display for UpnpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.description)
+    }
+}
+
+impl From<diesel::result::Error> for UpnpError {
+    fn from(e: diesel::result::Error) -> UpnpError {
+        match e {
+            diesel::result::Error::NotFound => UpnpError::no_such_object(),
+            e => UpnpError::new(501, &e.to_string()),
+        }
+    }
+}
+
+impl From<anyhow::Error> for UpnpError {
+    fn from(e: anyhow::Error) -> UpnpError {
+        match e.downcast::<diesel::result::Error>() {
+            Ok(e) => UpnpError::from(e),
+            Err(e) => UpnpError::new(501, &e.to_string()),
+        }
+    }
+}
+
+impl From<diesel::r2d2::PoolError> for UpnpError {
+    fn from(e: diesel::r2d2::PoolError) -> UpnpError {
+        UpnpError::new(501, &e.to_string())
+    }
+}
+
+// Returns the service type and action of a SOAPACTION header like "urn:...:1#Browse"
+pub fn soap_action(header: &str) -> Option<(&str, &str)> {
+    let header = header.trim().trim_matches('"');
+    let i = header.find('#')?;
+    Some((&header[..i], &header[i + 1..]))
+}
+
+// Arguments are the children of the action element, possibly with a namespace prefix
+pub fn soap_argument(body: &str, name: &str) -> Option<String> {
+    let mut rest = body;
+    while let Some(i) = rest.find('<') {
+        rest = &rest[i + 1..];
+        let end = rest.find('>')?;
+        let tag = &rest[..end];
+        let tag_name = tag
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .trim_end_matches('/');
+        let local_name = tag_name.rsplit(':').next().unwrap_or_default();
+        if local_name == name {
+            if tag.ends_with('/') {
+                return Some(String::new());
+            }
+            let content = &rest[end + 1..];
+            let close = content.find(&format!("</{}>", tag_name))?;
+            return Some(unescape(&content[..close]));
+        }
+    }
+    None
+}
+
+pub fn soap_response(service_type: &str, action: &str, arguments: &[(&str, String)]) -> String {
+    let mut body = String::new();
+    for (name, value) in arguments {
+        let _ = write!(body, "<{}>{}</{}>", name, escape(value), name);
+    }
+    envelope(&format!(
+        r#"<u:{}Response xmlns:u="{}">{}</u:{}Response>"#,
+        action, service_type, body, action
+    ))
+}
+
+pub fn soap_fault(code: i32, description: &str) -> String {
+    envelope(&format!(
+        r#"<s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail><UPnPError xmlns="urn:schemas-upnp-org:control-1-0"><errorCode>{}</errorCode><errorDescription>{}</errorDescription></UPnPError></detail></s:Fault>"#,
+        code,
+        escape(description)
+    ))
+}
+
+fn envelope(body: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body>{}</s:Body></s:Envelope>"#,
+        body
+    )
+}
+
+pub fn didl(objects: &[String]) -> String {
+    format!(
+        r#"<DIDL-Lite xmlns="urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:upnp="urn:schemas-upnp-org:metadata-1-0/upnp/">{}</DIDL-Lite>"#,
+        objects.concat()
+    )
+}
+
+pub fn container(id: &str, parent_id: &str, title: &str, class: &str) -> String {
+    format!(
+        r#"<container id="{}" parentID="{}" restricted="1" searchable="0"><dc:title>{}</dc:title><upnp:class>{}</upnp:class></container>"#,
+        escape(id),
+        escape(parent_id),
+        escape(title),
+        class
+    )
+}
+
+pub struct MusicTrack<'a> {
+    pub id: &'a str,
+    pub parent_id: &'a str,
+    pub title: &'a str,
+    pub artist: Option<&'a str>,
+    pub album: Option<&'a str>,
+    pub genre: Option<&'a str>,
+    pub track_number: Option<i32>,
+    // milliseconds
+    pub duration: i32,
+    pub size: Option<u64>,
+    pub url: &'a str,
+}
+
+impl MusicTrack<'_> {
+    pub fn to_didl(&self) -> String {
+        let mut xml = format!(
+            r#"<item id="{}" parentID="{}" restricted="1"><dc:title>{}</dc:title><upnp:class>object.item.audioItem.musicTrack</upnp:class>"#,
+            escape(self.id),
+            escape(self.parent_id),
+            escape(self.title)
+        );
+        if let Some(artist) = self.artist {
+            let _ = write!(
+                xml,
+                "<dc:creator>{}</dc:creator><upnp:artist>{}</upnp:artist>",
+                escape(artist),
+                escape(artist)
+            );
+        }
+        if let Some(album) = self.album {
+            let _ = write!(xml, "<upnp:album>{}</upnp:album>", escape(album));
+        }
+        if let Some(genre) = self.genre {
+            let _ = write!(xml, "<upnp:genre>{}</upnp:genre>", escape(genre));
+        }
+        if let Some(track_number) = self.track_number {
+            let _ = write!(
+                xml,
+                "<upnp:originalTrackNumber>{}</upnp:originalTrackNumber>",
+                track_number
+            );
+        }
+        let duration = self.duration.max(0);
+        let _ = write!(
+            xml,
+            r#"<res protocolInfo="{}" duration="{}:{:02}:{:02}.{:03}""#,
+            protocol_info(),
+            duration / 3_600_000,
+            duration / 60_000 % 60,
+            duration / 1000 % 60,
+            duration % 1000
+        );
+        if let Some(size) = self.size {
+            let _ = write!(xml, r#" size="{}""#, size);
+        }
+        let _ = write!(xml, ">{}</res></item>", escape(self.url));
+        xml
+    }
+}
+
+// Returns the search target of an M-SEARCH request
+fn parse_search(message: &str) -> Option<&str> {
+    let mut lines = message.lines();
+    if !lines.next()?.starts_with("M-SEARCH * ") {
+        return None;
+    }
+    let mut search_target = None;
+    let mut discover = false;
+    for line in lines {
+        let i = match line.find(':') {
+            Some(i) => i,
+            None => continue,
+        };
+        let value = line[i + 1..].trim();
+        match &line[..i].trim().to_uppercase()[..] {
+            "ST" => search_target = Some(value),
+            "MAN" => discover = value.trim_matches('"') == "ssdp:discover",
+            _ => {}
+        }
+    }
+    if discover {
+        search_target
+    } else {
+        None
+    }
+}
+
+// The address of the interface which is used to reach the given address
+fn local_ip(addr: SocketAddr) -> io::Result<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(addr)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+fn notify(socket: &UdpSocket, device: &Device) -> io::Result<()> {
+    let ssdp_addr = SocketAddr::from((SSDP_ADDR, SSDP_PORT));
+    let location = device.location(local_ip(ssdp_addr)?);
+    for (nt, usn) in device.notification_types() {
+        let message = format!(
+            "NOTIFY * HTTP/1.1\r\nHOST: {}\r\nCACHE-CONTROL: max-age={}\r\nLOCATION: {}\r\nNT: {}\r\nNTS: ssdp:alive\r\nSERVER: {}\r\nUSN: {}\r\n\r\n",
+            ssdp_addr,
+            MAX_AGE,
+            location,
+            nt,
+            server(),
+            usn
+        );
+        socket.send_to(message.as_bytes(), ssdp_addr)?;
+    }
+    Ok(())
+}
+
+fn server() -> String {
+    format!(
+        "{}/1.0 UPnP/1.0 pitunes/{}",
+        std::env::consts::OS,
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+// Answers M-SEARCH requests and periodically announces the device to the network
+pub fn announce(device: Device) -> io::Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", SSDP_PORT))?;
+    if let Err(e) = socket.join_multicast_v4(&SSDP_ADDR, &Ipv4Addr::UNSPECIFIED) {
+        eprintln!("Failed to join the SSDP multicast group: {}", e);
+    }
+    {
+        let device = device.clone();
+        thread::spawn(move || {
+            let socket = match UdpSocket::bind("0.0.0.0:0") {
+                Ok(socket) => socket,
+                Err(e) => return eprintln!("Failed to announce the DLNA server: {}", e),
+            };
+            let _ = socket.set_multicast_ttl_v4(4);
+            loop {
+                if let Err(e) = notify(&socket, &device) {
+                    eprintln!("Failed to announce the DLNA server: {}", e);
+                }
+                thread::sleep(Duration::from_secs(MAX_AGE / 2));
+            }
+        });
+    }
+    answer_searches(socket, device);
+    Ok(())
+}
+
+fn answer_searches(socket: UdpSocket, device: Device) {
+    thread::spawn(move || {
+        let mut buf = [0; 2048];
+        loop {
+            let (len, addr) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("Failed to receive SSDP message: {}", e);
+                    continue;
+                }
+            };
+            let message = String::from_utf8_lossy(&buf[..len]);
+            let search_target = match parse_search(&message) {
+                Some(search_target) => search_target,
+                None => continue,
+            };
+            let location = match local_ip(addr) {
+                Ok(ip) => device.location(ip),
+                Err(_) => continue,
+            };
+            for (nt, usn) in device.notification_types() {
+                if search_target != "ssdp:all" && search_target != nt {
+                    continue;
+                }
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age={}\r\nEXT:\r\nLOCATION: {}\r\nSERVER: {}\r\nST: {}\r\nUSN: {}\r\n\r\n",
+                    MAX_AGE,
+                    location,
+                    server(),
+                    nt,
+                    usn
+                );
+                let _ = socket.send_to(response.as_bytes(), addr);
+            }
+        }
+    });
+}
+
+#[test]
+fn it_parses_searches_and_soap_arguments() {
+    let search = "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\nST: ssdp:all\r\n\r\n";
+    assert_eq!(parse_search(search), Some("ssdp:all"));
+    assert_eq!(parse_search("NOTIFY * HTTP/1.1\r\nST: ssdp:all\r\n"), None);
+    let body = r#"<s:Envelope><s:Body><u:Browse xmlns:u="urn:schemas-upnp-org:service:ContentDirectory:1"><ObjectID>albums/a&amp;b</ObjectID><Filter/><u:StartingIndex>5</u:StartingIndex></u:Browse></s:Body></s:Envelope>"#;
+    assert_eq!(
+        soap_argument(body, "ObjectID").as_deref(),
+        Some("albums/a&b")
+    );
+    assert_eq!(soap_argument(body, "Filter").as_deref(), Some(""));
+    assert_eq!(soap_argument(body, "StartingIndex").as_deref(), Some("5"));
+    assert_eq!(soap_argument(body, "RequestedCount"), None);
+    assert_eq!(
+        soap_action(&format!("\"{}#Browse\"", CONTENT_DIRECTORY)),
+        Some((CONTENT_DIRECTORY, "Browse"))
+    );
+    let device = Device::new(Path::new("/tracks"), 8200);
+    assert_eq!(device.uuid.len(), 36);
+    assert_eq!(device.uuid, Device::new(Path::new("/tracks"), 8200).uuid);
+}
+
+#[test]
+fn it_answers_searches() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let device = Device::new(Path::new("/tracks"), 8200);
+    answer_searches(socket, device.clone());
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\nST: {}\r\n\r\n",
+        CONTENT_DIRECTORY
+    );
+    client.send_to(search.as_bytes(), addr).unwrap();
+    let mut buf = [0; 2048];
+    let (len, _) = client.recv_from(&mut buf).unwrap();
+    let response = String::from_utf8_lossy(&buf[..len]);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("LOCATION: http://127.0.0.1:8200/description.xml\r\n"));
+    assert!(response.contains(&format!("ST: {}\r\n", CONTENT_DIRECTORY)));
+    assert!(response.contains(&format!(
+        "USN: uuid:{}::{}\r\n",
+        device.uuid, CONTENT_DIRECTORY
+    )));
+    // events aren't supported, so they must not be advertised
+    assert!(!device.description().contains("/event/"));
+    assert!(!content_directory_scpd().contains(r#"sendEvents="yes""#));
+}
@@ -0,0 +1,537 @@
+use std::collections::HashMap;
+
+use actix_files::NamedFile;
+use actix_web::{
+    error::{self, BlockingError},
+    http::header::{ContentDisposition, DispositionType, HeaderName, HeaderValue},
+    web, Error, HttpRequest, HttpResponse,
+};
+use diesel::prelude::*;
+
+use crate::{
+    dlna::{
+        self, Device, MusicTrack, UpnpError, CONNECTION_MANAGER, CONTENT_DIRECTORY,
+        CONTENT_FEATURES,
+    },
+    external_id::{EntityKind, ExternalId},
+    graphql_schema::RequestContext,
+    models::{Playlist, Track},
+    schema::{albums, artists, genres, playlists, tracks},
+    tracks_service::track_filepath,
+};
+
+// Top level containers as (object id, title, kind of their children)
+const CATEGORIES: &[(&str, &str, Option<EntityKind>)] = &[
+    ("artists", "Artists", Some(EntityKind::Artist)),
+    ("albums", "Albums", Some(EntityKind::Album)),
+    ("genres", "Genres", Some(EntityKind::Genre)),
+    ("playlists", "Playlists", Some(EntityKind::Playlist)),
+    ("tracks", "All Tracks", None),
+];
+
+const STORAGE_FOLDER: &str = "object.container.storageFolder";
+
+// Object ids are paths such as "albums/<album id>/<track id>", the root container is "0"
+enum Object {
+    Root,
+    Category(usize),
+    Container(usize, i32),
+    Track(String, i32),
+}
+
+fn external_id(kind: EntityKind, id: i32) -> String {
+    ExternalId::new(kind, id).0.to_string()
+}
+
+fn decode(id: &str, kind: EntityKind) -> Result<i32, UpnpError> {
+    ExternalId(juniper::ID::from(String::from(id)))
+        .decode(kind)
+        .map_err(|_| UpnpError::no_such_object())
+}
+
+fn parse_object_id(object_id: &str) -> Result<Object, UpnpError> {
+    if object_id == "0" {
+        return Ok(Object::Root);
+    }
+    let segments: Vec<&str> = object_id.split('/').collect();
+    let category = CATEGORIES
+        .iter()
+        .position(|(id, _, _)| *id == segments[0])
+        .ok_or_else(UpnpError::no_such_object)?;
+    match (&segments[1..], CATEGORIES[category].2) {
+        ([], _) => Ok(Object::Category(category)),
+        ([track_id], None) => Ok(Object::Track(
+            String::from(segments[0]),
+            decode(track_id, EntityKind::Track)?,
+        )),
+        ([id], Some(kind)) => Ok(Object::Container(category, decode(id, kind)?)),
+        ([id, track_id], Some(kind)) => {
+            decode(id, kind)?;
+            Ok(Object::Track(
+                format!("{}/{}", segments[0], id),
+                decode(track_id, EntityKind::Track)?,
+            ))
+        }
+        _ => Err(UpnpError::no_such_object()),
+    }
+}
+
+fn xml(body: String) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(r#"text/xml; charset="utf-8""#)
+        .body(body)
+}
+
+pub async fn description(device: web::Data<Device>) -> HttpResponse {
+    xml(device.description())
+}
+
+pub async fn content_directory() -> HttpResponse {
+    xml(dlna::content_directory_scpd())
+}
+
+pub async fn connection_manager() -> HttpResponse {
+    xml(dlna::connection_manager_scpd())
+}
+
+// Renderers can't authenticate, so tracks are served without credentials like every other
+// resource of the DLNA server
+pub async fn get_track(
+    ctx: web::Data<RequestContext>,
+    req: HttpRequest,
+    web::Path(id): web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let id = ExternalId(juniper::ID::from(id))
+        .decode(EntityKind::Track)
+        .map_err(error::ErrorNotFound)?;
+    let mut response = NamedFile::open(track_filepath(&ctx.tracks_dir, id))?
+        .set_content_disposition(ContentDisposition {
+            disposition: DispositionType::Inline,
+            parameters: Vec::new(),
+        })
+        .into_response(&req)?;
+    let headers = response.headers_mut();
+    headers.insert(
+        HeaderName::from_static("transfermode.dlna.org"),
+        HeaderValue::from_static("Streaming"),
+    );
+    headers.insert(
+        HeaderName::from_static("contentfeatures.dlna.org"),
+        HeaderValue::from_static(CONTENT_FEATURES),
+    );
+    Ok(response)
+}
+
+pub async fn control(
+    ctx: web::Data<RequestContext>,
+    device: web::Data<Device>,
+    req: HttpRequest,
+    body: String,
+) -> Result<HttpResponse, Error> {
+    let (service_type, action) = req
+        .headers()
+        .get("SOAPACTION")
+        .and_then(|soap_action| soap_action.to_str().ok())
+        .and_then(dlna::soap_action)
+        .map(|(service_type, action)| (String::from(service_type), String::from(action)))
+        .unwrap_or_default();
+    // stream urls have to be reachable through the same address as the control url
+    let base_url = {
+        let connection_info = req.connection_info();
+        format!("{}://{}", connection_info.scheme(), connection_info.host())
+    };
+    let context = ctx.get_ref().clone();
+    let device = device.get_ref().clone();
+    let response = web::block(move || {
+        let conn = context.pool.get()?;
+        let arguments = match (&service_type[..], &action[..]) {
+            (CONTENT_DIRECTORY, "GetSearchCapabilities") => vec![("SearchCaps", String::new())],
+            (CONTENT_DIRECTORY, "GetSortCapabilities") => vec![("SortCaps", String::new())],
+            (CONTENT_DIRECTORY, "GetSystemUpdateID") => {
+                vec![("Id", device.update_id().to_string())]
+            }
+            (CONTENT_DIRECTORY, "Browse") => browse(&context, &device, &conn, &body, &base_url)?,
+            (CONNECTION_MANAGER, "GetProtocolInfo") => {
+                vec![("Source", dlna::protocol_info()), ("Sink", String::new())]
+            }
+            (CONNECTION_MANAGER, "GetCurrentConnectionIDs") => {
+                vec![("ConnectionIDs", String::from("0"))]
+            }
+            (CONNECTION_MANAGER, "GetCurrentConnectionInfo") => vec![
+                ("RcsID", String::from("-1")),
+                ("AVTransportID", String::from("-1")),
+                ("ProtocolInfo", String::new()),
+                ("PeerConnectionManager", String::new()),
+                ("PeerConnectionID", String::from("-1")),
+                ("Direction", String::from("Output")),
+                ("Status", String::from("OK")),
+            ],
+            _ => Err(UpnpError::new(401, "Invalid Action"))?,
+        };
+        Ok::<_, UpnpError>(dlna::soap_response(&service_type, &action, &arguments))
+    })
+    .await;
+    match response {
+        Ok(response) => Ok(xml(response)),
+        Err(BlockingError::Error(e)) => Ok(HttpResponse::InternalServerError()
+            .content_type(r#"text/xml; charset="utf-8""#)
+            .body(dlna::soap_fault(e.code, &e.description))),
+        Err(BlockingError::Canceled) => {
+            Err(error::ErrorInternalServerError("Request was canceled"))
+        }
+    }
+}
+
+fn browse(
+    context: &RequestContext,
+    device: &Device,
+    conn: &SqliteConnection,
+    body: &str,
+    base_url: &str,
+) -> Result<Vec<(&'static str, String)>, UpnpError> {
+    let argument = |name| dlna::soap_argument(body, name).ok_or_else(UpnpError::invalid_args);
+    let number = |name| -> Result<usize, UpnpError> {
+        match dlna::soap_argument(body, name) {
+            Some(value) if !value.is_empty() => {
+                value.parse().map_err(|_| UpnpError::invalid_args())
+            }
+            _ => Ok(0),
+        }
+    };
+    let object = parse_object_id(&argument("ObjectID")?)?;
+    let starting_index = number("StartingIndex")?;
+    let requested_count = number("RequestedCount")?;
+    let browser = Browser::new(context, conn, base_url)?;
+    let (objects, total_matches) = match &argument("BrowseFlag")?[..] {
+        "BrowseMetadata" => (vec![browser.metadata(&object)?], 1),
+        "BrowseDirectChildren" => browser.children(&object, starting_index, requested_count)?,
+        _ => return Err(UpnpError::invalid_args()),
+    };
+    Ok(vec![
+        ("Result", dlna::didl(&objects)),
+        ("NumberReturned", objects.len().to_string()),
+        ("TotalMatches", total_matches.to_string()),
+        ("UpdateID", device.update_id().to_string()),
+    ])
+}
+
+// Returns the requested slice and the total number of items, a requested count of 0 asks for
+// all of them
+fn page<T>(items: Vec<T>, starting_index: usize, requested_count: usize) -> (Vec<T>, usize) {
+    let total = items.len();
+    let requested_count = match requested_count {
+        0 => total,
+        requested_count => requested_count,
+    };
+    (
+        items
+            .into_iter()
+            .skip(starting_index)
+            .take(requested_count)
+            .collect(),
+        total,
+    )
+}
+
+struct Browser<'a> {
+    context: &'a RequestContext,
+    conn: &'a SqliteConnection,
+    base_url: &'a str,
+    albums: HashMap<i32, String>,
+    artists: HashMap<i32, String>,
+    genres: HashMap<i32, String>,
+}
+
+impl<'a> Browser<'a> {
+    fn new(
+        context: &'a RequestContext,
+        conn: &'a SqliteConnection,
+        base_url: &'a str,
+    ) -> Result<Browser<'a>, UpnpError> {
+        Ok(Browser {
+            context,
+            conn,
+            base_url,
+            albums: albums::table
+                .select((albums::id, albums::name))
+                .load::<(i32, String)>(conn)?
+                .into_iter()
+                .collect(),
+            artists: artists::table
+                .select((artists::id, artists::name))
+                .load::<(i32, String)>(conn)?
+                .into_iter()
+                .collect(),
+            genres: genres::table
+                .select((genres::id, genres::name))
+                .load::<(i32, String)>(conn)?
+                .into_iter()
+                .collect(),
+        })
+    }
+
+    fn item(&self, parent_id: &str, track: &Track) -> String {
+        let track_id = external_id(EntityKind::Track, track.id);
+        let size = std::fs::metadata(track_filepath(&self.context.tracks_dir, track.id))
+            .ok()
+            .map(|metadata| metadata.len());
+        MusicTrack {
+            id: &format!("{}/{}", parent_id, track_id),
+            parent_id,
+            title: &track.name,
+            artist: track
+                .artist_id
+                .and_then(|id| self.artists.get(&id))
+                .map(|name| &name[..]),
+            album: track
+                .album_id
+                .and_then(|id| self.albums.get(&id))
+                .map(|name| &name[..]),
+            genre: track
+                .genre_id
+                .and_then(|id| self.genres.get(&id))
+                .map(|name| &name[..]),
+            track_number: track.track_number,
+            duration: track.duration,
+            size,
+            url: &format!("{}/tracks/{}.mp3", self.base_url, track_id),
+        }
+        .to_didl()
+    }
+
+    fn names(&self, kind: EntityKind) -> Result<Vec<(i32, String)>, UpnpError> {
+        let names = match kind {
+            EntityKind::Artist => artists::table
+                .select((artists::id, artists::name))
+                .order(artists::name.asc())
+                .load(self.conn)?,
+            EntityKind::Album => albums::table
+                .select((albums::id, albums::name))
+                .order(albums::name.asc())
+                .load(self.conn)?,
+            EntityKind::Genre => genres::table
+                .select((genres::id, genres::name))
+                .order(genres::name.asc())
+                .load(self.conn)?,
+            EntityKind::Playlist => playlists::table
+                .select((playlists::id, playlists::name))
+                .order(playlists::name.asc())
+                .load(self.conn)?,
+            EntityKind::Track => tracks::table
+                .select((tracks::id, tracks::name))
+                .order(tracks::name.asc())
+                .load(self.conn)?,
+        };
+        Ok(names)
+    }
+
+    fn container(&self, category: usize, id: i32, name: &str) -> String {
+        let (category_id, _, kind) = CATEGORIES[category];
+        let kind = kind.unwrap_or(EntityKind::Track);
+        let class = match kind {
+            EntityKind::Artist => "object.container.person.musicArtist",
+            EntityKind::Album => "object.container.album.musicAlbum",
+            EntityKind::Genre => "object.container.genre.musicGenre",
+            EntityKind::Playlist => "object.container.playlistContainer",
+            EntityKind::Track => STORAGE_FOLDER,
+        };
+        dlna::container(
+            &format!("{}/{}", category_id, external_id(kind, id)),
+            category_id,
+            name,
+            class,
+        )
+    }
+
+    fn tracks(&self, category: usize, id: i32) -> Result<Vec<Track>, UpnpError> {
+        let tracks = match CATEGORIES[category].2 {
+            Some(EntityKind::Artist) => tracks::table
+                .filter(tracks::artist_id.eq(id))
+                .order((
+                    tracks::album_id.asc(),
+                    tracks::track_number.asc(),
+                    tracks::name.asc(),
+                ))
+                .load(self.conn)?,
+            Some(EntityKind::Album) => tracks::table
+                .filter(tracks::album_id.eq(id))
+                .order((tracks::track_number.asc(), tracks::name.asc()))
+                .load(self.conn)?,
+            Some(EntityKind::Genre) => tracks::table
+                .filter(tracks::genre_id.eq(id))
+                .order(tracks::name.asc())
+                .load(self.conn)?,
+            // smart playlists are evaluated without anyone's play history
+            Some(EntityKind::Playlist) => playlists::table
+                .find(id)
+                .get_result::<Playlist>(self.conn)?
+                .load_tracks(self.conn, "")?,
+            _ => tracks::table.order(tracks::name.asc()).load(self.conn)?,
+        };
+        Ok(tracks)
+    }
+
+    fn metadata(&self, object: &Object) -> Result<String, UpnpError> {
+        Ok(match object {
+            Object::Root => dlna::container("0", "-1", "pitunes", STORAGE_FOLDER),
+            Object::Category(category) => {
+                let (id, title, _) = CATEGORIES[*category];
+                dlna::container(id, "0", title, STORAGE_FOLDER)
+            }
+            Object::Container(category, id) => {
+                let name: String = match CATEGORIES[*category].2 {
+                    Some(EntityKind::Artist) => artists::table
+                        .find(id)
+                        .select(artists::name)
+                        .get_result(self.conn)?,
+                    Some(EntityKind::Album) => albums::table
+                        .find(id)
+                        .select(albums::name)
+                        .get_result(self.conn)?,
+                    Some(EntityKind::Genre) => genres::table
+                        .find(id)
+                        .select(genres::name)
+                        .get_result(self.conn)?,
+                    Some(EntityKind::Playlist) => playlists::table
+                        .find(id)
+                        .select(playlists::name)
+                        .get_result(self.conn)?,
+                    _ => return Err(UpnpError::no_such_object()),
+                };
+                self.container(*category, *id, &name)
+            }
+            Object::Track(parent_id, id) => {
+                let track: Track = tracks::table.find(id).get_result(self.conn)?;
+                self.item(parent_id, &track)
+            }
+        })
+    }
+
+    // Only the requested slice of the children is rendered, which looks up the file sizes of
+    // tracks
+    fn children(
+        &self,
+        object: &Object,
+        starting_index: usize,
+        requested_count: usize,
+    ) -> Result<(Vec<String>, usize), UpnpError> {
+        Ok(match object {
+            Object::Root => {
+                let (categories, total) =
+                    page(CATEGORIES.iter().collect(), starting_index, requested_count);
+                (
+                    categories
+                        .iter()
+                        .map(|(id, title, _)| dlna::container(id, "0", title, STORAGE_FOLDER))
+                        .collect(),
+                    total,
+                )
+            }
+            Object::Category(category) => match CATEGORIES[*category] {
+                (id, _, None) => {
+                    let (tracks, total) =
+                        page(self.tracks(*category, 0)?, starting_index, requested_count);
+                    (
+                        tracks.iter().map(|track| self.item(id, track)).collect(),
+                        total,
+                    )
+                }
+                (_, _, Some(kind)) => {
+                    let (names, total) = page(self.names(kind)?, starting_index, requested_count);
+                    (
+                        names
+                            .iter()
+                            .map(|(id, name)| self.container(*category, *id, name))
+                            .collect(),
+                        total,
+                    )
+                }
+            },
+            Object::Container(category, id) => {
+                let parent_id = format!(
+                    "{}/{}",
+                    CATEGORIES[*category].0,
+                    external_id(CATEGORIES[*category].2.unwrap(), *id)
+                );
+                let (tracks, total) = page(
+                    self.tracks(*category, *id)?,
+                    starting_index,
+                    requested_count,
+                );
+                (
+                    tracks
+                        .iter()
+                        .map(|track| self.item(&parent_id, track))
+                        .collect(),
+                    total,
+                )
+            }
+            Object::Track(_, _) => return Err(UpnpError::new(710, "No such container")),
+        })
+    }
+}
+
+#[actix_rt::test]
+async fn it_browses_over_soap() {
+    use actix_web::App;
+    use diesel::connection::SimpleConnection;
+
+    use crate::{db, devices::Devices, events::Events, orphans::OrphanPolicy};
+
+    let dir = tempfile::tempdir().unwrap();
+    let pool = db::establish_connection(dir.path().join("pitunes.db").to_str().unwrap());
+    pool.get()
+        .unwrap()
+        .batch_execute(
+            "INSERT INTO tracks (id, name, duration) VALUES
+                 (1, 'One', 1000), (2, 'Two', 1000), (3, 'Three', 1000);",
+        )
+        .unwrap();
+    let context = RequestContext::new(
+        pool,
+        dir.path().to_path_buf(),
+        OrphanPolicy::Never,
+        false,
+        false,
+        Events::default(),
+        Devices::default(),
+    );
+    let device = Device::new(dir.path(), 0);
+    let server = actix_web::test::start(move || {
+        App::new()
+            .data(context.clone())
+            .data(device.clone())
+            .route("/control/{service}", web::post().to(control))
+    });
+    let browse = format!(
+        r#"<?xml version="1.0"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"><s:Body><u:Browse xmlns:u="{}"><ObjectID>tracks</ObjectID><BrowseFlag>BrowseDirectChildren</BrowseFlag><Filter>*</Filter><StartingIndex>1</StartingIndex><RequestedCount>1</RequestedCount><SortCriteria></SortCriteria></u:Browse></s:Body></s:Envelope>"#,
+        CONTENT_DIRECTORY
+    );
+    let mut response = server
+        .post("/control/ContentDirectory")
+        .header("SOAPACTION", format!("\"{}#Browse\"", CONTENT_DIRECTORY))
+        .send_body(browse)
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    let body = String::from_utf8(response.body().await.unwrap().to_vec()).unwrap();
+    let result = dlna::soap_argument(&body, "Result").unwrap();
+    // tracks are sorted by name, so the second one is "Three"
+    assert!(result.contains("<dc:title>Three</dc:title>"));
+    assert!(!result.contains("<dc:title>One</dc:title>"));
+    assert_eq!(
+        dlna::soap_argument(&body, "NumberReturned").as_deref(),
+        Some("1")
+    );
+    assert_eq!(
+        dlna::soap_argument(&body, "TotalMatches").as_deref(),
+        Some("3")
+    );
+    let response = server
+        .post("/control/ContentDirectory")
+        .header("SOAPACTION", format!("\"{}#Unknown\"", CONTENT_DIRECTORY))
+        .send_body("")
+        .await
+        .unwrap();
+    assert!(response.status().is_server_error());
+}
@@ -0,0 +1,62 @@
+use std::sync::{Arc, Mutex};
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+
+use crate::external_id::EntityKind;
+
+// Changes which are pushed to subscribed clients once they have been committed
+#[derive(Clone, Debug)]
+pub enum Event {
+    TrackAdded(i32),
+    TrackUpdated(i32),
+    TrackDeleted(i32),
+    AlbumUpdated(i32),
+    AlbumDeleted(i32),
+    ArtistUpdated(i32),
+    ArtistDeleted(i32),
+    GenreUpdated(i32),
+    GenreDeleted(i32),
+    PlaylistChanged(i32),
+    PlaylistDeleted(i32),
+    QueueChanged(String),
+    PlayerChanged,
+    // stars and ratings only concern the user who changed them
+    StarChanged(String, EntityKind, i32),
+    RatingChanged(String, i32),
+}
+
+#[derive(Clone, Default)]
+pub struct Events {
+    subscribers: Arc<Mutex<Vec<UnboundedSender<Event>>>>,
+}
+
+impl Events {
+    pub fn subscribe(&self) -> UnboundedReceiver<Event> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    // Subscribers which went away are dropped on the next publish
+    pub fn publish(&self, event: Event) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+#[test]
+fn it_drops_closed_subscribers() {
+    use futures::StreamExt;
+
+    let events = Events::default();
+    let mut receiver = events.subscribe();
+    drop(events.subscribe());
+    events.publish(Event::TrackAdded(1));
+    assert_eq!(events.subscribers.lock().unwrap().len(), 1);
+    match futures::executor::block_on(receiver.next()) {
+        Some(Event::TrackAdded(1)) => {}
+        event => panic!("unexpected event {:?}", event),
+    }
+}
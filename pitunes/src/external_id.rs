@@ -1,27 +1,77 @@
 use std::convert::{TryFrom, TryInto};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use base64;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EntityKind {
+    Album = 1,
+    Artist = 2,
+    Genre = 3,
+    Playlist = 4,
+    Track = 5,
+}
+
+impl TryFrom<u8> for EntityKind {
+    type Error = anyhow::Error;
+
+    fn try_from(b: u8) -> Result<Self> {
+        match b {
+            1 => Ok(EntityKind::Album),
+            2 => Ok(EntityKind::Artist),
+            3 => Ok(EntityKind::Genre),
+            4 => Ok(EntityKind::Playlist),
+            5 => Ok(EntityKind::Track),
+            _ => Err(anyhow!("Unknown entity kind {}", b)),
+        }
+    }
+}
+
+// Ids are encoded as the entity kind followed by the little-endian internal id.
+// Untyped ids consist of the internal id only and remain valid for any kind.
 pub struct ExternalId(pub juniper::ID);
 
-impl From<i32> for ExternalId {
-    fn from(id: i32) -> Self {
+impl ExternalId {
+    pub fn new(kind: EntityKind, id: i32) -> Self {
+        let mut bytes = vec![kind as u8];
+        bytes.extend_from_slice(&id.to_le_bytes());
+        ExternalId(juniper::ID::from(base64::encode_config(
+            bytes,
+            base64::URL_SAFE_NO_PAD,
+        )))
+    }
+
+    // Used for file names which predate typed ids
+    pub fn untyped(id: i32) -> Self {
         ExternalId(juniper::ID::from(base64::encode_config(
             id.to_le_bytes(),
             base64::URL_SAFE_NO_PAD,
         )))
     }
-}
 
-impl TryFrom<ExternalId> for i32 {
-    type Error = anyhow::Error;
+    pub fn decode_any(&self) -> Result<(Option<EntityKind>, i32)> {
+        let v = base64::decode_config(&self.0[..], base64::URL_SAFE_NO_PAD)
+            .map_err(|_| anyhow!("Invalid id {}", &self.0[..]))?;
+        match v.len() {
+            4 => Ok((None, i32::from_le_bytes(v[..].try_into()?))),
+            5 => Ok((
+                Some(EntityKind::try_from(v[0])?),
+                i32::from_le_bytes(v[1..].try_into()?),
+            )),
+            _ => Err(anyhow!("Invalid id {}", &self.0[..])),
+        }
+    }
 
-    fn try_from(external_id: ExternalId) -> Result<Self> {
-        let v = base64::decode_config(&external_id.0[..], base64::URL_SAFE_NO_PAD)?;
-        let b = v.try_into().unwrap();
-        let i = i32::from_le_bytes(b);
-        Ok(i)
+    pub fn decode(&self, kind: EntityKind) -> Result<i32> {
+        match self.decode_any()? {
+            (Some(actual), _) if actual != kind => Err(anyhow!(
+                "Expected {:?} id but got {:?} id {}",
+                kind,
+                actual,
+                &self.0[..]
+            )),
+            (_, id) => Ok(id),
+        }
     }
 }
 
@@ -34,3 +84,15 @@ fn it_works() {
     let decoded = i64::from_le_bytes(decoded);
     assert_eq!(decoded, id);
 }
+
+#[test]
+fn it_validates_kinds() {
+    let id = -882286793;
+    let external_id = ExternalId::new(EntityKind::Track, id);
+    assert_eq!(external_id.decode(EntityKind::Track).unwrap(), id);
+    assert!(external_id.decode(EntityKind::Album).is_err());
+    let external_id = ExternalId::untyped(id);
+    assert_eq!(external_id.decode(EntityKind::Album).unwrap(), id);
+    let external_id = ExternalId(juniper::ID::from(String::from("AQID")));
+    assert!(external_id.decode(EntityKind::Track).is_err());
+}
@@ -0,0 +1,232 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use diesel::{dsl::exists, prelude::*, sql_types::Integer};
+use id3::{frame::Content, ErrorKind, Frame, Tag, Version};
+
+use crate::{
+    external_id::EntityKind,
+    models::{NewRating, NewStar},
+    schema::{albums, artists, playlists, ratings, stars, tracks},
+};
+
+fn entity_exists(conn: &SqliteConnection, kind: EntityKind, id: i32) -> Result<bool> {
+    Ok(match kind {
+        EntityKind::Album => diesel::select(exists(albums::table.find(id))).get_result(conn)?,
+        EntityKind::Artist => diesel::select(exists(artists::table.find(id))).get_result(conn)?,
+        EntityKind::Playlist => {
+            diesel::select(exists(playlists::table.find(id))).get_result(conn)?
+        }
+        EntityKind::Track => diesel::select(exists(tracks::table.find(id))).get_result(conn)?,
+        EntityKind::Genre => return Err(anyhow!("Genres can't be starred")),
+    })
+}
+
+pub fn star(conn: &SqliteConnection, username: &str, kind: EntityKind, id: i32) -> Result<()> {
+    if !entity_exists(conn, kind, id)? {
+        Err(diesel::result::Error::NotFound)?
+    }
+    diesel::insert_or_ignore_into(stars::table)
+        .values(&NewStar {
+            username: String::from(username),
+            entity_kind: kind as i32,
+            entity_id: id,
+        })
+        .execute(conn)?;
+    Ok(())
+}
+
+pub fn unstar(conn: &SqliteConnection, username: &str, kind: EntityKind, id: i32) -> Result<()> {
+    diesel::delete(stars::table.find((username, kind as i32, id))).execute(conn)?;
+    Ok(())
+}
+
+// Stars of merged entities are moved to the target before the triggers of the deleted ones remove
+// them, users who starred both keep their star of the target
+pub fn move_stars(
+    conn: &SqliteConnection,
+    kind: EntityKind,
+    source_ids: &[i32],
+    target_id: i32,
+) -> Result<()> {
+    for source_id in source_ids {
+        diesel::sql_query(
+            "UPDATE OR IGNORE stars SET entity_id = ? WHERE entity_kind = ? AND entity_id = ?",
+        )
+        .bind::<Integer, _>(target_id)
+        .bind::<Integer, _>(kind as i32)
+        .bind::<Integer, _>(source_id)
+        .execute(conn)?;
+    }
+    Ok(())
+}
+
+pub fn is_starred(
+    conn: &SqliteConnection,
+    username: &str,
+    kind: EntityKind,
+    id: i32,
+) -> Result<bool> {
+    Ok(diesel::select(exists(stars::table.find((username, kind as i32, id)))).get_result(conn)?)
+}
+
+pub fn rating(conn: &SqliteConnection, username: &str, track_id: i32) -> Result<Option<i32>> {
+    Ok(ratings::table
+        .find((username, track_id))
+        .select(ratings::rating)
+        .first(conn)
+        .optional()?)
+}
+
+pub fn set_rating(
+    conn: &SqliteConnection,
+    username: &str,
+    track_id: i32,
+    rating: Option<i32>,
+) -> Result<()> {
+    match rating {
+        Some(rating) if !(1..=5).contains(&rating) => {
+            Err(anyhow!("Rating must be between 1 and 5"))?
+        }
+        Some(rating) => {
+            if !entity_exists(conn, EntityKind::Track, track_id)? {
+                Err(diesel::result::Error::NotFound)?
+            }
+            diesel::replace_into(ratings::table)
+                .values(&NewRating {
+                    username: String::from(username),
+                    track_id,
+                    rating,
+                })
+                .execute(conn)?;
+        }
+        None => {
+            diesel::delete(ratings::table.find((username, track_id))).execute(conn)?;
+        }
+    }
+    Ok(())
+}
+
+// Popularimeter frames consist of the rater's email, a rating byte and an optional play counter.
+// The username takes the place of the email and ratings are mapped like Windows Media Player does.
+pub fn write_popm(filepath: &Path, username: &str, rating: Option<i32>) -> Result<()> {
+    let mut tag = match Tag::read_from_path(filepath) {
+        Ok(tag) => tag,
+        Err(ref e) if matches!(e.kind, ErrorKind::NoTag) => Tag::new(),
+        Err(e) => Err(e)?,
+    };
+    let mut email = username.as_bytes().to_vec();
+    email.push(0);
+    let others: Vec<Frame> = tag
+        .frames()
+        .filter(|frame| match frame.content() {
+            Content::Unknown(data) => frame.id() == "POPM" && !data.starts_with(&email),
+            _ => false,
+        })
+        .cloned()
+        .collect();
+    tag.remove("POPM");
+    for frame in others {
+        tag.add_frame(frame);
+    }
+    if let Some(rating) = rating {
+        let mut data = email;
+        data.push(match rating {
+            1 => 1,
+            2 => 64,
+            3 => 128,
+            4 => 196,
+            _ => 255,
+        });
+        tag.add_frame(Frame::with_content("POPM", Content::Unknown(data)));
+    }
+    // the tag is written to a copy which then replaces the file, so that a crash can't leave a
+    // partially written file behind
+    let dir = filepath.parent().ok_or_else(|| anyhow!("Invalid path"))?;
+    let tf = tempfile::Builder::new().prefix(".tags").tempfile_in(dir)?;
+    std::fs::copy(filepath, tf.path())?;
+    tag.write_to_path(tf.path(), Version::Id3v24)?;
+    tf.as_file().sync_all()?;
+    tf.persist(filepath)?;
+    std::fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[test]
+fn it_writes_popm_frames_per_user() {
+    let tf = tempfile::NamedTempFile::new().unwrap();
+    write_popm(tf.path(), "alice", Some(4)).unwrap();
+    write_popm(tf.path(), "bob", Some(2)).unwrap();
+    write_popm(tf.path(), "alice", Some(5)).unwrap();
+    let popms = |tag: Tag| -> Vec<Vec<u8>> {
+        tag.frames()
+            .filter(|frame| frame.id() == "POPM")
+            .map(|frame| match frame.content() {
+                Content::Unknown(data) => data.clone(),
+                _ => Vec::new(),
+            })
+            .collect()
+    };
+    let tag = Tag::read_from_path(tf.path()).unwrap();
+    assert_eq!(
+        popms(tag),
+        vec![b"bob\0\x40".to_vec(), b"alice\0\xff".to_vec()]
+    );
+    write_popm(tf.path(), "alice", None).unwrap();
+    let tag = Tag::read_from_path(tf.path()).unwrap();
+    assert_eq!(popms(tag), vec![b"bob\0\x40".to_vec()]);
+}
+
+#[test]
+fn it_removes_stars_of_deleted_entities() {
+    use diesel::connection::SimpleConnection;
+
+    use crate::db;
+
+    let dir = tempfile::tempdir().unwrap();
+    let pool = db::establish_connection(dir.path().join("pitunes.db").to_str().unwrap());
+    let conn = pool.get().unwrap();
+    conn.batch_execute(
+        "INSERT INTO albums (id, name) VALUES (1, 'Abbey Road');
+         INSERT INTO tracks (id, name, duration, album_id) VALUES (1, 'Come Together', 259000, 1);",
+    )
+    .unwrap();
+    star(&conn, "admin", EntityKind::Album, 1).unwrap();
+    star(&conn, "admin", EntityKind::Track, 1).unwrap();
+    assert!(star(&conn, "admin", EntityKind::Artist, 1).is_err());
+    diesel::delete(tracks::table.find(1))
+        .execute(&conn)
+        .unwrap();
+    assert!(!is_starred(&conn, "admin", EntityKind::Track, 1).unwrap());
+    assert!(is_starred(&conn, "admin", EntityKind::Album, 1).unwrap());
+    diesel::delete(albums::table.find(1))
+        .execute(&conn)
+        .unwrap();
+    assert!(!is_starred(&conn, "admin", EntityKind::Album, 1).unwrap());
+}
+
+#[test]
+fn it_moves_stars_of_merged_entities() {
+    use diesel::connection::SimpleConnection;
+
+    use crate::db;
+
+    let dir = tempfile::tempdir().unwrap();
+    let pool = db::establish_connection(dir.path().join("pitunes.db").to_str().unwrap());
+    let conn = pool.get().unwrap();
+    conn.batch_execute(
+        "INSERT INTO users (username, password) VALUES ('guest', x'00');
+         INSERT INTO albums (id, name) VALUES (1, 'Abbey Road'), (2, 'Abbey Road (Remaster)');",
+    )
+    .unwrap();
+    star(&conn, "admin", EntityKind::Album, 1).unwrap();
+    star(&conn, "admin", EntityKind::Album, 2).unwrap();
+    star(&conn, "guest", EntityKind::Album, 2).unwrap();
+    move_stars(&conn, EntityKind::Album, &[2], 1).unwrap();
+    diesel::delete(albums::table.find(2))
+        .execute(&conn)
+        .unwrap();
+    assert!(is_starred(&conn, "admin", EntityKind::Album, 1).unwrap());
+    assert!(is_starred(&conn, "guest", EntityKind::Album, 1).unwrap());
+    assert_eq!(stars::table.count().get_result::<i64>(&conn).unwrap(), 2);
+}
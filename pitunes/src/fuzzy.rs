@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+const FEATURING: [&str; 3] = ["feat", "ft", "featuring"];
+
+pub struct Group {
+    pub members: Vec<(i32, String)>,
+    pub confidence: f64,
+}
+
+// Reduces a name to a comparison key, e.g. "The Beatles (feat. Billy Preston)" -> "beatles"
+pub fn normalize(name: &str) -> String {
+    let name: String = name
+        .nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .map(|c| if c.is_whitespace() { ' ' } else { c })
+        .filter(|c| *c == ' ' || c.is_alphanumeric())
+        .collect();
+    let mut words: Vec<&str> = name.split_whitespace().collect();
+    if let Some(i) = words
+        .iter()
+        .skip(1)
+        .position(|word| FEATURING.contains(word))
+    {
+        words.truncate(i + 1);
+    }
+    // "The The" keeps its article so that it doesn't become "The"
+    if words.first() == Some(&"the") && words.iter().skip(1).any(|word| *word != "the") {
+        words.remove(0);
+    }
+    words.join(" ")
+}
+
+pub fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + if ca == *cb { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let len = a.chars().count().max(b.chars().count());
+    if len == 0 {
+        return 1.0;
+    }
+    1.0 - levenshtein(a, b) as f64 / len as f64
+}
+
+// Names of albums, artists or genres along with their normalized keys, so that many names can be
+// matched against them without normalizing all of them every time
+pub struct Names {
+    names: Vec<(i32, String, String)>,
+}
+
+impl Names {
+    pub fn new(names: Vec<(i32, String)>) -> Self {
+        Names {
+            names: names
+                .into_iter()
+                .map(|(id, name)| {
+                    let key = normalize(&name);
+                    (id, name, key)
+                })
+                .collect(),
+        }
+    }
+
+    pub fn insert(&mut self, id: i32, name: String) {
+        let key = normalize(&name);
+        self.names.push((id, name, key));
+    }
+
+    // Prefers an exact name match and falls back to a match on the normalized name
+    pub fn find(&self, name: &str) -> Option<i32> {
+        if let Some((id, _, _)) = self
+            .names
+            .iter()
+            .find(|(_, candidate, _)| candidate == name)
+        {
+            return Some(*id);
+        }
+        let key = normalize(name);
+        if key.is_empty() {
+            return None;
+        }
+        self.names
+            .iter()
+            .find(|(_, _, candidate)| *candidate == key)
+            .map(|(id, _, _)| *id)
+    }
+}
+
+pub fn group_duplicates(names: Vec<(i32, String)>, threshold: f64) -> Vec<Group> {
+    let mut members_by_key = BTreeMap::<String, Vec<(i32, String)>>::new();
+    for (id, name) in names {
+        let key = normalize(&name);
+        if !key.is_empty() {
+            members_by_key.entry(key).or_default().push((id, name));
+        }
+    }
+    let mut keys: Vec<(String, Vec<(i32, String)>)> = members_by_key.into_iter().collect();
+    keys.sort_by_key(|(key, _)| key.chars().count());
+    let lens: Vec<usize> = keys.iter().map(|(key, _)| key.chars().count()).collect();
+    let mut parents: Vec<usize> = (0..keys.len()).collect();
+    let mut confidences: Vec<f64> = vec![1.0; keys.len()];
+    for i in 0..keys.len() {
+        for j in i + 1..keys.len() {
+            // similarity can't reach the threshold once the length difference alone exceeds it
+            if (lens[i] as f64) < threshold * lens[j] as f64 {
+                break;
+            }
+            let confidence = similarity(&keys[i].0, &keys[j].0);
+            if confidence < threshold {
+                continue;
+            }
+            let (root_i, root_j) = (find(&mut parents, i), find(&mut parents, j));
+            let confidence = confidence.min(confidences[root_i]).min(confidences[root_j]);
+            parents[root_j] = root_i;
+            confidences[root_i] = confidence;
+        }
+    }
+    let mut groups = BTreeMap::<usize, Group>::new();
+    for (i, (_, members)) in keys.into_iter().enumerate() {
+        let root = find(&mut parents, i);
+        let group = groups.entry(root).or_insert_with(|| Group {
+            members: Vec::new(),
+            confidence: confidences[root],
+        });
+        group.members.extend(members);
+    }
+    let mut groups: Vec<Group> = groups
+        .into_values()
+        .filter(|group| group.members.len() > 1)
+        .collect();
+    for group in groups.iter_mut() {
+        group.members.sort();
+    }
+    groups.sort_by(|a, b| b.confidence.partial_cmp(&a.confidence).unwrap());
+    groups
+}
+
+fn find(parents: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    parents[i] = root;
+    root
+}
+
+#[test]
+fn it_normalizes() {
+    assert_eq!(normalize("AC/DC"), normalize("ACDC"));
+    assert_eq!(normalize("The Beatles"), "beatles");
+    assert_eq!(normalize("Beyoncé feat. Jay-Z"), "beyonce");
+    assert_eq!(normalize("The The"), "the the");
+    assert_ne!(normalize("The The"), normalize("The"));
+    let names = Names::new(vec![(1, String::from("The")), (2, String::from("The The"))]);
+    assert_eq!(names.find("the the"), Some(2));
+    assert_eq!(levenshtein("kitten", "sitting"), 3);
+    let mut names = Names::new(vec![(1, String::from("The Beatles"))]);
+    names.insert(2, String::from("AC/DC"));
+    assert_eq!(names.find("beatles"), Some(1));
+    assert_eq!(names.find("ACDC"), Some(2));
+    assert_eq!(names.find("Abba"), None);
+}
+
+#[test]
+fn it_groups_duplicates() {
+    let names = vec![
+        (1, String::from("AC/DC")),
+        (2, String::from("ACDC")),
+        (3, String::from("Metallica")),
+        (4, String::from("Metalica")),
+        (5, String::from("Abba")),
+    ];
+    let groups = group_duplicates(names, 0.85);
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups[0].confidence, 1.0);
+    assert_eq!(groups[0].members.len(), 2);
+    assert!(groups[1].confidence < 1.0);
+}
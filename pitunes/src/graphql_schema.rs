@@ -1,23 +1,33 @@
 use sha2::{Digest, Sha256};
 use std::sync::Arc;
-use std::{
-    convert::{TryFrom, TryInto},
-    path::PathBuf,
-};
+use std::{convert::TryFrom, path::PathBuf};
 
+use chrono::{NaiveDate, Utc};
 use diesel::prelude::*;
 
 use crate::{
+    check,
     db::SqlitePool,
-    external_id::ExternalId,
+    devices::Devices,
+    events::{Event, Events},
+    external_id::{EntityKind, ExternalId},
+    favorites, fuzzy, history,
     models::{
         Album, AlbumBatcher, AlbumInput, AlbumLoader, Artist, ArtistBatcher, ArtistInput,
-        ArtistLoader, Genre, GenreBatcher, GenreInput, GenreLoader, NewAlbum, NewArtist, NewGenre,
-        NewPlaylist, NewPlaylistTrack, Playlist, PlaylistInput, PlaylistTrack, PlaylistTrackInput,
-        PlaylistTrackOrderInput, Track, TrackChangeset, TrackInput, UserChangeset, UserInput,
+        ArtistLoader, Favorites, Genre, GenreBatcher, GenreInput, GenreLoader, Issue, MergeKind,
+        MergeSuggestion, NewAlbum, NewArtist, NewGenre, NewPlay, NewPlaylist, NewPlaylistTrack,
+        Node, Orphans, Play, PlayInput, PlayerState, Playlist, PlaylistInput, PlaylistTrack,
+        PlaylistTrackInput, PlaylistTrackOrderInput, Queue, QueueInput, ScrobbleInput,
+        ScrobbleService, Scrobbler, ScrobblerInput, Shuffle, ShuffleMode, SmartPlaylistInput,
+        Stats, Track, TrackChangeset, TrackInput, UserChangeset, UserInput,
+    },
+    orphans::{self, OrphanPolicy},
+    player::Player,
+    prng, queues,
+    schema::{
+        albums, artists, genres, playlists, playlists_tracks, plays, scrobblers, tracks, users,
     },
-    prng,
-    schema::{albums, artists, genres, playlists, playlists_tracks, tracks, users},
+    scrobbling, shuffle, smart_playlists, tracks_service,
 };
 
 #[derive(Clone)]
@@ -27,10 +37,27 @@ pub struct RequestContext {
     pub album_loader: AlbumLoader,
     pub artist_loader: ArtistLoader,
     pub genre_loader: GenreLoader,
+    pub orphan_policy: OrphanPolicy,
+    pub infer_plays: bool,
+    pub write_tags: bool,
+    pub events: Events,
+    pub devices: Devices,
+    pub player: Option<Player>,
+    pub username: Option<String>,
+    // the event which subscriptions are resolved against
+    pub event: Option<Event>,
 }
 
 impl RequestContext {
-    pub fn new(pool: SqlitePool, tracks_dir: PathBuf) -> RequestContext {
+    pub fn new(
+        pool: SqlitePool,
+        tracks_dir: PathBuf,
+        orphan_policy: OrphanPolicy,
+        infer_plays: bool,
+        write_tags: bool,
+        events: Events,
+        devices: Devices,
+    ) -> RequestContext {
         let pool = Arc::new(pool);
         let album_loader = AlbumLoader::new(AlbumBatcher { pool: pool.clone() });
         let artist_loader = ArtistLoader::new(ArtistBatcher { pool: pool.clone() });
@@ -41,8 +68,48 @@ impl RequestContext {
             album_loader,
             artist_loader,
             genre_loader,
+            orphan_policy,
+            infer_plays,
+            write_tags,
+            events,
+            devices,
+            player: None,
+            username: None,
+            event: None,
+        }
+    }
+
+    pub fn with_player(self, player: Option<Player>) -> RequestContext {
+        RequestContext { player, ..self }
+    }
+
+    pub fn with_username(&self, username: String) -> RequestContext {
+        RequestContext {
+            username: Some(username),
+            ..self.clone()
+        }
+    }
+
+    pub fn with_event(&self, event: Option<Event>) -> RequestContext {
+        RequestContext {
+            event,
+            ..self.clone()
         }
     }
+
+    pub fn username(&self) -> juniper::FieldResult<&str> {
+        Ok(self
+            .username
+            .as_deref()
+            .ok_or("Missing username of authenticated user")?)
+    }
+
+    pub fn player(&self) -> juniper::FieldResult<&Player> {
+        Ok(self
+            .player
+            .as_ref()
+            .ok_or("Playback is disabled, start pitunes with --sink to enable it")?)
+    }
 }
 
 // To make our context usable by Juniper, we have to implement a marker trait.
@@ -58,7 +125,7 @@ pub struct Query;
 )]
 impl Query {
     fn album(context: &RequestContext, id: juniper::ID) -> juniper::FieldResult<Album> {
-        let id: i32 = ExternalId(id).try_into()?;
+        let id: i32 = ExternalId(id).decode(EntityKind::Album)?;
         let conn = context.pool.get()?;
         Ok(albums::table.find(id).get_result(&conn)?)
     }
@@ -69,7 +136,7 @@ impl Query {
     }
 
     fn artist(context: &RequestContext, id: juniper::ID) -> juniper::FieldResult<Artist> {
-        let id: i32 = ExternalId(id).try_into()?;
+        let id: i32 = ExternalId(id).decode(EntityKind::Artist)?;
         let conn = context.pool.get()?;
         Ok(artists::table.find(id).get_result(&conn)?)
     }
@@ -80,7 +147,7 @@ impl Query {
     }
 
     fn genre(context: &RequestContext, id: juniper::ID) -> juniper::FieldResult<Genre> {
-        let id: i32 = ExternalId(id).try_into()?;
+        let id: i32 = ExternalId(id).decode(EntityKind::Genre)?;
         let conn = context.pool.get()?;
         Ok(genres::table.find(id).get_result(&conn)?)
     }
@@ -91,7 +158,7 @@ impl Query {
     }
 
     fn track(context: &RequestContext, id: juniper::ID) -> juniper::FieldResult<Track> {
-        let id: i32 = ExternalId(id).try_into()?;
+        let id: i32 = ExternalId(id).decode(EntityKind::Track)?;
         let conn = context.pool.get()?;
         Ok(tracks::table.find(id).get_result(&conn)?)
     }
@@ -102,7 +169,7 @@ impl Query {
     }
 
     fn playlist(context: &RequestContext, id: juniper::ID) -> juniper::FieldResult<Playlist> {
-        let id: i32 = ExternalId(id).try_into()?;
+        let id: i32 = ExternalId(id).decode(EntityKind::Playlist)?;
         let conn = context.pool.get()?;
         Ok(playlists::table.find(id).get_result(&conn)?)
     }
@@ -111,6 +178,176 @@ impl Query {
         let conn = context.pool.get()?;
         Ok(playlists::table.load::<Playlist>(&conn)?)
     }
+
+    fn node(context: &RequestContext, id: juniper::ID) -> juniper::FieldResult<Node> {
+        let (kind, id) = ExternalId(id).decode_any()?;
+        let conn = context.pool.get()?;
+        // untyped ids could belong to any kind of entity
+        let kinds = match kind {
+            Some(kind) => vec![kind],
+            None => vec![
+                EntityKind::Album,
+                EntityKind::Artist,
+                EntityKind::Genre,
+                EntityKind::Playlist,
+                EntityKind::Track,
+            ],
+        };
+        for kind in kinds {
+            let node = match kind {
+                EntityKind::Album => albums::table
+                    .find(id)
+                    .get_result(&conn)
+                    .optional()?
+                    .map(Node::Album),
+                EntityKind::Artist => artists::table
+                    .find(id)
+                    .get_result(&conn)
+                    .optional()?
+                    .map(Node::Artist),
+                EntityKind::Genre => genres::table
+                    .find(id)
+                    .get_result(&conn)
+                    .optional()?
+                    .map(Node::Genre),
+                EntityKind::Playlist => playlists::table
+                    .find(id)
+                    .get_result(&conn)
+                    .optional()?
+                    .map(Node::Playlist),
+                EntityKind::Track => tracks::table
+                    .find(id)
+                    .get_result(&conn)
+                    .optional()?
+                    .map(Node::Track),
+            };
+            if let Some(node) = node {
+                return Ok(node);
+            }
+        }
+        Err(diesel::result::Error::NotFound)?
+    }
+
+    fn shuffle(
+        context: &RequestContext,
+        source: juniper::ID,
+        mode: Option<ShuffleMode>,
+        seed: Option<i32>,
+    ) -> juniper::FieldResult<Shuffle> {
+        let (kind, id) = ExternalId(source).decode_any()?;
+        let kind = kind.ok_or("Shuffling requires a typed id")?;
+        let conn = context.pool.get()?;
+        Ok(shuffle::shuffle_collection(
+            &conn,
+            kind,
+            id,
+            context.username()?,
+            mode,
+            seed,
+        )?)
+    }
+
+    fn queue(context: &RequestContext) -> juniper::FieldResult<Queue> {
+        let conn = context.pool.get()?;
+        Ok(queues::find(&conn, context.username()?)?)
+    }
+
+    fn player(context: &RequestContext) -> juniper::FieldResult<PlayerState> {
+        Ok(context.player()?.state())
+    }
+
+    fn scrobblers(context: &RequestContext) -> juniper::FieldResult<Vec<Scrobbler>> {
+        let conn = context.pool.get()?;
+        Ok(scrobblers::table
+            .filter(scrobblers::username.eq(context.username()?))
+            .order(scrobblers::service.asc())
+            .load(&conn)?)
+    }
+
+    fn recently_played(
+        context: &RequestContext,
+        limit: Option<i32>,
+    ) -> juniper::FieldResult<Vec<Play>> {
+        let conn = context.pool.get()?;
+        Ok(plays::table
+            .filter(plays::username.eq(context.username()?))
+            .order(plays::started_at.desc())
+            .limit(limit.unwrap_or(50).into())
+            .load::<Play>(&conn)?)
+    }
+
+    fn stats(
+        context: &RequestContext,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+    ) -> juniper::FieldResult<Stats> {
+        Ok(Stats {
+            username: String::from(context.username()?),
+            from: from.unwrap_or_else(|| NaiveDate::from_ymd(1, 1, 1)),
+            to: to.unwrap_or_else(|| NaiveDate::from_ymd(9999, 12, 31)),
+        })
+    }
+
+    fn recap(context: &RequestContext, year: i32) -> juniper::FieldResult<Stats> {
+        Ok(Stats {
+            username: String::from(context.username()?),
+            from: NaiveDate::from_ymd_opt(year, 1, 1).ok_or("Invalid year")?,
+            to: NaiveDate::from_ymd_opt(year, 12, 31).ok_or("Invalid year")?,
+        })
+    }
+
+    fn suggested_merges(
+        context: &RequestContext,
+        threshold: Option<f64>,
+    ) -> juniper::FieldResult<Vec<MergeSuggestion>> {
+        let threshold = threshold.unwrap_or(0.85);
+        let conn = context.pool.get()?;
+        let albums = albums::table
+            .select((albums::id, albums::name))
+            .load::<(i32, String)>(&conn)?;
+        let artists = artists::table
+            .select((artists::id, artists::name))
+            .load::<(i32, String)>(&conn)?;
+        let genres = genres::table
+            .select((genres::id, genres::name))
+            .load::<(i32, String)>(&conn)?;
+        let mut merge_suggestions = Vec::new();
+        for group in fuzzy::group_duplicates(albums, threshold) {
+            merge_suggestions.push(MergeSuggestion::new(MergeKind::Album, group));
+        }
+        for group in fuzzy::group_duplicates(artists, threshold) {
+            merge_suggestions.push(MergeSuggestion::new(MergeKind::Artist, group));
+        }
+        for group in fuzzy::group_duplicates(genres, threshold) {
+            merge_suggestions.push(MergeSuggestion::new(MergeKind::Genre, group));
+        }
+        Ok(merge_suggestions)
+    }
+
+    fn favorites(context: &RequestContext) -> juniper::FieldResult<Favorites> {
+        Ok(Favorites {
+            username: String::from(context.username()?),
+        })
+    }
+
+    fn orphans(context: &RequestContext) -> juniper::FieldResult<Orphans> {
+        let conn = context.pool.get()?;
+        Ok(orphans::find_orphans(&conn)?)
+    }
+
+    // Decodes every file unless told otherwise to find undecodable audio and duration mismatches
+    fn library_check(
+        context: &RequestContext,
+        decode: Option<bool>,
+    ) -> juniper::FieldResult<Vec<Issue>> {
+        let conn = context.pool.get()?;
+        Ok(check::check(
+            &conn,
+            &context.tracks_dir,
+            false,
+            decode.unwrap_or(true),
+        )?)
+    }
 }
 
 pub struct Mutation;
@@ -120,13 +357,16 @@ impl Mutation {
     fn create_album(context: &RequestContext, input: AlbumInput) -> juniper::FieldResult<Album> {
         let conn = context.pool.get()?;
         conn.transaction::<_, juniper::FieldError, _>(|| {
-            let new_album = NewAlbum {
-                id: prng::rand_i32(&conn)?,
+            let mut new_album = NewAlbum {
+                id: 0,
                 name: input.name,
             };
-            diesel::insert_into(albums::table)
-                .values(&new_album)
-                .execute(&conn)?;
+            prng::insert_with_rand_i32(|id| {
+                new_album.id = id;
+                diesel::insert_into(albums::table)
+                    .values(&new_album)
+                    .execute(&conn)
+            })?;
             Ok(albums::table.find(new_album.id).get_result(&conn)?)
         })
     }
@@ -136,32 +376,121 @@ impl Mutation {
         id: juniper::ID,
         input: AlbumInput,
     ) -> juniper::FieldResult<Album> {
-        let id: i32 = ExternalId(id).try_into()?;
+        let id: i32 = ExternalId(id).decode(EntityKind::Album)?;
         let conn = context.pool.get()?;
-        conn.transaction::<_, juniper::FieldError, _>(|| {
+        let album: Album = conn.transaction::<_, juniper::FieldError, _>(|| {
             diesel::update(albums::table.find(id))
                 .set(&input)
                 .execute(&conn)?;
             Ok(albums::table.find(id).get_result(&conn)?)
-        })
+        })?;
+        context.events.publish(Event::AlbumUpdated(id));
+        Ok(album)
     }
 
     fn delete_album(context: &RequestContext, id: juniper::ID) -> juniper::FieldResult<bool> {
-        let id: i32 = ExternalId(id).try_into()?;
+        let id: i32 = ExternalId(id).decode(EntityKind::Album)?;
+        let conn = context.pool.get()?;
+        let deleted = diesel::delete(albums::table.find(id)).execute(&conn)? == 1;
+        if deleted {
+            context.events.publish(Event::AlbumDeleted(id));
+        }
+        Ok(deleted)
+    }
+
+    fn merge_albums(
+        context: &RequestContext,
+        source_ids: Vec<juniper::ID>,
+        target_id: juniper::ID,
+    ) -> juniper::FieldResult<Album> {
+        let target_id: i32 = ExternalId(target_id).decode(EntityKind::Album)?;
+        let source_ids = decode_ids(source_ids, EntityKind::Album, Some(target_id))?;
+        let conn = context.pool.get()?;
+        let (album, track_ids) = conn.transaction::<_, juniper::FieldError, _>(|| {
+            let album = albums::table.find(target_id).get_result::<Album>(&conn)?;
+            let existing = albums::table
+                .select(albums::id)
+                .filter(albums::id.eq_any(&source_ids))
+                .load::<i32>(&conn)?;
+            check_missing(EntityKind::Album, &source_ids, &existing)?;
+            let track_ids = tracks::table
+                .select(tracks::id)
+                .filter(tracks::album_id.eq_any(&source_ids))
+                .load::<i32>(&conn)?;
+            diesel::update(tracks::table.filter(tracks::id.eq_any(&track_ids)))
+                .set(tracks::album_id.eq(target_id))
+                .execute(&conn)?;
+            favorites::move_stars(&conn, EntityKind::Album, &source_ids, target_id)?;
+            diesel::delete(albums::table.filter(albums::id.eq_any(&source_ids))).execute(&conn)?;
+            Ok((album, track_ids))
+        })?;
+        for source_id in source_ids {
+            context.events.publish(Event::AlbumDeleted(source_id));
+        }
+        for track_id in track_ids {
+            context.events.publish(Event::TrackUpdated(track_id));
+        }
+        context.events.publish(Event::AlbumUpdated(target_id));
+        Ok(album)
+    }
+
+    fn split_album(
+        context: &RequestContext,
+        id: juniper::ID,
+        track_ids: Vec<juniper::ID>,
+        input: AlbumInput,
+    ) -> juniper::FieldResult<Album> {
+        let album_id: i32 = ExternalId(id).decode(EntityKind::Album)?;
+        let track_ids = decode_ids(track_ids, EntityKind::Track, None)?;
+        if track_ids.is_empty() {
+            Err("No tracks to split off")?;
+        }
         let conn = context.pool.get()?;
-        Ok(diesel::delete(albums::table.find(id)).execute(&conn)? == 1)
+        let album: Album = conn.transaction::<_, juniper::FieldError, _>(|| {
+            let existing = tracks::table
+                .select(tracks::id)
+                .filter(tracks::album_id.eq(album_id))
+                .filter(tracks::id.eq_any(&track_ids))
+                .load::<i32>(&conn)?;
+            check_missing(EntityKind::Track, &track_ids, &existing)?;
+            let previous = tracks::table.find(existing[0]).get_result::<Track>(&conn)?;
+            let mut new_album = NewAlbum {
+                id: 0,
+                name: input.name,
+            };
+            prng::insert_with_rand_i32(|id| {
+                new_album.id = id;
+                diesel::insert_into(albums::table)
+                    .values(&new_album)
+                    .execute(&conn)
+            })?;
+            diesel::update(tracks::table.filter(tracks::id.eq_any(&track_ids)))
+                .set(tracks::album_id.eq(new_album.id))
+                .execute(&conn)?;
+            orphans::prune_orphans_if_immediate(context.orphan_policy, &conn, &[previous])?;
+            Ok(albums::table.find(new_album.id).get_result(&conn)?)
+        })?;
+        for track_id in track_ids {
+            context.events.publish(Event::TrackUpdated(track_id));
+        }
+        context.events.publish(Event::AlbumUpdated(album_id));
+        context.events.publish(Event::AlbumUpdated(album.id));
+        Ok(album)
     }
 
     fn create_artist(context: &RequestContext, input: ArtistInput) -> juniper::FieldResult<Artist> {
         let conn = context.pool.get()?;
         conn.transaction::<_, juniper::FieldError, _>(|| {
-            let new_artist = NewArtist {
-                id: prng::rand_i32(&conn)?,
+            let mut new_artist = NewArtist {
+                id: 0,
                 name: input.name,
             };
-            diesel::insert_into(artists::table)
-                .values(&new_artist)
-                .execute(&conn)?;
+            prng::insert_with_rand_i32(|id| {
+                new_artist.id = id;
+                diesel::insert_into(artists::table)
+                    .values(&new_artist)
+                    .execute(&conn)
+            })?;
             Ok(artists::table.find(new_artist.id).get_result(&conn)?)
         })
     }
@@ -171,32 +500,78 @@ impl Mutation {
         id: juniper::ID,
         input: ArtistInput,
     ) -> juniper::FieldResult<Artist> {
-        let id: i32 = ExternalId(id).try_into()?;
+        let id: i32 = ExternalId(id).decode(EntityKind::Artist)?;
         let conn = context.pool.get()?;
-        conn.transaction::<_, juniper::FieldError, _>(|| {
+        let artist: Artist = conn.transaction::<_, juniper::FieldError, _>(|| {
             diesel::update(artists::table.find(id))
                 .set(&input)
                 .execute(&conn)?;
             Ok(artists::table.find(id).get_result(&conn)?)
-        })
+        })?;
+        context.events.publish(Event::ArtistUpdated(id));
+        Ok(artist)
     }
 
     fn delete_artist(context: &RequestContext, id: juniper::ID) -> juniper::FieldResult<bool> {
-        let id: i32 = ExternalId(id).try_into()?;
+        let id: i32 = ExternalId(id).decode(EntityKind::Artist)?;
+        let conn = context.pool.get()?;
+        let deleted = diesel::delete(artists::table.find(id)).execute(&conn)? == 1;
+        if deleted {
+            context.events.publish(Event::ArtistDeleted(id));
+        }
+        Ok(deleted)
+    }
+
+    fn merge_artists(
+        context: &RequestContext,
+        source_ids: Vec<juniper::ID>,
+        target_id: juniper::ID,
+    ) -> juniper::FieldResult<Artist> {
+        let target_id: i32 = ExternalId(target_id).decode(EntityKind::Artist)?;
+        let source_ids = decode_ids(source_ids, EntityKind::Artist, Some(target_id))?;
         let conn = context.pool.get()?;
-        Ok(diesel::delete(artists::table.find(id)).execute(&conn)? == 1)
+        let (artist, track_ids) = conn.transaction::<_, juniper::FieldError, _>(|| {
+            let artist = artists::table.find(target_id).get_result::<Artist>(&conn)?;
+            let existing = artists::table
+                .select(artists::id)
+                .filter(artists::id.eq_any(&source_ids))
+                .load::<i32>(&conn)?;
+            check_missing(EntityKind::Artist, &source_ids, &existing)?;
+            let track_ids = tracks::table
+                .select(tracks::id)
+                .filter(tracks::artist_id.eq_any(&source_ids))
+                .load::<i32>(&conn)?;
+            diesel::update(tracks::table.filter(tracks::id.eq_any(&track_ids)))
+                .set(tracks::artist_id.eq(target_id))
+                .execute(&conn)?;
+            favorites::move_stars(&conn, EntityKind::Artist, &source_ids, target_id)?;
+            diesel::delete(artists::table.filter(artists::id.eq_any(&source_ids)))
+                .execute(&conn)?;
+            Ok((artist, track_ids))
+        })?;
+        for source_id in source_ids {
+            context.events.publish(Event::ArtistDeleted(source_id));
+        }
+        for track_id in track_ids {
+            context.events.publish(Event::TrackUpdated(track_id));
+        }
+        context.events.publish(Event::ArtistUpdated(target_id));
+        Ok(artist)
     }
 
     fn create_genre(context: &RequestContext, input: GenreInput) -> juniper::FieldResult<Genre> {
         let conn = context.pool.get()?;
         conn.transaction::<_, juniper::FieldError, _>(|| {
-            let new_genre = NewGenre {
-                id: prng::rand_i32(&conn)?,
+            let mut new_genre = NewGenre {
+                id: 0,
                 name: input.name,
             };
-            diesel::insert_into(genres::table)
-                .values(&new_genre)
-                .execute(&conn)?;
+            prng::insert_with_rand_i32(|id| {
+                new_genre.id = id;
+                diesel::insert_into(genres::table)
+                    .values(&new_genre)
+                    .execute(&conn)
+            })?;
             Ok(genres::table.find(new_genre.id).get_result(&conn)?)
         })
     }
@@ -206,20 +581,62 @@ impl Mutation {
         id: juniper::ID,
         input: GenreInput,
     ) -> juniper::FieldResult<Genre> {
-        let id: i32 = ExternalId(id).try_into()?;
+        let id: i32 = ExternalId(id).decode(EntityKind::Genre)?;
         let conn = context.pool.get()?;
-        conn.transaction::<_, juniper::FieldError, _>(|| {
+        let genre: Genre = conn.transaction::<_, juniper::FieldError, _>(|| {
             diesel::update(genres::table.find(id))
                 .set(&input)
                 .execute(&conn)?;
             Ok(genres::table.find(id).get_result(&conn)?)
-        })
+        })?;
+        context.events.publish(Event::GenreUpdated(id));
+        Ok(genre)
     }
 
     fn delete_genre(context: &RequestContext, id: juniper::ID) -> juniper::FieldResult<bool> {
-        let id: i32 = ExternalId(id).try_into()?;
+        let id: i32 = ExternalId(id).decode(EntityKind::Genre)?;
+        let conn = context.pool.get()?;
+        let deleted = diesel::delete(genres::table.find(id)).execute(&conn)? == 1;
+        if deleted {
+            context.events.publish(Event::GenreDeleted(id));
+        }
+        Ok(deleted)
+    }
+
+    fn merge_genres(
+        context: &RequestContext,
+        source_ids: Vec<juniper::ID>,
+        target_id: juniper::ID,
+    ) -> juniper::FieldResult<Genre> {
+        let target_id: i32 = ExternalId(target_id).decode(EntityKind::Genre)?;
+        let source_ids = decode_ids(source_ids, EntityKind::Genre, Some(target_id))?;
         let conn = context.pool.get()?;
-        Ok(diesel::delete(genres::table.find(id)).execute(&conn)? == 1)
+        let (genre, track_ids) = conn.transaction::<_, juniper::FieldError, _>(|| {
+            let genre = genres::table.find(target_id).get_result::<Genre>(&conn)?;
+            let existing = genres::table
+                .select(genres::id)
+                .filter(genres::id.eq_any(&source_ids))
+                .load::<i32>(&conn)?;
+            check_missing(EntityKind::Genre, &source_ids, &existing)?;
+            let track_ids = tracks::table
+                .select(tracks::id)
+                .filter(tracks::genre_id.eq_any(&source_ids))
+                .load::<i32>(&conn)?;
+            diesel::update(tracks::table.filter(tracks::id.eq_any(&track_ids)))
+                .set(tracks::genre_id.eq(target_id))
+                .execute(&conn)?;
+            favorites::move_stars(&conn, EntityKind::Genre, &source_ids, target_id)?;
+            diesel::delete(genres::table.filter(genres::id.eq_any(&source_ids))).execute(&conn)?;
+            Ok((genre, track_ids))
+        })?;
+        for source_id in source_ids {
+            context.events.publish(Event::GenreDeleted(source_id));
+        }
+        for track_id in track_ids {
+            context.events.publish(Event::TrackUpdated(track_id));
+        }
+        context.events.publish(Event::GenreUpdated(target_id));
+        Ok(genre)
     }
 
     fn update_track(
@@ -227,21 +644,21 @@ impl Mutation {
         id: juniper::ID,
         input: TrackInput,
     ) -> juniper::FieldResult<Track> {
-        let id: i32 = ExternalId(id).try_into()?;
+        let id: i32 = ExternalId(id).decode(EntityKind::Track)?;
         let conn = context.pool.get()?;
-        conn.transaction::<_, juniper::FieldError, _>(|| {
+        let track: Track = conn.transaction::<_, juniper::FieldError, _>(|| {
             let album_id = if let Some(album_id) = input.album_id {
-                Some(ExternalId(album_id).try_into()?)
+                Some(ExternalId(album_id).decode(EntityKind::Album)?)
             } else {
                 None
             };
             let artist_id = if let Some(artist_id) = input.artist_id {
-                Some(ExternalId(artist_id).try_into()?)
+                Some(ExternalId(artist_id).decode(EntityKind::Artist)?)
             } else {
                 None
             };
             let genre_id = if let Some(genre_id) = input.genre_id {
-                Some(ExternalId(genre_id).try_into()?)
+                Some(ExternalId(genre_id).decode(EntityKind::Genre)?)
             } else {
                 None
             };
@@ -252,27 +669,265 @@ impl Mutation {
                 genre_id,
                 track_number: input.track_number,
             };
+            let previous = tracks::table.find(id).get_result::<Track>(&conn)?;
             diesel::update(tracks::table.find(id))
                 .set(&track_changeset)
                 .execute(&conn)?;
+            orphans::prune_orphans_if_immediate(context.orphan_policy, &conn, &[previous])?;
             Ok(tracks::table.find(id).get_result(&conn)?)
-        })
+        })?;
+        context.events.publish(Event::TrackUpdated(track.id));
+        Ok(track)
     }
 
     fn delete_track(context: &RequestContext, id: juniper::ID) -> juniper::FieldResult<bool> {
-        let filepath = {
-            let mut filepath = context.tracks_dir.clone();
-            filepath.push(&id[..]);
-            filepath.set_extension("mp3");
-            filepath
+        let id: i32 = ExternalId(id).decode(EntityKind::Track)?;
+        let filepath = tracks_service::track_filepath(&context.tracks_dir, id);
+        // the file is moved aside first so that it can be restored if the commit fails
+        let trash_filepath = filepath.with_extension("deleted");
+        let conn = context.pool.get()?;
+        let mut moved = false;
+        let result = conn.transaction::<_, juniper::FieldError, _>(|| {
+            let previous = tracks::table
+                .find(id)
+                .get_result::<Track>(&conn)
+                .optional()?;
+            let deleted = diesel::delete(tracks::table.find(id)).execute(&conn)? == 1;
+            if let Some(previous) = previous {
+                orphans::prune_orphans_if_immediate(context.orphan_policy, &conn, &[previous])?;
+            }
+            if deleted {
+                match std::fs::rename(&filepath, &trash_filepath) {
+                    Ok(()) => moved = true,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => Err(e)?,
+                }
+            }
+            Ok(deleted)
+        });
+        match result {
+            Ok(deleted) => {
+                if moved {
+                    std::fs::remove_file(trash_filepath)?;
+                }
+                if deleted {
+                    context.events.publish(Event::TrackDeleted(id));
+                }
+                Ok(deleted)
+            }
+            Err(e) => {
+                if moved {
+                    std::fs::rename(trash_filepath, filepath)?;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn prune_orphans(
+        context: &RequestContext,
+        dry_run: Option<bool>,
+    ) -> juniper::FieldResult<Orphans> {
+        let conn = context.pool.get()?;
+        if dry_run.unwrap_or(false) {
+            Ok(orphans::find_orphans(&conn)?)
+        } else {
+            Ok(orphans::prune_orphans(&conn)?)
+        }
+    }
+
+    fn record_play(context: &RequestContext, input: PlayInput) -> juniper::FieldResult<Play> {
+        let conn = context.pool.get()?;
+        let new_play = NewPlay {
+            username: String::from(context.username()?),
+            track_id: ExternalId(input.track_id).decode(EntityKind::Track)?,
+            started_at: input.started_at.unwrap_or_else(|| Utc::now().naive_utc()),
+            duration: input.duration,
+            client: input.client,
         };
-        let id: i32 = ExternalId(id).try_into()?;
+        Ok(history::record_play(&conn, &new_play)?)
+    }
+
+    // Records a play like recordPlay and queues it for the user's scrobblers. Now playing
+    // updates are only queued and return null.
+    fn scrobble(
+        context: &RequestContext,
+        input: ScrobbleInput,
+    ) -> juniper::FieldResult<Option<Play>> {
+        let username = context.username()?;
+        let track_id = ExternalId(input.track_id).decode(EntityKind::Track)?;
+        let started_at = input.started_at.unwrap_or_else(|| Utc::now().naive_utc());
         let conn = context.pool.get()?;
-        let deleted = diesel::delete(tracks::table.find(id)).execute(&conn)? == 1;
-        if deleted {
-            std::fs::remove_file(filepath)?;
+        let track: Track = tracks::table.find(track_id).get_result(&conn)?;
+        let duration = input.duration.unwrap_or(track.duration);
+        let client = input.client;
+        if input.now_playing.unwrap_or(false) {
+            scrobbling::enqueue(&conn, username, track_id, started_at, duration, true)?;
+            return Ok(None);
         }
-        Ok(deleted)
+        conn.transaction::<_, juniper::FieldError, _>(|| {
+            let play = history::record_play(
+                &conn,
+                &NewPlay {
+                    username: String::from(username),
+                    track_id,
+                    started_at,
+                    duration,
+                    client,
+                },
+            )?;
+            scrobbling::enqueue(&conn, username, track_id, started_at, duration, false)?;
+            Ok(Some(play))
+        })
+    }
+
+    fn update_scrobbler(
+        context: &RequestContext,
+        input: ScrobblerInput,
+    ) -> juniper::FieldResult<Scrobbler> {
+        let conn = context.pool.get()?;
+        Ok(scrobbling::update_scrobbler(
+            &conn,
+            context.username()?,
+            input,
+        )?)
+    }
+
+    fn delete_scrobbler(
+        context: &RequestContext,
+        service: ScrobbleService,
+    ) -> juniper::FieldResult<bool> {
+        let conn = context.pool.get()?;
+        Ok(scrobbling::delete_scrobbler(
+            &conn,
+            context.username()?,
+            service,
+        )?)
+    }
+
+    fn star(context: &RequestContext, id: juniper::ID) -> juniper::FieldResult<bool> {
+        let (kind, id) = ExternalId(id).decode_any()?;
+        let kind = kind.ok_or("Starring requires a typed id")?;
+        let conn = context.pool.get()?;
+        let username = context.username()?;
+        favorites::star(&conn, username, kind, id)?;
+        context
+            .events
+            .publish(Event::StarChanged(String::from(username), kind, id));
+        Ok(true)
+    }
+
+    fn unstar(context: &RequestContext, id: juniper::ID) -> juniper::FieldResult<bool> {
+        let (kind, id) = ExternalId(id).decode_any()?;
+        let kind = kind.ok_or("Unstarring requires a typed id")?;
+        let conn = context.pool.get()?;
+        let username = context.username()?;
+        favorites::unstar(&conn, username, kind, id)?;
+        context
+            .events
+            .publish(Event::StarChanged(String::from(username), kind, id));
+        Ok(false)
+    }
+
+    fn set_rating(
+        context: &RequestContext,
+        id: juniper::ID,
+        rating: Option<i32>,
+    ) -> juniper::FieldResult<Track> {
+        let id = ExternalId(id).decode(EntityKind::Track)?;
+        let username = context.username()?;
+        let conn = context.pool.get()?;
+        let track: Track = conn.transaction::<_, juniper::FieldError, _>(|| {
+            favorites::set_rating(&conn, username, id, rating)?;
+            if context.write_tags {
+                let filepath = tracks_service::track_filepath(&context.tracks_dir, id);
+                favorites::write_popm(&filepath, username, rating)?;
+            }
+            Ok(tracks::table.find(id).get_result(&conn)?)
+        })?;
+        context
+            .events
+            .publish(Event::RatingChanged(String::from(username), id));
+        Ok(track)
+    }
+
+    fn update_queue(context: &RequestContext, input: QueueInput) -> juniper::FieldResult<Queue> {
+        let username = context.username()?;
+        let conn = context.pool.get()?;
+        let queue = queues::update(&conn, username, input)?;
+        context
+            .events
+            .publish(Event::QueueChanged(String::from(username)));
+        if let Some(player) = &context.player {
+            player.reload(username)?;
+        }
+        Ok(queue)
+    }
+
+    fn clear_queue(context: &RequestContext) -> juniper::FieldResult<Queue> {
+        let username = context.username()?;
+        let conn = context.pool.get()?;
+        let queue = queues::clear(&conn, username)?;
+        context
+            .events
+            .publish(Event::QueueChanged(String::from(username)));
+        if let Some(player) = &context.player {
+            player.reload(username)?;
+        }
+        Ok(queue)
+    }
+
+    // Plays the queue of the user on the server-side player. The queue is replaced if track ids
+    // are given, otherwise it resumes.
+    fn play(
+        context: &RequestContext,
+        track_ids: Option<Vec<juniper::ID>>,
+        index: Option<i32>,
+    ) -> juniper::FieldResult<PlayerState> {
+        let username = context.username()?;
+        let player = context.player()?;
+        let track_ids = match track_ids {
+            Some(track_ids) => Some(
+                track_ids
+                    .into_iter()
+                    .map(|id| ExternalId(id).decode(EntityKind::Track))
+                    .collect::<anyhow::Result<Vec<i32>>>()?,
+            ),
+            None => None,
+        };
+        let len = match &track_ids {
+            Some(track_ids) => track_ids.len(),
+            None => queues::track_ids(&*context.pool.get()?, username)?.len(),
+        };
+        let index = match index {
+            Some(index) if index < 0 || index as usize >= len => Err("Index out of range")?,
+            Some(index) => Some(index as usize),
+            None => None,
+        };
+        Ok(player.play(username, track_ids, index)?)
+    }
+
+    fn pause(context: &RequestContext) -> juniper::FieldResult<PlayerState> {
+        Ok(context.player()?.pause())
+    }
+
+    fn stop(context: &RequestContext) -> juniper::FieldResult<PlayerState> {
+        Ok(context.player()?.stop())
+    }
+
+    // Skips forward by default, negative offsets skip backwards
+    fn skip(context: &RequestContext, offset: Option<i32>) -> juniper::FieldResult<PlayerState> {
+        Ok(context.player()?.skip(offset.unwrap_or(1)))
+    }
+
+    // Position within the current track in milliseconds
+    fn seek(context: &RequestContext, position: i32) -> juniper::FieldResult<PlayerState> {
+        Ok(context.player()?.seek(position))
+    }
+
+    // Volume between 0 and 1
+    fn set_volume(context: &RequestContext, volume: f64) -> juniper::FieldResult<PlayerState> {
+        Ok(context.player()?.set_volume(volume))
     }
 
     fn create_playlist(
@@ -280,16 +935,22 @@ impl Mutation {
         input: PlaylistInput,
     ) -> juniper::FieldResult<Playlist> {
         let conn = context.pool.get()?;
-        conn.transaction::<_, juniper::FieldError, _>(|| {
-            let new_playlist = NewPlaylist {
-                id: prng::rand_i32(&conn)?,
+        let playlist: Playlist = conn.transaction::<_, juniper::FieldError, _>(|| {
+            let mut new_playlist = NewPlaylist {
+                id: 0,
                 name: input.name,
+                rules: None,
             };
-            diesel::insert_into(playlists::table)
-                .values(&new_playlist)
-                .execute(&conn)?;
+            prng::insert_with_rand_i32(|id| {
+                new_playlist.id = id;
+                diesel::insert_into(playlists::table)
+                    .values(&new_playlist)
+                    .execute(&conn)
+            })?;
             Ok(playlists::table.find(new_playlist.id).get_result(&conn)?)
-        })
+        })?;
+        context.events.publish(Event::PlaylistChanged(playlist.id));
+        Ok(playlist)
     }
 
     fn update_playlist(
@@ -297,20 +958,88 @@ impl Mutation {
         id: juniper::ID,
         input: PlaylistInput,
     ) -> juniper::FieldResult<Playlist> {
-        let id: i32 = ExternalId(id).try_into()?;
+        let id: i32 = ExternalId(id).decode(EntityKind::Playlist)?;
         let conn = context.pool.get()?;
-        conn.transaction::<_, juniper::FieldError, _>(|| {
+        let playlist: Playlist = conn.transaction::<_, juniper::FieldError, _>(|| {
             diesel::update(playlists::table.find(id))
                 .set(&input)
                 .execute(&conn)?;
             Ok(playlists::table.find(id).get_result(&conn)?)
-        })
+        })?;
+        context.events.publish(Event::PlaylistChanged(playlist.id));
+        Ok(playlist)
+    }
+
+    fn create_smart_playlist(
+        context: &RequestContext,
+        input: SmartPlaylistInput,
+        snapshot: Option<bool>,
+    ) -> juniper::FieldResult<Playlist> {
+        let username = context.username()?;
+        smart_playlists::compile(&input.rules, username)?;
+        let conn = context.pool.get()?;
+        let playlist: Playlist = conn.transaction::<_, juniper::FieldError, _>(|| {
+            let mut new_playlist = NewPlaylist {
+                id: 0,
+                name: input.name,
+                rules: Some(input.rules),
+            };
+            prng::insert_with_rand_i32(|id| {
+                new_playlist.id = id;
+                diesel::insert_into(playlists::table)
+                    .values(&new_playlist)
+                    .execute(&conn)
+            })?;
+            if snapshot.unwrap_or(false) {
+                smart_playlists::snapshot(&conn, new_playlist.id, username)?;
+            }
+            Ok(playlists::table.find(new_playlist.id).get_result(&conn)?)
+        })?;
+        context.events.publish(Event::PlaylistChanged(playlist.id));
+        Ok(playlist)
+    }
+
+    fn update_smart_playlist(
+        context: &RequestContext,
+        id: juniper::ID,
+        input: SmartPlaylistInput,
+        snapshot: Option<bool>,
+    ) -> juniper::FieldResult<Playlist> {
+        let id: i32 = ExternalId(id).decode(EntityKind::Playlist)?;
+        let username = context.username()?;
+        smart_playlists::compile(&input.rules, username)?;
+        let conn = context.pool.get()?;
+        let playlist: Playlist = conn.transaction::<_, juniper::FieldError, _>(|| {
+            let rules: Option<String> = playlists::table
+                .find(id)
+                .select(playlists::rules)
+                .get_result(&conn)?;
+            if rules.is_none() {
+                Err("Only smart playlists have rules")?;
+            }
+            diesel::update(playlists::table.find(id))
+                .set(&input)
+                .execute(&conn)?;
+            // Smart playlists don't keep tracks of their own
+            diesel::delete(playlists_tracks::table.filter(playlists_tracks::playlist_id.eq(id)))
+                .execute(&conn)?;
+            if snapshot.unwrap_or(false) {
+                smart_playlists::snapshot(&conn, id, username)?;
+            }
+            Ok(playlists::table.find(id).get_result(&conn)?)
+        })?;
+        context.events.publish(Event::PlaylistChanged(playlist.id));
+        Ok(playlist)
     }
 
     fn delete_playlist(context: &RequestContext, id: juniper::ID) -> juniper::FieldResult<bool> {
-        let id: i32 = ExternalId(id).try_into()?;
+        let id: i32 = ExternalId(id).decode(EntityKind::Playlist)?;
         let conn = context.pool.get()?;
-        Ok(diesel::delete(playlists::table.find(id)).execute(&conn)? == 1)
+        let deleted = diesel::delete(playlists::table.find(id)).execute(&conn)? == 1;
+        if deleted {
+            context.events.publish(Event::PlaylistDeleted(id));
+        }
+        Ok(deleted)
     }
 
     fn create_playlist_track(
@@ -318,9 +1047,16 @@ impl Mutation {
         id: juniper::ID, // playlist_id
         input: PlaylistTrackInput,
     ) -> juniper::FieldResult<Playlist> {
-        let playlist_id: i32 = ExternalId(id).try_into()?;
+        let playlist_id: i32 = ExternalId(id).decode(EntityKind::Playlist)?;
         let conn = context.pool.get()?;
-        conn.transaction::<_, juniper::FieldError, _>(|| {
+        let playlist: Playlist = conn.transaction::<_, juniper::FieldError, _>(|| {
+            let rules: Option<String> = playlists::table
+                .find(playlist_id)
+                .select(playlists::rules)
+                .get_result(&conn)?;
+            if rules.is_some() {
+                Err("Tracks of smart playlists are determined by their rules")?;
+            }
             let count: i64 = playlists_tracks::table
                 .filter(playlists_tracks::playlist_id.eq(playlist_id))
                 .count()
@@ -344,7 +1080,7 @@ impl Mutation {
                 .execute(&conn)?;
             }
             let new_playlist_track = NewPlaylistTrack {
-                track_id: ExternalId(playlist_track_input.track_id).try_into()?,
+                track_id: ExternalId(playlist_track_input.track_id).decode(EntityKind::Track)?,
                 position: playlist_track_input.position,
             };
             diesel::insert_into(playlists_tracks::table)
@@ -354,7 +1090,9 @@ impl Mutation {
                 ))
                 .execute(&conn)?;
             Ok(playlists::table.find(playlist_id).get_result(&conn)?)
-        })
+        })?;
+        context.events.publish(Event::PlaylistChanged(playlist.id));
+        Ok(playlist)
     }
 
     fn update_playlist_track(
@@ -362,9 +1100,9 @@ impl Mutation {
         id: juniper::ID, // playlist_id
         input: PlaylistTrackOrderInput,
     ) -> juniper::FieldResult<Playlist> {
-        let playlist_id: i32 = ExternalId(id).try_into()?;
+        let playlist_id: i32 = ExternalId(id).decode(EntityKind::Playlist)?;
         let conn = context.pool.get()?;
-        conn.transaction::<_, juniper::FieldError, _>(|| {
+        let playlist: Playlist = conn.transaction::<_, juniper::FieldError, _>(|| {
             let range_start = usize::try_from(input.range_start)?;
             let range_length = usize::try_from(input.range_length.unwrap_or(1))?;
             if range_length < 1 {
@@ -404,7 +1142,9 @@ impl Mutation {
                 }
             }
             Ok(playlists::table.find(playlist_id).get_result(&conn)?)
-        })
+        })?;
+        context.events.publish(Event::PlaylistChanged(playlist.id));
+        Ok(playlist)
     }
 
     fn delete_playlist_track(
@@ -412,11 +1152,11 @@ impl Mutation {
         id: juniper::ID, // playlist_id
         input: PlaylistTrackInput,
     ) -> juniper::FieldResult<Playlist> {
-        let playlist_id: i32 = ExternalId(id).try_into()?;
+        let playlist_id: i32 = ExternalId(id).decode(EntityKind::Playlist)?;
         let conn = context.pool.get()?;
-        conn.transaction::<_, juniper::FieldError, _>(|| {
+        let playlist: Playlist = conn.transaction::<_, juniper::FieldError, _>(|| {
             let new_playlist_track = NewPlaylistTrack {
-                track_id: ExternalId(input.track_id).try_into()?,
+                track_id: ExternalId(input.track_id).decode(EntityKind::Track)?,
                 position: input.position,
             };
             let deleted = if let Some(position) = new_playlist_track.position {
@@ -453,7 +1193,22 @@ impl Mutation {
                 }
             }
             Ok(playlists::table.find(playlist_id).get_result(&conn)?)
-        })
+        })?;
+        context.events.publish(Event::PlaylistChanged(playlist.id));
+        Ok(playlist)
+    }
+
+    fn repair_library(
+        context: &RequestContext,
+        decode: Option<bool>,
+    ) -> juniper::FieldResult<Vec<Issue>> {
+        let conn = context.pool.get()?;
+        Ok(check::check(
+            &conn,
+            &context.tracks_dir,
+            true,
+            decode.unwrap_or(true),
+        )?)
     }
 
     fn update_user(
@@ -472,10 +1227,284 @@ impl Mutation {
             Ok(true)
         })
     }
+
+    // Subsonic clients authenticate with this password, which is stored in plain text.
+    // It should differ from the regular password. Omitting it disables Subsonic access.
+    fn update_subsonic_password(
+        context: &RequestContext,
+        username: String,
+        password: Option<String>,
+    ) -> juniper::FieldResult<bool> {
+        let conn = context.pool.get()?;
+        let updated = diesel::update(users::table.find(username))
+            .set(users::subsonic_password.eq(password))
+            .execute(&conn)?;
+        Ok(updated == 1)
+    }
 }
 
 pub type Schema = juniper::RootNode<'static, Query, Mutation>;
 
+// juniper can't execute subscription operations, so every published event is resolved against
+// the Subscription root as if it was a query, with the event in the context. Fields which don't match the event resolve to null.
+pub struct Subscription;
+
+#[juniper::object(Context = RequestContext)]
+impl Subscription {
+    fn track_added(&self, context: &RequestContext) -> juniper::FieldResult<Option<Track>> {
+        match context.event {
+            Some(Event::TrackAdded(id)) => {
+                let conn = context.pool.get()?;
+                Ok(tracks::table.find(id).get_result(&conn).optional()?)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn track_updated(&self, context: &RequestContext) -> juniper::FieldResult<Option<Track>> {
+        match context.event {
+            Some(Event::TrackUpdated(id)) => {
+                let conn = context.pool.get()?;
+                Ok(tracks::table.find(id).get_result(&conn).optional()?)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn track_deleted(&self, context: &RequestContext) -> Option<juniper::ID> {
+        match context.event {
+            Some(Event::TrackDeleted(id)) => Some(ExternalId::new(EntityKind::Track, id).0),
+            _ => None,
+        }
+    }
+
+    fn album_updated(&self, context: &RequestContext) -> juniper::FieldResult<Option<Album>> {
+        match context.event {
+            Some(Event::AlbumUpdated(id)) => {
+                let conn = context.pool.get()?;
+                Ok(albums::table.find(id).get_result(&conn).optional()?)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn album_deleted(&self, context: &RequestContext) -> Option<juniper::ID> {
+        match context.event {
+            Some(Event::AlbumDeleted(id)) => Some(ExternalId::new(EntityKind::Album, id).0),
+            _ => None,
+        }
+    }
+
+    fn artist_updated(&self, context: &RequestContext) -> juniper::FieldResult<Option<Artist>> {
+        match context.event {
+            Some(Event::ArtistUpdated(id)) => {
+                let conn = context.pool.get()?;
+                Ok(artists::table.find(id).get_result(&conn).optional()?)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn artist_deleted(&self, context: &RequestContext) -> Option<juniper::ID> {
+        match context.event {
+            Some(Event::ArtistDeleted(id)) => Some(ExternalId::new(EntityKind::Artist, id).0),
+            _ => None,
+        }
+    }
+
+    fn genre_updated(&self, context: &RequestContext) -> juniper::FieldResult<Option<Genre>> {
+        match context.event {
+            Some(Event::GenreUpdated(id)) => {
+                let conn = context.pool.get()?;
+                Ok(genres::table.find(id).get_result(&conn).optional()?)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn genre_deleted(&self, context: &RequestContext) -> Option<juniper::ID> {
+        match context.event {
+            Some(Event::GenreDeleted(id)) => Some(ExternalId::new(EntityKind::Genre, id).0),
+            _ => None,
+        }
+    }
+
+    // The id of the album, artist, playlist or track which was starred or unstarred
+    fn star_changed(&self, context: &RequestContext) -> juniper::FieldResult<Option<juniper::ID>> {
+        match &context.event {
+            Some(Event::StarChanged(username, kind, id)) if username == context.username()? => {
+                Ok(Some(ExternalId::new(*kind, *id).0))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn rating_changed(&self, context: &RequestContext) -> juniper::FieldResult<Option<Track>> {
+        match &context.event {
+            Some(Event::RatingChanged(username, id)) if username == context.username()? => {
+                let conn = context.pool.get()?;
+                Ok(tracks::table.find(id).get_result(&conn).optional()?)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn playlist_changed(&self, context: &RequestContext) -> juniper::FieldResult<Option<Playlist>> {
+        match context.event {
+            Some(Event::PlaylistChanged(id)) => {
+                let conn = context.pool.get()?;
+                Ok(playlists::table.find(id).get_result(&conn).optional()?)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn playlist_deleted(&self, context: &RequestContext) -> Option<juniper::ID> {
+        match context.event {
+            Some(Event::PlaylistDeleted(id)) => Some(ExternalId::new(EntityKind::Playlist, id).0),
+            _ => None,
+        }
+    }
+
+    // Only the owner of a queue is notified about its changes
+    fn queue_changed(&self, context: &RequestContext) -> juniper::FieldResult<Option<Queue>> {
+        match &context.event {
+            Some(Event::QueueChanged(username)) if username == context.username()? => {
+                let conn = context.pool.get()?;
+                Ok(Some(queues::find(&conn, username)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn player_changed(
+        &self,
+        context: &RequestContext,
+    ) -> juniper::FieldResult<Option<PlayerState>> {
+        match context.event {
+            Some(Event::PlayerChanged) => Ok(Some(context.player()?.state())),
+            _ => Ok(None),
+        }
+    }
+}
+
+pub type SubscriptionSchema =
+    juniper::RootNode<'static, Subscription, juniper::EmptyMutation<RequestContext>>;
+
+pub fn create_subscription_schema() -> SubscriptionSchema {
+    SubscriptionSchema::new(Subscription, juniper::EmptyMutation::new())
+}
+
 pub fn create_schema() -> Schema {
     Schema::new(Query {}, Mutation {})
 }
+
+// Decodes the ids of a merge or split, dropping duplicates and the target of a merge
+fn decode_ids(
+    ids: Vec<juniper::ID>,
+    kind: EntityKind,
+    target_id: Option<i32>,
+) -> juniper::FieldResult<Vec<i32>> {
+    let mut ids = ids
+        .into_iter()
+        .map(|id| ExternalId(id).decode(kind))
+        .collect::<Result<Vec<i32>, _>>()?;
+    ids.retain(|id| Some(*id) != target_id);
+    ids.sort_unstable();
+    ids.dedup();
+    Ok(ids)
+}
+
+fn check_missing(kind: EntityKind, ids: &[i32], existing: &[i32]) -> juniper::FieldResult<()> {
+    match ids.iter().find(|id| !existing.contains(id)) {
+        Some(id) => Err(format!(
+            "{:?} {} not found",
+            kind,
+            &ExternalId::new(kind, *id).0[..]
+        ))?,
+        None => Ok(()),
+    }
+}
+
+#[test]
+fn it_merges_and_splits_albums() {
+    use diesel::connection::SimpleConnection;
+
+    use crate::db;
+
+    let dir = tempfile::tempdir().unwrap();
+    let pool = db::establish_connection(dir.path().join("pitunes.db").to_str().unwrap());
+    pool.get()
+        .unwrap()
+        .batch_execute(
+            "INSERT INTO albums (id, name) VALUES (1, 'Kind of Blue'), (2, 'Kind Of Blue');
+             INSERT INTO tracks (id, name, duration, album_id) VALUES
+                 (1, 'So What', 562000, 1),
+                 (2, 'Blue in Green', 337000, 2),
+                 (3, 'Flamenco Sketches', 566000, 2);",
+        )
+        .unwrap();
+    let context = RequestContext::new(
+        pool.clone(),
+        dir.path().to_path_buf(),
+        OrphanPolicy::Never,
+        false,
+        false,
+        Events::default(),
+        Devices::default(),
+    );
+    let schema = create_schema();
+    let execute = |query: String| {
+        juniper::execute(&query, None, &schema, &juniper::Variables::new(), &context).unwrap()
+    };
+    let album = |id: i32| ExternalId::new(EntityKind::Album, id).0.to_string();
+    let track = |id: i32| ExternalId::new(EntityKind::Track, id).0.to_string();
+    let album_ids = || -> Vec<Option<i32>> {
+        tracks::table
+            .select(tracks::album_id)
+            .order(tracks::id)
+            .load(&pool.get().unwrap())
+            .unwrap()
+    };
+
+    let (_, errors) = execute(format!(
+        r#"mutation {{ mergeAlbums(sourceIds: ["{0}", "{0}", "{1}"], targetId: "{1}") {{ id }} }}"#,
+        album(2),
+        album(1)
+    ));
+    assert!(errors.is_empty());
+    assert_eq!(album_ids(), vec![Some(1), Some(1), Some(1)]);
+
+    let (_, errors) = execute(format!(
+        r#"mutation {{ mergeAlbums(sourceIds: ["{}"], targetId: "{}") {{ id }} }}"#,
+        album(3),
+        album(1)
+    ));
+    assert_eq!(
+        errors[0].error().message(),
+        format!("Album {} not found", album(3))
+    );
+
+    let (_, errors) = execute(format!(
+        r#"mutation {{ splitAlbum(id: "{}", trackIds: ["{}", "{}"], input: {{ name: "Sketches" }}) {{ id }} }}"#,
+        album(1),
+        track(3),
+        track(4)
+    ));
+    assert_eq!(
+        errors[0].error().message(),
+        format!("Track {} not found", track(4))
+    );
+    assert_eq!(album_ids(), vec![Some(1), Some(1), Some(1)]);
+
+    let (_, errors) = execute(format!(
+        r#"mutation {{ splitAlbum(id: "{}", trackIds: ["{}", "{}"], input: {{ name: "Sketches" }}) {{ id }} }}"#,
+        album(1),
+        track(3),
+        track(3)
+    ));
+    assert!(errors.is_empty());
+    let album_ids = album_ids();
+    assert_eq!(album_ids[..2], [Some(1), Some(1)]);
+    assert_ne!(album_ids[2], Some(1));
+}
@@ -1,16 +1,28 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use actix_web::{web, Error, HttpResponse};
-use juniper::http::GraphQLRequest;
+use actix::{
+    Actor, ActorContext, ActorFuture, AsyncContext, SpawnHandle, StreamHandler, WrapFuture,
+};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use futures::{Future, StreamExt};
+use juniper::{http::GraphQLRequest, InputValue};
+use serde_json::json;
 
-use crate::graphql_schema::{RequestContext, Schema};
+use crate::{
+    events::Event,
+    graphql_schema::{RequestContext, Schema, SubscriptionSchema},
+};
 
 #[post("/graphql")]
 async fn graphql(
     st: web::Data<Arc<Schema>>,
     ctx: web::Data<RequestContext>,
+    credentials: BasicAuth,
     data: web::Json<GraphQLRequest>,
 ) -> Result<HttpResponse, Error> {
+    let ctx = ctx.with_username(String::from(credentials.user_id().as_ref()));
     let json = web::block(move || {
         let res = data.execute(&st, &ctx);
         Ok::<_, serde_json::error::Error>(serde_json::to_string(&res)?)
@@ -20,3 +32,185 @@ async fn graphql(
         .content_type("application/json")
         .body(json))
 }
+
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+// Speaks the graphql-ws protocol of subscriptions-transport-ws
+struct GraphQLWebSocket {
+    schema: Arc<SubscriptionSchema>,
+    context: RequestContext,
+    operations: HashMap<String, (Arc<GraphQLRequest>, SpawnHandle)>,
+    // whether connection_init was acknowledged, which starts the keep-alive messages
+    initialized: bool,
+}
+
+impl GraphQLWebSocket {
+    fn send(&self, ctx: &mut ws::WebsocketContext<Self>, message: serde_json::Value) {
+        ctx.text(message.to_string());
+    }
+
+    fn connection_error(&self, ctx: &mut ws::WebsocketContext<Self>, message: &str) {
+        self.send(
+            ctx,
+            json!({ "type": "connection_error", "payload": { "message": message } }),
+        );
+    }
+
+    // Resolvers hit the database, so they run on the thread pool like those of queries do
+    fn execute(
+        &self,
+        request: Arc<GraphQLRequest>,
+        event: Option<Event>,
+    ) -> impl Future<Output = serde_json::Value> {
+        let schema = self.schema.clone();
+        let context = self.context.with_event(event);
+        async move {
+            web::block(move || serde_json::to_value(&request.execute(&schema, &context)))
+                .await
+                .unwrap_or(serde_json::Value::Null)
+        }
+    }
+
+    fn start(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        id: String,
+        payload: &serde_json::Value,
+    ) -> Result<(), serde_json::Value> {
+        // subscription operations are executed like queries against the Subscription root
+        let query = payload["query"]
+            .as_str()
+            .map(str::trim_start)
+            .and_then(|query| query.strip_prefix("subscription"))
+            .ok_or_else(|| json!({ "message": "Only subscriptions are supported" }))?;
+        let operation_name = payload["operationName"].as_str().map(String::from);
+        let variables = serde_json::from_value::<Option<InputValue>>(payload["variables"].clone())
+            .map_err(|e| json!({ "message": e.to_string() }))?;
+        let request = Arc::new(GraphQLRequest::new(
+            format!("query{}", query),
+            operation_name,
+            variables,
+        ));
+        // the operation is validated by executing it without an event, other messages wait for it
+        let validation = self.execute(request.clone(), None).into_actor(self);
+        ctx.wait(validation.map(move |response, act, ctx| {
+            if let Some(errors) = response.get("errors") {
+                return act.send(ctx, json!({ "type": "error", "id": id, "payload": errors }));
+            }
+            let operation_id = id.clone();
+            let events = act
+                .context
+                .events
+                .subscribe()
+                .map(move |event| (operation_id.clone(), event));
+            let handle = ctx.add_stream(events);
+            if let Some((_, handle)) = act.operations.insert(id, (request, handle)) {
+                ctx.cancel_future(handle);
+            }
+        }));
+        Ok(())
+    }
+}
+
+impl Actor for GraphQLWebSocket {
+    type Context = ws::WebsocketContext<Self>;
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for GraphQLWebSocket {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let text = match msg {
+            Ok(ws::Message::Text(text)) => text,
+            Ok(ws::Message::Ping(bytes)) => return ctx.pong(&bytes),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                return ctx.stop();
+            }
+            Ok(_) => return,
+            Err(_) => return ctx.stop(),
+        };
+        let message: serde_json::Value = match serde_json::from_str(&text) {
+            Ok(message) => message,
+            Err(e) => return self.connection_error(ctx, &e.to_string()),
+        };
+        let id = message["id"].as_str().map(String::from);
+        match (message["type"].as_str(), id) {
+            (Some("connection_init"), _) if self.initialized => {
+                self.connection_error(ctx, "Already initialized")
+            }
+            (Some("connection_init"), _) => {
+                self.initialized = true;
+                self.send(ctx, json!({ "type": "connection_ack" }));
+                self.send(ctx, json!({ "type": "ka" }));
+                ctx.run_interval(KEEP_ALIVE_INTERVAL, |act, ctx| {
+                    act.send(ctx, json!({ "type": "ka" }))
+                });
+            }
+            (Some("start"), _) if !self.initialized => {
+                self.connection_error(ctx, "Not initialized")
+            }
+            (Some("start"), Some(id)) => {
+                if let Err(payload) = self.start(ctx, id.clone(), &message["payload"]) {
+                    self.send(
+                        ctx,
+                        json!({ "type": "error", "id": id, "payload": payload }),
+                    );
+                }
+            }
+            (Some("stop"), Some(id)) => {
+                if let Some((_, handle)) = self.operations.remove(&id) {
+                    ctx.cancel_future(handle);
+                }
+                self.send(ctx, json!({ "type": "complete", "id": id }));
+            }
+            (Some("connection_terminate"), _) => ctx.stop(),
+            _ => self.connection_error(ctx, "Unknown message"),
+        }
+    }
+}
+
+impl StreamHandler<(String, Event)> for GraphQLWebSocket {
+    fn handle(&mut self, (id, event): (String, Event), ctx: &mut Self::Context) {
+        if let Some((request, _)) = self.operations.get(&id) {
+            // waiting for the response keeps the events in order
+            let response = self.execute(request.clone(), Some(event)).into_actor(self);
+            ctx.wait(response.map(move |response, act, ctx| {
+                // events which don't concern the subscription resolve to null
+                let matched = match response["data"].as_object() {
+                    Some(data) => data.values().any(|value| !value.is_null()),
+                    None => true,
+                };
+                if matched && act.operations.contains_key(&id) {
+                    act.send(
+                        ctx,
+                        json!({ "type": "data", "id": id, "payload": response }),
+                    );
+                }
+            }));
+        }
+    }
+
+    // the socket stays open when a subscription ends
+    fn finished(&mut self, _ctx: &mut Self::Context) {}
+}
+
+#[get("/graphql")]
+async fn graphql_ws(
+    schema: web::Data<Arc<SubscriptionSchema>>,
+    ctx: web::Data<RequestContext>,
+    credentials: BasicAuth,
+    req: HttpRequest,
+    stream: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let context = ctx.with_username(String::from(credentials.user_id().as_ref()));
+    ws::start_with_protocols(
+        GraphQLWebSocket {
+            schema: schema.get_ref().clone(),
+            context,
+            operations: HashMap::new(),
+            initialized: false,
+        },
+        &["graphql-ws"],
+        &req,
+        stream,
+    )
+}
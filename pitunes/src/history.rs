@@ -0,0 +1,82 @@
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+
+use crate::{
+    models::{NewPlay, Play, Track},
+    schema::{plays, tracks},
+};
+
+pub fn record_play(conn: &SqliteConnection, new_play: &NewPlay) -> Result<Play> {
+    conn.transaction::<_, anyhow::Error, _>(|| {
+        diesel::insert_into(plays::table)
+            .values(new_play)
+            .execute(conn)?;
+        Ok(plays::table.order(plays::id.desc()).first(conn)?)
+    })
+}
+
+// Streaming a track from its beginning counts as a play unless the same user already
+// started playing it within the track's duration, e.g. a browser issuing several range requests.
+// Inferred plays assume that the whole track was listened to.
+pub fn infer_play(
+    conn: &SqliteConnection,
+    username: &str,
+    track_id: i32,
+    client: Option<String>,
+) -> Result<()> {
+    let track = tracks::table.find(track_id).get_result::<Track>(conn)?;
+    let started_at = Utc::now().naive_utc();
+    let recently_played: bool = diesel::dsl::select(diesel::dsl::exists(
+        plays::table
+            .filter(plays::username.eq(username))
+            .filter(plays::track_id.eq(track_id))
+            .filter(
+                plays::started_at.gt(started_at - Duration::milliseconds(track.duration.into())),
+            ),
+    ))
+    .get_result(conn)?;
+    if !recently_played {
+        record_play(
+            conn,
+            &NewPlay {
+                username: String::from(username),
+                track_id,
+                started_at,
+                duration: track.duration,
+                client,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+#[test]
+fn it_infers_plays() {
+    use diesel::connection::SimpleConnection;
+
+    use crate::db;
+
+    let dir = tempfile::tempdir().unwrap();
+    let pool = db::establish_connection(dir.path().join("pitunes.db").to_str().unwrap());
+    let conn = pool.get().unwrap();
+    conn.batch_execute(
+        "INSERT INTO users (username, password) VALUES ('alice', '');
+         INSERT INTO tracks (id, name, duration) VALUES (1, 'So What', 562000);
+         INSERT INTO plays (username, track_id, started_at, duration) VALUES
+             ('alice', 1, datetime('now', '-1 days'), 562000);",
+    )
+    .unwrap();
+    let count = || -> i64 { plays::table.count().get_result(&conn).unwrap() };
+    infer_play(&conn, "alice", 1, Some(String::from("mpv"))).unwrap();
+    assert_eq!(count(), 2);
+    // a second request while the track is still playing doesn't count
+    infer_play(&conn, "alice", 1, None).unwrap();
+    assert_eq!(count(), 2);
+    assert!(infer_play(&conn, "alice", 2, None).is_err());
+    // plays go along with their track
+    diesel::delete(tracks::table.find(1))
+        .execute(&conn)
+        .unwrap();
+    assert_eq!(count(), 0);
+}
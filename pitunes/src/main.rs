@@ -5,38 +5,69 @@ extern crate diesel;
 #[macro_use]
 extern crate diesel_migrations;
 
-mod chunker;
+mod check;
 mod db;
+mod devices;
+mod devices_service;
+mod dlna;
+mod dlna_service;
+mod events;
 mod external_id;
+mod favorites;
+mod fuzzy;
 mod graphql_schema;
 mod graphql_service;
+mod history;
 mod mk_certs;
 mod models;
+mod mpd;
+mod orphans;
+mod player;
+mod playlist_files;
 mod playlists_service;
 mod prng;
+mod queues;
+mod radio;
+mod radio_service;
 mod schema;
+mod scrobbling;
+mod shuffle;
+mod sinks;
+mod smart_playlists;
+mod stats;
+mod subsonic;
+mod subsonic_service;
 mod tracks_service;
+mod xml;
+mod zip;
+mod zip_service;
 
-use std::sync::Arc;
+use std::{net::IpAddr, sync::Arc, time::Duration};
 
-use actix_files::Files;
 use actix_web::{
     dev::ServiceRequest,
     error,
     web::{self, Data},
-    App, Error, HttpResponse, HttpServer,
+    App, Error, HttpServer,
 };
 use actix_web_httpauth::{extractors::basic::BasicAuth, middleware::HttpAuthentication};
 use actix_web_middleware_redirect_scheme::RedirectSchemeBuilder;
 use actix_web_static_files;
-use clap::{self, value_t};
+use clap::{self, value_t, value_t_or_exit};
+use devices::Devices;
 use diesel::prelude::*;
-use graphql_schema::{create_schema, RequestContext};
+use dlna::Device;
+use events::Events;
+use graphql_schema::{create_schema, create_subscription_schema, RequestContext};
 use mk_certs::{mk_ca_cert, mk_ca_signed_cert};
 use models::User;
 use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use orphans::OrphanPolicy;
+use player::Player;
+use radio::Stations;
 use schema::users;
 use sha2::{Digest, Sha256};
+use sinks::SinkKind;
 
 async fn validator(req: ServiceRequest, credentials: BasicAuth) -> Result<ServiceRequest, Error> {
     let valid = {
@@ -108,12 +139,105 @@ async fn main() -> std::io::Result<()> {
                 .value_name("BOOL")
                 .help("Redirect HTTP to HTTPS (defaults to true)")
         )
+        .arg(
+            clap::Arg::with_name("prune-orphans")
+                .long("prune-orphans")
+                .value_name("POLICY")
+                .help("Remove albums, artists and genres without tracks: never, immediate or periodic (defaults to never)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("prune-interval")
+                .long("prune-interval")
+                .value_name("SECONDS")
+                .help("Interval between periodic orphan removals (defaults to 3600)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("infer-plays")
+                .long("infer-plays")
+                .value_name("BOOL")
+                .help("Record a play whenever a track is streamed from its beginning (defaults to false)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("write-tags")
+                .long("write-tags")
+                .value_name("BOOL")
+                .help("Write changes such as ratings back to the ID3 tags of tracks (defaults to false)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("sink")
+                .long("sink")
+                .value_name("SINK")
+                .help("Play audio on the server itself through alsa[:DEVICE], file:PATH or null (defaults to none)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("mpd-port")
+                .long("mpd-port")
+                .value_name("PORT")
+                .help("Port of an optional MPD protocol server (defaults to none)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("mpd-address")
+                .long("mpd-address")
+                .value_name("ADDRESS")
+                .help("Address the MPD protocol server binds to (defaults to 0.0.0.0)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("dlna-port")
+                .long("dlna-port")
+                .value_name("PORT")
+                .help("Port of an optional UPnP/DLNA media server which is announced on the local network (defaults to none)")
+                .takes_value(true),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("check")
+                .about("Checks the database against the tracks directory")
+                .arg(
+                    clap::Arg::with_name("repair")
+                        .long("repair")
+                        .help("Repairs the issues that can be repaired"),
+                ),
+        )
         .get_matches();
     let http_port = value_t!(matches, "http-port", u16).unwrap_or(8080);
     let https_port = value_t!(matches, "https-port", u16).unwrap_or(8443);
     let cert = value_t!(matches, "cert", String);
     let key = value_t!(matches, "key", String);
     let redirect_http_to_https = value_t!(matches, "redirect-http-to-https", bool).unwrap_or(true);
+    let orphan_policy = if matches.is_present("prune-orphans") {
+        value_t_or_exit!(matches, "prune-orphans", OrphanPolicy)
+    } else {
+        OrphanPolicy::Never
+    };
+    let prune_interval = value_t!(matches, "prune-interval", u64).unwrap_or(3600);
+    let infer_plays = value_t!(matches, "infer-plays", bool).unwrap_or(false);
+    let write_tags = value_t!(matches, "write-tags", bool).unwrap_or(false);
+    let sink = if matches.is_present("sink") {
+        Some(value_t_or_exit!(matches, "sink", SinkKind))
+    } else {
+        None
+    };
+    let mpd_port = if matches.is_present("mpd-port") {
+        Some(value_t_or_exit!(matches, "mpd-port", u16))
+    } else {
+        None
+    };
+    let mpd_address = if matches.is_present("mpd-address") {
+        value_t_or_exit!(matches, "mpd-address", IpAddr)
+    } else {
+        IpAddr::from([0, 0, 0, 0])
+    };
+    let dlna_port = if matches.is_present("dlna-port") {
+        Some(value_t_or_exit!(matches, "dlna-port", u16))
+    } else {
+        None
+    };
 
     let config_dir = {
         let mut config_dir = dirs::config_dir().unwrap();
@@ -138,7 +262,56 @@ async fn main() -> std::io::Result<()> {
         };
         db::establish_connection(&pitunes_db[..])
     };
+
+    if let Some(matches) = matches.subcommand_matches("check") {
+        let conn = pool.get().unwrap();
+        let issues = check::check(&conn, &tracks_dir, matches.is_present("repair"), true).unwrap();
+        for issue in issues.iter() {
+            println!(
+                "{:?} {}{}",
+                issue.kind,
+                issue.path,
+                if issue.repaired { " (repaired)" } else { "" }
+            );
+        }
+        println!("{} issue(s) found", issues.len());
+        return Ok(());
+    }
+
     let st = Arc::new(create_schema());
+    let subscription_schema = Arc::new(create_subscription_schema());
+
+    if orphan_policy == OrphanPolicy::Periodic {
+        let pool = pool.clone();
+        actix_rt::spawn(async move {
+            let mut interval = actix_rt::time::interval(Duration::from_secs(prune_interval));
+            loop {
+                interval.tick().await;
+                let pool = pool.clone();
+                if let Err(e) = web::block(move || {
+                    let conn = pool.get()?;
+                    orphans::prune_orphans(&conn)
+                })
+                .await
+                {
+                    eprintln!("Failed to prune orphans: {}", e);
+                }
+            }
+        });
+    }
+
+    {
+        let pool = pool.clone();
+        actix_rt::spawn(async move {
+            let mut interval = actix_rt::time::interval(scrobbling::SUBMIT_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = scrobbling::submit_due(&pool).await {
+                    eprintln!("Failed to submit scrobbles: {}", e);
+                }
+            }
+        });
+    }
 
     // load ssl keys
     let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
@@ -152,30 +325,109 @@ async fn main() -> std::io::Result<()> {
         builder.set_certificate(&cert).unwrap();
     }
 
+    // shared by all workers so that subscribers see changes made through any of them
+    let events = Events::default();
+    let devices = Devices::default();
+    let stations = Stations::default();
+    let ctx = RequestContext::new(
+        pool,
+        tracks_dir.clone(),
+        orphan_policy,
+        infer_plays,
+        write_tags,
+        events.clone(),
+        devices,
+    );
+    let player = match sink {
+        Some(sink) => Some(Player::spawn(
+            tracks_dir.clone(),
+            sink.open()?,
+            events.clone(),
+            ctx.pool.clone(),
+        )),
+        None => None,
+    };
+    let ctx = ctx.with_player(player);
+
+    if let Some(mpd_port) = mpd_port {
+        mpd::listen(mpd_address, mpd_port, ctx.clone())?;
+    }
+
+    // renderers can neither authenticate nor follow redirects to https, so the DLNA server
+    // listens on a plain http port of its own
+    let dlna_server = match dlna_port {
+        Some(dlna_port) => {
+            let device = Device::new(&tracks_dir, dlna_port);
+            device.watch(&events);
+            dlna::announce(device.clone())?;
+            let ctx = ctx.clone();
+            Some(
+                HttpServer::new(move || {
+                    App::new()
+                        .data(ctx.clone())
+                        .data(device.clone())
+                        .route("/description.xml", web::get().to(dlna_service::description))
+                        .route(
+                            "/ContentDirectory.xml",
+                            web::get().to(dlna_service::content_directory),
+                        )
+                        .route(
+                            "/ConnectionManager.xml",
+                            web::get().to(dlna_service::connection_manager),
+                        )
+                        .route("/control/{service}", web::post().to(dlna_service::control))
+                        .route("/tracks/{id}.mp3", web::get().to(dlna_service::get_track))
+                })
+                .bind(format!("0.0.0.0:{}", dlna_port))?,
+            )
+        }
+        None => None,
+    };
+
     let http_server = HttpServer::new(move || {
-        let ctx = RequestContext::new(pool.clone(), tracks_dir.clone());
+        let ctx = ctx.clone();
         let auth = HttpAuthentication::basic(validator);
         let pitunes_frontend = pitunes_frontend::generate();
         App::new()
-            .wrap(auth)
             .wrap(RedirectSchemeBuilder::new().replacements(&[(format!(":{}", http_port), format!(":{}", https_port))]).enable(redirect_http_to_https).build())
             .data(st.clone())
+            .data(subscription_schema.clone())
             .data(ctx)
+            .data(stations.clone())
+            // Subsonic clients authenticate on their own
             .service(
-                web::scope("/api")
-                    .service(graphql_service::graphql)
-                    .service(tracks_service::post_tracks)
-                    .service(playlists_service::get_playlist)
-                    .service(Files::new("/tracks", tracks_dir.clone()))
-                    .service(
-                        web::resource("/tracks/{id}.mp3")
-                            .name("get_track")
-                            .to(|| HttpResponse::NotFound()),
-                    ), // only used for resource url generation
+                web::scope("/rest").service(
+                    web::resource("/{method}")
+                        .route(web::get().to(subsonic_service::rest))
+                        .route(web::post().to(subsonic_service::rest)),
+                ),
             )
             .service(
-                actix_web_static_files::ResourceFiles::new("/", pitunes_frontend)
-                    .resolve_not_found_to_root(),
+                web::scope("")
+                    .wrap(auth)
+                    .service(
+                        web::scope("/api")
+                            .service(graphql_service::graphql)
+                            .service(graphql_service::graphql_ws)
+                            .service(devices_service::devices)
+                            .service(tracks_service::post_tracks)
+                            .service(tracks_service::get_cover)
+                            .service(tracks_service::download_track)
+                            .service(zip_service::get_archive)
+                            .service(playlists_service::get_collection)
+                            .service(playlists_service::get_shuffle)
+                            .service(playlists_service::import_playlists)
+                            .service(radio_service::get_radio)
+                            .service(
+                                web::resource("/tracks/{id}.mp3")
+                                    .name("get_track")
+                                    .route(web::get().to(tracks_service::get_track)),
+                            ),
+                    )
+                    .service(
+                        actix_web_static_files::ResourceFiles::new("/", pitunes_frontend)
+                            .resolve_not_found_to_root(),
+                    ),
             )
     })
     .bind(format!("0.0.0.0:{}", http_port))?
@@ -196,5 +448,12 @@ async fn main() -> std::io::Result<()> {
         println!("Listening on {}://{}", scheme, addrs);
     }
 
+    if let Some(dlna_server) = dlna_server {
+        for addrs in dlna_server.addrs() {
+            println!("DLNA server listening on http://{}", addrs);
+        }
+        dlna_server.run();
+    }
+
     http_server.run().await
 }
@@ -1,17 +1,23 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, convert::TryFrom, str::FromStr, sync::Arc};
 
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use chrono::NaiveDateTime;
+use chrono::{NaiveDate, NaiveDateTime, Utc};
 use dataloader::{cached::Loader, BatchFn};
 use diesel::prelude::*;
 use futures::executor::block_on;
-use oorandom::Rand32;
 
 use crate::{
     db::SqlitePool,
-    external_id::ExternalId,
+    external_id::{EntityKind, ExternalId},
+    favorites,
+    fuzzy::Group,
     graphql_schema::RequestContext,
-    schema::{albums, artists, genres, playlists, playlists_tracks, prngs, tracks, users},
+    schema::{
+        albums, artists, genres, playlists, playlists_tracks, plays, queues, queues_tracks,
+        ratings, scrobblers, scrobbles, stars, tracks, users,
+    },
+    smart_playlists, stats,
 };
 
 #[derive(Clone)]
@@ -89,6 +95,34 @@ impl BatchFn<i32, Genre> for GenreBatcher {
 
 pub type GenreLoader = Loader<i32, Genre, GenreBatcher>;
 
+pub enum Node {
+    Album(Album),
+    Artist(Artist),
+    Genre(Genre),
+    Playlist(Playlist),
+    Track(Track),
+}
+
+juniper::graphql_interface!(Node: RequestContext |&self| {
+    field id() -> juniper::ID {
+        match *self {
+            Node::Album(ref album) => ExternalId::new(EntityKind::Album, album.id).0,
+            Node::Artist(ref artist) => ExternalId::new(EntityKind::Artist, artist.id).0,
+            Node::Genre(ref genre) => ExternalId::new(EntityKind::Genre, genre.id).0,
+            Node::Playlist(ref playlist) => ExternalId::new(EntityKind::Playlist, playlist.id).0,
+            Node::Track(ref track) => ExternalId::new(EntityKind::Track, track.id).0,
+        }
+    }
+
+    instance_resolvers: |_| {
+        &Album => match *self { Node::Album(ref album) => Some(album), _ => None },
+        &Artist => match *self { Node::Artist(ref artist) => Some(artist), _ => None },
+        &Genre => match *self { Node::Genre(ref genre) => Some(genre), _ => None },
+        &Playlist => match *self { Node::Playlist(ref playlist) => Some(playlist), _ => None },
+        &Track => match *self { Node::Track(ref track) => Some(track), _ => None },
+    }
+});
+
 #[derive(Identifiable, Queryable, Clone)]
 pub struct Album {
     pub id: i32,
@@ -96,10 +130,10 @@ pub struct Album {
     pub name: String,
 }
 
-#[juniper::object(Context = RequestContext)]
+#[juniper::object(Context = RequestContext, interfaces = [&Node])]
 impl Album {
     pub fn id(&self) -> juniper::ID {
-        ExternalId::from(self.id).0
+        ExternalId::new(EntityKind::Album, self.id).0
     }
 
     pub fn created_at(&self) -> NaiveDateTime {
@@ -110,6 +144,16 @@ impl Album {
         &self.name[..]
     }
 
+    pub fn starred(&self, context: &RequestContext) -> juniper::FieldResult<bool> {
+        let conn = context.pool.get()?;
+        Ok(favorites::is_starred(
+            &conn,
+            context.username()?,
+            EntityKind::Album,
+            self.id,
+        )?)
+    }
+
     pub fn tracks(&self, context: &RequestContext) -> juniper::FieldResult<Vec<Track>> {
         let conn = context.pool.get()?;
         Ok(Track::belonging_to(self).load::<Track>(&conn)?)
@@ -137,10 +181,10 @@ pub struct Artist {
     pub name: String,
 }
 
-#[juniper::object(Context = RequestContext)]
+#[juniper::object(Context = RequestContext, interfaces = [&Node])]
 impl Artist {
     pub fn id(&self) -> juniper::ID {
-        ExternalId::from(self.id).0
+        ExternalId::new(EntityKind::Artist, self.id).0
     }
 
     pub fn created_at(&self) -> NaiveDateTime {
@@ -151,6 +195,16 @@ impl Artist {
         &self.name[..]
     }
 
+    pub fn starred(&self, context: &RequestContext) -> juniper::FieldResult<bool> {
+        let conn = context.pool.get()?;
+        Ok(favorites::is_starred(
+            &conn,
+            context.username()?,
+            EntityKind::Artist,
+            self.id,
+        )?)
+    }
+
     pub fn albums(&self, context: &RequestContext) -> juniper::FieldResult<Vec<Album>> {
         let conn = context.pool.get()?;
         let album_ids: Vec<i32> = Track::belonging_to(self)
@@ -193,10 +247,10 @@ pub struct Genre {
     pub name: String,
 }
 
-#[juniper::object(Context = RequestContext)]
+#[juniper::object(Context = RequestContext, interfaces = [&Node])]
 impl Genre {
     pub fn id(&self) -> juniper::ID {
-        ExternalId::from(self.id).0
+        ExternalId::new(EntityKind::Genre, self.id).0
     }
 
     pub fn created_at(&self) -> NaiveDateTime {
@@ -227,7 +281,7 @@ pub struct GenreInput {
     pub name: String,
 }
 
-#[derive(Identifiable, Associations, Queryable)]
+#[derive(Identifiable, Associations, Queryable, Clone)]
 #[belongs_to(Album)]
 #[belongs_to(Artist)]
 #[belongs_to(Genre)]
@@ -242,10 +296,10 @@ pub struct Track {
     pub track_number: Option<i32>,
 }
 
-#[juniper::object(Context = RequestContext)]
+#[juniper::object(Context = RequestContext, interfaces = [&Node])]
 impl Track {
     pub fn id(&self) -> juniper::ID {
-        ExternalId::from(self.id).0
+        ExternalId::new(EntityKind::Track, self.id).0
     }
 
     pub fn created_at(&self) -> NaiveDateTime {
@@ -288,6 +342,115 @@ impl Track {
     pub fn track_number(&self) -> Option<i32> {
         self.track_number
     }
+
+    pub fn play_count(&self, context: &RequestContext) -> juniper::FieldResult<i32> {
+        let conn = context.pool.get()?;
+        let count: i64 = Play::belonging_to(self)
+            .filter(plays::username.eq(context.username()?))
+            .count()
+            .get_result(&conn)?;
+        Ok(i32::try_from(count)?)
+    }
+
+    pub fn last_played_at(
+        &self,
+        context: &RequestContext,
+    ) -> juniper::FieldResult<Option<NaiveDateTime>> {
+        let conn = context.pool.get()?;
+        Ok(Play::belonging_to(self)
+            .filter(plays::username.eq(context.username()?))
+            .select(diesel::dsl::max(plays::started_at))
+            .first(&conn)?)
+    }
+
+    pub fn starred(&self, context: &RequestContext) -> juniper::FieldResult<bool> {
+        let conn = context.pool.get()?;
+        Ok(favorites::is_starred(
+            &conn,
+            context.username()?,
+            EntityKind::Track,
+            self.id,
+        )?)
+    }
+
+    pub fn rating(&self, context: &RequestContext) -> juniper::FieldResult<Option<i32>> {
+        let conn = context.pool.get()?;
+        Ok(favorites::rating(&conn, context.username()?, self.id)?)
+    }
+}
+
+// Returns the name and tracks of an album, artist, genre or playlist. Smart playlists are
+// evaluated for the given user.
+pub fn collection(
+    conn: &SqliteConnection,
+    kind: EntityKind,
+    id: i32,
+    username: &str,
+) -> Result<(String, Vec<Track>)> {
+    Ok(match kind {
+        EntityKind::Album => {
+            let album = albums::table.find(id).get_result::<Album>(conn)?;
+            let tracks = Track::belonging_to(&album)
+                .order((tracks::track_number.asc(), tracks::name.asc()))
+                .load(conn)?;
+            (album.name, tracks)
+        }
+        EntityKind::Artist => {
+            let artist = artists::table.find(id).get_result::<Artist>(conn)?;
+            let tracks = Track::belonging_to(&artist)
+                .order((
+                    tracks::album_id.asc(),
+                    tracks::track_number.asc(),
+                    tracks::name.asc(),
+                ))
+                .load(conn)?;
+            (artist.name, tracks)
+        }
+        EntityKind::Genre => {
+            let genre = genres::table.find(id).get_result::<Genre>(conn)?;
+            let tracks = Track::belonging_to(&genre)
+                .order(tracks::name.asc())
+                .load(conn)?;
+            (genre.name, tracks)
+        }
+        EntityKind::Playlist => {
+            let playlist = playlists::table.find(id).get_result::<Playlist>(conn)?;
+            let tracks = playlist.load_tracks(conn, username)?;
+            (playlist.name, tracks)
+        }
+        EntityKind::Track => Err(anyhow!(
+            "Only albums, artists, genres and playlists are collections of tracks"
+        ))?,
+    })
+}
+
+// A track along with the names of its album and artist
+pub type NamedTrack = (Track, Option<String>, Option<String>);
+
+pub fn with_names(conn: &SqliteConnection, tracks: Vec<Track>) -> Result<Vec<NamedTrack>> {
+    let album_names: HashMap<i32, String> = albums::table
+        .filter(albums::id.eq_any(tracks.iter().filter_map(|track| track.album_id)))
+        .select((albums::id, albums::name))
+        .load::<(i32, String)>(conn)?
+        .into_iter()
+        .collect();
+    let artist_names: HashMap<i32, String> = artists::table
+        .filter(artists::id.eq_any(tracks.iter().filter_map(|track| track.artist_id)))
+        .select((artists::id, artists::name))
+        .load::<(i32, String)>(conn)?
+        .into_iter()
+        .collect();
+    Ok(tracks
+        .into_iter()
+        .map(|track| {
+            let album = track.album_id.and_then(|id| album_names.get(&id)).cloned();
+            let artist = track
+                .artist_id
+                .and_then(|id| artist_names.get(&id))
+                .cloned();
+            (track, album, artist)
+        })
+        .collect())
 }
 
 #[derive(Insertable)]
@@ -322,17 +485,480 @@ pub struct TrackChangeset {
     pub track_number: Option<i32>,
 }
 
+#[derive(Identifiable, Associations, Queryable)]
+#[belongs_to(Track)]
+pub struct Play {
+    pub id: i32,
+    pub created_at: NaiveDateTime,
+    pub username: String,
+    pub track_id: i32,
+    pub started_at: NaiveDateTime,
+    pub duration: i32,
+    pub client: Option<String>,
+}
+
+#[juniper::object(Context = RequestContext)]
+impl Play {
+    pub fn username(&self) -> &str {
+        &self.username[..]
+    }
+
+    pub fn track(&self, context: &RequestContext) -> juniper::FieldResult<Track> {
+        let conn = context.pool.get()?;
+        Ok(tracks::table.find(self.track_id).get_result(&conn)?)
+    }
+
+    pub fn started_at(&self) -> NaiveDateTime {
+        self.started_at
+    }
+
+    pub fn duration(&self) -> i32 {
+        self.duration
+    }
+
+    pub fn client(&self) -> Option<&str> {
+        self.client.as_deref()
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "plays"]
+pub struct NewPlay {
+    pub username: String,
+    pub track_id: i32,
+    pub started_at: NaiveDateTime,
+    pub duration: i32,
+    pub client: Option<String>,
+}
+
+#[derive(juniper::GraphQLInputObject)]
+pub struct PlayInput {
+    pub track_id: juniper::ID,
+    pub started_at: Option<NaiveDateTime>,
+    pub duration: i32,
+    pub client: Option<String>,
+}
+
+// Now playing updates aren't recorded as plays. The duration that was listened to defaults to the
+// duration of the track.
+#[derive(juniper::GraphQLInputObject)]
+pub struct ScrobbleInput {
+    pub track_id: juniper::ID,
+    pub started_at: Option<NaiveDateTime>,
+    pub duration: Option<i32>,
+    pub client: Option<String>,
+    pub now_playing: Option<bool>,
+}
+
+#[derive(Identifiable, Queryable, Clone)]
+#[primary_key(username, service)]
+pub struct Scrobbler {
+    pub username: String,
+    pub service: String,
+    pub url: String,
+    pub token: String,
+    pub api_key: Option<String>,
+    pub api_secret: Option<String>,
+    pub last_error: Option<String>,
+}
+
+// Tokens and secrets are write-only
+#[juniper::object(Context = RequestContext)]
+impl Scrobbler {
+    pub fn service(&self) -> juniper::FieldResult<ScrobbleService> {
+        Ok(self.service.parse()?)
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url[..]
+    }
+
+    // The error of the last submission which failed, if the next one didn't succeed yet
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    // Number of plays waiting to be submitted
+    pub fn pending(&self, context: &RequestContext) -> juniper::FieldResult<i32> {
+        let conn = context.pool.get()?;
+        let pending: i64 = scrobbles::table
+            .filter(scrobbles::username.eq(&self.username))
+            .filter(scrobbles::service.eq(&self.service))
+            .filter(scrobbles::now_playing.eq(false))
+            .count()
+            .get_result(&conn)?;
+        Ok(pending as i32)
+    }
+}
+
+#[derive(juniper::GraphQLEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ScrobbleService {
+    ListenBrainz,
+    LastFm,
+}
+
+impl ScrobbleService {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ScrobbleService::ListenBrainz => "LISTENBRAINZ",
+            ScrobbleService::LastFm => "LASTFM",
+        }
+    }
+}
+
+impl FromStr for ScrobbleService {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "LISTENBRAINZ" => Ok(ScrobbleService::ListenBrainz),
+            "LASTFM" => Ok(ScrobbleService::LastFm),
+            _ => Err(anyhow!("Unknown scrobble service {}", s)),
+        }
+    }
+}
+
+// The token is a ListenBrainz user token or a Last.fm session key, which requires the api key and
+// secret as well. The url defaults to the service's official API and may point to compatible
+// services instead.
+#[derive(juniper::GraphQLInputObject)]
+pub struct ScrobblerInput {
+    pub service: ScrobbleService,
+    pub token: String,
+    pub url: Option<String>,
+    pub api_key: Option<String>,
+    pub api_secret: Option<String>,
+}
+
+#[derive(Insertable, AsChangeset)]
+#[changeset_options(treat_none_as_null = "true")]
+#[table_name = "scrobblers"]
+pub struct NewScrobbler {
+    pub username: String,
+    pub service: String,
+    pub url: String,
+    pub token: String,
+    pub api_key: Option<String>,
+    pub api_secret: Option<String>,
+}
+
+#[derive(Identifiable, Queryable)]
+pub struct Scrobble {
+    pub id: i32,
+    pub created_at: NaiveDateTime,
+    pub username: String,
+    pub service: String,
+    pub now_playing: bool,
+    pub started_at: NaiveDateTime,
+    pub artist: String,
+    pub track: String,
+    pub album: Option<String>,
+    pub duration: i32,
+    pub attempts: i32,
+    pub next_attempt_at: NaiveDateTime,
+}
+
+// Scrobbles are queued with the names at the time of the play, so that they can still be
+// submitted after the track was edited or deleted
+#[derive(Insertable)]
+#[table_name = "scrobbles"]
+pub struct NewScrobble {
+    pub username: String,
+    pub service: String,
+    pub now_playing: bool,
+    pub started_at: NaiveDateTime,
+    pub artist: String,
+    pub track: String,
+    pub album: Option<String>,
+    pub duration: i32,
+}
+
+pub struct Stats {
+    pub username: String,
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+}
+
+#[juniper::object(Context = RequestContext)]
+impl Stats {
+    pub fn username(&self) -> &str {
+        &self.username[..]
+    }
+
+    pub fn from(&self) -> NaiveDate {
+        self.from
+    }
+
+    pub fn to(&self) -> NaiveDate {
+        self.to
+    }
+
+    pub fn top_tracks(
+        &self,
+        context: &RequestContext,
+        limit: Option<i32>,
+    ) -> juniper::FieldResult<Vec<TopTrack>> {
+        let conn = context.pool.get()?;
+        let rankings = stats::top_tracks(
+            &conn,
+            &self.username,
+            self.from,
+            self.to,
+            limit.unwrap_or(10).into(),
+        )?;
+        let track_ids: Vec<i32> = rankings.iter().map(|ranking| ranking.id).collect();
+        let mut tracks: HashMap<i32, Track> = tracks::table
+            .filter(tracks::id.eq_any(track_ids))
+            .load::<Track>(&conn)?
+            .into_iter()
+            .map(|track| (track.id, track))
+            .collect();
+        let mut top_tracks = Vec::new();
+        for ranking in rankings {
+            if let Some(track) = tracks.remove(&ranking.id) {
+                top_tracks.push(TopTrack {
+                    track,
+                    play_count: i32::try_from(ranking.play_count)?,
+                    duration: ranking.duration as f64,
+                });
+            }
+        }
+        Ok(top_tracks)
+    }
+
+    pub fn top_albums(
+        &self,
+        context: &RequestContext,
+        limit: Option<i32>,
+    ) -> juniper::FieldResult<Vec<TopAlbum>> {
+        let conn = context.pool.get()?;
+        let rankings = stats::top_by_track_column(
+            &conn,
+            "album_id",
+            &self.username,
+            self.from,
+            self.to,
+            limit.unwrap_or(10).into(),
+        )?;
+        let mut top_albums = Vec::new();
+        for ranking in rankings {
+            top_albums.push(TopAlbum {
+                album: block_on(context.album_loader.load(ranking.id)),
+                play_count: i32::try_from(ranking.play_count)?,
+                duration: ranking.duration as f64,
+            });
+        }
+        Ok(top_albums)
+    }
+
+    pub fn top_artists(
+        &self,
+        context: &RequestContext,
+        limit: Option<i32>,
+    ) -> juniper::FieldResult<Vec<TopArtist>> {
+        let conn = context.pool.get()?;
+        let rankings = stats::top_by_track_column(
+            &conn,
+            "artist_id",
+            &self.username,
+            self.from,
+            self.to,
+            limit.unwrap_or(10).into(),
+        )?;
+        let mut top_artists = Vec::new();
+        for ranking in rankings {
+            top_artists.push(TopArtist {
+                artist: block_on(context.artist_loader.load(ranking.id)),
+                play_count: i32::try_from(ranking.play_count)?,
+                duration: ranking.duration as f64,
+            });
+        }
+        Ok(top_artists)
+    }
+
+    pub fn top_genres(
+        &self,
+        context: &RequestContext,
+        limit: Option<i32>,
+    ) -> juniper::FieldResult<Vec<TopGenre>> {
+        let conn = context.pool.get()?;
+        let rankings = stats::top_by_track_column(
+            &conn,
+            "genre_id",
+            &self.username,
+            self.from,
+            self.to,
+            limit.unwrap_or(10).into(),
+        )?;
+        let mut top_genres = Vec::new();
+        for ranking in rankings {
+            top_genres.push(TopGenre {
+                genre: block_on(context.genre_loader.load(ranking.id)),
+                play_count: i32::try_from(ranking.play_count)?,
+                duration: ranking.duration as f64,
+            });
+        }
+        Ok(top_genres)
+    }
+
+    pub fn listening_time(
+        &self,
+        context: &RequestContext,
+        period: StatsPeriod,
+    ) -> juniper::FieldResult<Vec<ListeningTime>> {
+        let conn = context.pool.get()?;
+        let format = match period {
+            StatsPeriod::Day => "%Y-%m-%d",
+            StatsPeriod::Week => "%Y-W%W",
+            StatsPeriod::Month => "%Y-%m",
+        };
+        let mut listening_time = Vec::new();
+        for period in stats::listening_time(&conn, format, &self.username, self.from, self.to)? {
+            listening_time.push(ListeningTime {
+                period: period.period,
+                play_count: i32::try_from(period.play_count)?,
+                duration: period.duration as f64,
+            });
+        }
+        Ok(listening_time)
+    }
+
+    pub fn streaks(&self, context: &RequestContext) -> juniper::FieldResult<Streaks> {
+        let conn = context.pool.get()?;
+        let days = stats::days_played(&conn, &self.username, self.from, self.to)?;
+        let (current, longest) = stats::streaks(&days, Utc::now().naive_utc().date());
+        Ok(Streaks { current, longest })
+    }
+
+    pub fn discovered_artists(
+        &self,
+        context: &RequestContext,
+    ) -> juniper::FieldResult<Vec<Artist>> {
+        let conn = context.pool.get()?;
+        let artist_ids = stats::discovered_artists(&conn, &self.username, self.from, self.to)?;
+        Ok(artist_ids
+            .into_iter()
+            .map(|artist_id| block_on(context.artist_loader.load(artist_id)))
+            .collect())
+    }
+}
+
+pub struct TopTrack {
+    pub track: Track,
+    pub play_count: i32,
+    pub duration: f64,
+}
+
+#[juniper::object(Context = RequestContext)]
+impl TopTrack {
+    pub fn track(&self) -> &Track {
+        &self.track
+    }
+
+    pub fn play_count(&self) -> i32 {
+        self.play_count
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.duration
+    }
+}
+
+pub struct TopAlbum {
+    pub album: Album,
+    pub play_count: i32,
+    pub duration: f64,
+}
+
+#[juniper::object(Context = RequestContext)]
+impl TopAlbum {
+    pub fn album(&self) -> &Album {
+        &self.album
+    }
+
+    pub fn play_count(&self) -> i32 {
+        self.play_count
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.duration
+    }
+}
+
+pub struct TopArtist {
+    pub artist: Artist,
+    pub play_count: i32,
+    pub duration: f64,
+}
+
+#[juniper::object(Context = RequestContext)]
+impl TopArtist {
+    pub fn artist(&self) -> &Artist {
+        &self.artist
+    }
+
+    pub fn play_count(&self) -> i32 {
+        self.play_count
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.duration
+    }
+}
+
+pub struct TopGenre {
+    pub genre: Genre,
+    pub play_count: i32,
+    pub duration: f64,
+}
+
+#[juniper::object(Context = RequestContext)]
+impl TopGenre {
+    pub fn genre(&self) -> &Genre {
+        &self.genre
+    }
+
+    pub fn play_count(&self) -> i32 {
+        self.play_count
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.duration
+    }
+}
+
+#[derive(juniper::GraphQLEnum)]
+pub enum StatsPeriod {
+    Day,
+    Week,
+    Month,
+}
+
+#[derive(juniper::GraphQLObject)]
+pub struct ListeningTime {
+    pub period: String,
+    pub play_count: i32,
+    pub duration: f64,
+}
+
+#[derive(juniper::GraphQLObject)]
+pub struct Streaks {
+    pub current: i32,
+    pub longest: i32,
+}
+
 #[derive(Identifiable, Queryable)]
 pub struct Playlist {
     pub id: i32,
     pub created_at: NaiveDateTime,
     pub name: String,
+    pub rules: Option<String>,
 }
 
-#[juniper::object(Context = RequestContext)]
+#[juniper::object(Context = RequestContext, interfaces = [&Node])]
 impl Playlist {
     pub fn id(&self) -> juniper::ID {
-        ExternalId::from(self.id).0
+        ExternalId::new(EntityKind::Playlist, self.id).0
     }
 
     pub fn created_at(&self) -> NaiveDateTime {
@@ -343,13 +969,36 @@ impl Playlist {
         &self.name[..]
     }
 
+    pub fn starred(&self, context: &RequestContext) -> juniper::FieldResult<bool> {
+        let conn = context.pool.get()?;
+        Ok(favorites::is_starred(
+            &conn,
+            context.username()?,
+            EntityKind::Playlist,
+            self.id,
+        )?)
+    }
+
+    pub fn rules(&self) -> Option<&str> {
+        self.rules.as_deref()
+    }
+
     pub fn tracks(&self, context: &RequestContext) -> juniper::FieldResult<Vec<Track>> {
         let conn = context.pool.get()?;
+        Ok(self.load_tracks(&conn, context.username()?)?)
+    }
+}
+
+impl Playlist {
+    pub fn load_tracks(&self, conn: &SqliteConnection, username: &str) -> Result<Vec<Track>> {
+        if let Some(rules) = &self.rules {
+            return smart_playlists::evaluate(conn, rules, username);
+        }
         Ok(PlaylistTrack::belonging_to(self)
             .inner_join(tracks::table)
             .select(tracks::all_columns)
             .order_by(playlists_tracks::position.asc())
-            .load::<Track>(&conn)?)
+            .load::<Track>(conn)?)
     }
 }
 
@@ -358,6 +1007,7 @@ impl Playlist {
 pub struct NewPlaylist {
     pub id: i32,
     pub name: String,
+    pub rules: Option<String>,
 }
 
 #[derive(AsChangeset, juniper::GraphQLInputObject)]
@@ -367,6 +1017,13 @@ pub struct PlaylistInput {
     pub name: String,
 }
 
+#[derive(AsChangeset, juniper::GraphQLInputObject)]
+#[table_name = "playlists"]
+pub struct SmartPlaylistInput {
+    pub name: String,
+    pub rules: String,
+}
+
 #[derive(Identifiable, Associations, Queryable)]
 #[belongs_to(Playlist)]
 #[belongs_to(Track)]
@@ -400,11 +1057,349 @@ pub struct PlaylistTrackOrderInput {
     pub insert_before: i32,
 }
 
+#[derive(juniper::GraphQLEnum)]
+pub enum ShuffleMode {
+    Random,
+    ArtistSpread,
+    WeightedByRating,
+}
+
+pub struct Shuffle {
+    pub seed: i32,
+    pub tracks: Vec<Track>,
+}
+
+// The seed is returned so that clients can reproduce a shuffle which used a random seed
+#[juniper::object(Context = RequestContext)]
+impl Shuffle {
+    pub fn seed(&self) -> i32 {
+        self.seed
+    }
+
+    pub fn tracks(&self) -> &Vec<Track> {
+        &self.tracks
+    }
+}
+
+#[derive(Identifiable, Queryable)]
+#[primary_key(username)]
+pub struct Queue {
+    pub username: String,
+    pub updated_at: NaiveDateTime,
+    // position of the current row of queues_tracks, which keeps its place when tracks are deleted
+    pub current_position: i32,
+    pub position: i32,
+    pub shuffle: bool,
+    pub repeat_mode: String,
+}
+
+#[juniper::object(Context = RequestContext)]
+impl Queue {
+    pub fn tracks(&self, context: &RequestContext) -> juniper::FieldResult<Vec<Track>> {
+        let conn = context.pool.get()?;
+        Ok(queues_tracks::table
+            .filter(queues_tracks::username.eq(&self.username))
+            .inner_join(tracks::table)
+            .select(tracks::all_columns)
+            .order_by(queues_tracks::position.asc())
+            .load::<Track>(&conn)?)
+    }
+
+    pub fn current_index(&self, context: &RequestContext) -> juniper::FieldResult<i32> {
+        let conn = context.pool.get()?;
+        Ok(crate::queues::current_index(&conn, self)?)
+    }
+
+    // Position within the current track in milliseconds
+    pub fn position(&self) -> i32 {
+        self.position
+    }
+
+    pub fn shuffle(&self) -> bool {
+        self.shuffle
+    }
+
+    pub fn repeat(&self) -> juniper::FieldResult<RepeatMode> {
+        Ok(self.repeat_mode.parse()?)
+    }
+
+    pub fn updated_at(&self) -> NaiveDateTime {
+        self.updated_at
+    }
+}
+
+#[derive(juniper::GraphQLEnum, Clone, Copy, Debug, PartialEq)]
+pub enum RepeatMode {
+    Off,
+    All,
+    One,
+}
+
+impl RepeatMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RepeatMode::Off => "OFF",
+            RepeatMode::All => "ALL",
+            RepeatMode::One => "ONE",
+        }
+    }
+}
+
+impl FromStr for RepeatMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "OFF" => Ok(RepeatMode::Off),
+            "ALL" => Ok(RepeatMode::All),
+            "ONE" => Ok(RepeatMode::One),
+            _ => Err(anyhow!("Unknown repeat mode {}", s)),
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "queues_tracks"]
+pub struct NewQueueTrack {
+    pub username: String,
+    pub track_id: i32,
+    pub position: i32,
+}
+
+// Omitted fields keep their value. Replacing the tracks starts over at the first track unless
+// currentIndex and position are given as well.
+#[derive(juniper::GraphQLInputObject, Default)]
+pub struct QueueInput {
+    pub track_ids: Option<Vec<juniper::ID>>,
+    pub current_index: Option<i32>,
+    pub position: Option<i32>,
+    pub shuffle: Option<bool>,
+    pub repeat: Option<RepeatMode>,
+}
+
+#[derive(juniper::GraphQLEnum, Clone, Copy, Debug, PartialEq)]
+pub enum PlayerStatus {
+    Stopped,
+    Playing,
+    Paused,
+}
+
+#[derive(Clone)]
+pub struct PlayerState {
+    pub status: PlayerStatus,
+    pub track_ids: Vec<i32>,
+    pub index: i32,
+    pub position: i32,
+    pub volume: f64,
+}
+
+#[juniper::object(Context = RequestContext)]
+impl PlayerState {
+    pub fn status(&self) -> PlayerStatus {
+        self.status
+    }
+
+    // Tracks which have been deleted in the meantime are left out
+    pub fn tracks(&self, context: &RequestContext) -> juniper::FieldResult<Vec<Track>> {
+        let conn = context.pool.get()?;
+        let mut tracks: HashMap<i32, Track> = tracks::table
+            .filter(tracks::id.eq_any(&self.track_ids))
+            .load::<Track>(&conn)?
+            .into_iter()
+            .map(|track| (track.id, track))
+            .collect();
+        Ok(self
+            .track_ids
+            .iter()
+            .filter_map(|id| tracks.get(id).cloned())
+            .collect())
+    }
+
+    pub fn current_index(&self) -> i32 {
+        self.index
+    }
+
+    pub fn current_track(&self, context: &RequestContext) -> juniper::FieldResult<Option<Track>> {
+        match self.track_ids.get(self.index as usize) {
+            Some(id) => {
+                let conn = context.pool.get()?;
+                Ok(tracks::table.find(id).get_result(&conn).optional()?)
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Position within the current track in milliseconds
+    pub fn position(&self) -> i32 {
+        self.position
+    }
+
+    pub fn volume(&self) -> f64 {
+        self.volume
+    }
+}
+
+pub struct Orphans {
+    pub albums: Vec<Album>,
+    pub artists: Vec<Artist>,
+    pub genres: Vec<Genre>,
+}
+
+#[juniper::object(Context = RequestContext)]
+impl Orphans {
+    pub fn albums(&self) -> &Vec<Album> {
+        &self.albums
+    }
+
+    pub fn artists(&self) -> &Vec<Artist> {
+        &self.artists
+    }
+
+    pub fn genres(&self) -> &Vec<Genre> {
+        &self.genres
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "stars"]
+pub struct NewStar {
+    pub username: String,
+    pub entity_kind: i32,
+    pub entity_id: i32,
+}
+
+#[derive(Insertable)]
+#[table_name = "ratings"]
+pub struct NewRating {
+    pub username: String,
+    pub track_id: i32,
+    pub rating: i32,
+}
+
+// Everything the given user has starred, most recently starred first
+pub struct Favorites {
+    pub username: String,
+}
+
+#[juniper::object(Context = RequestContext)]
+impl Favorites {
+    pub fn albums(&self, context: &RequestContext) -> juniper::FieldResult<Vec<Album>> {
+        let conn = context.pool.get()?;
+        Ok(albums::table
+            .inner_join(
+                stars::table.on(stars::entity_id
+                    .eq(albums::id)
+                    .and(stars::entity_kind.eq(EntityKind::Album as i32))
+                    .and(stars::username.eq(&self.username))),
+            )
+            .select(albums::all_columns)
+            .order(stars::created_at.desc())
+            .load::<Album>(&conn)?)
+    }
+
+    pub fn artists(&self, context: &RequestContext) -> juniper::FieldResult<Vec<Artist>> {
+        let conn = context.pool.get()?;
+        Ok(artists::table
+            .inner_join(
+                stars::table.on(stars::entity_id
+                    .eq(artists::id)
+                    .and(stars::entity_kind.eq(EntityKind::Artist as i32))
+                    .and(stars::username.eq(&self.username))),
+            )
+            .select(artists::all_columns)
+            .order(stars::created_at.desc())
+            .load::<Artist>(&conn)?)
+    }
+
+    pub fn playlists(&self, context: &RequestContext) -> juniper::FieldResult<Vec<Playlist>> {
+        let conn = context.pool.get()?;
+        Ok(playlists::table
+            .inner_join(
+                stars::table.on(stars::entity_id
+                    .eq(playlists::id)
+                    .and(stars::entity_kind.eq(EntityKind::Playlist as i32))
+                    .and(stars::username.eq(&self.username))),
+            )
+            .select(playlists::all_columns)
+            .order(stars::created_at.desc())
+            .load::<Playlist>(&conn)?)
+    }
+
+    pub fn tracks(&self, context: &RequestContext) -> juniper::FieldResult<Vec<Track>> {
+        let conn = context.pool.get()?;
+        Ok(tracks::table
+            .inner_join(
+                stars::table.on(stars::entity_id
+                    .eq(tracks::id)
+                    .and(stars::entity_kind.eq(EntityKind::Track as i32))
+                    .and(stars::username.eq(&self.username))),
+            )
+            .select(tracks::all_columns)
+            .order(stars::created_at.desc())
+            .load::<Track>(&conn)?)
+    }
+}
+
+#[derive(juniper::GraphQLEnum, Debug)]
+pub enum IssueKind {
+    MissingFile,
+    OrphanFile,
+    EmptyFile,
+    UndecodableAudio,
+    DurationMismatch,
+}
+
+#[derive(juniper::GraphQLObject)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub track_id: Option<juniper::ID>,
+    pub path: String,
+    pub repaired: bool,
+}
+
+#[derive(juniper::GraphQLEnum)]
+pub enum MergeKind {
+    Album,
+    Artist,
+    Genre,
+}
+
+#[derive(juniper::GraphQLObject)]
+pub struct MergeSuggestion {
+    pub kind: MergeKind,
+    pub ids: Vec<juniper::ID>,
+    pub names: Vec<String>,
+    pub confidence: f64,
+}
+
+impl MergeSuggestion {
+    pub fn new(kind: MergeKind, group: Group) -> Self {
+        let entity_kind = match kind {
+            MergeKind::Album => EntityKind::Album,
+            MergeKind::Artist => EntityKind::Artist,
+            MergeKind::Genre => EntityKind::Genre,
+        };
+        let (ids, names) = group
+            .members
+            .into_iter()
+            .map(|(id, name)| (ExternalId::new(entity_kind, id).0, name))
+            .unzip();
+        MergeSuggestion {
+            kind,
+            ids,
+            names,
+            confidence: group.confidence,
+        }
+    }
+}
+
 #[derive(Identifiable, Queryable)]
 #[primary_key(username)]
 pub struct User {
     pub username: String,
     pub password: Vec<u8>,
+    // Subsonic token authentication needs the password in plain text, so it is kept separately
+    pub subsonic_password: Option<String>,
 }
 
 #[derive(juniper::GraphQLInputObject)]
@@ -418,30 +1413,3 @@ pub struct UserInput {
 pub struct UserChangeset {
     pub password: Vec<u8>,
 }
-
-#[derive(AsChangeset, Identifiable, Insertable, Queryable)]
-pub struct Prng {
-    pub id: i32,
-    pub state: i64,
-    pub inc: i64,
-}
-
-impl From<Rand32> for Prng {
-    fn from(rand32: Rand32) -> Self {
-        let (state, inc) = rand32.state();
-        Prng {
-            id: 1,
-            state: i64::from_le_bytes(state.to_le_bytes()),
-            inc: i64::from_le_bytes(inc.to_le_bytes()),
-        }
-    }
-}
-
-impl From<Prng> for Rand32 {
-    fn from(prng: Prng) -> Self {
-        let state = u64::from_le_bytes(prng.state.to_le_bytes());
-        let inc = u64::from_le_bytes(prng.inc.to_le_bytes());
-        let state = (state, inc);
-        Rand32::from_state(state)
-    }
-}
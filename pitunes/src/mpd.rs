@@ -0,0 +1,1099 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{IpAddr, TcpListener, TcpStream},
+    sync::mpsc,
+    thread,
+    time::Instant,
+};
+
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use futures::StreamExt;
+
+use crate::{
+    events::Event,
+    external_id::{EntityKind, ExternalId},
+    graphql_schema::RequestContext,
+    models::{
+        Album, Artist, Genre, PlayerState, PlayerStatus, Playlist, QueueInput, RepeatMode, Track,
+        User,
+    },
+    player::Player,
+    queues,
+    schema::{albums, artists, genres, playlists, tracks, users},
+    subsonic,
+};
+
+const PROTOCOL_VERSION: &str = "0.21.0";
+
+// Longer lines and command lists end the connection
+const MAX_LINE_LENGTH: usize = 4096;
+const MAX_COMMAND_LIST_LENGTH: usize = 1024;
+
+// Error codes of the MPD protocol
+const ACK_ERROR_ARG: i32 = 2;
+const ACK_ERROR_PASSWORD: i32 = 3;
+const ACK_ERROR_PERMISSION: i32 = 4;
+const ACK_ERROR_UNKNOWN: i32 = 5;
+const ACK_ERROR_NO_EXIST: i32 = 50;
+const ACK_ERROR_SYSTEM: i32 = 52;
+
+const COMMANDS: &[&str] = &[
+    "add",
+    "addid",
+    "clear",
+    "close",
+    "command_list_begin",
+    "command_list_end",
+    "command_list_ok_begin",
+    "commands",
+    "currentsong",
+    "find",
+    "idle",
+    "list",
+    "listplaylist",
+    "listplaylistinfo",
+    "listplaylists",
+    "load",
+    "next",
+    "noidle",
+    "notcommands",
+    "outputs",
+    "password",
+    "pause",
+    "ping",
+    "play",
+    "playid",
+    "playlistinfo",
+    "plchanges",
+    "previous",
+    "random",
+    "repeat",
+    "search",
+    "seek",
+    "seekcur",
+    "seekid",
+    "setvol",
+    "single",
+    "stats",
+    "status",
+    "stop",
+    "tagtypes",
+    "urlhandlers",
+];
+
+// Commands which may be used before authenticating with the password command
+const PUBLIC_COMMANDS: &[&str] = &["close", "commands", "notcommands", "password", "ping"];
+
+const TAG_TYPES: &[&str] = &["Artist", "Album", "Title", "Track", "Genre"];
+
+#[derive(Debug)]
+struct Ack {
+    code: i32,
+    message: String,
+}
+
+impl Ack {
+    fn new<S: Into<String>>(code: i32, message: S) -> Ack {
+        Ack {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for Ack {
+    fn from(e: anyhow::Error) -> Ack {
+        Ack::new(ACK_ERROR_SYSTEM, e.to_string())
+    }
+}
+
+impl From<diesel::result::Error> for Ack {
+    fn from(e: diesel::result::Error) -> Ack {
+        match e {
+            diesel::result::Error::NotFound => Ack::new(ACK_ERROR_NO_EXIST, "No such object"),
+            e => Ack::new(ACK_ERROR_SYSTEM, e.to_string()),
+        }
+    }
+}
+
+impl From<diesel::r2d2::PoolError> for Ack {
+    fn from(e: diesel::r2d2::PoolError) -> Ack {
+        Ack::new(ACK_ERROR_SYSTEM, e.to_string())
+    }
+}
+
+// Arguments are separated by whitespace and may be quoted, with backslashes escaping the next
+// character inside of quotes
+fn tokenize(line: &str) -> Result<Vec<String>, Ack> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let mut arg = String::new();
+        match chars.peek() {
+            None => break,
+            Some('"') => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => arg.push(c),
+                            None => return Err(Ack::new(ACK_ERROR_ARG, "Missing closing '\"'")),
+                        },
+                        Some(c) => arg.push(c),
+                        None => return Err(Ack::new(ACK_ERROR_ARG, "Missing closing '\"'")),
+                    }
+                }
+            }
+            Some(_) => {
+                while let Some(c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    arg.push(*c);
+                    chars.next();
+                }
+            }
+        }
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+type Song = (Track, Option<Album>, Option<Artist>, Option<Genre>);
+
+fn load_songs(conn: &SqliteConnection, track_ids: Option<&[i32]>) -> Result<Vec<Song>, Ack> {
+    let query = tracks::table
+        .left_join(albums::table)
+        .left_join(artists::table)
+        .left_join(genres::table)
+        .order((tracks::name.asc(), tracks::id.asc()))
+        .into_boxed();
+    let query = match track_ids {
+        Some(track_ids) => query.filter(tracks::id.eq_any(track_ids.to_vec())),
+        None => query,
+    };
+    Ok(query.load::<Song>(conn)?)
+}
+
+fn uri(track_id: i32) -> String {
+    format!(
+        "tracks/{}.mp3",
+        &*ExternalId::new(EntityKind::Track, track_id).0
+    )
+}
+
+fn parse_uri(conn: &SqliteConnection, uri: &str) -> Result<i32, Ack> {
+    let no_such_song = || Ack::new(ACK_ERROR_NO_EXIST, "No such song");
+    let id = uri
+        .strip_prefix("tracks/")
+        .and_then(|uri| uri.strip_suffix(".mp3"))
+        .ok_or_else(no_such_song)?;
+    let id = ExternalId(juniper::ID::from(String::from(id)))
+        .decode(EntityKind::Track)
+        .map_err(|_| no_such_song())?;
+    let exists: bool =
+        diesel::select(diesel::dsl::exists(tracks::table.find(id))).get_result(conn)?;
+    if exists {
+        Ok(id)
+    } else {
+        Err(no_such_song())
+    }
+}
+
+// Values can't span several lines
+fn value(s: &str) -> String {
+    s.replace('\n', " ")
+}
+
+fn timestamp(datetime: NaiveDateTime) -> String {
+    datetime.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+fn tag(song: &Song, tag: &str) -> Option<String> {
+    let (track, album, artist, genre) = song;
+    match tag {
+        "file" => Some(uri(track.id)),
+        "title" => Some(track.name.clone()),
+        "artist" | "albumartist" => artist.as_ref().map(|artist| artist.name.clone()),
+        "album" => album.as_ref().map(|album| album.name.clone()),
+        "genre" => genre.as_ref().map(|genre| genre.name.clone()),
+        "track" => track
+            .track_number
+            .map(|track_number| track_number.to_string()),
+        _ => None,
+    }
+}
+
+fn song_info(song: &Song) -> String {
+    let (track, _, _, _) = song;
+    let mut info = String::new();
+    let _ = writeln!(info, "file: {}", uri(track.id));
+    let _ = writeln!(info, "Last-Modified: {}", timestamp(track.created_at));
+    for name in TAG_TYPES {
+        if let Some(value) = tag(song, &name.to_lowercase()) {
+            let _ = writeln!(info, "{}: {}", name, self::value(&value));
+        }
+    }
+    let _ = writeln!(info, "Time: {}", track.duration / 1000);
+    let _ = writeln!(info, "duration: {:.3}", f64::from(track.duration) / 1000.0);
+    info
+}
+
+struct Filter {
+    tag: String,
+    value: String,
+    exact: bool,
+    negated: bool,
+}
+
+impl Filter {
+    fn matches(&self, song: &Song) -> bool {
+        let tags: Vec<String> = match &self.tag[..] {
+            "any" => ["file", "title", "artist", "album", "genre"]
+                .iter()
+                .filter_map(|name| tag(song, name))
+                .collect(),
+            name => tag(song, name).into_iter().collect(),
+        };
+        let matches = tags.iter().any(|tag| {
+            if self.exact {
+                *tag == self.value
+            } else {
+                tag.to_lowercase().contains(&self.value.to_lowercase())
+            }
+        });
+        matches != self.negated
+    }
+}
+
+// Supports the legacy "TAG VALUE" pairs as well as single filter expressions like
+// (artist == "VALUE") or (album contains 'VALUE')
+fn parse_filters(args: &[String], exact: bool) -> Result<Vec<Filter>, Ack> {
+    let invalid = || Ack::new(ACK_ERROR_ARG, "Invalid filter");
+    let mut filters = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if let Some(expression) = arg.strip_prefix('(').and_then(|e| e.strip_suffix(')')) {
+            let (tag, op, value) = ["==", "!=", "contains"]
+                .iter()
+                .find_map(|op| {
+                    expression
+                        .find(&format!(" {} ", op))
+                        .map(|i| (&expression[..i], *op, &expression[i + op.len() + 2..]))
+                })
+                .ok_or_else(invalid)?;
+            let value = value.trim();
+            let quote = value.chars().next().ok_or_else(invalid)?;
+            if !(quote == '"' || quote == '\'') || value.len() < 2 || !value.ends_with(quote) {
+                return Err(invalid());
+            }
+            let mut unescaped = String::new();
+            let mut chars = value[1..value.len() - 1].chars();
+            while let Some(c) = chars.next() {
+                unescaped.push(if c == '\\' {
+                    chars.next().ok_or_else(invalid)?
+                } else {
+                    c
+                });
+            }
+            filters.push(Filter {
+                tag: tag.trim().to_lowercase(),
+                value: unescaped,
+                exact: exact && op != "contains",
+                negated: op == "!=",
+            });
+        } else {
+            let value = args.next().ok_or_else(invalid)?;
+            filters.push(Filter {
+                tag: arg.to_lowercase(),
+                value: value.clone(),
+                exact,
+                negated: false,
+            });
+        }
+    }
+    Ok(filters)
+}
+
+enum Input {
+    Line(String),
+    Event(Event),
+    Closed,
+}
+
+struct Session {
+    context: RequestContext,
+    started: Instant,
+    username: Option<String>,
+    player_state: Option<PlayerState>,
+    // subsystems which changed since the last idle command
+    changed: BTreeSet<&'static str>,
+}
+
+impl Session {
+    fn player(&self) -> Result<&Player, Ack> {
+        self.context.player.as_ref().ok_or_else(|| {
+            Ack::new(
+                ACK_ERROR_SYSTEM,
+                "Playback is disabled, start pitunes with --sink to enable it",
+            )
+        })
+    }
+
+    fn username(&self) -> &str {
+        self.username.as_deref().unwrap_or_default()
+    }
+
+    // The player if it plays the queue of the user
+    fn playing(&self) -> Option<&Player> {
+        self.context
+            .player
+            .as_ref()
+            .filter(|player| player.username().as_deref() == Some(self.username()))
+    }
+
+    // The track ids of the user's persistent queue and the index of its current track
+    fn queue(&self, conn: &SqliteConnection) -> Result<(Vec<i32>, usize), Ack> {
+        if let Some(player) = self.playing() {
+            let state = player.state();
+            return Ok((state.track_ids, state.index as usize));
+        }
+        let queue = queues::find(conn, self.username())?;
+        Ok((
+            queues::track_ids(conn, self.username())?,
+            queues::current_index(conn, &queue)? as usize,
+        ))
+    }
+
+    // Lets other clients and the player know that the user's queue changed
+    fn queue_changed(&self) -> Result<String, Ack> {
+        self.context
+            .events
+            .publish(Event::QueueChanged(String::from(self.username())));
+        if let Some(player) = &self.context.player {
+            player.reload(self.username())?;
+        }
+        Ok(String::new())
+    }
+
+    fn record(&mut self, event: Event) {
+        match event {
+            Event::TrackAdded(_)
+            | Event::TrackUpdated(_)
+            | Event::TrackDeleted(_)
+            | Event::AlbumUpdated(_)
+            | Event::AlbumDeleted(_)
+            | Event::ArtistUpdated(_)
+            | Event::ArtistDeleted(_)
+            | Event::GenreUpdated(_)
+            | Event::GenreDeleted(_) => {
+                self.changed.insert("database");
+            }
+            Event::PlaylistChanged(_) | Event::PlaylistDeleted(_) => {
+                self.changed.insert("stored_playlist");
+            }
+            Event::QueueChanged(username) => {
+                if self.username.as_ref() == Some(&username) {
+                    self.changed.insert("playlist");
+                    self.changed.insert("player");
+                    self.changed.insert("options");
+                }
+            }
+            Event::PlayerChanged => {
+                if let Some(player) = &self.context.player {
+                    let state = player.state();
+                    if let Some(previous) = &self.player_state {
+                        if (previous.volume - state.volume).abs() > f64::EPSILON {
+                            self.changed.insert("mixer");
+                        }
+                        if previous.status != state.status
+                            || previous.index != state.index
+                            || previous.position != state.position
+                        {
+                            self.changed.insert("player");
+                        }
+                    }
+                    self.player_state = Some(state);
+                }
+            }
+            Event::StarChanged(..) | Event::RatingChanged(..) => {}
+        }
+    }
+
+    // Returns the response to a pending idle command if any of the subsystems changed
+    fn take_changed(&mut self, subsystems: &BTreeSet<String>) -> Option<String> {
+        let changed: Vec<&'static str> = self
+            .changed
+            .iter()
+            .filter(|changed| subsystems.is_empty() || subsystems.contains(**changed))
+            .cloned()
+            .collect();
+        if changed.is_empty() {
+            return None;
+        }
+        let mut response = String::new();
+        for subsystem in changed {
+            self.changed.remove(subsystem);
+            let _ = writeln!(response, "changed: {}", subsystem);
+        }
+        response.push_str("OK\n");
+        Some(response)
+    }
+
+    fn authorize(&self, command: &str) -> Result<(), Ack> {
+        if self.username.is_none() && !PUBLIC_COMMANDS.contains(&command) {
+            return Err(Ack::new(
+                ACK_ERROR_PERMISSION,
+                format!("you don't have permission for \"{}\"", command),
+            ));
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, args: &[String]) -> Result<String, Ack> {
+        let command = &args[0][..];
+        self.authorize(command)?;
+        let arg = |i: usize| {
+            args.get(i).map(|arg| &arg[..]).ok_or_else(|| {
+                Ack::new(
+                    ACK_ERROR_ARG,
+                    format!("wrong number of arguments for \"{}\"", command),
+                )
+            })
+        };
+        let int = |i: usize| -> Result<i32, Ack> {
+            arg(i)?
+                .parse()
+                .map_err(|_| Ack::new(ACK_ERROR_ARG, format!("Integer expected: {}", args[i])))
+        };
+        let seconds = |i: usize| -> Result<f64, Ack> {
+            arg(i)?
+                .parse()
+                .map_err(|_| Ack::new(ACK_ERROR_ARG, format!("Number expected: {}", args[i])))
+        };
+        let conn = self.context.pool.get()?;
+        match command {
+            "ping" | "notcommands" | "urlhandlers" => Ok(String::new()),
+            "password" => self.password(&conn, arg(1)?),
+            "commands" => Ok(COMMANDS
+                .iter()
+                .map(|command| format!("command: {}\n", command))
+                .collect()),
+            // tagtypes subcommands which configure the tags are accepted but have no effect
+            "tagtypes" if args.len() > 1 => Ok(String::new()),
+            "tagtypes" => Ok(TAG_TYPES
+                .iter()
+                .map(|tag_type| format!("tagtype: {}\n", tag_type))
+                .collect()),
+            "outputs" => Ok(format!(
+                "outputid: 0\noutputname: pitunes\noutputenabled: {}\n",
+                self.context.player.is_some() as i32
+            )),
+            "status" => self.status(&conn),
+            "stats" => self.stats(&conn),
+            "currentsong" => {
+                let (track_ids, index) = self.queue(&conn)?;
+                self.queue_info(&conn, &track_ids, Some(index))
+            }
+            "playlistinfo" => {
+                let (track_ids, _) = self.queue(&conn)?;
+                let position = match args.len() {
+                    1 => None,
+                    _ => Some(int(1)? as usize),
+                };
+                self.queue_info(&conn, &track_ids, position)
+            }
+            // changes aren't tracked per version, so clients receive the whole queue
+            "plchanges" => {
+                let (track_ids, _) = self.queue(&conn)?;
+                self.queue_info(&conn, &track_ids, None)
+            }
+            "add" | "addid" => {
+                let track_id = parse_uri(&conn, arg(1)?)?;
+                queues::append(&conn, self.username(), &[track_id])?;
+                self.queue_changed()?;
+                match command {
+                    "addid" => Ok(format!(
+                        "Id: {}\n",
+                        queues::track_ids(&conn, self.username())?.len() - 1
+                    )),
+                    _ => Ok(String::new()),
+                }
+            }
+            "clear" => {
+                queues::clear(&conn, self.username())?;
+                self.queue_changed()
+            }
+            // song ids are positions within the queue
+            "play" | "playid" => {
+                let index = match args.len() {
+                    1 => None,
+                    _ => Some(int(1)?),
+                };
+                let (track_ids, _) = self.queue(&conn)?;
+                let player = self.player()?;
+                match index {
+                    Some(index) if index < 0 || index as usize >= track_ids.len() => {
+                        Err(Ack::new(ACK_ERROR_ARG, "Bad song index"))
+                    }
+                    index => player
+                        .play(self.username(), None, index.map(|index| index as usize))?
+                        .empty(),
+                }
+            }
+            "pause" => {
+                let player = self.player()?;
+                let pause = match args.len() {
+                    1 => {
+                        self.playing().map(Player::state).map(|state| state.status)
+                            == Some(PlayerStatus::Playing)
+                    }
+                    _ => int(1)? == 1,
+                };
+                match self.playing() {
+                    Some(player) if pause => player.pause().empty(),
+                    None if pause => Ok(String::new()),
+                    _ => player.play(self.username(), None, None)?.empty(),
+                }
+            }
+            // the player is left alone while it plays the queue of another user
+            "stop" => self.playing().map(Player::stop).empty(),
+            "next" => self.playing().map(|player| player.skip(1)).empty(),
+            "previous" => self.playing().map(|player| player.skip(-1)).empty(),
+            "seek" | "seekid" => {
+                let player = self.player()?;
+                let index = int(1)?;
+                let position = seconds(2)?;
+                let (track_ids, _) = self.queue(&conn)?;
+                if index < 0 || index as usize >= track_ids.len() {
+                    return Err(Ack::new(ACK_ERROR_ARG, "Bad song index"));
+                }
+                match self.playing().map(Player::state) {
+                    Some(state)
+                        if index == state.index && state.status != PlayerStatus::Stopped => {}
+                    _ => {
+                        player.play(self.username(), None, Some(index as usize))?;
+                    }
+                }
+                player.seek((position * 1000.0) as i32).empty()
+            }
+            // relative to the current position if prefixed with + or -
+            "seekcur" => {
+                let player = match self.playing() {
+                    Some(player) => player,
+                    None => return Err(Ack::new(ACK_ERROR_ARG, "Not playing")),
+                };
+                let time = arg(1)?;
+                let position = seconds(1)? * 1000.0;
+                let position = if time.starts_with('+') || time.starts_with('-') {
+                    f64::from(player.state().position) + position
+                } else {
+                    position
+                };
+                player.seek(position as i32).empty()
+            }
+            "setvol" => {
+                let volume = int(1)?;
+                if !(0..=100).contains(&volume) {
+                    return Err(Ack::new(ACK_ERROR_ARG, "Invalid volume value"));
+                }
+                self.player()?.set_volume(f64::from(volume) / 100.0).empty()
+            }
+            "random" => {
+                let input = QueueInput {
+                    shuffle: Some(int(1)? == 1),
+                    ..QueueInput::default()
+                };
+                queues::update(&conn, self.username(), input)?;
+                self.queue_changed()
+            }
+            // the current track repeats when both repeat and single are on. Stopping after the
+            // current track, which single alone means to MPD, isn't supported so it repeats too.
+            "repeat" | "single" => {
+                let repeat = queues::find(&conn, self.username())?
+                    .repeat_mode
+                    .parse::<RepeatMode>()?;
+                let repeat = match (command, int(1)? == 1) {
+                    ("repeat", true) if repeat == RepeatMode::Off => RepeatMode::All,
+                    ("repeat", true) => repeat,
+                    ("repeat", false) => RepeatMode::Off,
+                    (_, true) => RepeatMode::One,
+                    (_, false) if repeat == RepeatMode::One => RepeatMode::All,
+                    (_, false) => repeat,
+                };
+                let input = QueueInput {
+                    repeat: Some(repeat),
+                    ..QueueInput::default()
+                };
+                queues::update(&conn, self.username(), input)?;
+                self.queue_changed()
+            }
+            "list" => self.list(&conn, &args[1..]),
+            "find" | "search" => {
+                let filters = parse_filters(&args[1..], command == "find")?;
+                Ok(load_songs(&conn, None)?
+                    .iter()
+                    .filter(|song| filters.iter().all(|filter| filter.matches(song)))
+                    .map(song_info)
+                    .collect())
+            }
+            "listplaylists" => Ok(playlists::table
+                .order(playlists::name.asc())
+                .load::<Playlist>(&conn)?
+                .iter()
+                .map(|playlist| {
+                    format!(
+                        "playlist: {}\nLast-Modified: {}\n",
+                        value(&playlist.name),
+                        timestamp(playlist.created_at)
+                    )
+                })
+                .collect()),
+            "listplaylist" => Ok(self
+                .playlist_tracks(&conn, arg(1)?)?
+                .iter()
+                .map(|track| format!("file: {}\n", uri(track.id)))
+                .collect()),
+            "listplaylistinfo" => {
+                let track_ids: Vec<i32> = self
+                    .playlist_tracks(&conn, arg(1)?)?
+                    .iter()
+                    .map(|track| track.id)
+                    .collect();
+                let songs = songs_by_id(&conn, &track_ids)?;
+                Ok(track_ids
+                    .iter()
+                    .filter_map(|id| songs.get(id))
+                    .cloned()
+                    .collect())
+            }
+            "load" => {
+                let track_ids: Vec<i32> = self
+                    .playlist_tracks(&conn, arg(1)?)?
+                    .iter()
+                    .map(|track| track.id)
+                    .collect();
+                queues::append(&conn, self.username(), &track_ids)?;
+                self.queue_changed()
+            }
+            _ => Err(Ack::new(
+                ACK_ERROR_UNKNOWN,
+                format!("unknown command \"{}\"", command),
+            )),
+        }
+    }
+
+    // The password consists of a pitunes username and its Subsonic password separated by a colon.
+    // MPD sends it in plain text, so the main password of the user isn't accepted.
+    fn password(&mut self, conn: &SqliteConnection, password: &str) -> Result<String, Ack> {
+        let incorrect = || Ack::new(ACK_ERROR_PASSWORD, "incorrect password");
+        let i = password.find(':').ok_or_else(incorrect)?;
+        let user = users::table
+            .find(&password[..i])
+            .get_result::<User>(conn)
+            .optional()?
+            .ok_or_else(incorrect)?;
+        let valid = match &user.subsonic_password {
+            Some(subsonic_password) => subsonic::constant_time_eq(
+                subsonic_password.as_bytes(),
+                &password.as_bytes()[i + 1..],
+            ),
+            None => false,
+        };
+        if !valid {
+            return Err(incorrect());
+        }
+        self.username = Some(user.username);
+        Ok(String::new())
+    }
+
+    fn status(&self, conn: &SqliteConnection) -> Result<String, Ack> {
+        let mut status = String::new();
+        let volume = match &self.context.player {
+            Some(player) => (player.state().volume * 100.0).round() as i32,
+            None => -1,
+        };
+        let _ = writeln!(status, "volume: {}", volume);
+        let queue = queues::find(conn, self.username())?;
+        let repeat = queue.repeat_mode.parse::<RepeatMode>()?;
+        let _ = writeln!(status, "repeat: {}", (repeat != RepeatMode::Off) as i32);
+        let _ = writeln!(status, "random: {}", queue.shuffle as i32);
+        let _ = writeln!(status, "single: {}", (repeat == RepeatMode::One) as i32);
+        status.push_str("consume: 0\n");
+        let (track_ids, index) = self.queue(conn)?;
+        // the version only has to change whenever the queue does
+        let version = track_ids.iter().fold(1u32, |version, id| {
+            version.wrapping_mul(31).wrapping_add(*id as u32)
+        });
+        let _ = writeln!(status, "playlist: {}", version);
+        let _ = writeln!(status, "playlistlength: {}", track_ids.len());
+        let state = self.playing().map(Player::state);
+        let _ = writeln!(
+            status,
+            "state: {}",
+            match state.as_ref().map(|state| state.status) {
+                Some(PlayerStatus::Playing) => "play",
+                Some(PlayerStatus::Paused) => "pause",
+                _ => "stop",
+            }
+        );
+        if let Some(track_id) = track_ids.get(index) {
+            let _ = writeln!(status, "song: {}", index);
+            let _ = writeln!(status, "songid: {}", index);
+            if let Some(state) = state.filter(|state| state.status != PlayerStatus::Stopped) {
+                let duration: i32 = tracks::table
+                    .find(track_id)
+                    .select(tracks::duration)
+                    .get_result(conn)?;
+                let elapsed = f64::from(state.position) / 1000.0;
+                let _ = writeln!(status, "time: {}:{}", elapsed as i32, duration / 1000);
+                let _ = writeln!(status, "elapsed: {:.3}", elapsed);
+                let _ = writeln!(status, "duration: {:.3}", f64::from(duration) / 1000.0);
+            }
+        }
+        Ok(status)
+    }
+
+    fn stats(&self, conn: &SqliteConnection) -> Result<String, Ack> {
+        let artists: i64 = artists::table.count().get_result(conn)?;
+        let albums: i64 = albums::table.count().get_result(conn)?;
+        let songs: i64 = tracks::table.count().get_result(conn)?;
+        let db_playtime: Option<i64> = tracks::table
+            .select(diesel::dsl::sum(tracks::duration))
+            .get_result(conn)?;
+        let db_update: Option<NaiveDateTime> = tracks::table
+            .select(diesel::dsl::max(tracks::created_at))
+            .get_result(conn)?;
+        Ok(format!(
+            "artists: {}\nalbums: {}\nsongs: {}\nuptime: {}\nplaytime: 0\ndb_playtime: {}\ndb_update: {}\n",
+            artists,
+            albums,
+            songs,
+            self.started.elapsed().as_secs(),
+            db_playtime.unwrap_or(0) / 1000,
+            db_update.map_or(0, |db_update| db_update.timestamp())
+        ))
+    }
+
+    fn queue_info(
+        &self,
+        conn: &SqliteConnection,
+        track_ids: &[i32],
+        position: Option<usize>,
+    ) -> Result<String, Ack> {
+        let track_ids: Vec<i32> = match position {
+            Some(position) => track_ids.get(position).cloned().into_iter().collect(),
+            None => track_ids.to_vec(),
+        };
+        let songs = songs_by_id(conn, &track_ids)?;
+        let mut info = String::new();
+        for (i, track_id) in track_ids.iter().enumerate() {
+            if let Some(song) = songs.get(track_id) {
+                let position = position.unwrap_or(i);
+                info.push_str(song);
+                let _ = writeln!(info, "Pos: {}\nId: {}", position, position);
+            }
+        }
+        Ok(info)
+    }
+
+    fn playlist_tracks(&self, conn: &SqliteConnection, name: &str) -> Result<Vec<Track>, Ack> {
+        let playlist = playlists::table
+            .filter(playlists::name.eq(name))
+            .first::<Playlist>(conn)
+            .optional()?
+            .ok_or_else(|| Ack::new(ACK_ERROR_NO_EXIST, "No such playlist"))?;
+        Ok(playlist.load_tracks(conn, self.username())?)
+    }
+
+    // list TAG [FILTER...] where the legacy form "list album ARTIST" filters by artist
+    fn list(&self, conn: &SqliteConnection, args: &[String]) -> Result<String, Ack> {
+        let name = args
+            .first()
+            .ok_or_else(|| Ack::new(ACK_ERROR_ARG, "too few arguments for \"list\""))?;
+        let tag_type = TAG_TYPES
+            .iter()
+            .find(|tag_type| tag_type.eq_ignore_ascii_case(name))
+            .or_else(|| {
+                if name.eq_ignore_ascii_case("albumartist") {
+                    Some(&"Artist")
+                } else {
+                    None
+                }
+            })
+            .ok_or_else(|| Ack::new(ACK_ERROR_ARG, format!("Unknown tag type: {}", name)))?;
+        let mut filter_args = &args[1..];
+        // grouping isn't supported, the values are listed without it
+        if let Some(i) = filter_args
+            .iter()
+            .position(|arg| arg.eq_ignore_ascii_case("group"))
+        {
+            filter_args = &filter_args[..i];
+        }
+        let filters = match filter_args {
+            [artist] if tag_type == &"Album" => vec![Filter {
+                tag: String::from("artist"),
+                value: artist.clone(),
+                exact: true,
+                negated: false,
+            }],
+            filter_args => parse_filters(filter_args, true)?,
+        };
+        let values: BTreeSet<String> = load_songs(conn, None)?
+            .iter()
+            .filter(|song| filters.iter().all(|filter| filter.matches(song)))
+            .filter_map(|song| tag(song, &tag_type.to_lowercase()))
+            .collect();
+        Ok(values
+            .iter()
+            .map(|value| format!("{}: {}\n", tag_type, self::value(value)))
+            .collect())
+    }
+}
+
+fn songs_by_id(conn: &SqliteConnection, track_ids: &[i32]) -> Result<HashMap<i32, String>, Ack> {
+    Ok(load_songs(conn, Some(track_ids))?
+        .iter()
+        .map(|song| (song.0.id, song_info(song)))
+        .collect())
+}
+
+trait EmptyResponse {
+    fn empty(self) -> Result<String, Ack>;
+}
+
+// Playback commands respond with an empty body
+impl EmptyResponse for PlayerState {
+    fn empty(self) -> Result<String, Ack> {
+        Ok(String::new())
+    }
+}
+
+impl EmptyResponse for Option<PlayerState> {
+    fn empty(self) -> Result<String, Ack> {
+        Ok(String::new())
+    }
+}
+
+fn ack(ack: &Ack, index: usize, command: &str) -> String {
+    format!(
+        "ACK [{}@{}] {{{}}} {}\n",
+        ack.code, index, command, ack.message
+    )
+}
+
+// Returns None at the end of the stream. Lines which are too long or not UTF-8 are errors.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_LINE_LENGTH as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    } else if line.len() > MAX_LINE_LENGTH {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Line too long"));
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn serve(stream: TcpStream, context: RequestContext, started: Instant) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let (sender, receiver) = mpsc::channel();
+    {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(stream);
+            while let Ok(Some(line)) = read_line(&mut reader) {
+                if sender.send(Input::Line(line)).is_err() {
+                    return;
+                }
+            }
+            let _ = sender.send(Input::Closed);
+        });
+    }
+    {
+        let mut events = context.events.subscribe();
+        thread::spawn(move || {
+            while let Some(event) = futures::executor::block_on(events.next()) {
+                if sender.send(Input::Event(event)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    writer.write_all(format!("OK MPD {}\n", PROTOCOL_VERSION).as_bytes())?;
+    let mut session = Session {
+        player_state: context.player.as_ref().map(Player::state),
+        context,
+        started,
+        username: None,
+        changed: BTreeSet::new(),
+    };
+    let mut idle: Option<BTreeSet<String>> = None;
+    let mut command_list: Option<(bool, Vec<Vec<String>>)> = None;
+    for input in receiver {
+        let line = match input {
+            Input::Line(line) => line,
+            Input::Event(event) => {
+                session.record(event);
+                if let Some(subsystems) = &idle {
+                    if let Some(response) = session.take_changed(subsystems) {
+                        writer.write_all(response.as_bytes())?;
+                        idle = None;
+                    }
+                }
+                continue;
+            }
+            Input::Closed => break,
+        };
+        if let Some(subsystems) = &idle {
+            // anything but noidle ends the connection while idling
+            if line.trim() != "noidle" {
+                break;
+            }
+            let response = session
+                .take_changed(subsystems)
+                .unwrap_or_else(|| String::from("OK\n"));
+            writer.write_all(response.as_bytes())?;
+            idle = None;
+            continue;
+        }
+        let args = match tokenize(&line) {
+            Ok(args) if args.is_empty() => {
+                writer.write_all(
+                    ack(&Ack::new(ACK_ERROR_UNKNOWN, "No command given"), 0, "").as_bytes(),
+                )?;
+                continue;
+            }
+            Ok(args) => args,
+            Err(e) => {
+                writer.write_all(ack(&e, 0, "").as_bytes())?;
+                continue;
+            }
+        };
+        let response = match (&mut command_list, &args[0][..]) {
+            (None, "command_list_begin") | (None, "command_list_ok_begin") => {
+                command_list = Some((args[0] == "command_list_ok_begin", Vec::new()));
+                continue;
+            }
+            (Some(_), "command_list_end") => {
+                let (list_ok, commands) = command_list.take().unwrap();
+                let mut response = String::new();
+                let mut failed = false;
+                for (i, args) in commands.iter().enumerate() {
+                    match session.execute(args) {
+                        Ok(body) => {
+                            response.push_str(&body);
+                            if list_ok {
+                                response.push_str("list_OK\n");
+                            }
+                        }
+                        Err(e) => {
+                            response.push_str(&ack(&e, i, &args[0]));
+                            failed = true;
+                            break;
+                        }
+                    }
+                }
+                if !failed {
+                    response.push_str("OK\n");
+                }
+                response
+            }
+            (Some((_, commands)), _) if commands.len() >= MAX_COMMAND_LIST_LENGTH => {
+                let e = Ack::new(ACK_ERROR_ARG, "Command list size exceeded");
+                writer.write_all(ack(&e, commands.len(), &args[0]).as_bytes())?;
+                break;
+            }
+            (Some((_, commands)), _) => {
+                commands.push(args);
+                continue;
+            }
+            (None, "close") => break,
+            (None, "idle") => {
+                if let Err(e) = session.authorize("idle") {
+                    writer.write_all(ack(&e, 0, "idle").as_bytes())?;
+                    continue;
+                }
+                let subsystems: BTreeSet<String> = args[1..].iter().cloned().collect();
+                match session.take_changed(&subsystems) {
+                    Some(response) => response,
+                    None => {
+                        idle = Some(subsystems);
+                        continue;
+                    }
+                }
+            }
+            (None, command) => match session.execute(&args) {
+                Ok(body) => body + "OK\n",
+                Err(e) => ack(&e, 0, command),
+            },
+        };
+        writer.write_all(response.as_bytes())?;
+    }
+    Ok(())
+}
+
+// Every client connection is served by its own thread
+pub fn listen(address: IpAddr, port: u16, context: RequestContext) -> io::Result<()> {
+    let listener = TcpListener::bind((address, port))?;
+    let started = Instant::now();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let context = context.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve(stream, context, started) {
+                            eprintln!("MPD connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Failed to accept MPD connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[test]
+fn it_tokenizes_and_parses_filters() {
+    let args = tokenize(r#"find artist "The \"Beatles\"" album Help!"#).unwrap();
+    assert_eq!(
+        args,
+        vec!["find", "artist", r#"The "Beatles""#, "album", "Help!"]
+    );
+    assert!(tokenize(r#"find "artist"#).is_err());
+    let filters = parse_filters(&args[1..], true).unwrap();
+    assert_eq!(filters.len(), 2);
+    assert_eq!(filters[0].value, r#"The "Beatles""#);
+    let filters = parse_filters(&[String::from(r#"(album contains 'it\'s')"#)], true).unwrap();
+    assert_eq!(filters[0].tag, "album");
+    assert_eq!(filters[0].value, "it's");
+    assert!(!filters[0].exact);
+    assert!(parse_filters(&[String::from("artist")], true).is_err());
+}
+
+#[test]
+fn it_limits_the_line_length() {
+    let mut reader = io::Cursor::new(b"ping\r\nstatus".to_vec());
+    assert_eq!(read_line(&mut reader).unwrap().as_deref(), Some("ping"));
+    assert_eq!(read_line(&mut reader).unwrap().as_deref(), Some("status"));
+    assert_eq!(read_line(&mut reader).unwrap(), None);
+    let mut reader = io::Cursor::new(vec![b'a'; MAX_LINE_LENGTH + 1]);
+    assert!(read_line(&mut reader).is_err());
+}
@@ -0,0 +1,149 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use diesel::{
+    dsl::{exists, not},
+    prelude::*,
+};
+
+use crate::{
+    models::{Album, Artist, Genre, Orphans, Track},
+    schema::{albums, artists, genres, tracks},
+};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum OrphanPolicy {
+    Never,
+    Immediate,
+    Periodic,
+}
+
+impl FromStr for OrphanPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "never" => Ok(OrphanPolicy::Never),
+            "immediate" => Ok(OrphanPolicy::Immediate),
+            "periodic" => Ok(OrphanPolicy::Periodic),
+            _ => Err(anyhow!("unknown orphan policy {}", s)),
+        }
+    }
+}
+
+// Albums, artists and genres that are not referenced by any track
+pub fn find_orphans(conn: &SqliteConnection) -> Result<Orphans> {
+    let albums = albums::table
+        .left_join(tracks::table)
+        .filter(tracks::id.is_null())
+        .select(albums::all_columns)
+        .load::<Album>(conn)?;
+    let artists = artists::table
+        .left_join(tracks::table)
+        .filter(tracks::id.is_null())
+        .select(artists::all_columns)
+        .load::<Artist>(conn)?;
+    let genres = genres::table
+        .left_join(tracks::table)
+        .filter(tracks::id.is_null())
+        .select(genres::all_columns)
+        .load::<Genre>(conn)?;
+    Ok(Orphans {
+        albums,
+        artists,
+        genres,
+    })
+}
+
+pub fn prune_orphans(conn: &SqliteConnection) -> Result<Orphans> {
+    conn.transaction::<_, anyhow::Error, _>(|| {
+        let orphans = find_orphans(conn)?;
+        let album_ids: Vec<i32> = orphans.albums.iter().map(|album| album.id).collect();
+        diesel::delete(albums::table.filter(albums::id.eq_any(album_ids))).execute(conn)?;
+        let artist_ids: Vec<i32> = orphans.artists.iter().map(|artist| artist.id).collect();
+        diesel::delete(artists::table.filter(artists::id.eq_any(artist_ids))).execute(conn)?;
+        let genre_ids: Vec<i32> = orphans.genres.iter().map(|genre| genre.id).collect();
+        diesel::delete(genres::table.filter(genres::id.eq_any(genre_ids))).execute(conn)?;
+        Ok(orphans)
+    })
+}
+
+// Called after mutations with the affected tracks as they were before. Only the albums, artists
+// and genres which these tracks referred to are pruned, so that entities which were just created
+// and have no tracks yet are left alone.
+pub fn prune_orphans_if_immediate(
+    policy: OrphanPolicy,
+    conn: &SqliteConnection,
+    previous: &[Track],
+) -> Result<()> {
+    if policy != OrphanPolicy::Immediate {
+        return Ok(());
+    }
+    let album_ids: Vec<i32> = previous.iter().filter_map(|track| track.album_id).collect();
+    diesel::delete(
+        albums::table
+            .filter(albums::id.eq_any(album_ids))
+            .filter(not(exists(
+                tracks::table.filter(tracks::album_id.eq(albums::id.nullable())),
+            ))),
+    )
+    .execute(conn)?;
+    let artist_ids: Vec<i32> = previous
+        .iter()
+        .filter_map(|track| track.artist_id)
+        .collect();
+    diesel::delete(
+        artists::table
+            .filter(artists::id.eq_any(artist_ids))
+            .filter(not(exists(
+                tracks::table.filter(tracks::artist_id.eq(artists::id.nullable())),
+            ))),
+    )
+    .execute(conn)?;
+    let genre_ids: Vec<i32> = previous.iter().filter_map(|track| track.genre_id).collect();
+    diesel::delete(
+        genres::table
+            .filter(genres::id.eq_any(genre_ids))
+            .filter(not(exists(
+                tracks::table.filter(tracks::genre_id.eq(genres::id.nullable())),
+            ))),
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+#[test]
+fn it_prunes_orphans() {
+    use diesel::connection::SimpleConnection;
+
+    use crate::db;
+
+    let dir = tempfile::tempdir().unwrap();
+    let pool = db::establish_connection(dir.path().join("pitunes.db").to_str().unwrap());
+    let conn = pool.get().unwrap();
+    conn.batch_execute(
+        "INSERT INTO albums (id, name) VALUES (1, 'Abbey Road'), (2, 'Let It Be'), (3, 'Help!');
+         INSERT INTO artists (id, name) VALUES (1, 'The Beatles'), (2, 'Beatles');
+         INSERT INTO tracks (id, name, duration, album_id, artist_id) VALUES
+             (1, 'Come Together', 259000, 1, 1),
+             (2, 'Something', 182000, 2, 2);",
+    )
+    .unwrap();
+    let previous: Vec<Track> = tracks::table.load(&conn).unwrap();
+    conn.batch_execute("UPDATE tracks SET album_id = 1, artist_id = 1;")
+        .unwrap();
+    let ids = |orphans: Orphans| -> (Vec<i32>, Vec<i32>) {
+        (
+            orphans.albums.iter().map(|album| album.id).collect(),
+            orphans.artists.iter().map(|artist| artist.id).collect(),
+        )
+    };
+    assert_eq!(ids(find_orphans(&conn).unwrap()), (vec![2, 3], vec![2]));
+    prune_orphans_if_immediate(OrphanPolicy::Never, &conn, &previous).unwrap();
+    assert_eq!(ids(find_orphans(&conn).unwrap()), (vec![2, 3], vec![2]));
+    // the album without tracks which no track referred to before is kept
+    prune_orphans_if_immediate(OrphanPolicy::Immediate, &conn, &previous).unwrap();
+    assert_eq!(ids(find_orphans(&conn).unwrap()), (vec![3], vec![]));
+    assert_eq!(ids(prune_orphans(&conn).unwrap()), (vec![3], vec![]));
+    assert_eq!(ids(find_orphans(&conn).unwrap()), (vec![], vec![]));
+}
@@ -0,0 +1,465 @@
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+
+use anyhow::Result;
+use minimp3::{Decoder, Error};
+use oorandom::Rand32;
+
+use crate::{
+    db::SqlitePool,
+    events::{Event, Events},
+    models::{PlayerState, PlayerStatus, Queue, RepeatMode},
+    prng, queues,
+    sinks::Sink,
+    tracks_service::track_filepath,
+};
+
+struct State {
+    // the user whose queue is being played
+    username: Option<String>,
+    queue: Vec<i32>,
+    index: usize,
+    status: PlayerStatus,
+    position: f64,
+    volume: f64,
+    seek: Option<f64>,
+    // bumped whenever the track being decoded has to be abandoned
+    generation: u64,
+    shuffle: bool,
+    repeat: RepeatMode,
+    // indices which were played since the queue was shuffled
+    played: HashSet<usize>,
+    rng: Rand32,
+}
+
+impl State {
+    fn new() -> State {
+        State {
+            username: None,
+            queue: Vec::new(),
+            index: 0,
+            status: PlayerStatus::Stopped,
+            position: 0.0,
+            volume: 1.0,
+            seek: None,
+            generation: 0,
+            shuffle: false,
+            repeat: RepeatMode::Off,
+            played: HashSet::new(),
+            rng: prng::seeded(prng::rand_i32().unwrap_or(0)),
+        }
+    }
+
+    // Jumping past the end stops at the last track
+    fn jump(&mut self, index: usize) {
+        self.generation += 1;
+        self.position = 0.0;
+        self.seek = None;
+        if index < self.queue.len() {
+            self.index = index;
+        } else {
+            self.index = self.queue.len().saturating_sub(1);
+            self.status = PlayerStatus::Stopped;
+        }
+    }
+
+    fn set_queue(&mut self, track_ids: Vec<i32>, queue: &Queue) -> Result<()> {
+        if self.queue != track_ids || !queue.shuffle {
+            self.played.clear();
+        }
+        self.queue = track_ids;
+        self.shuffle = queue.shuffle;
+        self.repeat = queue.repeat_mode.parse()?;
+        Ok(())
+    }
+
+    // The track which follows the current one once it ended. Shuffled queues play every track
+    // once in random order before they repeat or stop.
+    fn next_index(&mut self) -> Option<usize> {
+        let len = self.queue.len();
+        if len == 0 {
+            return None;
+        }
+        if self.repeat == RepeatMode::One {
+            return Some(self.index);
+        }
+        if !self.shuffle {
+            return match self.index + 1 {
+                next if next < len => Some(next),
+                _ if self.repeat == RepeatMode::All => Some(0),
+                _ => None,
+            };
+        }
+        self.played.insert(self.index);
+        if self.played.len() >= len {
+            self.played.clear();
+            if self.repeat == RepeatMode::Off {
+                return None;
+            }
+            // the track which just ended doesn't follow itself
+            if len > 1 {
+                self.played.insert(self.index);
+            }
+        }
+        let unplayed: Vec<usize> = (0..len).filter(|i| !self.played.contains(i)).collect();
+        Some(unplayed[self.rng.rand_range(0..unplayed.len() as u32) as usize])
+    }
+}
+
+// Decodes and plays the persistent queue of a user on a dedicated thread. Changes of the current
+// track are saved to the queue so that other clients of the user continue where it left off.
+#[derive(Clone)]
+pub struct Player {
+    shared: Arc<(Mutex<State>, Condvar)>,
+    events: Events,
+    pool: Arc<SqlitePool>,
+}
+
+impl Player {
+    pub fn spawn(
+        tracks_dir: PathBuf,
+        mut sink: Box<dyn Sink>,
+        events: Events,
+        pool: Arc<SqlitePool>,
+    ) -> Player {
+        let player = Player {
+            shared: Arc::new((Mutex::new(State::new()), Condvar::new())),
+            events,
+            pool,
+        };
+        let shared = player.shared.clone();
+        let events = player.events.clone();
+        let saver = player.clone();
+        thread::spawn(move || {
+            let (lock, cvar) = &*shared;
+            loop {
+                let (track_id, generation) = {
+                    let mut state = lock.lock().unwrap();
+                    while state.status != PlayerStatus::Playing || state.index >= state.queue.len()
+                    {
+                        state = cvar.wait(state).unwrap();
+                    }
+                    (state.queue[state.index], state.generation)
+                };
+                let filepath = track_filepath(&tracks_dir, track_id);
+                if let Err(e) = play_track(&shared, &filepath, generation, &mut *sink) {
+                    eprintln!("Failed to play track {}: {}", track_id, e);
+                }
+                let mut state = lock.lock().unwrap();
+                if state.generation == generation {
+                    match state.next_index() {
+                        Some(next) => state.jump(next),
+                        None => {
+                            state.status = PlayerStatus::Stopped;
+                            let index = state.index;
+                            state.jump(index);
+                        }
+                    }
+                    events.publish(Event::PlayerChanged);
+                    let (username, index) = (state.username.clone(), state.index);
+                    drop(state);
+                    saver.save(username, index, 0);
+                } else {
+                    drop(state);
+                }
+                cvar.notify_all();
+            }
+        });
+        player
+    }
+
+    // Saves the current track to the queue of the user who is listening
+    fn save(&self, username: Option<String>, index: usize, position: i32) {
+        let username = match username {
+            Some(username) => username,
+            None => return,
+        };
+        let result = self
+            .pool
+            .get()
+            .map_err(anyhow::Error::from)
+            .and_then(|conn| queues::seek(&conn, &username, i32::try_from(index)?, position));
+        match result {
+            Ok(_) => self.events.publish(Event::QueueChanged(username)),
+            Err(e) => eprintln!("Failed to save the queue of {}: {}", username, e),
+        }
+    }
+
+    fn update<F: FnOnce(&mut State)>(&self, f: F) -> PlayerState {
+        let (lock, cvar) = &*self.shared;
+        let mut state = lock.lock().unwrap();
+        f(&mut state);
+        cvar.notify_all();
+        self.events.publish(Event::PlayerChanged);
+        snapshot(&state)
+    }
+
+    pub fn state(&self) -> PlayerState {
+        let (lock, _) = &*self.shared;
+        snapshot(&lock.lock().unwrap())
+    }
+
+    // Plays the queue of the user, replacing it if track ids are given. Without track ids or an
+    // index the queue continues where the user left off.
+    pub fn play(
+        &self,
+        username: &str,
+        track_ids: Option<Vec<i32>>,
+        index: Option<usize>,
+    ) -> Result<PlayerState> {
+        let conn = self.pool.get()?;
+        let resume = track_ids.is_none() && index.is_none();
+        let queue = match (track_ids, index) {
+            (Some(track_ids), index) => queues::replace(
+                &conn,
+                username,
+                &track_ids,
+                i32::try_from(index.unwrap_or(0))?,
+            )?,
+            (None, Some(index)) => queues::seek(&conn, username, i32::try_from(index)?, 0)?,
+            (None, None) => queues::find(&conn, username)?,
+        };
+        let track_ids = queues::track_ids(&conn, username)?;
+        let index = usize::try_from(queues::current_index(&conn, &queue)?)?;
+        if !resume {
+            self.events
+                .publish(Event::QueueChanged(String::from(username)));
+        }
+        let mut result = Ok(());
+        let state = self.update(|state| {
+            let playing = state.username.as_deref() == Some(username)
+                && state.queue.get(state.index) == track_ids.get(index);
+            state.username = Some(String::from(username));
+            result = state.set_queue(track_ids, &queue);
+            if resume && playing {
+                state.index = index;
+            } else {
+                state.jump(index);
+                if resume && queue.position > 0 {
+                    state.seek = Some(f64::from(queue.position) / 1000.0);
+                }
+            }
+            if state.index < state.queue.len() {
+                state.status = PlayerStatus::Playing;
+            }
+        });
+        result.map(|_| state)
+    }
+
+    // Picks up changes of the queue which is being played. The current track keeps playing if it
+    // is still the current one.
+    pub fn reload(&self, username: &str) -> Result<PlayerState> {
+        if self.state_of(|state| state.username.as_deref() != Some(username)) {
+            return Ok(self.state());
+        }
+        let conn = self.pool.get()?;
+        let queue = queues::find(&conn, username)?;
+        let track_ids = queues::track_ids(&conn, username)?;
+        let index = usize::try_from(queues::current_index(&conn, &queue)?)?;
+        let mut result = Ok(());
+        let state = self.update(|state| {
+            if state.username.as_deref() != Some(username) {
+                return;
+            }
+            let playing = state.queue.get(state.index) == track_ids.get(index);
+            result = state.set_queue(track_ids, &queue);
+            if playing {
+                state.index = index;
+            } else {
+                state.jump(index);
+            }
+        });
+        result.map(|_| state)
+    }
+
+    // The user whose queue is being played
+    pub fn username(&self) -> Option<String> {
+        self.state_of(|state| state.username.clone())
+    }
+
+    fn state_of<T, F: FnOnce(&State) -> T>(&self, f: F) -> T {
+        let (lock, _) = &*self.shared;
+        f(&lock.lock().unwrap())
+    }
+
+    pub fn pause(&self) -> PlayerState {
+        let state = self.update(|state| {
+            if state.status == PlayerStatus::Playing {
+                state.status = PlayerStatus::Paused;
+            }
+        });
+        self.save(self.username(), state.index as usize, state.position);
+        state
+    }
+
+    pub fn stop(&self) -> PlayerState {
+        self.update(|state| {
+            state.status = PlayerStatus::Stopped;
+            let index = state.index;
+            state.jump(index);
+        })
+    }
+
+    pub fn skip(&self, offset: i32) -> PlayerState {
+        let state = self.update(|state| {
+            let index = (state.index as i64 + i64::from(offset)).max(0) as usize;
+            state.jump(index);
+        });
+        self.save(self.username(), state.index as usize, 0);
+        state
+    }
+
+    // Position in milliseconds
+    pub fn seek(&self, position: i32) -> PlayerState {
+        self.update(|state| state.seek = Some(f64::from(position.max(0)) / 1000.0))
+    }
+
+    pub fn set_volume(&self, volume: f64) -> PlayerState {
+        self.update(|state| state.volume = volume.clamp(0.0, 1.0))
+    }
+}
+
+fn snapshot(state: &State) -> PlayerState {
+    PlayerState {
+        status: state.status,
+        track_ids: state.queue.clone(),
+        index: state.index as i32,
+        position: (state.seek.unwrap_or(state.position) * 1000.0) as i32,
+        volume: state.volume,
+    }
+}
+
+fn play_track(
+    shared: &(Mutex<State>, Condvar),
+    filepath: &Path,
+    generation: u64,
+    sink: &mut dyn Sink,
+) -> Result<()> {
+    let (lock, cvar) = shared;
+    let mut decoder = Decoder::new(File::open(filepath)?);
+    let mut position = 0.0;
+    let mut skip_until = 0.0;
+    loop {
+        let volume = {
+            let mut state = lock.lock().unwrap();
+            while state.generation == generation && state.status == PlayerStatus::Paused {
+                state = cvar.wait(state).unwrap();
+            }
+            if state.generation != generation {
+                return Ok(());
+            }
+            if let Some(target) = state.seek.take() {
+                // frames can't be located without decoding, so seeking backwards starts over
+                if target < position {
+                    decoder = Decoder::new(File::open(filepath)?);
+                    position = 0.0;
+                }
+                skip_until = target;
+            }
+            state.position = position.max(skip_until);
+            state.volume
+        };
+        let frame = match decoder.next_frame() {
+            Ok(frame) => frame,
+            Err(Error::Eof) => return Ok(()),
+            Err(Error::SkippedData) => continue,
+            Err(e) => Err(e)?,
+        };
+        position += frame.data.len() as f64 / frame.channels as f64 / frame.sample_rate as f64;
+        if position < skip_until {
+            continue;
+        }
+        let samples: Vec<i16> = frame
+            .data
+            .iter()
+            .map(|sample| (f64::from(*sample) * volume) as i16)
+            .collect();
+        sink.write(frame.sample_rate as u32, frame.channels as u16, &samples)?;
+    }
+}
+
+#[test]
+fn it_advances_through_the_queue() {
+    use std::time::{Duration, Instant};
+
+    use diesel::connection::SimpleConnection;
+
+    use crate::db;
+
+    let dir = tempfile::tempdir().unwrap();
+    let pool = db::establish_connection(dir.path().join("pitunes.db").to_str().unwrap());
+    let conn = pool.get().unwrap();
+    conn.batch_execute(
+        "INSERT INTO tracks (id, name, duration) VALUES
+             (1, 'One', 1000), (2, 'Two', 1000), (3, 'Three', 1000);",
+    )
+    .unwrap();
+    for id in 1..=3 {
+        File::create(track_filepath(dir.path(), id)).unwrap();
+    }
+    let player = Player::spawn(
+        dir.path().to_path_buf(),
+        Box::new(crate::sinks::NullSink),
+        Events::default(),
+        Arc::new(pool.clone()),
+    );
+    assert!(player.state().status == PlayerStatus::Stopped);
+    player.play("admin", Some(vec![1, 2, 3]), Some(1)).unwrap();
+    let started = Instant::now();
+    while player.state().status != PlayerStatus::Stopped {
+        assert!(started.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+    // the player stops at the last track
+    let state = player.state();
+    assert_eq!(state.track_ids, vec![1, 2, 3]);
+    assert_eq!(state.index, 2);
+    let queue = queues::find(&conn, "admin").unwrap();
+    assert_eq!(queues::current_index(&conn, &queue).unwrap(), 2);
+    assert_eq!(player.skip(-2).index, 0);
+    assert_eq!(player.set_volume(1.5).volume, 1.0);
+    // the player plays the persistent queue of the user
+    let queue = queues::find(&conn, "admin").unwrap();
+    assert_eq!(queues::track_ids(&conn, "admin").unwrap(), vec![1, 2, 3]);
+    assert_eq!(queues::current_index(&conn, &queue).unwrap(), 0);
+    queues::append(&conn, "admin", &[1]).unwrap();
+    assert_eq!(player.reload("admin").unwrap().track_ids, vec![1, 2, 3, 1]);
+    queues::clear(&conn, "admin").unwrap();
+    assert_eq!(player.reload("admin").unwrap().track_ids, Vec::<i32>::new());
+}
+
+#[test]
+fn it_repeats_and_shuffles() {
+    let mut state = State::new();
+    state.queue = vec![1, 2, 3];
+    state.index = 2;
+    assert_eq!(state.next_index(), None);
+    state.repeat = RepeatMode::All;
+    assert_eq!(state.next_index(), Some(0));
+    state.repeat = RepeatMode::One;
+    assert_eq!(state.next_index(), Some(2));
+    // every track is played once before the shuffled queue stops
+    state.repeat = RepeatMode::Off;
+    state.shuffle = true;
+    let mut played = vec![state.index];
+    while let Some(next) = state.next_index() {
+        state.index = next;
+        played.push(next);
+    }
+    played.sort_unstable();
+    assert_eq!(played, vec![0, 1, 2]);
+    // and starts over with another track if it repeats
+    state.repeat = RepeatMode::All;
+    for _ in 0..10 {
+        let index = state.index;
+        let next = state.next_index().unwrap();
+        assert_ne!(next, index);
+        state.index = next;
+    }
+}
@@ -0,0 +1,644 @@
+use std::path::Path;
+
+use anyhow::Result;
+use diesel::prelude::*;
+use serde_json::json;
+
+use crate::{
+    external_id::{EntityKind, ExternalId},
+    fuzzy,
+    models::{Artist, Track},
+    schema::{artists, tracks},
+    xml::{escape, unescape},
+};
+
+// Entries which don't match exactly are matched by similarity of artist and title if they are at
+// least this similar, and by duration if it's known
+const MIN_SIMILARITY: f64 = 0.8;
+const MAX_DURATION_DIFFERENCE: i32 = 5000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    M3u,
+    Pls,
+    Xspf,
+}
+
+impl Format {
+    // Formats are recognized by their content and otherwise by the file extension
+    pub fn detect(content: &str, filename: Option<&str>) -> Format {
+        let start = content.trim_start();
+        if start.starts_with("<?xml") || start.starts_with("<playlist") {
+            return Format::Xspf;
+        }
+        if start.to_lowercase().starts_with("[playlist]") {
+            return Format::Pls;
+        }
+        match filename
+            .and_then(|filename| Path::new(filename).extension())
+            .and_then(|extension| extension.to_str())
+            .map(str::to_lowercase)
+            .as_deref()
+        {
+            Some("xspf") => Format::Xspf,
+            Some("pls") => Format::Pls,
+            _ => Format::M3u,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Entry {
+    pub location: Option<String>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    // in milliseconds
+    pub duration: Option<i32>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct PlaylistFile {
+    pub name: Option<String>,
+    pub entries: Vec<Entry>,
+}
+
+pub fn parse(content: &str, format: Format) -> PlaylistFile {
+    let content = content.trim_start_matches('\u{feff}');
+    match format {
+        Format::M3u => parse_m3u(content),
+        Format::Pls => parse_pls(content),
+        Format::Xspf => parse_xspf(content),
+    }
+}
+
+// Plain M3U is just a list of locations, extended M3U adds "#EXTINF:<seconds>,<artist> - <title>"
+fn parse_m3u(content: &str) -> PlaylistFile {
+    let mut playlist = PlaylistFile::default();
+    let mut info: Option<Entry> = None;
+    for line in content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            let (duration, title) = match extinf.find(',') {
+                Some(i) => (&extinf[..i], &extinf[i + 1..]),
+                None => (extinf, ""),
+            };
+            // attributes like tvg-id="..." may follow the duration
+            let duration = duration.split_whitespace().next().unwrap_or_default();
+            let mut entry = split_title(title);
+            entry.duration = seconds(duration);
+            info = Some(entry);
+        } else if let Some(name) = line.strip_prefix("#PLAYLIST:") {
+            playlist.name = Some(String::from(name.trim()));
+        } else if !line.starts_with('#') {
+            let mut entry = info.take().unwrap_or_default();
+            entry.location = Some(String::from(line));
+            playlist.entries.push(entry);
+        }
+    }
+    playlist
+}
+
+// PLS is an INI file with numbered FileN, TitleN and LengthN keys
+fn parse_pls(content: &str) -> PlaylistFile {
+    let mut entries = std::collections::BTreeMap::<u32, Entry>::new();
+    for line in content.lines().map(str::trim) {
+        let i = match line.find('=') {
+            Some(i) => i,
+            None => continue,
+        };
+        let (key, value) = (line[..i].trim().to_lowercase(), line[i + 1..].trim());
+        let split = key.find(|c: char| c.is_ascii_digit()).unwrap_or(key.len());
+        let number = match key[split..].parse::<u32>() {
+            Ok(number) => number,
+            Err(_) => continue,
+        };
+        let entry = entries.entry(number).or_default();
+        match &key[..split] {
+            "file" => entry.location = Some(String::from(value)),
+            "title" => {
+                let title = split_title(value);
+                entry.title = title.title;
+                entry.artist = title.artist;
+            }
+            "length" => entry.duration = seconds(value),
+            _ => {}
+        }
+    }
+    PlaylistFile {
+        name: None,
+        entries: entries
+            .into_values()
+            .filter(|entry| entry.location.is_some())
+            .collect(),
+    }
+}
+
+fn parse_xspf(content: &str) -> PlaylistFile {
+    let track_list = match content.find("<trackList") {
+        Some(i) => i,
+        None => return PlaylistFile::default(),
+    };
+    let mut entries = Vec::new();
+    let mut rest = &content[track_list..];
+    while let Some(start) = rest.find("<track>").or_else(|| rest.find("<track ")) {
+        let end = rest[start..]
+            .find("</track>")
+            .map(|end| start + end)
+            .unwrap_or(rest.len());
+        let track = &rest[start..end];
+        entries.push(Entry {
+            location: element(track, "location"),
+            title: element(track, "title"),
+            artist: element(track, "creator"),
+            album: element(track, "album"),
+            duration: element(track, "duration").and_then(|duration| duration.parse().ok()),
+        });
+        rest = &rest[end..];
+    }
+    PlaylistFile {
+        // the playlist's title precedes its track list
+        name: element(&content[..track_list], "title"),
+        entries,
+    }
+}
+
+// Returns the unescaped text of the first element with the given name
+fn element(xml: &str, name: &str) -> Option<String> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&close)?;
+    let text = xml[start..end].trim();
+    let text = text
+        .strip_prefix("<![CDATA[")
+        .and_then(|text| text.strip_suffix("]]>"))
+        .map(String::from)
+        .unwrap_or_else(|| unescape(text));
+    Some(text).filter(|text| !text.is_empty())
+}
+
+// Splits titles like "Artist - Title"
+fn split_title(title: &str) -> Entry {
+    let title = title.trim();
+    match title.find(" - ") {
+        Some(i) => Entry {
+            artist: Some(String::from(title[..i].trim())),
+            title: Some(String::from(title[i + 3..].trim())),
+            ..Entry::default()
+        },
+        None if !title.is_empty() => Entry {
+            title: Some(String::from(title)),
+            ..Entry::default()
+        },
+        None => Entry::default(),
+    }
+}
+
+// Negative lengths mean that the length is unknown
+fn seconds(s: &str) -> Option<i32> {
+    s.trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| *seconds > 0.0)
+        .map(|seconds| (seconds * 1000.0) as i32)
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Returns the file name of a path or url without its extension, e.g. "01 Come Together" for
+// "file:///music/Abbey%20Road/01%20Come%20Together.mp3"
+fn file_stem(location: &str) -> Option<String> {
+    let location = location.split(['?', '#']).next()?;
+    let location = percent_decode(location);
+    let name = location.rsplit(['/', '\\']).next()?;
+    let stem = match name.rfind('.') {
+        Some(i) if i > 0 => &name[..i],
+        _ => name,
+    };
+    Some(String::from(stem)).filter(|stem| !stem.is_empty())
+}
+
+// Guesses artist and title from a file name, skipping a leading track number
+fn entry_from_stem(stem: &str) -> Entry {
+    let stem = stem.replace('_', " ");
+    let number = stem.trim_start_matches(|c: char| c.is_ascii_digit());
+    let stem = match number.chars().next() {
+        Some(c) if number.len() < stem.len() && (c == '.' || c == '-' || c == ' ') => {
+            number.trim_start_matches(['.', '-', ' '])
+        }
+        _ => &stem[..],
+    };
+    if stem.is_empty() {
+        return Entry::default();
+    }
+    split_title(stem)
+}
+
+pub struct Library {
+    tracks: Vec<LibraryTrack>,
+}
+
+// The title and artist name are normalized once for matching
+struct LibraryTrack {
+    track: Track,
+    title: String,
+    artist: Option<String>,
+}
+
+impl Library {
+    pub fn load(conn: &SqliteConnection) -> Result<Library> {
+        Ok(Library {
+            tracks: tracks::table
+                .left_join(artists::table)
+                .load::<(Track, Option<Artist>)>(conn)?
+                .into_iter()
+                .map(|(track, artist)| LibraryTrack {
+                    title: fuzzy::normalize(&track.name),
+                    artist: artist.map(|artist| fuzzy::normalize(&artist.name)),
+                    track,
+                })
+                .collect(),
+        })
+    }
+
+    // Matches an entry by the track id in its location, e.g. for playlists exported by piTunes,
+    // then by its tags, and finally by the file name of its location
+    pub fn find(&self, entry: &Entry) -> Option<i32> {
+        let stem = entry.location.as_deref().and_then(file_stem);
+        if let Some(stem) = &stem {
+            if let Ok(id) = ExternalId(juniper::ID::from(stem.clone())).decode(EntityKind::Track) {
+                if self.tracks.iter().any(|candidate| candidate.track.id == id) {
+                    return Some(id);
+                }
+            }
+        }
+        if entry.title.is_some() {
+            if let Some(id) = self.find_by_tags(entry) {
+                return Some(id);
+            }
+        }
+        let guess = entry_from_stem(&stem?);
+        self.find_by_tags(&Entry {
+            duration: entry.duration,
+            ..guess
+        })
+    }
+
+    fn find_by_tags(&self, entry: &Entry) -> Option<i32> {
+        let title = fuzzy::normalize(entry.title.as_deref()?);
+        let artist = entry.artist.as_deref().map(fuzzy::normalize);
+        let duration_difference = |track: &Track| match entry.duration {
+            Some(duration) => (track.duration - duration).abs(),
+            None => 0,
+        };
+        let mut best: Option<(f64, i32, i32)> = None;
+        for LibraryTrack {
+            track,
+            title: track_title,
+            artist: track_artist,
+        } in &self.tracks
+        {
+            let difference = duration_difference(track);
+            if difference > MAX_DURATION_DIFFERENCE {
+                continue;
+            }
+            let similarity = match (&artist, track_artist) {
+                (Some(artist), Some(track_artist)) => fuzzy::similarity(
+                    &format!("{} {}", artist, title),
+                    &format!("{} {}", track_artist, track_title),
+                ),
+                // entries without an artist have to agree on the title alone
+                (None, _) => fuzzy::similarity(&title, track_title),
+                (Some(_), None) => continue,
+            };
+            if similarity < MIN_SIMILARITY {
+                continue;
+            }
+            let better = match best {
+                Some((best_similarity, best_difference, _)) => {
+                    similarity > best_similarity
+                        || (similarity == best_similarity && difference < best_difference)
+                }
+                None => true,
+            };
+            if better {
+                best = Some((similarity, difference, track.id));
+            }
+        }
+        best.map(|(_, _, id)| id)
+    }
+}
+
+// Formats playlists can be exported as. M3U8 refers to tracks by url, whereas M3U refers to them
+// by the file names of the playlist's ZIP download, e.g. for copying them to a USB stick along
+// with the playlist.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    M3u8,
+    M3u,
+    Pls,
+    Xspf,
+    Json,
+}
+
+impl ExportFormat {
+    pub fn from_extension(extension: &str) -> Option<ExportFormat> {
+        match &extension.to_lowercase()[..] {
+            "m3u8" => Some(ExportFormat::M3u8),
+            "m3u" => Some(ExportFormat::M3u),
+            "pls" => Some(ExportFormat::Pls),
+            "xspf" => Some(ExportFormat::Xspf),
+            "json" => Some(ExportFormat::Json),
+            _ => None,
+        }
+    }
+
+    // Picks the first media type of an Accept header which corresponds to a format
+    pub fn from_accept(accept: &str) -> Option<ExportFormat> {
+        accept.split(',').find_map(|media_type| {
+            let media_type = media_type.split(';').next()?.trim().to_lowercase();
+            match &media_type[..] {
+                "audio/x-mpegurl" | "audio/mpegurl" | "application/vnd.apple.mpegurl" => {
+                    Some(ExportFormat::M3u8)
+                }
+                "audio/x-scpls" => Some(ExportFormat::Pls),
+                "application/xspf+xml" => Some(ExportFormat::Xspf),
+                "application/json" => Some(ExportFormat::Json),
+                _ => None,
+            }
+        })
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::M3u8 | ExportFormat::M3u => "audio/x-mpegurl; charset=utf-8",
+            ExportFormat::Pls => "audio/x-scpls; charset=utf-8",
+            ExportFormat::Xspf => "application/xspf+xml; charset=utf-8",
+            ExportFormat::Json => "application/json",
+        }
+    }
+}
+
+pub struct Item {
+    pub track: Track,
+    pub album: Option<String>,
+    pub artist: Option<String>,
+    pub url: String,
+    pub image: String,
+    // relative file path used by M3U
+    pub path: String,
+}
+
+impl Item {
+    fn title(&self) -> String {
+        match &self.artist {
+            Some(artist) => format!("{} - {}", artist, self.track.name),
+            None => self.track.name.clone(),
+        }
+    }
+}
+
+// Characters which aren't allowed in file names on common file systems like FAT are replaced
+pub fn sanitize(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim().trim_end_matches('.');
+    if name.is_empty() {
+        String::from("_")
+    } else {
+        String::from(name)
+    }
+}
+
+// Names tracks after their position among the count tracks of a playlist or album, like
+// "01 - Artist - Title.mp3", which is how ZIP downloads lay them out
+pub fn relative_path(position: usize, count: usize, track: &Track, artist: Option<&str>) -> String {
+    let width = count.to_string().len().max(2);
+    let name = match artist {
+        Some(artist) => format!("{:0width$} - {} - {}", position, artist, track.name),
+        None => format!("{:0width$} - {}", position, track.name),
+    };
+    format!("{}.mp3", sanitize(&name))
+}
+
+pub fn write(format: ExportFormat, id: &str, name: &str, items: &[Item]) -> String {
+    match format {
+        ExportFormat::M3u8 | ExportFormat::M3u => write_m3u(format, name, items),
+        ExportFormat::Pls => write_pls(items),
+        ExportFormat::Xspf => write_xspf(name, items),
+        ExportFormat::Json => write_json(id, name, items),
+    }
+}
+
+fn write_m3u(format: ExportFormat, name: &str, items: &[Item]) -> String {
+    let mut lines = vec![
+        String::from("#EXTM3U"),
+        format!("#PLAYLIST:{}", name.replace('\n', " ")),
+    ];
+    for item in items {
+        lines.push(format!(
+            "#EXTINF:{},{}",
+            item.track.duration / 1000,
+            item.title()
+        ));
+        lines.push(match format {
+            ExportFormat::M3u => item.path.clone(),
+            _ => item.url.clone(),
+        });
+    }
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+fn write_pls(items: &[Item]) -> String {
+    let mut lines = vec![String::from("[playlist]")];
+    for (i, item) in items.iter().enumerate() {
+        lines.push(format!("File{}={}", i + 1, item.url));
+        lines.push(format!("Title{}={}", i + 1, item.title()));
+        lines.push(format!("Length{}={}", i + 1, item.track.duration / 1000));
+    }
+    lines.push(format!("NumberOfEntries={}", items.len()));
+    lines.push(String::from("Version=2"));
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+fn write_xspf(name: &str, items: &[Item]) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n",
+    );
+    xml.push_str(&format!(
+        "  <title>{}</title>\n  <trackList>\n",
+        escape(name)
+    ));
+    for item in items {
+        xml.push_str("    <track>\n");
+        xml.push_str(&format!(
+            "      <location>{}</location>\n",
+            escape(&item.url)
+        ));
+        xml.push_str(&format!(
+            "      <title>{}</title>\n",
+            escape(&item.track.name)
+        ));
+        if let Some(artist) = &item.artist {
+            xml.push_str(&format!("      <creator>{}</creator>\n", escape(artist)));
+        }
+        if let Some(album) = &item.album {
+            xml.push_str(&format!("      <album>{}</album>\n", escape(album)));
+        }
+        if let Some(track_number) = item.track.track_number {
+            xml.push_str(&format!("      <trackNum>{}</trackNum>\n", track_number));
+        }
+        xml.push_str(&format!(
+            "      <duration>{}</duration>\n",
+            item.track.duration
+        ));
+        xml.push_str(&format!("      <image>{}</image>\n", escape(&item.image)));
+        xml.push_str("    </track>\n");
+    }
+    xml.push_str("  </trackList>\n</playlist>\n");
+    xml
+}
+
+fn write_json(id: &str, name: &str, items: &[Item]) -> String {
+    json!({
+        "id": id,
+        "name": name,
+        "tracks": items
+            .iter()
+            .map(|item| {
+                json!({
+                    "id": &*ExternalId::new(EntityKind::Track, item.track.id).0,
+                    "name": item.track.name,
+                    "artist": item.artist,
+                    "album": item.album,
+                    "trackNumber": item.track.track_number,
+                    "duration": item.track.duration,
+                    "url": item.url,
+                    "image": item.image,
+                })
+            })
+            .collect::<Vec<_>>(),
+    })
+    .to_string()
+}
+
+#[test]
+fn it_parses_playlist_files() {
+    let m3u = "#EXTM3U\n#PLAYLIST:Road trip\n#EXTINF:259,The Beatles - Come Together\n/music/01 Come Together.mp3\n\nhttp://example.com/b.mp3\n";
+    let playlist = parse(m3u, Format::detect(m3u, Some("road.m3u8")));
+    assert_eq!(playlist.name.as_deref(), Some("Road trip"));
+    assert_eq!(
+        playlist.entries[0],
+        Entry {
+            location: Some(String::from("/music/01 Come Together.mp3")),
+            title: Some(String::from("Come Together")),
+            artist: Some(String::from("The Beatles")),
+            album: None,
+            duration: Some(259_000),
+        }
+    );
+    assert_eq!(playlist.entries[1].title, None);
+
+    let pls = "[playlist]\nFile2=b.mp3\nFile1=a.mp3\nTitle1=A - B\nLength1=-1\nNumberOfEntries=2\n";
+    let playlist = parse(pls, Format::detect(pls, None));
+    assert_eq!(playlist.entries.len(), 2);
+    assert_eq!(playlist.entries[0].artist.as_deref(), Some("A"));
+    assert_eq!(playlist.entries[0].duration, None);
+    assert_eq!(playlist.entries[1].location.as_deref(), Some("b.mp3"));
+
+    let xspf = r#"<?xml version="1.0"?><playlist version="1" xmlns="http://xspf.org/ns/0/"><title>Mix &amp; Match</title><trackList><track><location>file:///music/Abbey%20Road/01%20Come%20Together.mp3</location><title>Come Together</title><creator>The Beatles</creator><duration>259000</duration></track></trackList></playlist>"#;
+    let playlist = parse(xspf, Format::detect(xspf, None));
+    assert_eq!(playlist.name.as_deref(), Some("Mix & Match"));
+    assert_eq!(playlist.entries[0].duration, Some(259_000));
+    assert_eq!(
+        file_stem(playlist.entries[0].location.as_deref().unwrap()).as_deref(),
+        Some("01 Come Together")
+    );
+    assert_eq!(
+        entry_from_stem("07 - Queen - Bohemian Rhapsody")
+            .artist
+            .as_deref(),
+        Some("Queen")
+    );
+}
+
+#[test]
+fn it_writes_playlist_files() {
+    let item = Item {
+        track: Track {
+            id: 1,
+            created_at: chrono::NaiveDateTime::from_timestamp(0, 0),
+            name: String::from("Come Together"),
+            duration: 259_500,
+            album_id: Some(2),
+            artist_id: Some(3),
+            genre_id: None,
+            track_number: Some(1),
+        },
+        album: Some(String::from("Abbey Road")),
+        artist: Some(String::from("The Beatles")),
+        url: String::from("https://example.com/api/tracks/a.mp3"),
+        image: String::from("https://example.com/api/covers/b"),
+        path: String::from("01 - The Beatles - Come Together.mp3"),
+    };
+    let items = [item];
+    assert_eq!(
+        write(ExportFormat::M3u, "id", "Mix", &items),
+        "#EXTM3U\n#PLAYLIST:Mix\n#EXTINF:259,The Beatles - Come Together\n01 - The Beatles - Come Together.mp3\n"
+    );
+    // exported playlists can be imported again
+    let pls = write(ExportFormat::Pls, "id", "Mix", &items);
+    assert_eq!(parse(&pls, Format::Pls).entries[0].duration, Some(259_000));
+    let xspf = parse(
+        &write(ExportFormat::Xspf, "id", "Mix & Match", &items),
+        Format::Xspf,
+    );
+    assert_eq!(xspf.name.as_deref(), Some("Mix & Match"));
+    assert_eq!(xspf.entries[0].album.as_deref(), Some("Abbey Road"));
+    assert_eq!(
+        ExportFormat::from_accept("text/html, application/xspf+xml;q=0.9"),
+        Some(ExportFormat::Xspf)
+    );
+    assert_eq!(sanitize("AC/DC: Live?. "), "AC_DC_ Live_");
+    assert_eq!(
+        relative_path(1, 9, &items[0].track, Some("The Beatles")),
+        items[0].path
+    );
+    assert_eq!(
+        relative_path(7, 100, &items[0].track, Some("AC/DC")),
+        "007 - AC_DC - Come Together.mp3"
+    );
+}
@@ -1,105 +1,249 @@
-use std::{sync::Arc, time::Duration};
+use std::{collections::HashMap, path::Path};
 
-use actix_web::{web, Error, HttpRequest, HttpResponse};
-use juniper::{execute, DefaultScalarValue, InputValue, Value, Variables};
+use actix_multipart::Multipart;
+use actix_web::{
+    error::{self, ErrorBadRequest},
+    http::header,
+    web, Error, HttpRequest, HttpResponse,
+};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use anyhow::Result;
+use diesel::prelude::*;
+use futures::{StreamExt, TryStreamExt};
+use juniper::{DefaultScalarValue, FromInputValue, InputValue};
+use serde_json::json;
 
-use crate::graphql_schema::{RequestContext, Schema};
+use crate::{
+    events::Event,
+    external_id::{EntityKind, ExternalId},
+    graphql_schema::RequestContext,
+    models::{self, NamedTrack, NewPlaylist, NewPlaylistTrack, ShuffleMode},
+    playlist_files::{self, ExportFormat, Format, Item, Library},
+    prng,
+    schema::{playlists, playlists_tracks},
+    shuffle,
+    tracks_service::blocking_error,
+};
 
-#[get("/playlists/{playlist_id}.m3u8")]
-async fn get_playlist(
-    st: web::Data<Arc<Schema>>,
+// Playlists, albums, artists and genres are exported in the format of the extension, e.g.
+// /playlists/{id}.xspf, or otherwise in the format asked for by the Accept header
+#[get("/{kind:playlists|albums|artists|genres}/{file}")]
+async fn get_collection(
     ctx: web::Data<RequestContext>,
+    credentials: BasicAuth,
     req: HttpRequest,
-    web::Path(playlist_id): web::Path<String>,
+    web::Path((kind, file)): web::Path<(String, String)>,
 ) -> Result<HttpResponse, Error> {
-    let body = {
-        let query = r#"query PlaylistTracksQuery($id: ID!) {
-  playlist(id: $id) {
-    id
-    name
-    tracks {
-      id
-      name
-      duration
-      album {
-        id
-        name
-      }
-      artist {
-        id
-        name
-      }
-      genre {
-        id
-        name
-      }
-      trackNumber
+    let kind = match &kind[..] {
+        "albums" => EntityKind::Album,
+        "artists" => EntityKind::Artist,
+        "genres" => EntityKind::Genre,
+        _ => EntityKind::Playlist,
+    };
+    let (id, format) = split_file(&req, &file)?;
+    let external_id = id.clone();
+    let id = ExternalId(juniper::ID::from(id))
+        .decode(kind)
+        .map_err(error::ErrorNotFound)?;
+    // Smart playlists and per-user fields depend on the authenticated user
+    let username = String::from(credentials.user_id().as_ref());
+    let pool = ctx.pool.clone();
+    let (name, tracks) = web::block(move || {
+        let conn = pool.get()?;
+        let (name, tracks) = models::collection(&conn, kind, id, &username)?;
+        Ok::<_, anyhow::Error>((name, models::with_names(&conn, tracks)?))
+    })
+    .await
+    .map_err(blocking_error)?;
+    export(&req, format, &external_id, &name, tracks)
+}
+
+#[get("/shuffle/{file}")]
+async fn get_shuffle(
+    ctx: web::Data<RequestContext>,
+    credentials: BasicAuth,
+    req: HttpRequest,
+    web::Path(file): web::Path<String>,
+    web::Query(params): web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, Error> {
+    let (source_id, format) = split_file(&req, &file)?;
+    let (kind, id) = ExternalId(juniper::ID::from(source_id.clone()))
+        .decode_any()
+        .map_err(error::ErrorNotFound)?;
+    let kind = kind.ok_or_else(|| ErrorBadRequest("Shuffling requires a typed id"))?;
+    // modes are named like their GraphQL enum values, e.g. ARTIST_SPREAD
+    let mode = match params.get("mode") {
+        Some(mode) => Some(
+            ShuffleMode::from_input_value(&InputValue::<DefaultScalarValue>::Enum(mode.clone()))
+                .ok_or_else(|| ErrorBadRequest(format!("Unknown shuffle mode {}", mode)))?,
+        ),
+        None => None,
+    };
+    let seed = match params.get("seed") {
+        Some(seed) => Some(seed.parse::<i32>().map_err(ErrorBadRequest)?),
+        None => None,
+    };
+    let username = String::from(credentials.user_id().as_ref());
+    let pool = ctx.pool.clone();
+    let (name, tracks) = web::block(move || {
+        let conn = pool.get()?;
+        let (name, _) = models::collection(&conn, kind, id, &username)?;
+        let shuffle = shuffle::shuffle_collection(&conn, kind, id, &username, mode, seed)?;
+        Ok::<_, anyhow::Error>((name, models::with_names(&conn, shuffle.tracks)?))
+    })
+    .await
+    .map_err(blocking_error)?;
+    export(&req, format, &source_id, &name, tracks)
+}
+
+// Splits "{id}.{extension}" into the id and the format to export
+fn split_file(req: &HttpRequest, file: &str) -> Result<(String, ExportFormat), Error> {
+    match file.rfind('.') {
+        Some(i) => {
+            let format = ExportFormat::from_extension(&file[i + 1..]).ok_or_else(|| {
+                error::ErrorNotFound(format!("Unknown format {}", &file[i + 1..]))
+            })?;
+            Ok((String::from(&file[..i]), format))
+        }
+        None => {
+            let format = req
+                .headers()
+                .get(header::ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .and_then(ExportFormat::from_accept)
+                .unwrap_or(ExportFormat::M3u8);
+            Ok((String::from(file), format))
+        }
+    }
+}
+
+fn export(
+    req: &HttpRequest,
+    format: ExportFormat,
+    id: &str,
+    name: &str,
+    tracks: Vec<NamedTrack>,
+) -> Result<HttpResponse, Error> {
+    let mut items = Vec::new();
+    let count = tracks.len();
+    for (i, (track, album, artist)) in tracks.into_iter().enumerate() {
+        let track_id = ExternalId::new(EntityKind::Track, track.id);
+        let cover_id = match track.album_id {
+            Some(album_id) => ExternalId::new(EntityKind::Album, album_id),
+            None => ExternalId::new(EntityKind::Track, track.id),
+        };
+        items.push(Item {
+            url: req.url_for("get_track", [&track_id.0[..]])?.to_string(),
+            image: req.url_for("get_cover", [&cover_id.0[..]])?.to_string(),
+            path: playlist_files::relative_path(i + 1, count, &track, artist.as_deref()),
+            track,
+            album,
+            artist,
+        });
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .body(playlist_files::write(format, id, name, &items)))
+}
+
+// Playlist files are read into memory, so larger uploads are refused
+const MAX_IMPORT_SIZE: usize = 1024 * 1024;
+
+// Every uploaded file becomes a playlist named after its title or file name. Entries which don't
+// match any track are left out and reported. Either all files are imported or none of them.
+#[post("/playlists/import")]
+async fn import_playlists(
+    ctx: web::Data<RequestContext>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    let mut files = Vec::new();
+    while let Some(mut field) = payload.try_next().await? {
+        let filename = field
+            .content_disposition()
+            .and_then(|content_disposition| content_disposition.get_filename().map(String::from));
+        let mut data = Vec::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk?;
+            if data.len() + chunk.len() > MAX_IMPORT_SIZE {
+                return Err(error::ErrorPayloadTooLarge(format!(
+                    "Playlist files must not be larger than {} bytes",
+                    MAX_IMPORT_SIZE
+                )));
+            }
+            data.extend_from_slice(&chunk);
+        }
+        files.push((filename, String::from_utf8_lossy(&data).into_owned()));
     }
-  }
-}"#;
-        let mut variables = Variables::<DefaultScalarValue>::new();
-        variables.insert(
-            String::from("id"),
-            InputValue::Scalar(DefaultScalarValue::String(playlist_id)),
-        );
-        let mut lines = Vec::<String>::new();
-        lines.push(String::from("#EXTM3U"));
-        if let Ok((value, _errors)) =
-            execute(query, Some("PlaylistTracksQuery"), &st, &variables, &ctx)
-        {
-            if let Value::Object(playlists) = value {
-                if let Some(Value::Object(playlist)) = playlists.get_field_value("playlist") {
-                    if let Some(Value::List(tracks)) = playlist.get_field_value("tracks") {
-                        for track in tracks {
-                            if let Value::Object(track) = track {
-                                if let (
-                                    Some(Value::Scalar(DefaultScalarValue::String(track_id))),
-                                    Some(Value::Scalar(DefaultScalarValue::String(track_name))),
-                                    Some(Value::Scalar(DefaultScalarValue::Int(duration))),
-                                    artist,
-                                ) = (
-                                    track.get_field_value("id"),
-                                    track.get_field_value("name"),
-                                    track.get_field_value("duration"),
-                                    track.get_field_value("artist"),
-                                ) {
-                                    if let Some(Value::Object(artist)) = artist {
-                                        if let Some(Value::Scalar(DefaultScalarValue::String(
-                                            artist_name,
-                                        ))) = artist.get_field_value("name")
-                                        {
-                                            lines.push(format!(
-                                                "#EXTINF:{},{} - {}",
-                                                Duration::from_millis(*duration as u64).as_secs(),
-                                                artist_name,
-                                                track_name
-                                            ));
-                                        } else {
-                                            lines.push(format!(
-                                                "#EXTINF:{},{}",
-                                                Duration::from_millis(*duration as u64).as_secs(),
-                                                track_name
-                                            ));
-                                        }
-                                    } else {
-                                        lines.push(format!(
-                                            "#EXTINF:{},{}",
-                                            Duration::from_millis(*duration as u64).as_secs(),
-                                            track_name
-                                        ));
-                                    }
-                                    lines.push(
-                                        req.url_for("get_track", &[&track_id[..]])?.to_string(),
-                                    );
-                                }
-                            }
-                        }
+    let pool = ctx.pool.clone();
+    let imported = web::block(move || {
+        let conn = pool.get()?;
+        let library = Library::load(&conn)?;
+        conn.transaction::<_, anyhow::Error, _>(|| {
+            let mut imported = Vec::new();
+            for (filename, content) in files {
+                let format = Format::detect(&content, filename.as_deref());
+                let playlist = playlist_files::parse(&content, format);
+                let name = playlist
+                    .name
+                    .or_else(|| {
+                        let filename = filename.as_deref()?;
+                        let stem = Path::new(filename).file_stem()?;
+                        Some(stem.to_string_lossy().into_owned())
+                    })
+                    .unwrap_or_else(|| String::from("Imported playlist"));
+                let mut track_ids = Vec::new();
+                let mut unmatched = Vec::new();
+                for (position, entry) in playlist.entries.iter().enumerate() {
+                    match library.find(entry) {
+                        Some(track_id) => track_ids.push(track_id),
+                        None => unmatched.push(json!({
+                            "position": position,
+                            "location": entry.location,
+                            "title": entry.title,
+                            "artist": entry.artist,
+                            "duration": entry.duration,
+                        })),
                     }
                 }
+                let mut new_playlist = NewPlaylist {
+                    id: 0,
+                    name,
+                    rules: None,
+                };
+                prng::insert_with_rand_i32(|id| {
+                    new_playlist.id = id;
+                    diesel::insert_into(playlists::table)
+                        .values(&new_playlist)
+                        .execute(&conn)
+                })?;
+                for (position, track_id) in track_ids.iter().enumerate() {
+                    diesel::insert_into(playlists_tracks::table)
+                        .values((
+                            playlists_tracks::playlist_id.eq(new_playlist.id),
+                            &NewPlaylistTrack {
+                                track_id: *track_id,
+                                position: Some(position as i32),
+                            },
+                        ))
+                        .execute(&conn)?;
+                }
+                imported.push((new_playlist, format, track_ids.len(), unmatched));
             }
-        }
-        lines.push(String::new());
-        lines.join("\n")
-    };
-    Ok(HttpResponse::Ok().body(body))
+            Ok(imported)
+        })
+    })
+    .await
+    .map_err(blocking_error)?;
+    let mut body = Vec::new();
+    for (playlist, format, matched, unmatched) in imported {
+        ctx.events.publish(Event::PlaylistChanged(playlist.id));
+        body.push(json!({
+            "id": &*ExternalId::new(EntityKind::Playlist, playlist.id).0,
+            "name": playlist.name,
+            "format": format!("{:?}", format).to_uppercase(),
+            "matched": matched,
+            "unmatched": unmatched,
+        }));
+    }
+    Ok(HttpResponse::Created().json(body))
 }
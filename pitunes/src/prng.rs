@@ -1,31 +1,62 @@
-use anyhow::Result;
-use diesel::prelude::*;
+use anyhow::{anyhow, Result};
+use diesel::{
+    result::{DatabaseErrorKind, Error},
+    QueryResult,
+};
 use getrandom::getrandom;
 use oorandom::Rand32;
 
-use crate::{models::Prng, schema::prngs};
+const MAX_ATTEMPTS: usize = 8;
 
-pub fn insert_prng_if_not_exists(conn: &SqliteConnection) -> Result<()> {
-    let exists: bool =
-        diesel::dsl::select(diesel::dsl::exists(prngs::table.find(1))).get_result(conn)?;
-    if !exists {
-        let mut buf = [0u8; 8];
-        getrandom(&mut buf).unwrap();
-        let seed = u64::from_le_bytes(buf);
-        let rand32 = Rand32::new(seed);
-        let prng: Prng = rand32.into();
-        diesel::insert_into(prngs::table)
-            .values(&prng)
-            .execute(conn)?;
+// Ids are drawn from the operating system's CSPRNG so that they can't be predicted
+pub fn rand_i32() -> Result<i32> {
+    let mut buf = [0u8; 4];
+    getrandom(&mut buf)?;
+    Ok(i32::from_le_bytes(buf))
+}
+
+// Seeded generators produce the same sequence for the same seed, e.g. for reproducible shuffles.
+// They are predictable and must not be used for ids.
+pub fn seeded(seed: i32) -> Rand32 {
+    Rand32::new(u64::from(seed as u32))
+}
+
+// Calls `insert` with fresh random ids until it doesn't conflict with an existing row
+pub fn insert_with_rand_i32<F>(mut insert: F) -> Result<i32>
+where
+    F: FnMut(i32) -> QueryResult<usize>,
+{
+    for _ in 0..MAX_ATTEMPTS {
+        let id = rand_i32()?;
+        match insert(id) {
+            Err(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => continue,
+            result => {
+                result?;
+                return Ok(id);
+            }
+        }
     }
-    Ok(())
+    Err(anyhow!(
+        "Failed to allocate an unused id after {} attempts",
+        MAX_ATTEMPTS
+    ))
 }
 
-pub fn rand_i32(conn: &SqliteConnection) -> Result<i32> {
-    let prng = prngs::table.find(1).get_result::<Prng>(conn)?;
-    let mut rand32: Rand32 = prng.into();
-    let i = rand32.rand_i32();
-    let prng: Prng = rand32.into();
-    diesel::update(prngs::table).set(&prng).execute(conn)?;
-    Ok(i)
+#[test]
+fn it_retries_on_conflict() {
+    use diesel::{Connection, RunQueryDsl, SqliteConnection};
+
+    let conn = SqliteConnection::establish(":memory:").unwrap();
+    conn.execute("CREATE TABLE t (id INTEGER NOT NULL PRIMARY KEY)")
+        .unwrap();
+    conn.execute("INSERT INTO t VALUES (1)").unwrap();
+    let mut attempts = 0;
+    let id = insert_with_rand_i32(|id| {
+        attempts += 1;
+        let id = if attempts == 1 { 1 } else { id };
+        diesel::sql_query(format!("INSERT INTO t VALUES ({})", id)).execute(&conn)
+    })
+    .unwrap();
+    assert_eq!(attempts, 2);
+    assert_ne!(id, 1);
 }
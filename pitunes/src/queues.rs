@@ -0,0 +1,241 @@
+use std::convert::TryFrom;
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use diesel::prelude::*;
+
+use crate::{
+    external_id::{EntityKind, ExternalId},
+    models::{NewQueueTrack, Queue, QueueInput, RepeatMode},
+    schema::{queues, queues_tracks},
+};
+
+// Users who never touched their queue get an empty one
+pub fn find(conn: &SqliteConnection, username: &str) -> Result<Queue> {
+    Ok(queues::table
+        .find(username)
+        .get_result(conn)
+        .optional()?
+        .unwrap_or_else(|| Queue {
+            username: String::from(username),
+            updated_at: Utc::now().naive_utc(),
+            current_position: 0,
+            position: 0,
+            shuffle: false,
+            repeat_mode: String::from(RepeatMode::Off.as_str()),
+        }))
+}
+
+pub fn update(conn: &SqliteConnection, username: &str, input: QueueInput) -> Result<Queue> {
+    conn.transaction::<_, anyhow::Error, _>(|| {
+        diesel::insert_or_ignore_into(queues::table)
+            .values(queues::username.eq(username))
+            .execute(conn)?;
+        let queue: Queue = queues::table.find(username).get_result(conn)?;
+        let replaced = input.track_ids.is_some();
+        if let Some(track_ids) = input.track_ids {
+            let track_ids = track_ids
+                .into_iter()
+                .map(|track_id| ExternalId(track_id).decode(EntityKind::Track))
+                .collect::<Result<Vec<i32>>>()?;
+            diesel::delete(queues_tracks::table.filter(queues_tracks::username.eq(username)))
+                .execute(conn)?;
+            insert_tracks(conn, username, &track_ids, 0)?;
+        }
+        let current_position = match input.current_index {
+            Some(current_index) if current_index < 0 => {
+                return Err(anyhow!("Current index {} is out of range", current_index));
+            }
+            Some(current_index) => queues_tracks::table
+                .filter(queues_tracks::username.eq(username))
+                .select(queues_tracks::position)
+                .order(queues_tracks::position.asc())
+                .offset(current_index.into())
+                .first(conn)
+                .optional()?
+                // an empty queue has no current track at index 0
+                .or_else(|| Some(0).filter(|_| current_index == 0))
+                .ok_or_else(|| anyhow!("Current index {} is out of range", current_index))?,
+            None if replaced => 0,
+            None => queue.current_position,
+        };
+        let position = match input.position {
+            Some(position) => position,
+            None if replaced || input.current_index.is_some() => 0,
+            None => queue.position,
+        };
+        if position < 0 {
+            return Err(anyhow!("Position must not be negative"));
+        }
+        let repeat_mode = match input.repeat {
+            Some(repeat) => String::from(repeat.as_str()),
+            None => queue.repeat_mode,
+        };
+        diesel::update(queues::table.find(username))
+            .set((
+                queues::updated_at.eq(Utc::now().naive_utc()),
+                queues::current_position.eq(current_position),
+                queues::position.eq(position),
+                queues::shuffle.eq(input.shuffle.unwrap_or(queue.shuffle)),
+                queues::repeat_mode.eq(repeat_mode),
+            ))
+            .execute(conn)?;
+        Ok(queues::table.find(username).get_result(conn)?)
+    })
+}
+
+// Replaces the tracks and starts over at the given index
+pub fn replace(
+    conn: &SqliteConnection,
+    username: &str,
+    track_ids: &[i32],
+    current_index: i32,
+) -> Result<Queue> {
+    conn.transaction::<_, anyhow::Error, _>(|| {
+        update(conn, username, QueueInput::default())?;
+        diesel::delete(queues_tracks::table.filter(queues_tracks::username.eq(username)))
+            .execute(conn)?;
+        insert_tracks(conn, username, track_ids, 0)?;
+        seek(conn, username, current_index, 0)
+    })
+}
+
+pub fn append(conn: &SqliteConnection, username: &str, track_ids: &[i32]) -> Result<Queue> {
+    conn.transaction::<_, anyhow::Error, _>(|| {
+        let queue = update(conn, username, QueueInput::default())?;
+        let last: Option<i32> = queues_tracks::table
+            .filter(queues_tracks::username.eq(username))
+            .select(diesel::dsl::max(queues_tracks::position))
+            .get_result(conn)?;
+        insert_tracks(conn, username, track_ids, last.map_or(0, |last| last + 1))?;
+        Ok(queue)
+    })
+}
+
+// Moves to the track at the index and the position in milliseconds within it
+pub fn seek(
+    conn: &SqliteConnection,
+    username: &str,
+    current_index: i32,
+    position: i32,
+) -> Result<Queue> {
+    update(
+        conn,
+        username,
+        QueueInput {
+            current_index: Some(current_index),
+            position: Some(position),
+            ..QueueInput::default()
+        },
+    )
+}
+
+pub fn track_ids(conn: &SqliteConnection, username: &str) -> Result<Vec<i32>> {
+    Ok(queues_tracks::table
+        .filter(queues_tracks::username.eq(username))
+        .select(queues_tracks::track_id)
+        .order(queues_tracks::position.asc())
+        .load(conn)?)
+}
+
+fn insert_tracks(
+    conn: &SqliteConnection,
+    username: &str,
+    track_ids: &[i32],
+    first_position: i32,
+) -> Result<()> {
+    for (i, track_id) in track_ids.iter().enumerate() {
+        diesel::insert_into(queues_tracks::table)
+            .values(&NewQueueTrack {
+                username: String::from(username),
+                track_id: *track_id,
+                position: first_position + i32::try_from(i)?,
+            })
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+pub fn clear(conn: &SqliteConnection, username: &str) -> Result<Queue> {
+    conn.transaction::<_, anyhow::Error, _>(|| {
+        diesel::delete(queues_tracks::table.filter(queues_tracks::username.eq(username)))
+            .execute(conn)?;
+        diesel::delete(queues::table.find(username)).execute(conn)?;
+        find(conn, username)
+    })
+}
+
+// The index of the current track among the remaining ones. If the current track was deleted, the
+// track after it becomes the current one.
+pub fn current_index(conn: &SqliteConnection, queue: &Queue) -> Result<i32> {
+    let queue_tracks = queues_tracks::table.filter(queues_tracks::username.eq(&queue.username));
+    let before: i64 = queue_tracks
+        .filter(queues_tracks::position.lt(queue.current_position))
+        .count()
+        .get_result(conn)?;
+    let len: i64 = queue_tracks.count().get_result(conn)?;
+    Ok(i32::try_from(before.min(len - 1).max(0))?)
+}
+
+#[test]
+fn it_keeps_the_current_track_when_tracks_are_deleted() {
+    use diesel::connection::SimpleConnection;
+
+    use crate::{db, schema::tracks};
+
+    let dir = tempfile::tempdir().unwrap();
+    let pool = db::establish_connection(dir.path().join("pitunes.db").to_str().unwrap());
+    let conn = pool.get().unwrap();
+    conn.batch_execute(
+        "INSERT INTO tracks (id, name, duration) VALUES
+             (1, 'One', 1000), (2, 'Two', 1000), (3, 'Three', 1000), (4, 'Four', 1000);",
+    )
+    .unwrap();
+    let track_ids = (1..=4)
+        .map(|id| ExternalId::new(EntityKind::Track, id).0)
+        .collect();
+    let queue = update(
+        &conn,
+        "admin",
+        QueueInput {
+            track_ids: Some(track_ids),
+            current_index: Some(2),
+            position: None,
+            shuffle: None,
+            repeat: None,
+        },
+    )
+    .unwrap();
+    assert_eq!(current_index(&conn, &queue).unwrap(), 2);
+    let current_track = |queue: &Queue| -> i32 {
+        queues_tracks::table
+            .filter(queues_tracks::username.eq(&queue.username))
+            .select(queues_tracks::track_id)
+            .order(queues_tracks::position.asc())
+            .offset(current_index(&conn, queue).unwrap().into())
+            .first(&conn)
+            .unwrap()
+    };
+    assert_eq!(current_track(&queue), 3);
+    diesel::delete(tracks::table.find(1))
+        .execute(&conn)
+        .unwrap();
+    assert_eq!(current_index(&conn, &queue).unwrap(), 1);
+    assert_eq!(current_track(&queue), 3);
+    diesel::delete(tracks::table.find(3))
+        .execute(&conn)
+        .unwrap();
+    assert_eq!(current_track(&queue), 4);
+    diesel::delete(tracks::table.find(4))
+        .execute(&conn)
+        .unwrap();
+    assert_eq!(current_track(&queue), 2);
+    let out_of_range = QueueInput {
+        track_ids: None,
+        current_index: Some(1),
+        position: None,
+        shuffle: None,
+        repeat: None,
+    };
+    assert!(update(&conn, "admin", out_of_range).is_err());
+}
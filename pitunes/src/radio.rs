@@ -0,0 +1,373 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use diesel::prelude::*;
+use futures::channel::mpsc::{self, Receiver, Sender};
+
+use crate::{
+    db::SqlitePool,
+    models::{Playlist, ShuffleMode, Track},
+    prng,
+    schema::{artists, playlists, tracks},
+    shuffle,
+    tracks_service::track_filepath,
+};
+
+// Audio is sent in chunks of about this many seconds
+const CHUNK_DURATION: f64 = 0.25;
+// Stations run slightly ahead of real time so that listeners don't starve
+const LEAD: f64 = 1.0;
+// New listeners receive this much of the recent audio to fill their buffers right away
+const BURST: f64 = 2.0;
+// Listeners which fall behind by this many chunks are dropped
+const BACKLOG: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Source {
+    Playlist(i32),
+    Genre(i32),
+}
+
+#[derive(Clone)]
+pub struct Chunk {
+    pub data: Arc<Vec<u8>>,
+    pub title: Arc<String>,
+    duration: f64,
+}
+
+struct Station {
+    listeners: Vec<Sender<Chunk>>,
+    recent: VecDeque<Chunk>,
+}
+
+// Every source is broadcast by a single station so that all of its listeners hear the same
+// position. A station starts with its first listener and stops once the last one is gone.
+#[derive(Clone, Default)]
+pub struct Stations {
+    stations: Arc<Mutex<HashMap<Source, Station>>>,
+}
+
+impl Stations {
+    pub fn tune(
+        &self,
+        source: Source,
+        pool: Arc<SqlitePool>,
+        tracks_dir: PathBuf,
+    ) -> Receiver<Chunk> {
+        let (mut sender, receiver) = mpsc::channel(BACKLOG);
+        let mut stations = self.stations.lock().unwrap();
+        match stations.get_mut(&source) {
+            Some(station) => {
+                for chunk in station.recent.iter() {
+                    let _ = sender.try_send(chunk.clone());
+                }
+                station.listeners.push(sender);
+            }
+            None => {
+                stations.insert(
+                    source,
+                    Station {
+                        listeners: vec![sender],
+                        recent: VecDeque::new(),
+                    },
+                );
+                let stations = self.clone();
+                thread::spawn(move || stations.broadcast(source, &pool, &tracks_dir));
+            }
+        }
+        receiver
+    }
+
+    // Returns false once the station has no listeners left, which also removes it. Listeners
+    // whose buffers are full are dropped rather than holding back the others.
+    fn send(&self, source: Source, chunk: Chunk) -> bool {
+        let mut stations = self.stations.lock().unwrap();
+        let station = match stations.get_mut(&source) {
+            Some(station) => station,
+            None => return false,
+        };
+        station
+            .listeners
+            .retain_mut(|listener| listener.try_send(chunk.clone()).is_ok());
+        if station.listeners.is_empty() {
+            stations.remove(&source);
+            return false;
+        }
+        station.recent.push_back(chunk);
+        while station
+            .recent
+            .iter()
+            .map(|chunk| chunk.duration)
+            .sum::<f64>()
+            > BURST
+        {
+            station.recent.pop_front();
+        }
+        true
+    }
+
+    fn broadcast(&self, source: Source, pool: &SqlitePool, tracks_dir: &Path) {
+        let started = Instant::now();
+        let mut elapsed = 0.0;
+        loop {
+            // the tracks are reloaded for every pass so that changes to the source are picked up
+            let tracks = match pool
+                .get()
+                .map_err(anyhow::Error::from)
+                .and_then(|conn| load_tracks(&conn, source))
+            {
+                Ok(tracks) => tracks,
+                Err(e) => {
+                    eprintln!("Failed to load tracks of station {:?}: {}", source, e);
+                    Vec::new()
+                }
+            };
+            let mut played = false;
+            for (track, title) in tracks {
+                let data = match fs::read(track_filepath(tracks_dir, track.id)) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        eprintln!("Failed to read track {}: {}", track.id, e);
+                        continue;
+                    }
+                };
+                let title = Arc::new(title);
+                let mut chunk = Vec::new();
+                let mut duration = 0.0;
+                let frames = frames(&data);
+                let last = frames.len().saturating_sub(1);
+                for (i, (frame, frame_duration)) in frames.into_iter().enumerate() {
+                    chunk.extend_from_slice(frame);
+                    duration += frame_duration;
+                    if duration < CHUNK_DURATION && i < last {
+                        continue;
+                    }
+                    let sent = self.send(
+                        source,
+                        Chunk {
+                            data: Arc::new(std::mem::take(&mut chunk)),
+                            title: title.clone(),
+                            duration,
+                        },
+                    );
+                    if !sent {
+                        return;
+                    }
+                    played = true;
+                    elapsed += duration;
+                    duration = 0.0;
+                    let ahead = elapsed - started.elapsed().as_secs_f64();
+                    if ahead > LEAD {
+                        thread::sleep(Duration::from_secs_f64(ahead - LEAD));
+                    }
+                }
+            }
+            if !played {
+                // there is nothing to play, but the station is kept alive for its listeners
+                let silence = Chunk {
+                    data: Arc::new(Vec::new()),
+                    title: Arc::new(String::new()),
+                    duration: 0.0,
+                };
+                if !self.send(source, silence) {
+                    return;
+                }
+                thread::sleep(Duration::from_secs(1));
+                elapsed = started.elapsed().as_secs_f64();
+            }
+        }
+    }
+}
+
+// Returns the tracks in the order they are played along with their stream titles
+fn load_tracks(conn: &SqliteConnection, source: Source) -> Result<Vec<(Track, String)>> {
+    let tracks = match source {
+        // smart playlists are evaluated without anyone's play history
+        Source::Playlist(id) => playlists::table
+            .find(id)
+            .get_result::<Playlist>(conn)?
+            .load_tracks(conn, "")?,
+        Source::Genre(id) => shuffle::shuffle(
+            tracks::table.filter(tracks::genre_id.eq(id)).load(conn)?,
+            ShuffleMode::ArtistSpread,
+            &HashMap::new(),
+            prng::rand_i32()?,
+        ),
+    };
+    let artists: HashMap<i32, String> = artists::table
+        .select((artists::id, artists::name))
+        .load::<(i32, String)>(conn)?
+        .into_iter()
+        .collect();
+    Ok(tracks
+        .into_iter()
+        .map(|track| {
+            let title = match track.artist_id.and_then(|id| artists.get(&id)) {
+                Some(artist) => format!("{} - {}", artist, track.name),
+                None => track.name.clone(),
+            };
+            (track, title)
+        })
+        .collect())
+}
+
+// Splits an MP3 file into its frames along with their durations in seconds, skipping ID3 tags
+// and anything else which isn't audio
+fn frames(data: &[u8]) -> Vec<(&[u8], f64)> {
+    let mut frames = Vec::new();
+    let mut i = 0;
+    if data.len() >= 10 && &data[..3] == b"ID3" {
+        let size = data[6..10]
+            .iter()
+            .fold(0usize, |size, b| (size << 7) | usize::from(b & 0x7f));
+        i = 10 + size;
+    }
+    while i + 4 <= data.len() {
+        match frame_header(&data[i..i + 4]) {
+            Some((length, duration)) if i + length <= data.len() => {
+                frames.push((&data[i..i + length], duration));
+                i += length;
+            }
+            _ => i += 1,
+        }
+    }
+    frames
+}
+
+// Returns the length in bytes and duration in seconds of an MPEG audio layer III frame
+fn frame_header(header: &[u8]) -> Option<(usize, f64)> {
+    if header[0] != 0xff || header[1] & 0xe0 != 0xe0 {
+        return None;
+    }
+    let version = (header[1] >> 3) & 0x03;
+    let layer = (header[1] >> 1) & 0x03;
+    let bitrate_index = usize::from(header[2] >> 4);
+    let sample_rate_index = usize::from((header[2] >> 2) & 0x03);
+    let padding = usize::from((header[2] >> 1) & 0x01);
+    // only layer III, which is 0b01, is supported
+    if version == 0b01 || layer != 0b01 || bitrate_index == 0 || bitrate_index == 15 {
+        return None;
+    }
+    let mpeg1 = version == 0b11;
+    let bitrate = if mpeg1 {
+        [
+            0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+        ][bitrate_index]
+    } else {
+        [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160][bitrate_index]
+    } * 1000;
+    let sample_rate = match (version, sample_rate_index) {
+        (_, 3) => return None,
+        (0b11, i) => [44100, 48000, 32000][i],
+        (0b10, i) => [22050, 24000, 16000][i],
+        (_, i) => [11025, 12000, 8000][i],
+    };
+    let samples = if mpeg1 { 1152 } else { 576 };
+    let length = samples / 8 * bitrate / sample_rate + padding;
+    Some((length, samples as f64 / sample_rate as f64))
+}
+
+// Interleaves the stream with ICY metadata blocks every `metaint` bytes. Blocks only carry the
+// title when it changed, otherwise they are empty.
+pub struct IcyWriter {
+    metaint: usize,
+    remaining: usize,
+    title: Option<Arc<String>>,
+}
+
+impl IcyWriter {
+    pub fn new(metaint: usize) -> IcyWriter {
+        IcyWriter {
+            metaint,
+            remaining: metaint,
+            title: None,
+        }
+    }
+
+    pub fn write(&mut self, chunk: &Chunk) -> Vec<u8> {
+        let mut data = &chunk.data[..];
+        let mut output = Vec::with_capacity(data.len() + 16);
+        while data.len() >= self.remaining {
+            output.extend_from_slice(&data[..self.remaining]);
+            data = &data[self.remaining..];
+            self.remaining = self.metaint;
+            if self.title.as_ref() == Some(&chunk.title) {
+                output.push(0);
+                continue;
+            }
+            // quotes would end the title early
+            let mut metadata =
+                format!("StreamTitle='{}';", chunk.title.replace('\'', "\u{2019}")).into_bytes();
+            metadata.truncate(255 * 16);
+            let blocks = metadata.len().div_ceil(16);
+            metadata.resize(blocks * 16, 0);
+            output.push(blocks as u8);
+            output.extend_from_slice(&metadata);
+            self.title = Some(chunk.title.clone());
+        }
+        output.extend_from_slice(data);
+        self.remaining -= data.len();
+        output
+    }
+}
+
+#[test]
+fn it_splits_frames_and_injects_metadata() {
+    // 128 kbit/s, 44.1 kHz, MPEG-1 layer III frames without padding are 417 bytes long
+    let mut frame = vec![0xff, 0xfb, 0x90, 0x00];
+    frame.resize(417, 0);
+    let mut data = b"ID3\x03\x00\x00\x00\x00\x00\x02\x00\x00".to_vec();
+    data.extend_from_slice(&frame);
+    data.extend_from_slice(&frame);
+    let frames = frames(&data);
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].0.len(), 417);
+    assert!((frames[0].1 - 1152.0 / 44100.0).abs() < 1e-9);
+
+    let mut icy = IcyWriter::new(4);
+    let chunk = |data: &[u8], title: &str| Chunk {
+        data: Arc::new(data.to_vec()),
+        title: Arc::new(String::from(title)),
+        duration: 0.0,
+    };
+    let output = icy.write(&chunk(b"abcdef", "A - B"));
+    assert_eq!(&output[..4], b"abcd");
+    assert_eq!(output[4], 2);
+    assert_eq!(&output[5..25], b"StreamTitle='A - B';");
+    assert_eq!(&output[25..37], &[0; 12]);
+    assert_eq!(&output[37..], b"ef");
+    // unchanged titles are sent as empty blocks
+    assert_eq!(icy.write(&chunk(b"gh", "A - B")), b"gh\0");
+}
+
+#[test]
+fn it_drops_listeners_which_fall_behind() {
+    let stations = Stations::default();
+    let (sender, mut receiver) = mpsc::channel(BACKLOG);
+    stations.stations.lock().unwrap().insert(
+        Source::Genre(1),
+        Station {
+            listeners: vec![sender],
+            recent: VecDeque::new(),
+        },
+    );
+    let chunk = Chunk {
+        data: Arc::new(Vec::new()),
+        title: Arc::new(String::new()),
+        duration: 0.0,
+    };
+    let sent = (0..BACKLOG * 2)
+        .take_while(|_| stations.send(Source::Genre(1), chunk.clone()))
+        .count();
+    assert!((BACKLOG..BACKLOG * 2).contains(&sent));
+    assert!(stations.stations.lock().unwrap().is_empty());
+    // chunks which fit into the buffer are still delivered
+    assert!(receiver.try_next().unwrap().is_some());
+}
@@ -0,0 +1,74 @@
+use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+use diesel::prelude::*;
+use futures::StreamExt;
+
+use crate::{
+    external_id::{EntityKind, ExternalId},
+    graphql_schema::RequestContext,
+    radio::{IcyWriter, Source, Stations},
+    schema::{genres, playlists},
+};
+
+// Metadata is sent every this many bytes to clients asking for it
+const ICY_METAINT: usize = 16000;
+
+#[get("/radio/{id}")]
+async fn get_radio(
+    ctx: web::Data<RequestContext>,
+    stations: web::Data<Stations>,
+    req: HttpRequest,
+    web::Path(id): web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let source = match ExternalId(juniper::ID::from(id.clone())).decode_any() {
+        Ok((Some(EntityKind::Playlist), id)) => Source::Playlist(id),
+        Ok((Some(EntityKind::Genre), id)) => Source::Genre(id),
+        _ => return Err(error::ErrorNotFound("Expected a playlist or genre id")),
+    };
+    let pool = ctx.pool.clone();
+    let name = web::block(move || {
+        let conn = pool.get()?;
+        let name = match source {
+            Source::Playlist(id) => playlists::table
+                .find(id)
+                .select(playlists::name)
+                .get_result::<String>(&conn),
+            Source::Genre(id) => genres::table
+                .find(id)
+                .select(genres::name)
+                .get_result::<String>(&conn),
+        }
+        .optional()?;
+        Ok::<_, anyhow::Error>(name)
+    })
+    .await?
+    .ok_or_else(|| error::ErrorNotFound(format!("No playlist or genre with id {}", id)))?;
+    let metaint = match req.headers().get("Icy-MetaData") {
+        Some(value) if value == "1" => Some(ICY_METAINT),
+        _ => None,
+    };
+    let mut icy = metaint.map(IcyWriter::new);
+    let body = stations
+        .tune(source, ctx.pool.clone(), ctx.tracks_dir.clone())
+        .map(move |chunk| {
+            let data = match &mut icy {
+                Some(icy) => icy.write(&chunk),
+                None => chunk.data.to_vec(),
+            };
+            Ok::<_, Error>(web::Bytes::from(data))
+        });
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type("audio/mpeg")
+        .header("Cache-Control", "no-cache")
+        // header values are restricted to visible ASCII characters
+        .header(
+            "icy-name",
+            name.chars()
+                .map(|c| if c.is_ascii_graphic() { c } else { ' ' })
+                .collect::<String>(),
+        );
+    if let Some(metaint) = metaint {
+        response.header("icy-metaint", metaint.to_string());
+    }
+    Ok(response.streaming(body))
+}
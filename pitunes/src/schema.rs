@@ -27,6 +27,7 @@ table! {
         id -> Integer,
         created_at -> Timestamp,
         name -> Text,
+        rules -> Nullable<Text>,
     }
 }
 
@@ -40,14 +41,6 @@ table! {
     }
 }
 
-table! {
-    prngs (id) {
-        id -> Integer,
-        state -> BigInt,
-        inc -> BigInt,
-    }
-}
-
 table! {
     tracks (id) {
         id -> Integer,
@@ -65,6 +58,96 @@ table! {
     users (username) {
         username -> Text,
         password -> Binary,
+        subsonic_password -> Nullable<Text>,
+    }
+}
+
+table! {
+    plays (id) {
+        id -> Integer,
+        created_at -> Timestamp,
+        username -> Text,
+        track_id -> Integer,
+        started_at -> Timestamp,
+        duration -> Integer,
+        client -> Nullable<Text>,
+    }
+}
+
+table! {
+    plays_daily (username, day, track_id) {
+        username -> Text,
+        day -> Date,
+        track_id -> Integer,
+        play_count -> Integer,
+        duration -> Integer,
+    }
+}
+
+table! {
+    stars (username, entity_kind, entity_id) {
+        username -> Text,
+        entity_kind -> Integer,
+        entity_id -> Integer,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    ratings (username, track_id) {
+        username -> Text,
+        track_id -> Integer,
+        created_at -> Timestamp,
+        rating -> Integer,
+    }
+}
+
+table! {
+    queues (username) {
+        username -> Text,
+        updated_at -> Timestamp,
+        current_position -> Integer,
+        position -> Integer,
+        shuffle -> Bool,
+        repeat_mode -> Text,
+    }
+}
+
+table! {
+    queues_tracks (id) {
+        id -> Integer,
+        username -> Text,
+        track_id -> Integer,
+        position -> Integer,
+    }
+}
+
+table! {
+    scrobblers (username, service) {
+        username -> Text,
+        service -> Text,
+        url -> Text,
+        token -> Text,
+        api_key -> Nullable<Text>,
+        api_secret -> Nullable<Text>,
+        last_error -> Nullable<Text>,
+    }
+}
+
+table! {
+    scrobbles (id) {
+        id -> Integer,
+        created_at -> Timestamp,
+        username -> Text,
+        service -> Text,
+        now_playing -> Bool,
+        started_at -> Timestamp,
+        artist -> Text,
+        track -> Text,
+        album -> Nullable<Text>,
+        duration -> Integer,
+        attempts -> Integer,
+        next_attempt_at -> Timestamp,
     }
 }
 
@@ -73,6 +156,18 @@ joinable!(playlists_tracks -> tracks (track_id));
 joinable!(tracks -> albums (album_id));
 joinable!(tracks -> artists (artist_id));
 joinable!(tracks -> genres (genre_id));
+joinable!(plays -> tracks (track_id));
+joinable!(plays -> users (username));
+joinable!(plays_daily -> tracks (track_id));
+joinable!(plays_daily -> users (username));
+joinable!(stars -> users (username));
+joinable!(ratings -> tracks (track_id));
+joinable!(ratings -> users (username));
+joinable!(queues -> users (username));
+joinable!(queues_tracks -> queues (username));
+joinable!(queues_tracks -> tracks (track_id));
+joinable!(scrobblers -> users (username));
+joinable!(scrobbles -> users (username));
 
 allow_tables_to_appear_in_same_query!(
     albums,
@@ -80,7 +175,14 @@ allow_tables_to_appear_in_same_query!(
     genres,
     playlists,
     playlists_tracks,
-    prngs,
     tracks,
     users,
+    plays,
+    plays_daily,
+    stars,
+    ratings,
+    queues,
+    queues_tracks,
+    scrobblers,
+    scrobbles,
 );
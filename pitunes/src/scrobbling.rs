@@ -0,0 +1,585 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::Duration,
+};
+
+use actix_web::{http::StatusCode, web};
+use anyhow::{anyhow, Result};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use md5::{Digest, Md5};
+use serde_json::{json, Value};
+
+use crate::{
+    db::SqlitePool,
+    models::{
+        Album, Artist, NewScrobble, NewScrobbler, Scrobble, ScrobbleService, Scrobbler,
+        ScrobblerInput, Track,
+    },
+    schema::{albums, artists, scrobblers, scrobbles, tracks},
+};
+
+pub const LISTENBRAINZ_URL: &str = "https://api.listenbrainz.org";
+pub const LASTFM_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+
+// How often the queue is checked for scrobbles which are due
+pub const SUBMIT_INTERVAL: Duration = Duration::from_secs(10);
+
+const BATCH_SIZE: i64 = 50;
+// Failed submissions are retried after this many seconds, doubling with every attempt
+const RETRY_DELAY: i64 = 30;
+const MAX_RETRY_DELAY: i64 = 6 * 60 * 60;
+
+pub fn update_scrobbler(
+    conn: &SqliteConnection,
+    username: &str,
+    input: ScrobblerInput,
+) -> Result<Scrobbler> {
+    if input.service == ScrobbleService::LastFm
+        && (input.api_key.is_none() || input.api_secret.is_none())
+    {
+        return Err(anyhow!("Last.fm requires an api key and secret"));
+    }
+    let service = input.service;
+    let new_scrobbler = NewScrobbler {
+        username: String::from(username),
+        service: String::from(service.as_str()),
+        url: input.url.unwrap_or_else(|| {
+            String::from(match service {
+                ScrobbleService::ListenBrainz => LISTENBRAINZ_URL,
+                ScrobbleService::LastFm => LASTFM_URL,
+            })
+        }),
+        token: input.token,
+        api_key: input.api_key,
+        api_secret: input.api_secret,
+    };
+    let key = (username, service.as_str());
+    conn.transaction::<_, anyhow::Error, _>(|| {
+        diesel::insert_or_ignore_into(scrobblers::table)
+            .values(&new_scrobbler)
+            .execute(conn)?;
+        diesel::update(scrobblers::table.find(key))
+            .set((&new_scrobbler, scrobblers::last_error.eq(None::<String>)))
+            .execute(conn)?;
+        Ok(scrobblers::table.find(key).get_result(conn)?)
+    })
+}
+
+// Plays which weren't submitted yet are discarded as well
+pub fn delete_scrobbler(
+    conn: &SqliteConnection,
+    username: &str,
+    service: ScrobbleService,
+) -> Result<bool> {
+    conn.transaction::<_, anyhow::Error, _>(|| {
+        diesel::delete(
+            scrobbles::table
+                .filter(scrobbles::username.eq(username))
+                .filter(scrobbles::service.eq(service.as_str())),
+        )
+        .execute(conn)?;
+        let deleted =
+            diesel::delete(scrobblers::table.find((username, service.as_str()))).execute(conn)?;
+        Ok(deleted > 0)
+    })
+}
+
+// Follows the rules of both services: tracks have to be longer than 30 seconds and have to be
+// listened to for at least half of their duration or 4 minutes
+fn eligible(track_duration: i32, duration: i32) -> bool {
+    track_duration > 30_000 && duration >= (track_duration / 2).min(240_000)
+}
+
+// Queues a play or now playing update for every scrobbler of the user. Tracks without an artist
+// can't be scrobbled.
+pub fn enqueue(
+    conn: &SqliteConnection,
+    username: &str,
+    track_id: i32,
+    started_at: NaiveDateTime,
+    duration: i32,
+    now_playing: bool,
+) -> Result<()> {
+    let (track, album, artist) = tracks::table
+        .find(track_id)
+        .left_join(albums::table)
+        .left_join(artists::table)
+        .get_result::<(Track, Option<Album>, Option<Artist>)>(conn)?;
+    let artist = match artist {
+        Some(artist) => artist,
+        None => return Ok(()),
+    };
+    if !now_playing && !eligible(track.duration, duration) {
+        return Ok(());
+    }
+    let services: Vec<String> = scrobblers::table
+        .filter(scrobblers::username.eq(username))
+        .select(scrobblers::service)
+        .load(conn)?;
+    conn.transaction::<_, anyhow::Error, _>(|| {
+        if now_playing {
+            // a track which is now playing replaces the previous one
+            diesel::delete(
+                scrobbles::table
+                    .filter(scrobbles::username.eq(username))
+                    .filter(scrobbles::now_playing.eq(true)),
+            )
+            .execute(conn)?;
+        }
+        for service in services {
+            diesel::insert_into(scrobbles::table)
+                .values(&NewScrobble {
+                    username: String::from(username),
+                    service,
+                    now_playing,
+                    started_at,
+                    artist: artist.name.clone(),
+                    track: track.name.clone(),
+                    album: album.as_ref().map(|album| album.name.clone()),
+                    duration: track.duration,
+                })
+                .execute(conn)?;
+        }
+        Ok(())
+    })
+}
+
+// Returns the scrobbles which are due in the order they were queued. Now playing updates which
+// are over by now are dropped instead.
+fn due(conn: &SqliteConnection, now: NaiveDateTime) -> Result<Vec<(Scrobbler, Scrobble)>> {
+    let scrobbles: Vec<Scrobble> = scrobbles::table
+        .filter(scrobbles::next_attempt_at.le(now))
+        .order(scrobbles::id.asc())
+        .limit(BATCH_SIZE)
+        .load(conn)?;
+    let (over, scrobbles): (Vec<Scrobble>, Vec<Scrobble>) =
+        scrobbles.into_iter().partition(|scrobble| {
+            scrobble.now_playing
+                && scrobble.started_at + chrono::Duration::milliseconds(scrobble.duration.into())
+                    < now
+        });
+    diesel::delete(
+        scrobbles::table.filter(
+            scrobbles::id.eq_any(over.iter().map(|scrobble| scrobble.id).collect::<Vec<_>>()),
+        ),
+    )
+    .execute(conn)?;
+    let scrobblers: HashMap<(String, String), Scrobbler> = scrobblers::table
+        .filter(
+            scrobblers::username.eq_any(
+                scrobbles
+                    .iter()
+                    .map(|scrobble| &scrobble.username)
+                    .collect::<Vec<_>>(),
+            ),
+        )
+        .load::<Scrobbler>(conn)?
+        .into_iter()
+        .map(|scrobbler| {
+            (
+                (scrobbler.username.clone(), scrobbler.service.clone()),
+                scrobbler,
+            )
+        })
+        .collect();
+    // plays are submitted in order, so they wait for earlier ones which are retried later
+    let waiting: HashSet<(String, String)> = scrobbles::table
+        .filter(scrobbles::next_attempt_at.gt(now))
+        .filter(scrobbles::now_playing.eq(false))
+        .select((scrobbles::username, scrobbles::service))
+        .distinct()
+        .load(conn)?
+        .into_iter()
+        .collect();
+    Ok(scrobbles
+        .into_iter()
+        .filter(|scrobble| {
+            scrobble.now_playing
+                || !waiting.contains(&(scrobble.username.clone(), scrobble.service.clone()))
+        })
+        .filter_map(|scrobble| {
+            let key = (scrobble.username.clone(), scrobble.service.clone());
+            Some((scrobblers.get(&key)?.clone(), scrobble))
+        })
+        .collect())
+}
+
+enum Body {
+    Json(Value),
+    Form(Vec<(String, String)>),
+}
+
+struct Request {
+    url: String,
+    authorization: Option<String>,
+    body: Body,
+}
+
+fn request(scrobbler: &Scrobbler, scrobble: &Scrobble) -> Result<Request> {
+    match scrobbler.service.parse()? {
+        ScrobbleService::ListenBrainz => Ok(listenbrainz(scrobbler, scrobble)),
+        ScrobbleService::LastFm => lastfm(scrobbler, scrobble),
+    }
+}
+
+fn listenbrainz(scrobbler: &Scrobbler, scrobble: &Scrobble) -> Request {
+    let mut track_metadata = json!({
+        "artist_name": scrobble.artist,
+        "track_name": scrobble.track,
+        "additional_info": {
+            "duration_ms": scrobble.duration,
+            "submission_client": "pitunes",
+            "submission_client_version": env!("CARGO_PKG_VERSION"),
+        },
+    });
+    if let Some(album) = &scrobble.album {
+        track_metadata["release_name"] = json!(album);
+    }
+    let (listen_type, listen) = if scrobble.now_playing {
+        ("playing_now", json!({ "track_metadata": track_metadata }))
+    } else {
+        (
+            "single",
+            json!({
+                "listened_at": scrobble.started_at.timestamp(),
+                "track_metadata": track_metadata,
+            }),
+        )
+    };
+    Request {
+        url: format!("{}/1/submit-listens", scrobbler.url.trim_end_matches('/')),
+        authorization: Some(format!("Token {}", scrobbler.token)),
+        body: Body::Json(json!({
+            "listen_type": listen_type,
+            "payload": [listen],
+        })),
+    }
+}
+
+// Calls are signed with the md5 hash of the parameters sorted by name followed by the secret
+fn lastfm(scrobbler: &Scrobbler, scrobble: &Scrobble) -> Result<Request> {
+    let api_key = scrobbler
+        .api_key
+        .as_ref()
+        .ok_or_else(|| anyhow!("Last.fm requires an api key"))?;
+    let api_secret = scrobbler
+        .api_secret
+        .as_ref()
+        .ok_or_else(|| anyhow!("Last.fm requires an api secret"))?;
+    let mut params = BTreeMap::new();
+    params.insert("api_key", api_key.clone());
+    params.insert("sk", scrobbler.token.clone());
+    params.insert("artist", scrobble.artist.clone());
+    params.insert("track", scrobble.track.clone());
+    params.insert("duration", (scrobble.duration / 1000).to_string());
+    if let Some(album) = &scrobble.album {
+        params.insert("album", album.clone());
+    }
+    if scrobble.now_playing {
+        params.insert("method", String::from("track.updateNowPlaying"));
+    } else {
+        params.insert("method", String::from("track.scrobble"));
+        params.insert("timestamp", scrobble.started_at.timestamp().to_string());
+    }
+    let signature: String = params
+        .iter()
+        .map(|(name, value)| format!("{}{}", name, value))
+        .chain(std::iter::once(api_secret.clone()))
+        .collect();
+    let signature: String = Md5::digest(signature.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let mut form: Vec<(String, String)> = params
+        .into_iter()
+        .map(|(name, value)| (String::from(name), value))
+        .collect();
+    form.push((String::from("api_sig"), signature));
+    form.push((String::from("format"), String::from("json")));
+    Ok(Request {
+        url: scrobbler.url.clone(),
+        authorization: None,
+        body: Body::Form(form),
+    })
+}
+
+enum Outcome {
+    Submitted,
+    // the submission failed but may succeed later, e.g. because the network is down
+    Retry(String),
+    Rejected(String),
+}
+
+async fn send(client: &awc::Client, request: Request) -> Outcome {
+    let mut builder = client.post(&request.url);
+    if let Some(authorization) = request.authorization {
+        builder = builder.header("Authorization", authorization);
+    }
+    let response = match request.body {
+        Body::Json(json) => builder.send_json(&json).await,
+        Body::Form(form) => builder.send_form(&form).await,
+    };
+    let mut response = match response {
+        Ok(response) => response,
+        Err(e) => return Outcome::Retry(e.to_string()),
+    };
+    let status = response.status();
+    let body = response.body().await.unwrap_or_default();
+    let body: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    // Last.fm reports errors in the body, where 11, 16 and 29 are temporary
+    let error = match (&body["error"], &body["message"]) {
+        (Value::Number(code), message) => Some((code.as_i64(), message.as_str())),
+        (Value::String(message), _) => Some((None, Some(&message[..]))),
+        _ => None,
+    };
+    let message = format!(
+        "{} {}",
+        status,
+        error.and_then(|(_, message)| message).unwrap_or_default()
+    );
+    match error {
+        _ if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() => {
+            Outcome::Retry(message)
+        }
+        Some((Some(11), _)) | Some((Some(16), _)) | Some((Some(29), _)) => Outcome::Retry(message),
+        None if status.is_success() => Outcome::Submitted,
+        _ => Outcome::Rejected(message),
+    }
+}
+
+fn retry_delay(attempts: i32) -> chrono::Duration {
+    chrono::Duration::seconds(
+        RETRY_DELAY
+            .saturating_mul(1 << attempts.clamp(0, 16))
+            .min(MAX_RETRY_DELAY),
+    )
+}
+
+fn record(conn: &SqliteConnection, scrobble: &Scrobble, outcome: &Outcome) -> Result<()> {
+    let scrobbler = scrobblers::table.find((&scrobble.username, &scrobble.service));
+    conn.transaction::<_, anyhow::Error, _>(|| {
+        match outcome {
+            Outcome::Submitted => {
+                diesel::delete(scrobbles::table.find(scrobble.id)).execute(conn)?;
+                diesel::update(scrobbler)
+                    .set(scrobblers::last_error.eq(None::<String>))
+                    .execute(conn)?;
+            }
+            Outcome::Retry(error) => {
+                diesel::update(scrobbles::table.find(scrobble.id))
+                    .set((
+                        scrobbles::attempts.eq(scrobble.attempts + 1),
+                        scrobbles::next_attempt_at
+                            .eq(Utc::now().naive_utc() + retry_delay(scrobble.attempts)),
+                    ))
+                    .execute(conn)?;
+                diesel::update(scrobbler)
+                    .set(scrobblers::last_error.eq(error))
+                    .execute(conn)?;
+            }
+            // rejected plays would be rejected again, e.g. because of an invalid token
+            Outcome::Rejected(error) => {
+                diesel::delete(scrobbles::table.find(scrobble.id)).execute(conn)?;
+                diesel::update(scrobbler)
+                    .set(scrobblers::last_error.eq(error))
+                    .execute(conn)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+// Submits the scrobbles which are due. Once a submission has to be retried, the following
+// scrobbles of the same scrobbler are held back to keep them in order.
+pub async fn submit_due(pool: &SqlitePool) -> Result<()> {
+    let due = {
+        let pool = pool.clone();
+        web::block(move || {
+            let conn = pool.get()?;
+            due(&conn, Utc::now().naive_utc())
+        })
+        .await?
+    };
+    let client = awc::Client::default();
+    let mut held_back = HashSet::new();
+    for (scrobbler, scrobble) in due {
+        let key = (scrobble.username.clone(), scrobble.service.clone());
+        if held_back.contains(&key) {
+            continue;
+        }
+        let outcome = match request(&scrobbler, &scrobble) {
+            Ok(request) => send(&client, request).await,
+            Err(e) => Outcome::Rejected(e.to_string()),
+        };
+        if let Outcome::Retry(_) = outcome {
+            held_back.insert(key);
+        }
+        let pool = pool.clone();
+        web::block(move || {
+            let conn = pool.get()?;
+            record(&conn, &scrobble, &outcome)
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+#[test]
+fn it_builds_signed_requests() {
+    let scrobbler = Scrobbler {
+        username: String::from("admin"),
+        service: String::from("LASTFM"),
+        url: String::from(LASTFM_URL),
+        token: String::from("session"),
+        api_key: Some(String::from("key")),
+        api_secret: Some(String::from("secret")),
+        last_error: None,
+    };
+    let scrobble = Scrobble {
+        id: 1,
+        created_at: NaiveDateTime::from_timestamp(0, 0),
+        username: String::from("admin"),
+        service: String::from("LASTFM"),
+        now_playing: false,
+        started_at: NaiveDateTime::from_timestamp(1_700_000_000, 0),
+        artist: String::from("The Beatles"),
+        track: String::from("Come Together"),
+        album: None,
+        duration: 259_000,
+        attempts: 0,
+        next_attempt_at: NaiveDateTime::from_timestamp(0, 0),
+    };
+    let form = match lastfm(&scrobbler, &scrobble).unwrap().body {
+        Body::Form(form) => form,
+        Body::Json(_) => unreachable!(),
+    };
+    let signature = "api_keykeyartistThe Beatlesduration259methodtrack.scrobblesksessiontimestamp1700000000trackCome Togethersecret";
+    let signature: String = Md5::digest(signature.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    assert!(form.contains(&(String::from("api_sig"), signature)));
+    let request = listenbrainz(&scrobbler, &scrobble);
+    assert_eq!(request.authorization.as_deref(), Some("Token session"));
+    match request.body {
+        Body::Json(json) => {
+            assert_eq!(json["listen_type"], "single");
+            assert_eq!(json["payload"][0]["listened_at"], 1_700_000_000);
+        }
+        Body::Form(_) => unreachable!(),
+    }
+    assert!(!eligible(29_000, 29_000));
+    assert!(eligible(600_000, 240_000));
+    assert_eq!(retry_delay(100).num_seconds(), MAX_RETRY_DELAY);
+}
+
+#[actix_rt::test]
+async fn it_submits_due_scrobbles_in_order() {
+    use std::sync::{Arc, Mutex};
+
+    use actix_web::{App, HttpResponse};
+    use diesel::connection::SimpleConnection;
+
+    use crate::db;
+
+    // the mock service is unavailable for the first request
+    let requests = Arc::new(Mutex::new(Vec::<Value>::new()));
+    let server = {
+        let requests = requests.clone();
+        actix_web::test::start(move || {
+            let requests = requests.clone();
+            App::new().route(
+                "/1/submit-listens",
+                web::post().to(move |body: web::Json<Value>| {
+                    let requests = requests.clone();
+                    async move {
+                        let mut requests = requests.lock().unwrap();
+                        requests.push(body.into_inner());
+                        Ok::<_, actix_web::Error>(if requests.len() == 1 {
+                            HttpResponse::ServiceUnavailable().finish()
+                        } else {
+                            HttpResponse::Ok().json(json!({ "status": "ok" }))
+                        })
+                    }
+                }),
+            )
+        })
+    };
+
+    let dir = tempfile::tempdir().unwrap();
+    let pool = db::establish_connection(dir.path().join("pitunes.db").to_str().unwrap());
+    let conn = pool.get().unwrap();
+    conn.batch_execute(
+        "INSERT INTO artists (id, name) VALUES (1, 'The Beatles');
+         INSERT INTO tracks (id, name, duration, artist_id) VALUES
+             (1, 'Come Together', 300000, 1), (2, 'Something', 300000, 1);",
+    )
+    .unwrap();
+    update_scrobbler(
+        &conn,
+        "admin",
+        ScrobblerInput {
+            service: ScrobbleService::ListenBrainz,
+            token: String::from("token"),
+            url: Some(format!("http://{}/", server.addr())),
+            api_key: None,
+            api_secret: None,
+        },
+    )
+    .unwrap();
+    let now = Utc::now().naive_utc();
+    let hour_ago = now - chrono::Duration::hours(1);
+    enqueue(&conn, "admin", 1, hour_ago, 0, true).unwrap();
+    enqueue(&conn, "admin", 1, hour_ago, 300_000, false).unwrap();
+    enqueue(
+        &conn,
+        "admin",
+        2,
+        hour_ago + chrono::Duration::minutes(5),
+        300_000,
+        false,
+    )
+    .unwrap();
+    let pending = |conn: &SqliteConnection| -> Vec<Scrobble> {
+        scrobbles::table
+            .order(scrobbles::id.asc())
+            .load(conn)
+            .unwrap()
+    };
+
+    // the now playing update is over, the first play fails and holds back the second one
+    submit_due(&pool).await.unwrap();
+    assert_eq!(requests.lock().unwrap().len(), 1);
+    let scrobbles = pending(&conn);
+    assert_eq!(scrobbles.len(), 2);
+    assert!(scrobbles.iter().all(|scrobble| !scrobble.now_playing));
+    assert_eq!(
+        (scrobbles[0].track.as_str(), scrobbles[0].attempts),
+        ("Come Together", 1)
+    );
+    let delay = scrobbles[0].next_attempt_at - Utc::now().naive_utc();
+    assert!(delay > chrono::Duration::seconds(RETRY_DELAY - 5));
+    assert!(delay <= retry_delay(0));
+    assert_eq!(scrobbles[1].attempts, 0);
+    let scrobbler: Scrobbler = scrobblers::table.first(&conn).unwrap();
+    assert!(scrobbler.last_error.unwrap().starts_with("503"));
+
+    // the second play keeps waiting until the first one is retried
+    submit_due(&pool).await.unwrap();
+    assert_eq!(requests.lock().unwrap().len(), 1);
+    diesel::update(scrobbles::table.find(scrobbles[0].id))
+        .set(scrobbles::next_attempt_at.eq(now))
+        .execute(&conn)
+        .unwrap();
+    submit_due(&pool).await.unwrap();
+    let requests = requests.lock().unwrap();
+    let tracks: Vec<&Value> = requests
+        .iter()
+        .map(|request| &request["payload"][0]["track_metadata"]["track_name"])
+        .collect();
+    assert_eq!(tracks, vec!["Come Together", "Come Together", "Something"]);
+    assert!(pending(&conn).is_empty());
+    let scrobbler: Scrobbler = scrobblers::table.first(&conn).unwrap();
+    assert_eq!(scrobbler.last_error, None);
+}
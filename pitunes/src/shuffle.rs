@@ -0,0 +1,163 @@
+use std::{cmp::Ordering, collections::HashMap};
+
+use anyhow::Result;
+use diesel::prelude::*;
+use oorandom::Rand32;
+
+use crate::{
+    external_id::EntityKind,
+    models::{self, Shuffle, ShuffleMode, Track},
+    prng,
+    schema::ratings,
+};
+
+// Unrated tracks are weighted like tracks with an average rating
+const DEFAULT_RATING: i32 = 3;
+
+pub fn shuffle(
+    tracks: Vec<Track>,
+    mode: ShuffleMode,
+    ratings: &HashMap<i32, i32>,
+    seed: i32,
+) -> Vec<Track> {
+    let mut rng = prng::seeded(seed);
+    match mode {
+        ShuffleMode::Random => random(tracks, &mut rng),
+        ShuffleMode::ArtistSpread => artist_spread(tracks, &mut rng),
+        ShuffleMode::WeightedByRating => weighted_by_rating(tracks, ratings, &mut rng),
+    }
+}
+
+// Shuffles an album, artist, genre or playlist, using a random seed unless one is given
+pub fn shuffle_collection(
+    conn: &SqliteConnection,
+    kind: EntityKind,
+    id: i32,
+    username: &str,
+    mode: Option<ShuffleMode>,
+    seed: Option<i32>,
+) -> Result<Shuffle> {
+    let (_, tracks) = models::collection(conn, kind, id, username)?;
+    let ratings: HashMap<i32, i32> = ratings::table
+        .filter(ratings::username.eq(username))
+        .select((ratings::track_id, ratings::rating))
+        .load::<(i32, i32)>(conn)?
+        .into_iter()
+        .collect();
+    let seed = match seed {
+        Some(seed) => seed,
+        None => prng::rand_i32()?,
+    };
+    Ok(Shuffle {
+        seed,
+        tracks: shuffle(tracks, mode.unwrap_or(ShuffleMode::Random), &ratings, seed),
+    })
+}
+
+// Fisher-Yates shuffle
+fn random(mut tracks: Vec<Track>, rng: &mut Rand32) -> Vec<Track> {
+    for i in (1..tracks.len()).rev() {
+        let j = rng.rand_range(0..i as u32 + 1) as usize;
+        tracks.swap(i, j);
+    }
+    tracks
+}
+
+// Repeatedly picks a track of the artist with the most remaining tracks, skipping the artist of
+// the previous track. This avoids consecutive tracks of the same artist whenever possible.
+fn artist_spread(tracks: Vec<Track>, rng: &mut Rand32) -> Vec<Track> {
+    let mut groups: Vec<Vec<Track>> = Vec::new();
+    let mut group_indices: HashMap<i32, usize> = HashMap::new();
+    for track in random(tracks, rng) {
+        match track.artist_id {
+            Some(artist_id) if group_indices.contains_key(&artist_id) => {
+                groups[group_indices[&artist_id]].push(track)
+            }
+            artist_id => {
+                // Tracks without an artist don't have anything in common
+                if let Some(artist_id) = artist_id {
+                    group_indices.insert(artist_id, groups.len());
+                }
+                groups.push(vec![track]);
+            }
+        }
+    }
+    let mut spread = Vec::new();
+    let mut previous: Option<usize> = None;
+    loop {
+        let mut next: Option<usize> = None;
+        for (i, group) in groups.iter().enumerate() {
+            if Some(i) == previous || group.is_empty() {
+                continue;
+            }
+            match next {
+                Some(next) if group.len() <= groups[next].len() => {}
+                _ => next = Some(i),
+            }
+        }
+        let next = match next.or(previous) {
+            Some(next) if !groups[next].is_empty() => next,
+            _ => break,
+        };
+        spread.extend(groups[next].pop());
+        previous = Some(next);
+    }
+    spread
+}
+
+// Weighted random sampling without replacement (Efraimidis-Spirakis), higher rated tracks tend to
+// come first
+fn weighted_by_rating(
+    tracks: Vec<Track>,
+    ratings: &HashMap<i32, i32>,
+    rng: &mut Rand32,
+) -> Vec<Track> {
+    let mut keyed: Vec<(f32, Track)> = tracks
+        .into_iter()
+        .map(|track| {
+            let weight = *ratings.get(&track.id).unwrap_or(&DEFAULT_RATING) as f32;
+            ((1.0 - rng.rand_float()).ln() / weight, track)
+        })
+        .collect();
+    keyed.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+    keyed.into_iter().map(|(_, track)| track).collect()
+}
+
+#[test]
+fn it_spreads_artists() {
+    use chrono::NaiveDateTime;
+
+    let track = |id, artist_id| Track {
+        id,
+        created_at: NaiveDateTime::from_timestamp(0, 0),
+        name: format!("Track {}", id),
+        duration: 0,
+        album_id: None,
+        artist_id,
+        genre_id: None,
+        track_number: None,
+    };
+    let tracks = || {
+        vec![
+            track(1, Some(1)),
+            track(2, Some(1)),
+            track(3, Some(1)),
+            track(4, Some(2)),
+            track(5, Some(2)),
+            track(6, None),
+        ]
+    };
+    let ids =
+        |tracks: Vec<Track>| -> Vec<i32> { tracks.into_iter().map(|track| track.id).collect() };
+    for seed in 0..32 {
+        let shuffled = shuffle(tracks(), ShuffleMode::ArtistSpread, &HashMap::new(), seed);
+        assert_eq!(shuffled.len(), 6);
+        for pair in shuffled.windows(2) {
+            assert!(pair[0].artist_id.is_none() || pair[0].artist_id != pair[1].artist_id);
+        }
+    }
+    assert_eq!(
+        ids(shuffle(tracks(), ShuffleMode::Random, &HashMap::new(), 42)),
+        ids(shuffle(tracks(), ShuffleMode::Random, &HashMap::new(), 42))
+    );
+}
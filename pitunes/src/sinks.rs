@@ -0,0 +1,152 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    process::{Child, ChildStdin, Command, Stdio},
+    str::FromStr,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+
+// Receives decoded audio as interleaved signed 16-bit samples
+pub trait Sink: Send {
+    fn write(&mut self, sample_rate: u32, channels: u16, samples: &[i16]) -> io::Result<()>;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SinkKind {
+    Alsa(String),
+    File(PathBuf),
+    Null,
+}
+
+impl FromStr for SinkKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, arg) = match s.find(':') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        match (kind, arg) {
+            ("alsa", device) => Ok(SinkKind::Alsa(String::from(device.unwrap_or("default")))),
+            ("file", Some(path)) if !path.is_empty() => Ok(SinkKind::File(PathBuf::from(path))),
+            ("null", None) => Ok(SinkKind::Null),
+            _ => Err(anyhow!("unknown sink {}", s)),
+        }
+    }
+}
+
+impl SinkKind {
+    pub fn open(&self) -> io::Result<Box<dyn Sink>> {
+        Ok(match self {
+            SinkKind::Alsa(device) => Box::new(AlsaSink {
+                device: device.clone(),
+                aplay: None,
+            }),
+            SinkKind::File(path) => Box::new(FileSink {
+                file: OpenOptions::new().create(true).append(true).open(path)?,
+            }),
+            SinkKind::Null => Box::new(NullSink),
+        })
+    }
+}
+
+fn to_le_bytes(samples: &[i16]) -> Vec<u8> {
+    samples
+        .iter()
+        .flat_map(|sample| sample.to_le_bytes())
+        .collect()
+}
+
+// Discards everything, e.g. for tests, but takes as long as playing it would so that positions
+// advance in real time
+pub struct NullSink;
+
+impl Sink for NullSink {
+    fn write(&mut self, sample_rate: u32, channels: u16, samples: &[i16]) -> io::Result<()> {
+        let frames = samples.len() as f64 / f64::from(channels.max(1));
+        thread::sleep(Duration::from_secs_f64(
+            frames / f64::from(sample_rate.max(1)),
+        ));
+        Ok(())
+    }
+}
+
+// Writes raw little-endian PCM, which makes it usable with named pipes of other audio software
+pub struct FileSink {
+    file: File,
+}
+
+impl Sink for FileSink {
+    fn write(&mut self, _sample_rate: u32, _channels: u16, samples: &[i16]) -> io::Result<()> {
+        self.file.write_all(&to_le_bytes(samples))
+    }
+}
+
+// Plays through aplay from alsa-utils, which is restarted whenever the sample format changes
+pub struct AlsaSink {
+    device: String,
+    aplay: Option<(u32, u16, Child, ChildStdin)>,
+}
+
+impl AlsaSink {
+    fn stop(&mut self) {
+        if let Some((_, _, mut child, stdin)) = self.aplay.take() {
+            drop(stdin);
+            let _ = child.wait();
+        }
+    }
+}
+
+impl Sink for AlsaSink {
+    fn write(&mut self, sample_rate: u32, channels: u16, samples: &[i16]) -> io::Result<()> {
+        match &self.aplay {
+            Some((rate, chans, _, _)) if *rate == sample_rate && *chans == channels => {}
+            _ => {
+                self.stop();
+                let mut child = Command::new("aplay")
+                    .args(["-q", "-t", "raw", "-f", "S16_LE", "-D", &self.device])
+                    .arg(format!("-r{}", sample_rate))
+                    .arg(format!("-c{}", channels))
+                    .stdin(Stdio::piped())
+                    .spawn()?;
+                let stdin = child.stdin.take().unwrap();
+                self.aplay = Some((sample_rate, channels, child, stdin));
+            }
+        }
+        let (_, _, _, stdin) = self.aplay.as_mut().unwrap();
+        let result = stdin.write_all(&to_le_bytes(samples));
+        if result.is_err() {
+            self.stop();
+        }
+        result
+    }
+}
+
+impl Drop for AlsaSink {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[test]
+fn it_parses_sinks() {
+    assert_eq!(
+        "alsa".parse::<SinkKind>().unwrap(),
+        SinkKind::Alsa(String::from("default"))
+    );
+    assert_eq!(
+        "alsa:hw:0,0".parse::<SinkKind>().unwrap(),
+        SinkKind::Alsa(String::from("hw:0,0"))
+    );
+    assert_eq!(
+        "file:/tmp/pitunes.fifo".parse::<SinkKind>().unwrap(),
+        SinkKind::File(PathBuf::from("/tmp/pitunes.fifo"))
+    );
+    assert_eq!("null".parse::<SinkKind>().unwrap(), SinkKind::Null);
+    assert!("file:".parse::<SinkKind>().is_err());
+    assert!("pulse".parse::<SinkKind>().is_err());
+}
@@ -0,0 +1,458 @@
+use std::{convert::TryFrom, marker::PhantomData};
+
+use anyhow::{anyhow, Result};
+use diesel::{
+    expression::{AppearsOnTable, Expression, NonAggregate, SelectableExpression},
+    prelude::*,
+    query_builder::{AstPass, QueryFragment, QueryId},
+    sql_types::{BigInt, Bool, Text},
+    sqlite::Sqlite,
+};
+use serde_json::{Map, Value};
+
+use crate::{
+    db::like_pattern,
+    external_id::EntityKind,
+    models::{NewPlaylistTrack, Track},
+    schema::{playlists, playlists_tracks, tracks},
+};
+
+// The rules of a smart playlist are stored as JSON and compiled to SQL whenever the playlist is
+// read, e.g. Jazz rated 4 or higher that hasn't been played in 30 days:
+// {
+//   "match": {
+//     "all": [
+//       { "field": "genre", "op": "is", "value": "Jazz" },
+//       { "field": "rating", "op": "gte", "value": 4 },
+//       { "field": "lastPlayed", "op": "notInTheLast", "value": 30 }
+//     ]
+//   },
+//   "sort": "random",
+//   "limit": 100
+// }
+// Conditions can be nested with "all", "any" and "not". Tracks are sorted by "random" or by a
+// field, descending if the field is prefixed with "-".
+
+enum Piece {
+    Sql(String),
+    Text(String),
+    BigInt(i64),
+}
+
+// Raw SQL with bound parameters, the parameters never end up in the SQL itself
+pub struct Sql<ST> {
+    pieces: Vec<Piece>,
+    _marker: PhantomData<ST>,
+}
+
+impl<ST> Sql<ST> {
+    fn new(sql: &str) -> Self {
+        Sql {
+            pieces: vec![Piece::Sql(String::from(sql))],
+            _marker: PhantomData,
+        }
+    }
+
+    fn sql(mut self, sql: &str) -> Self {
+        self.pieces.push(Piece::Sql(String::from(sql)));
+        self
+    }
+
+    fn text(mut self, value: String) -> Self {
+        self.pieces.push(Piece::Text(value));
+        self
+    }
+
+    fn big_int(mut self, value: i64) -> Self {
+        self.pieces.push(Piece::BigInt(value));
+        self
+    }
+
+    fn append<ST2>(mut self, other: Sql<ST2>) -> Self {
+        self.pieces.extend(other.pieces);
+        self
+    }
+}
+
+impl<ST> Expression for Sql<ST> {
+    type SqlType = ST;
+}
+
+impl<ST> QueryFragment<Sqlite> for Sql<ST> {
+    fn walk_ast(&self, mut out: AstPass<Sqlite>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+        for piece in &self.pieces {
+            match piece {
+                Piece::Sql(sql) => out.push_sql(sql),
+                Piece::Text(value) => out.push_bind_param::<Text, _>(value)?,
+                Piece::BigInt(value) => out.push_bind_param::<BigInt, _>(value)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<ST> QueryId for Sql<ST> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<ST, QS> AppearsOnTable<QS> for Sql<ST> {}
+
+impl<ST, QS> SelectableExpression<QS> for Sql<ST> {}
+
+impl<ST> NonAggregate for Sql<ST> {}
+
+enum FieldType {
+    Text,
+    Number,
+    Date,
+    Boolean,
+}
+
+fn field(name: &str, username: &str) -> Result<(FieldType, Sql<()>)> {
+    let username = String::from(username);
+    Ok(match name {
+        "name" => (FieldType::Text, Sql::new("tracks.name")),
+        "album" => (
+            FieldType::Text,
+            Sql::new("(SELECT albums.name FROM albums WHERE albums.id = tracks.album_id)"),
+        ),
+        "artist" => (
+            FieldType::Text,
+            Sql::new("(SELECT artists.name FROM artists WHERE artists.id = tracks.artist_id)"),
+        ),
+        "genre" => (
+            FieldType::Text,
+            Sql::new("(SELECT genres.name FROM genres WHERE genres.id = tracks.genre_id)"),
+        ),
+        "duration" => (FieldType::Number, Sql::new("tracks.duration")),
+        "trackNumber" => (FieldType::Number, Sql::new("tracks.track_number")),
+        "rating" => (
+            FieldType::Number,
+            Sql::new(
+                "(SELECT ratings.rating FROM ratings \
+                 WHERE ratings.track_id = tracks.id AND ratings.username = ",
+            )
+            .text(username)
+            .sql(")"),
+        ),
+        "playCount" => (
+            FieldType::Number,
+            Sql::new(
+                "(SELECT COUNT(*) FROM plays \
+                 WHERE plays.track_id = tracks.id AND plays.username = ",
+            )
+            .text(username)
+            .sql(")"),
+        ),
+        "lastPlayed" => (
+            FieldType::Date,
+            Sql::new(
+                "(SELECT MAX(plays.started_at) FROM plays \
+                 WHERE plays.track_id = tracks.id AND plays.username = ",
+            )
+            .text(username)
+            .sql(")"),
+        ),
+        "createdAt" => (FieldType::Date, Sql::new("tracks.created_at")),
+        "starred" => (
+            FieldType::Boolean,
+            Sql::new(&format!(
+                "EXISTS (SELECT 1 FROM stars WHERE stars.entity_kind = {} \
+                 AND stars.entity_id = tracks.id AND stars.username = ",
+                EntityKind::Track as i32
+            ))
+            .text(username)
+            .sql(")"),
+        ),
+        _ => return Err(anyhow!("Unknown field {}", name)),
+    })
+}
+
+fn text_value(value: Option<&Value>) -> Result<String> {
+    Ok(String::from(
+        value
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("Expected a string value"))?,
+    ))
+}
+
+fn number_value(value: Option<&Value>) -> Result<i64> {
+    value
+        .and_then(Value::as_i64)
+        .ok_or_else(|| anyhow!("Expected an integer value"))
+}
+
+fn days_value(value: Option<&Value>) -> Result<i64> {
+    match number_value(value)? {
+        days if days > 0 => Ok(days),
+        _ => Err(anyhow!("Expected a positive number of days")),
+    }
+}
+
+fn boolean_value(value: Option<&Value>) -> Result<bool> {
+    value
+        .and_then(Value::as_bool)
+        .ok_or_else(|| anyhow!("Expected a boolean value"))
+}
+
+fn compile_condition(rule: &Value, username: &str) -> Result<Sql<Bool>> {
+    let rule = rule
+        .as_object()
+        .ok_or_else(|| anyhow!("Expected a condition object"))?;
+    if let Some(conditions) = rule.get("all") {
+        return conditions_joined(conditions, " AND ", "1", username);
+    }
+    if let Some(conditions) = rule.get("any") {
+        return conditions_joined(conditions, " OR ", "0", username);
+    }
+    if let Some(condition) = rule.get("not") {
+        return Ok(Sql::new("NOT ").append(compile_condition(condition, username)?));
+    }
+    let name = rule
+        .get("field")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("Expected all, any, not or field"))?;
+    let op = rule
+        .get("op")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("Missing op of field {}", name))?;
+    let value = rule.get("value");
+    let (field_type, field) = field(name, username)?;
+    // Comparisons with NULL, e.g. the rating of an unrated track, don't match
+    let sql = Sql::new("COALESCE(").append(field);
+    let sql = match (field_type, op) {
+        (FieldType::Text, "is") => sql
+            .sql(" = ")
+            .text(text_value(value)?)
+            .sql(" COLLATE NOCASE"),
+        (FieldType::Text, "isNot") => sql
+            .sql(" <> ")
+            .text(text_value(value)?)
+            .sql(" COLLATE NOCASE"),
+        (FieldType::Text, "contains") => sql
+            .sql(" LIKE ")
+            .text(like_pattern(&text_value(value)?))
+            .sql(" ESCAPE '\\'"),
+        (FieldType::Text, "notContains") => sql
+            .sql(" NOT LIKE ")
+            .text(like_pattern(&text_value(value)?))
+            .sql(" ESCAPE '\\'"),
+        (FieldType::Number, "is") => sql.sql(" = ").big_int(number_value(value)?),
+        (FieldType::Number, "isNot") => sql.sql(" <> ").big_int(number_value(value)?),
+        (FieldType::Number, "lt") => sql.sql(" < ").big_int(number_value(value)?),
+        (FieldType::Number, "lte") => sql.sql(" <= ").big_int(number_value(value)?),
+        (FieldType::Number, "gt") => sql.sql(" > ").big_int(number_value(value)?),
+        (FieldType::Number, "gte") => sql.sql(" >= ").big_int(number_value(value)?),
+        (FieldType::Date, "inTheLast") | (FieldType::Date, "notInTheLast") => sql
+            .sql(" >= datetime('now', ")
+            .text(format!("-{} days", days_value(value)?))
+            .sql(")"),
+        (FieldType::Boolean, "is") => sql.sql(" = ").big_int(i64::from(boolean_value(value)?)),
+        _ => return Err(anyhow!("Unsupported op {} for field {}", op, name)),
+    };
+    let sql = sql.sql(", 0)");
+    // Tracks which have never been played haven't been played in the last days either
+    if op == "notInTheLast" {
+        Ok(Sql::new("NOT ").append(sql))
+    } else {
+        Ok(sql)
+    }
+}
+
+fn conditions_joined(
+    conditions: &Value,
+    separator: &str,
+    empty: &str,
+    username: &str,
+) -> Result<Sql<Bool>> {
+    let conditions = conditions
+        .as_array()
+        .ok_or_else(|| anyhow!("Expected an array of conditions"))?;
+    if conditions.is_empty() {
+        return Ok(Sql::new(empty));
+    }
+    let mut sql = Sql::new("(");
+    for (i, condition) in conditions.iter().enumerate() {
+        if i > 0 {
+            sql = sql.sql(separator);
+        }
+        sql = sql.append(compile_condition(condition, username)?);
+    }
+    Ok(sql.sql(")"))
+}
+
+fn compile_order(sort: Option<&Value>, username: &str) -> Result<Sql<()>> {
+    let sort = match sort {
+        Some(sort) => sort
+            .as_str()
+            .ok_or_else(|| anyhow!("Expected sort to be a string"))?,
+        None => "name",
+    };
+    if sort == "random" {
+        return Ok(Sql::new("RANDOM()"));
+    }
+    let (name, direction) = if let Some(name) = sort.strip_prefix('-') {
+        (name, " DESC")
+    } else {
+        (sort, " ASC")
+    };
+    let (_, field) = field(name, username)?;
+    Ok(field.sql(direction))
+}
+
+pub struct Rules {
+    pub condition: Sql<Bool>,
+    pub order: Sql<()>,
+    pub limit: Option<i64>,
+}
+
+pub fn compile(rules: &str, username: &str) -> Result<Rules> {
+    let rules: Map<String, Value> = serde_json::from_str(rules)?;
+    if let Some(key) = rules
+        .keys()
+        .find(|key| !matches!(&key[..], "match" | "sort" | "limit"))
+    {
+        return Err(anyhow!("Unknown key {}", key));
+    }
+    let condition = match rules.get("match") {
+        Some(rule) => compile_condition(rule, username)?,
+        None => Sql::new("1"),
+    };
+    let order = compile_order(rules.get("sort"), username)?;
+    let limit = match rules.get("limit") {
+        Some(limit) => Some(
+            limit
+                .as_u64()
+                .filter(|limit| *limit > 0)
+                .ok_or_else(|| anyhow!("Expected limit to be a positive integer"))?
+                as i64,
+        ),
+        None => None,
+    };
+    Ok(Rules {
+        condition,
+        order,
+        limit,
+    })
+}
+
+pub fn evaluate(conn: &SqliteConnection, rules: &str, username: &str) -> Result<Vec<Track>> {
+    let rules = compile(rules, username)?;
+    let mut query = tracks::table
+        .filter(rules.condition)
+        .order(rules.order)
+        .into_boxed();
+    if let Some(limit) = rules.limit {
+        query = query.limit(limit);
+    }
+    Ok(query.load::<Track>(conn)?)
+}
+
+// Turns a smart playlist into a static playlist containing the tracks its rules currently match
+pub fn snapshot(conn: &SqliteConnection, playlist_id: i32, username: &str) -> Result<()> {
+    let rules: Option<String> = playlists::table
+        .find(playlist_id)
+        .select(playlists::rules)
+        .get_result(conn)?;
+    let rules = rules.ok_or_else(|| anyhow!("Only smart playlists can be snapshotted"))?;
+    let tracks = evaluate(conn, &rules, username)?;
+    conn.transaction::<_, anyhow::Error, _>(|| {
+        diesel::delete(
+            playlists_tracks::table.filter(playlists_tracks::playlist_id.eq(playlist_id)),
+        )
+        .execute(conn)?;
+        for (position, track) in tracks.iter().enumerate() {
+            let new_playlist_track = NewPlaylistTrack {
+                track_id: track.id,
+                position: Some(i32::try_from(position)?),
+            };
+            diesel::insert_into(playlists_tracks::table)
+                .values((
+                    playlists_tracks::playlist_id.eq(playlist_id),
+                    &new_playlist_track,
+                ))
+                .execute(conn)?;
+        }
+        diesel::update(playlists::table.find(playlist_id))
+            .set(playlists::rules.eq(None::<String>))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+#[test]
+fn it_evaluates_rules() {
+    use diesel::connection::SimpleConnection;
+
+    use crate::db;
+
+    let dir = tempfile::tempdir().unwrap();
+    let pool = db::establish_connection(dir.path().join("pitunes.db").to_str().unwrap());
+    let conn = pool.get().unwrap();
+    conn.batch_execute(
+        "INSERT INTO genres (id, name) VALUES (1, 'Jazz'), (2, 'Rock');
+         INSERT INTO tracks (id, name, duration, genre_id) VALUES
+             (1, 'So What', 562000, 1),
+             (2, 'Blue in Green', 337000, 1),
+             (3, 'Paranoid', 168000, 2),
+             (4, '100% Jazz', 60000, NULL);
+         INSERT INTO ratings (username, track_id, rating) VALUES
+             ('admin', 1, 5), ('admin', 2, 3), ('admin', 3, 5);
+         INSERT INTO plays (username, track_id, started_at, duration) VALUES
+             ('admin', 1, datetime('now', '-1 days'), 562000);",
+    )
+    .unwrap();
+    let names = |rules: &str| -> Vec<String> {
+        evaluate(&conn, rules, "admin")
+            .unwrap()
+            .into_iter()
+            .map(|track| track.name)
+            .collect()
+    };
+    assert_eq!(
+        names(r#"{"match": {"field": "genre", "op": "is", "value": "jazz"}}"#),
+        vec!["Blue in Green", "So What"]
+    );
+    // tracks without a genre or rating match neither is nor isNot
+    assert_eq!(
+        names(r#"{"match": {"field": "genre", "op": "isNot", "value": "jazz"}}"#),
+        vec!["Paranoid"]
+    );
+    assert_eq!(
+        names(r#"{"match": {"field": "rating", "op": "isNot", "value": 5}}"#),
+        vec!["Blue in Green"]
+    );
+    assert_eq!(
+        names(
+            r#"{"match": {"all": [
+                {"field": "rating", "op": "gte", "value": 4},
+                {"field": "lastPlayed", "op": "notInTheLast", "value": 30}
+            ]}}"#
+        ),
+        vec!["Paranoid"]
+    );
+    assert_eq!(
+        names(
+            r#"{"match": {"not": {"field": "rating", "op": "gte", "value": 4}}, "sort": "-duration", "limit": 1}"#
+        ),
+        vec!["Blue in Green"]
+    );
+    assert_eq!(
+        names(r#"{"match": {"field": "name", "op": "contains", "value": "0%"}}"#),
+        vec!["100% Jazz"]
+    );
+    assert!(compile(
+        r#"{"match": {"field": "rating", "op": "contains", "value": 4}}"#,
+        "admin"
+    )
+    .is_err());
+    assert!(compile(r#"{"sort": "password"}"#, "admin").is_err());
+    assert!(compile(
+        r#"{"match": {"field": "lastPlayed", "op": "inTheLast", "value": -30}}"#,
+        "admin"
+    )
+    .is_err());
+}
@@ -0,0 +1,184 @@
+use anyhow::Result;
+use chrono::{Duration, NaiveDate};
+use diesel::{
+    prelude::*,
+    sql_types::{BigInt, Date, Integer, Text},
+};
+
+// All aggregates are computed over the plays_daily rollup table which is maintained by a trigger
+// on plays, so their cost depends on the number of distinct tracks played per day rather than on
+// the number of plays.
+
+#[derive(QueryableByName)]
+pub struct Ranking {
+    #[sql_type = "Integer"]
+    pub id: i32,
+    #[sql_type = "BigInt"]
+    pub play_count: i64,
+    #[sql_type = "BigInt"]
+    pub duration: i64,
+}
+
+#[derive(QueryableByName)]
+pub struct Period {
+    #[sql_type = "Text"]
+    pub period: String,
+    #[sql_type = "BigInt"]
+    pub play_count: i64,
+    #[sql_type = "BigInt"]
+    pub duration: i64,
+}
+
+#[derive(QueryableByName)]
+struct Day {
+    #[sql_type = "Date"]
+    day: NaiveDate,
+}
+
+#[derive(QueryableByName)]
+struct Id {
+    #[sql_type = "Integer"]
+    id: i32,
+}
+
+pub fn top_tracks(
+    conn: &SqliteConnection,
+    username: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+    limit: i64,
+) -> Result<Vec<Ranking>> {
+    Ok(diesel::sql_query(
+        "SELECT track_id AS id, SUM(play_count) AS play_count, SUM(duration) AS duration \
+         FROM plays_daily \
+         WHERE username = ? AND day >= ? AND day <= ? \
+         GROUP BY track_id \
+         ORDER BY play_count DESC, duration DESC \
+         LIMIT ?",
+    )
+    .bind::<Text, _>(username)
+    .bind::<Date, _>(from)
+    .bind::<Date, _>(to)
+    .bind::<BigInt, _>(limit)
+    .load(conn)?)
+}
+
+// `column` is one of the foreign key columns of tracks, i.e. album_id, artist_id or genre_id
+pub fn top_by_track_column(
+    conn: &SqliteConnection,
+    column: &str,
+    username: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+    limit: i64,
+) -> Result<Vec<Ranking>> {
+    Ok(diesel::sql_query(format!(
+        "SELECT tracks.{column} AS id, SUM(plays_daily.play_count) AS play_count, \
+         SUM(plays_daily.duration) AS duration \
+         FROM plays_daily INNER JOIN tracks ON tracks.id = plays_daily.track_id \
+         WHERE plays_daily.username = ? AND plays_daily.day >= ? AND plays_daily.day <= ? \
+         AND tracks.{column} IS NOT NULL \
+         GROUP BY tracks.{column} \
+         ORDER BY play_count DESC, duration DESC \
+         LIMIT ?",
+        column = column
+    ))
+    .bind::<Text, _>(username)
+    .bind::<Date, _>(from)
+    .bind::<Date, _>(to)
+    .bind::<BigInt, _>(limit)
+    .load(conn)?)
+}
+
+// `format` is a strftime format such as %Y-%m-%d, %Y-W%W or %Y-%m
+pub fn listening_time(
+    conn: &SqliteConnection,
+    format: &str,
+    username: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<Vec<Period>> {
+    Ok(diesel::sql_query(
+        "SELECT strftime(?, day) AS period, SUM(play_count) AS play_count, \
+         SUM(duration) AS duration \
+         FROM plays_daily \
+         WHERE username = ? AND day >= ? AND day <= ? \
+         GROUP BY period \
+         ORDER BY period",
+    )
+    .bind::<Text, _>(format)
+    .bind::<Text, _>(username)
+    .bind::<Date, _>(from)
+    .bind::<Date, _>(to)
+    .load(conn)?)
+}
+
+pub fn days_played(
+    conn: &SqliteConnection,
+    username: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<Vec<NaiveDate>> {
+    let days: Vec<Day> = diesel::sql_query(
+        "SELECT DISTINCT day FROM plays_daily \
+         WHERE username = ? AND day >= ? AND day <= ? \
+         ORDER BY day",
+    )
+    .bind::<Text, _>(username)
+    .bind::<Date, _>(from)
+    .bind::<Date, _>(to)
+    .load(conn)?;
+    Ok(days.into_iter().map(|day| day.day).collect())
+}
+
+// Artists whose first play by the given user falls into the given window
+pub fn discovered_artists(
+    conn: &SqliteConnection,
+    username: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<Vec<i32>> {
+    let ids: Vec<Id> = diesel::sql_query(
+        "SELECT tracks.artist_id AS id \
+         FROM plays_daily INNER JOIN tracks ON tracks.id = plays_daily.track_id \
+         WHERE plays_daily.username = ? AND tracks.artist_id IS NOT NULL \
+         GROUP BY tracks.artist_id \
+         HAVING MIN(plays_daily.day) >= ? AND MIN(plays_daily.day) <= ? \
+         ORDER BY MIN(plays_daily.day)",
+    )
+    .bind::<Text, _>(username)
+    .bind::<Date, _>(from)
+    .bind::<Date, _>(to)
+    .load(conn)?;
+    Ok(ids.into_iter().map(|id| id.id).collect())
+}
+
+// Returns the current and the longest number of consecutive days with plays.
+// The current streak is still alive if the last play happened today or yesterday.
+pub fn streaks(days: &[NaiveDate], today: NaiveDate) -> (i32, i32) {
+    let mut longest = 0;
+    let mut streak = 0;
+    let mut previous: Option<NaiveDate> = None;
+    for day in days {
+        streak = match previous {
+            Some(previous) if *day - previous == Duration::days(1) => streak + 1,
+            _ => 1,
+        };
+        longest = longest.max(streak);
+        previous = Some(*day);
+    }
+    let current = match previous {
+        Some(previous) if today - previous <= Duration::days(1) => streak,
+        _ => 0,
+    };
+    (current, longest)
+}
+
+#[test]
+fn it_counts_streaks() {
+    let day = |d| NaiveDate::from_ymd(2026, 10, d);
+    let days = vec![day(1), day(2), day(3), day(10), day(17), day(18)];
+    assert_eq!(streaks(&days, day(19)), (2, 3));
+    assert_eq!(streaks(&days, day(20)), (0, 3));
+    assert_eq!(streaks(&[], day(20)), (0, 0));
+}
@@ -0,0 +1,393 @@
+use std::fmt;
+
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use md5::{Digest, Md5};
+use serde_json::{json, Map, Value};
+
+use crate::{models::User, schema::users, xml::escape};
+
+pub const API_VERSION: &str = "1.16.1";
+
+// Responses are built as a tree which is rendered as either XML or JSON. In JSON, elements added
+// through `children` always become arrays, even if there is only one of them.
+pub struct Element {
+    name: &'static str,
+    attributes: Vec<(&'static str, Value)>,
+    children: Vec<Children>,
+}
+
+enum Children {
+    One(Element),
+    Many(&'static str, Vec<Element>),
+}
+
+impl Element {
+    pub fn new(name: &'static str) -> Element {
+        Element {
+            name,
+            attributes: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn attr<V: Into<Value>>(mut self, name: &'static str, value: V) -> Element {
+        self.attributes.push((name, value.into()));
+        self
+    }
+
+    pub fn attr_opt<V: Into<Value>>(self, name: &'static str, value: Option<V>) -> Element {
+        match value {
+            Some(value) => self.attr(name, value),
+            None => self,
+        }
+    }
+
+    pub fn child(mut self, child: Element) -> Element {
+        self.children.push(Children::One(child));
+        self
+    }
+
+    pub fn children(mut self, name: &'static str, children: Vec<Element>) -> Element {
+        self.children.push(Children::Many(name, children));
+        self
+    }
+
+    fn write_xml(&self, xml: &mut String) {
+        xml.push('<');
+        xml.push_str(self.name);
+        for (name, value) in self.attributes.iter() {
+            let value = match value {
+                Value::String(s) => s.clone(),
+                value => value.to_string(),
+            };
+            xml.push_str(&format!(" {}=\"{}\"", name, escape(&value)));
+        }
+        if self.children.is_empty() {
+            xml.push_str("/>");
+            return;
+        }
+        xml.push('>');
+        for children in self.children.iter() {
+            match children {
+                Children::One(child) => child.write_xml(xml),
+                Children::Many(_, children) => {
+                    for child in children.iter() {
+                        child.write_xml(xml);
+                    }
+                }
+            }
+        }
+        xml.push_str(&format!("</{}>", self.name));
+    }
+
+    pub fn to_xml(&self) -> String {
+        let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+        self.write_xml(&mut xml);
+        xml
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut object = Map::new();
+        for (name, value) in self.attributes.iter() {
+            object.insert(String::from(*name), value.clone());
+        }
+        for children in self.children.iter() {
+            match children {
+                Children::One(child) => {
+                    object.insert(String::from(child.name), child.to_json());
+                }
+                Children::Many(name, children) => {
+                    object.insert(
+                        String::from(*name),
+                        Value::Array(children.iter().map(Element::to_json).collect()),
+                    );
+                }
+            }
+        }
+        Value::Object(object)
+    }
+}
+
+pub fn timestamp(datetime: NaiveDateTime) -> String {
+    format!("{}Z", datetime.format("%Y-%m-%dT%H:%M:%S%.3f"))
+}
+
+pub fn response(payload: Result<Option<Element>, SubsonicError>) -> Element {
+    let root = Element::new("subsonic-response")
+        .attr("version", API_VERSION)
+        .attr("type", "pitunes")
+        .attr("serverVersion", env!("CARGO_PKG_VERSION"))
+        .attr("openSubsonic", true);
+    match payload {
+        Ok(Some(payload)) => root.attr("status", "ok").child(payload),
+        Ok(None) => root.attr("status", "ok"),
+        Err(e) => root.attr("status", "failed").child(
+            Element::new("error")
+                .attr("code", e.code)
+                .attr("message", e.message),
+        ),
+    }
+}
+
+pub enum Format {
+    Xml,
+    Json,
+    Jsonp(String),
+}
+
+impl Format {
+    // The callback of JSONP is restricted to names so that it can't inject script
+    pub fn from_params(params: &Params) -> Result<Format, SubsonicError> {
+        match (params.get("f"), params.get("callback")) {
+            (Some("json"), _) => Ok(Format::Json),
+            (Some("jsonp"), Some(callback))
+                if !callback.is_empty()
+                    && callback
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$') =>
+            {
+                Ok(Format::Jsonp(String::from(callback)))
+            }
+            (Some("jsonp"), _) => Err(SubsonicError::new(10, "Invalid callback")),
+            _ => Ok(Format::Xml),
+        }
+    }
+
+    // Returns the content type and body
+    pub fn render(&self, root: &Element) -> (&'static str, String) {
+        match self {
+            Format::Xml => ("text/xml; charset=utf-8", {
+                let xml = root.to_xml();
+                xml.replacen(
+                    "<subsonic-response",
+                    r#"<subsonic-response xmlns="http://subsonic.org/restapi""#,
+                    1,
+                )
+            }),
+            Format::Json => (
+                "application/json",
+                json!({ "subsonic-response": root.to_json() }).to_string(),
+            ),
+            Format::Jsonp(callback) => (
+                "application/javascript",
+                format!(
+                    "{}({});",
+                    callback,
+                    json!({ "subsonic-response": root.to_json() })
+                ),
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SubsonicError {
+    pub code: i32,
+    pub message: String,
+}
+
+impl SubsonicError {
+    pub fn new(code: i32, message: &str) -> SubsonicError {
+        SubsonicError {
+            code,
+            message: String::from(message),
+        }
+    }
+
+    pub fn missing(param: &str) -> SubsonicError {
+        SubsonicError::new(10, &format!("Required parameter {} is missing", param))
+    }
+
+    pub fn not_found() -> SubsonicError {
+        SubsonicError::new(70, "The requested data was not found")
+    }
+}
+
+impl fmt::Display for SubsonicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl From<diesel::result::Error> for SubsonicError {
+    fn from(e: diesel::result::Error) -> SubsonicError {
+        match e {
+            diesel::result::Error::NotFound => SubsonicError::not_found(),
+            e => SubsonicError::new(0, &e.to_string()),
+        }
+    }
+}
+
+impl From<anyhow::Error> for SubsonicError {
+    fn from(e: anyhow::Error) -> SubsonicError {
+        match e.downcast::<diesel::result::Error>() {
+            Ok(e) => SubsonicError::from(e),
+            Err(e) => SubsonicError::new(0, &e.to_string()),
+        }
+    }
+}
+
+impl From<diesel::r2d2::PoolError> for SubsonicError {
+    fn from(e: diesel::r2d2::PoolError) -> SubsonicError {
+        SubsonicError::new(0, &e.to_string())
+    }
+}
+
+// Parameters may be repeated, e.g. several songId parameters
+pub struct Params(Vec<(String, String)>);
+
+impl Params {
+    pub fn parse(query: &str, form: &[u8]) -> Params {
+        let mut params: Vec<(String, String)> =
+            serde_urlencoded::from_str(query).unwrap_or_default();
+        if let Ok(form) = serde_urlencoded::from_bytes::<Vec<(String, String)>>(form) {
+            params.extend(form);
+        }
+        Params(params)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| &value[..])
+    }
+
+    pub fn all(&self, name: &str) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter(|(key, _)| key == name)
+            .map(|(_, value)| &value[..])
+            .collect()
+    }
+
+    pub fn require(&self, name: &str) -> Result<&str, SubsonicError> {
+        self.get(name).ok_or_else(|| SubsonicError::missing(name))
+    }
+
+    pub fn int(&self, name: &str) -> Result<Option<i64>, SubsonicError> {
+        match self.get(name) {
+            Some(value) => Ok(Some(value.parse().map_err(|_| {
+                SubsonicError::new(0, &format!("Parameter {} must be a number", name))
+            })?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn md5_hex(s: &str) -> String {
+    Md5::digest(s.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Compares secrets in time which only depends on their lengths
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+// Supports both the legacy password parameter, optionally hex encoded, and token authentication
+// where the token is the md5 hash of the Subsonic password followed by the salt. Either way the
+// password travels in the URL, so the main password of the user isn't accepted.
+pub fn authenticate(conn: &SqliteConnection, params: &Params) -> Result<String, SubsonicError> {
+    let wrong_credentials = || SubsonicError::new(40, "Wrong username or password");
+    let username = params.require("u")?;
+    let user = users::table
+        .find(username)
+        .get_result::<User>(conn)
+        .optional()?
+        .ok_or_else(wrong_credentials)?;
+    let valid = match (params.get("t"), params.get("s"), params.get("p")) {
+        (Some(token), Some(salt), _) => match &user.subsonic_password {
+            Some(password) => constant_time_eq(
+                md5_hex(&format!("{}{}", password, salt)).as_bytes(),
+                token.to_lowercase().as_bytes(),
+            ),
+            None => Err(SubsonicError::new(
+                41,
+                "Token authentication requires a Subsonic password, see updateSubsonicPassword",
+            ))?,
+        },
+        (_, _, Some(password)) => {
+            let password = match password.strip_prefix("enc:") {
+                Some(hex) => (0..hex.len())
+                    .step_by(2)
+                    .map(|i| {
+                        hex.get(i..i + 2)
+                            .and_then(|b| u8::from_str_radix(b, 16).ok())
+                    })
+                    .collect::<Option<Vec<u8>>>()
+                    .and_then(|bytes| String::from_utf8(bytes).ok())
+                    .ok_or_else(wrong_credentials)?,
+                None => String::from(password),
+            };
+            match &user.subsonic_password {
+                Some(subsonic_password) => {
+                    constant_time_eq(subsonic_password.as_bytes(), password.as_bytes())
+                }
+                None => false,
+            }
+        }
+        _ => Err(SubsonicError::missing("p"))?,
+    };
+    if valid {
+        Ok(user.username)
+    } else {
+        Err(wrong_credentials())
+    }
+}
+
+#[test]
+fn it_renders_xml_and_json() {
+    let root = response(Ok(Some(Element::new("artists").children(
+        "index",
+        vec![Element::new("index")
+            .attr("name", "A")
+            .children("artist", vec![Element::new("artist").attr("name", "A & B")])],
+    ))));
+    let (_, xml) = Format::Xml.render(&root);
+    assert!(
+        xml.contains(r#"<artists><index name="A"><artist name="A &amp; B"/></index></artists>"#)
+    );
+    let json = root.to_json();
+    assert_eq!(json["status"], "ok");
+    assert_eq!(json["artists"]["index"][0]["artist"][0]["name"], "A & B");
+    let root = response(Err(SubsonicError::not_found()));
+    assert_eq!(root.to_json()["error"]["code"], 70);
+}
+
+#[test]
+fn it_rejects_invalid_callbacks() {
+    let format = Format::from_params(&Params::parse("f=jsonp&callback=jQuery_1.$cb", &[]));
+    assert!(matches!(format, Ok(Format::Jsonp(callback)) if callback == "jQuery_1.$cb"));
+    let format = Format::from_params(&Params::parse("f=jsonp&callback=alert(1)//", &[]));
+    assert_eq!(format.err().map(|e| e.code), Some(10));
+    assert!(Format::from_params(&Params::parse("f=jsonp", &[])).is_err());
+}
+
+#[test]
+fn it_accepts_only_the_subsonic_password() {
+    use diesel::connection::SimpleConnection;
+
+    use crate::db;
+
+    let dir = tempfile::tempdir().unwrap();
+    let pool = db::establish_connection(dir.path().join("pitunes.db").to_str().unwrap());
+    let conn = pool.get().unwrap();
+    conn.batch_execute("UPDATE users SET subsonic_password = 'sesame' WHERE username = 'admin';")
+        .unwrap();
+    let authenticate = |query: &str| authenticate(&conn, &Params::parse(query, b"")).ok();
+    let admin = Some(String::from("admin"));
+    assert_eq!(authenticate("u=admin&p=sesame"), admin);
+    assert_eq!(authenticate("u=admin&p=enc:736573616d65"), admin);
+    let token = md5_hex("sesamesalt");
+    assert_eq!(authenticate(&format!("u=admin&t={}&s=salt", token)), admin);
+    assert_eq!(authenticate("u=admin&p=password"), None);
+    assert_eq!(authenticate("u=admin&p=sesam"), None);
+    assert_eq!(authenticate("u=admin&t=0123&s=salt"), None);
+    assert!(constant_time_eq(b"abc", b"abc"));
+    assert!(!constant_time_eq(b"abc", b"abd"));
+    assert!(!constant_time_eq(b"abc", b"ab"));
+}
@@ -0,0 +1,873 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::PathBuf,
+};
+
+use actix_files::NamedFile;
+use actix_web::{
+    error::BlockingError,
+    http::header::{ContentDisposition, DispositionType},
+    web, Error, HttpRequest, HttpResponse,
+};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+
+use crate::{
+    db,
+    events::Event,
+    external_id::{EntityKind, ExternalId},
+    favorites,
+    graphql_schema::RequestContext,
+    history,
+    models::{Album, Artist, Genre, NewPlay, NewPlaylist, NewPlaylistTrack, Playlist, Track},
+    playlist_files, prng,
+    schema::{albums, artists, genres, playlists, playlists_tracks, ratings, stars, tracks},
+    scrobbling,
+    subsonic::{self, timestamp, Element, Format, Params, SubsonicError},
+    tracks_service::{attachment, cover_art, track_filepath},
+};
+
+const IGNORED_ARTICLES: &[&str] = &["The", "El", "La", "Los", "Las", "Le", "Les"];
+
+// The whole library is exposed as a single music folder
+const MUSIC_FOLDER_ID: i32 = 1;
+
+enum Reply {
+    Element(Option<Element>),
+    File(PathBuf, ContentDisposition),
+    Image(String, Vec<u8>),
+}
+
+// Subsonic clients authenticate through query parameters instead of basic auth.
+// Methods may be called with or without the .view suffix, using GET or a form POST.
+pub async fn rest(
+    ctx: web::Data<RequestContext>,
+    req: HttpRequest,
+    web::Path(method): web::Path<String>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let params = Params::parse(req.query_string(), &body);
+    let format = match Format::from_params(&params) {
+        Ok(format) => format,
+        Err(e) => {
+            let (content_type, body) = Format::Json.render(&subsonic::response(Err(e)));
+            return Ok(HttpResponse::Ok().content_type(content_type).body(body));
+        }
+    };
+    let context = ctx.get_ref().clone();
+    let reply = web::block(move || {
+        let conn = context.pool.get()?;
+        let username = subsonic::authenticate(&conn, &params)?;
+        let method = method.trim_end_matches(".view");
+        dispatch(&context, &conn, &username, method, &params)
+    })
+    .await;
+    let payload = match reply {
+        Ok(Reply::File(filepath, content_disposition)) => {
+            return NamedFile::open(filepath)?
+                .set_content_disposition(content_disposition)
+                .into_response(&req);
+        }
+        Ok(Reply::Image(mime_type, data)) => {
+            return Ok(HttpResponse::Ok().content_type(mime_type).body(data));
+        }
+        Ok(Reply::Element(element)) => Ok(element),
+        Err(BlockingError::Error(e)) => Err(e),
+        Err(BlockingError::Canceled) => Err(SubsonicError::new(0, "Request was canceled")),
+    };
+    // errors are reported with status 200 as well
+    let (content_type, body) = format.render(&subsonic::response(payload));
+    Ok(HttpResponse::Ok().content_type(content_type).body(body))
+}
+
+fn dispatch(
+    context: &RequestContext,
+    conn: &SqliteConnection,
+    username: &str,
+    method: &str,
+    params: &Params,
+) -> Result<Reply, SubsonicError> {
+    let element = match method {
+        "ping" => None,
+        "getLicense" => Some(Element::new("license").attr("valid", true)),
+        "getMusicFolders" => Some(Element::new("musicFolders").children(
+            "musicFolder",
+            vec![Element::new("musicFolder")
+                .attr("id", MUSIC_FOLDER_ID)
+                .attr("name", "Music")],
+        )),
+        "getIndexes" => Some(get_indexes(conn, username)?),
+        "getArtists" => Some(get_artists(conn, username)?),
+        "getArtist" => Some(get_artist(conn, username, params)?),
+        "getMusicDirectory" => Some(get_music_directory(context, conn, username, params)?),
+        "getAlbum" => Some(get_album(context, conn, username, params)?),
+        "getSong" => Some(get_song(context, conn, username, params)?),
+        "search3" => Some(search3(context, conn, username, params)?),
+        "getPlaylists" => Some(get_playlists(conn, username)?),
+        "getPlaylist" => Some(get_playlist(context, conn, username, params)?),
+        "createPlaylist" => Some(create_playlist(context, conn, username, params)?),
+        "updatePlaylist" => update_playlist(context, conn, params).map(|_| None)?,
+        "deletePlaylist" => delete_playlist(context, conn, params).map(|_| None)?,
+        "scrobble" => scrobble(conn, username, params).map(|_| None)?,
+        "star" => star(conn, username, params, true).map(|_| None)?,
+        "unstar" => star(conn, username, params, false).map(|_| None)?,
+        "stream" | "download" => {
+            let id = decode(params.require("id")?, EntityKind::Track)?;
+            let track: Track = tracks::table.find(id).get_result(conn)?;
+            let content_disposition = if method == "download" {
+                attachment(&format!("{}.mp3", playlist_files::sanitize(&track.name)))
+            } else {
+                ContentDisposition {
+                    disposition: DispositionType::Inline,
+                    parameters: Vec::new(),
+                }
+            };
+            return Ok(Reply::File(
+                track_filepath(&context.tracks_dir, id),
+                content_disposition,
+            ));
+        }
+        "getCoverArt" => {
+            let (mime_type, data) = get_cover_art(context, conn, username, params)?;
+            return Ok(Reply::Image(mime_type, data));
+        }
+        _ => Err(SubsonicError::new(
+            0,
+            &format!("Method {} is not supported", method),
+        ))?,
+    };
+    Ok(Reply::Element(element))
+}
+
+fn external_id(kind: EntityKind, id: i32) -> String {
+    ExternalId::new(kind, id).0.to_string()
+}
+
+fn decode(id: &str, kind: EntityKind) -> Result<i32, SubsonicError> {
+    ExternalId(juniper::ID::from(String::from(id)))
+        .decode(kind)
+        .map_err(|_| SubsonicError::not_found())
+}
+
+fn decode_any(id: &str) -> Result<(EntityKind, i32), SubsonicError> {
+    match ExternalId(juniper::ID::from(String::from(id))).decode_any() {
+        Ok((Some(kind), id)) => Ok((kind, id)),
+        _ => Err(SubsonicError::not_found()),
+    }
+}
+
+fn starred(
+    conn: &SqliteConnection,
+    username: &str,
+    kind: EntityKind,
+) -> Result<HashMap<i32, NaiveDateTime>, SubsonicError> {
+    Ok(stars::table
+        .filter(stars::username.eq(username))
+        .filter(stars::entity_kind.eq(kind as i32))
+        .select((stars::entity_id, stars::created_at))
+        .load::<(i32, NaiveDateTime)>(conn)?
+        .into_iter()
+        .collect())
+}
+
+fn sort_name(name: &str) -> String {
+    let name = name.trim();
+    for article in IGNORED_ARTICLES {
+        if let Some(rest) = name.strip_prefix(article) {
+            if rest.starts_with(' ') {
+                return rest.trim_start().to_lowercase();
+            }
+        }
+    }
+    name.to_lowercase()
+}
+
+fn index_name(name: &str) -> String {
+    match sort_name(name).chars().next() {
+        Some(c) if c.is_alphabetic() => c.to_uppercase().collect(),
+        _ => String::from("#"),
+    }
+}
+
+// Number of distinct albums per artist
+fn album_counts(conn: &SqliteConnection) -> Result<HashMap<i32, i32>, SubsonicError> {
+    let mut album_counts = HashMap::new();
+    for (artist_id, _) in tracks::table
+        .select((tracks::artist_id, tracks::album_id))
+        .filter(tracks::album_id.is_not_null())
+        .distinct()
+        .load::<(Option<i32>, Option<i32>)>(conn)?
+    {
+        if let Some(artist_id) = artist_id {
+            *album_counts.entry(artist_id).or_insert(0) += 1;
+        }
+    }
+    Ok(album_counts)
+}
+
+fn artist_element(
+    artist: &Artist,
+    album_counts: &HashMap<i32, i32>,
+    starred: &HashMap<i32, NaiveDateTime>,
+) -> Element {
+    Element::new("artist")
+        .attr("id", external_id(EntityKind::Artist, artist.id))
+        .attr("name", &artist.name[..])
+        .attr("coverArt", external_id(EntityKind::Artist, artist.id))
+        .attr(
+            "albumCount",
+            album_counts.get(&artist.id).cloned().unwrap_or(0),
+        )
+        .attr_opt("starred", starred.get(&artist.id).cloned().map(timestamp))
+}
+
+fn artist_indexes(conn: &SqliteConnection, username: &str) -> Result<Vec<Element>, SubsonicError> {
+    let album_counts = album_counts(conn)?;
+    let starred = starred(conn, username, EntityKind::Artist)?;
+    let mut artists = artists::table.load::<Artist>(conn)?;
+    artists.sort_by_key(|artist| sort_name(&artist.name));
+    let mut indexes: BTreeMap<String, Vec<Element>> = BTreeMap::new();
+    for artist in artists.iter() {
+        indexes
+            .entry(index_name(&artist.name))
+            .or_default()
+            .push(artist_element(artist, &album_counts, &starred));
+    }
+    Ok(indexes
+        .into_iter()
+        .map(|(name, artists)| {
+            Element::new("index")
+                .attr("name", name)
+                .children("artist", artists)
+        })
+        .collect())
+}
+
+fn get_indexes(conn: &SqliteConnection, username: &str) -> Result<Element, SubsonicError> {
+    let last_modified: Option<NaiveDateTime> = tracks::table
+        .select(diesel::dsl::max(tracks::created_at))
+        .get_result(conn)?;
+    Ok(Element::new("indexes")
+        .attr(
+            "lastModified",
+            last_modified.map_or(0, |last_modified| last_modified.timestamp_millis()),
+        )
+        .attr("ignoredArticles", IGNORED_ARTICLES.join(" "))
+        .children("index", artist_indexes(conn, username)?))
+}
+
+fn get_artists(conn: &SqliteConnection, username: &str) -> Result<Element, SubsonicError> {
+    Ok(Element::new("artists")
+        .attr("ignoredArticles", IGNORED_ARTICLES.join(" "))
+        .children("index", artist_indexes(conn, username)?))
+}
+
+fn artist_albums(conn: &SqliteConnection, artist_id: i32) -> Result<Vec<Album>, SubsonicError> {
+    let album_ids: Vec<i32> = tracks::table
+        .filter(tracks::artist_id.eq(artist_id))
+        .select(tracks::album_id)
+        .distinct()
+        .load::<Option<i32>>(conn)?
+        .into_iter()
+        .flatten()
+        .collect();
+    Ok(albums::table
+        .filter(albums::id.eq_any(album_ids))
+        .order(albums::name.asc())
+        .load::<Album>(conn)?)
+}
+
+// Albums don't have an artist of their own, so it is derived from their tracks
+fn album_elements(
+    conn: &SqliteConnection,
+    username: &str,
+    albums: &[Album],
+    name: &'static str,
+) -> Result<Vec<Element>, SubsonicError> {
+    let album_ids: Vec<i32> = albums.iter().map(|album| album.id).collect();
+    let tracks = tracks::table
+        .left_join(artists::table)
+        .filter(tracks::album_id.eq_any(&album_ids))
+        .load::<(Track, Option<Artist>)>(conn)?;
+    let starred = starred(conn, username, EntityKind::Album)?;
+    let mut summaries: HashMap<i32, (i32, i32, HashMap<i32, String>)> = HashMap::new();
+    for (track, artist) in tracks {
+        if let Some(album_id) = track.album_id {
+            let summary = summaries
+                .entry(album_id)
+                .or_insert_with(|| (0, 0, HashMap::new()));
+            summary.0 += 1;
+            summary.1 += track.duration;
+            if let Some(artist) = artist {
+                summary.2.insert(artist.id, artist.name);
+            }
+        }
+    }
+    Ok(albums
+        .iter()
+        .map(|album| {
+            let (song_count, duration, artists) = summaries
+                .remove(&album.id)
+                .unwrap_or_else(|| (0, 0, HashMap::new()));
+            let element = Element::new(name)
+                .attr("id", external_id(EntityKind::Album, album.id))
+                .attr("name", &album.name[..])
+                .attr("title", &album.name[..])
+                .attr("coverArt", external_id(EntityKind::Album, album.id))
+                .attr("songCount", song_count)
+                .attr("duration", duration / 1000)
+                .attr("created", timestamp(album.created_at))
+                .attr_opt("starred", starred.get(&album.id).cloned().map(timestamp));
+            match artists.len() {
+                0 => element,
+                1 => {
+                    let (artist_id, artist) = artists.into_iter().next().unwrap();
+                    element
+                        .attr("artist", artist)
+                        .attr("artistId", external_id(EntityKind::Artist, artist_id))
+                }
+                _ => element.attr("artist", "Various Artists"),
+            }
+        })
+        .collect())
+}
+
+fn path_segment(name: &str) -> String {
+    name.replace('/', "_")
+}
+
+fn song_elements(
+    context: &RequestContext,
+    conn: &SqliteConnection,
+    username: &str,
+    tracks: &[Track],
+    name: &'static str,
+) -> Result<Vec<Element>, SubsonicError> {
+    let track_ids: Vec<i32> = tracks.iter().map(|track| track.id).collect();
+    let album_ids: Vec<i32> = tracks.iter().filter_map(|track| track.album_id).collect();
+    let artist_ids: Vec<i32> = tracks.iter().filter_map(|track| track.artist_id).collect();
+    let genre_ids: Vec<i32> = tracks.iter().filter_map(|track| track.genre_id).collect();
+    let albums: HashMap<i32, Album> = albums::table
+        .filter(albums::id.eq_any(album_ids))
+        .load::<Album>(conn)?
+        .into_iter()
+        .map(|album| (album.id, album))
+        .collect();
+    let artists: HashMap<i32, Artist> = artists::table
+        .filter(artists::id.eq_any(artist_ids))
+        .load::<Artist>(conn)?
+        .into_iter()
+        .map(|artist| (artist.id, artist))
+        .collect();
+    let genres: HashMap<i32, Genre> = genres::table
+        .filter(genres::id.eq_any(genre_ids))
+        .load::<Genre>(conn)?
+        .into_iter()
+        .map(|genre| (genre.id, genre))
+        .collect();
+    let ratings: HashMap<i32, i32> = ratings::table
+        .filter(ratings::username.eq(username))
+        .filter(ratings::track_id.eq_any(&track_ids))
+        .select((ratings::track_id, ratings::rating))
+        .load::<(i32, i32)>(conn)?
+        .into_iter()
+        .collect();
+    let starred = starred(conn, username, EntityKind::Track)?;
+    Ok(tracks
+        .iter()
+        .map(|track| {
+            let album = track.album_id.and_then(|id| albums.get(&id));
+            let artist = track.artist_id.and_then(|id| artists.get(&id));
+            let genre = track.genre_id.and_then(|id| genres.get(&id));
+            let size = std::fs::metadata(track_filepath(&context.tracks_dir, track.id))
+                .map(|metadata| metadata.len())
+                .unwrap_or(0);
+            let path = {
+                let mut path = Vec::new();
+                if let Some(artist) = artist {
+                    path.push(path_segment(&artist.name));
+                }
+                if let Some(album) = album {
+                    path.push(path_segment(&album.name));
+                }
+                path.push(match track.track_number {
+                    Some(track_number) => {
+                        format!("{:02} - {}.mp3", track_number, path_segment(&track.name))
+                    }
+                    None => format!("{}.mp3", path_segment(&track.name)),
+                });
+                path.join("/")
+            };
+            let cover_art = match album {
+                Some(album) => external_id(EntityKind::Album, album.id),
+                None => external_id(EntityKind::Track, track.id),
+            };
+            Element::new(name)
+                .attr("id", external_id(EntityKind::Track, track.id))
+                .attr_opt(
+                    "parent",
+                    album.map(|album| external_id(EntityKind::Album, album.id)),
+                )
+                .attr("isDir", false)
+                .attr("title", &track.name[..])
+                .attr_opt("album", album.map(|album| &album.name[..]))
+                .attr_opt("artist", artist.map(|artist| &artist.name[..]))
+                .attr_opt("track", track.track_number)
+                .attr_opt("genre", genre.map(|genre| &genre.name[..]))
+                .attr("coverArt", cover_art)
+                .attr("size", size)
+                .attr("contentType", "audio/mpeg")
+                .attr("suffix", "mp3")
+                .attr("duration", track.duration / 1000)
+                .attr("path", path)
+                .attr_opt(
+                    "albumId",
+                    album.map(|album| external_id(EntityKind::Album, album.id)),
+                )
+                .attr_opt(
+                    "artistId",
+                    artist.map(|artist| external_id(EntityKind::Artist, artist.id)),
+                )
+                .attr("type", "music")
+                .attr("mediaType", "song")
+                .attr("created", timestamp(track.created_at))
+                .attr_opt("starred", starred.get(&track.id).cloned().map(timestamp))
+                .attr_opt("userRating", ratings.get(&track.id).cloned())
+        })
+        .collect())
+}
+
+fn album_tracks(conn: &SqliteConnection, album_id: i32) -> Result<Vec<Track>, SubsonicError> {
+    Ok(tracks::table
+        .filter(tracks::album_id.eq(album_id))
+        .order((tracks::track_number.asc(), tracks::name.asc()))
+        .load::<Track>(conn)?)
+}
+
+fn get_artist(
+    conn: &SqliteConnection,
+    username: &str,
+    params: &Params,
+) -> Result<Element, SubsonicError> {
+    let id = decode(params.require("id")?, EntityKind::Artist)?;
+    let artist: Artist = artists::table.find(id).get_result(conn)?;
+    let albums = artist_albums(conn, id)?;
+    let album_counts = album_counts(conn)?;
+    let starred = starred(conn, username, EntityKind::Artist)?;
+    Ok(artist_element(&artist, &album_counts, &starred)
+        .children("album", album_elements(conn, username, &albums, "album")?))
+}
+
+fn get_album(
+    context: &RequestContext,
+    conn: &SqliteConnection,
+    username: &str,
+    params: &Params,
+) -> Result<Element, SubsonicError> {
+    let id = decode(params.require("id")?, EntityKind::Album)?;
+    let album: Album = albums::table.find(id).get_result(conn)?;
+    let tracks = album_tracks(conn, id)?;
+    let element = album_elements(conn, username, &[album], "album")?.remove(0);
+    Ok(element.children(
+        "song",
+        song_elements(context, conn, username, &tracks, "song")?,
+    ))
+}
+
+// Artists and albums are presented as nested directories
+fn get_music_directory(
+    context: &RequestContext,
+    conn: &SqliteConnection,
+    username: &str,
+    params: &Params,
+) -> Result<Element, SubsonicError> {
+    let id = params.require("id")?;
+    match decode_any(id)? {
+        (EntityKind::Artist, artist_id) => {
+            let artist: Artist = artists::table.find(artist_id).get_result(conn)?;
+            let albums = artist_albums(conn, artist_id)?;
+            let children = album_elements(conn, username, &albums, "child")?
+                .into_iter()
+                .map(|child| child.attr("isDir", true).attr("parent", id))
+                .collect();
+            Ok(Element::new("directory")
+                .attr("id", id)
+                .attr("name", artist.name)
+                .children("child", children))
+        }
+        (EntityKind::Album, album_id) => {
+            let album: Album = albums::table.find(album_id).get_result(conn)?;
+            let tracks = album_tracks(conn, album_id)?;
+            Ok(Element::new("directory")
+                .attr("id", id)
+                .attr("name", album.name)
+                .children(
+                    "child",
+                    song_elements(context, conn, username, &tracks, "child")?,
+                ))
+        }
+        _ => Err(SubsonicError::not_found()),
+    }
+}
+
+fn get_song(
+    context: &RequestContext,
+    conn: &SqliteConnection,
+    username: &str,
+    params: &Params,
+) -> Result<Element, SubsonicError> {
+    let id = decode(params.require("id")?, EntityKind::Track)?;
+    let track: Track = tracks::table.find(id).get_result(conn)?;
+    Ok(song_elements(context, conn, username, &[track], "song")?.remove(0))
+}
+
+// An empty query matches everything, which clients use to synchronize the whole library
+fn search3(
+    context: &RequestContext,
+    conn: &SqliteConnection,
+    username: &str,
+    params: &Params,
+) -> Result<Element, SubsonicError> {
+    let query = params.get("query").unwrap_or("").trim_matches('"');
+    let pattern = db::like_pattern(query);
+    let page = |count: &str, offset: &str| -> Result<(i64, i64), SubsonicError> {
+        Ok((
+            params.int(count)?.unwrap_or(20).max(0),
+            params.int(offset)?.unwrap_or(0).max(0),
+        ))
+    };
+    let (artist_count, artist_offset) = page("artistCount", "artistOffset")?;
+    let (album_count, album_offset) = page("albumCount", "albumOffset")?;
+    let (song_count, song_offset) = page("songCount", "songOffset")?;
+    let artists = artists::table
+        .filter(artists::name.like(&pattern).escape('\\'))
+        .order(artists::name.asc())
+        .limit(artist_count)
+        .offset(artist_offset)
+        .load::<Artist>(conn)?;
+    let albums = albums::table
+        .filter(albums::name.like(&pattern).escape('\\'))
+        .order(albums::name.asc())
+        .limit(album_count)
+        .offset(album_offset)
+        .load::<Album>(conn)?;
+    let tracks = tracks::table
+        .filter(tracks::name.like(&pattern).escape('\\'))
+        .order(tracks::name.asc())
+        .limit(song_count)
+        .offset(song_offset)
+        .load::<Track>(conn)?;
+    let album_counts = album_counts(conn)?;
+    let starred = starred(conn, username, EntityKind::Artist)?;
+    Ok(Element::new("searchResult3")
+        .children(
+            "artist",
+            artists
+                .iter()
+                .map(|artist| artist_element(artist, &album_counts, &starred))
+                .collect(),
+        )
+        .children("album", album_elements(conn, username, &albums, "album")?)
+        .children(
+            "song",
+            song_elements(context, conn, username, &tracks, "song")?,
+        ))
+}
+
+// Playlists are shared by all users, so each user sees themselves as the owner
+fn playlist_element(username: &str, playlist: &Playlist, tracks: &[Track]) -> Element {
+    Element::new("playlist")
+        .attr("id", external_id(EntityKind::Playlist, playlist.id))
+        .attr("name", &playlist.name[..])
+        .attr("owner", username)
+        .attr("public", true)
+        .attr("songCount", tracks.len())
+        .attr(
+            "duration",
+            tracks.iter().map(|track| track.duration).sum::<i32>() / 1000,
+        )
+        .attr("created", timestamp(playlist.created_at))
+        .attr("changed", timestamp(playlist.created_at))
+        .attr("readonly", playlist.rules.is_some())
+}
+
+fn get_playlists(conn: &SqliteConnection, username: &str) -> Result<Element, SubsonicError> {
+    let playlists = playlists::table
+        .order(playlists::name.asc())
+        .load::<Playlist>(conn)?;
+    let mut children = Vec::new();
+    for playlist in playlists.iter() {
+        let tracks = playlist.load_tracks(conn, username)?;
+        children.push(playlist_element(username, playlist, &tracks));
+    }
+    Ok(Element::new("playlists").children("playlist", children))
+}
+
+fn playlist_with_entries(
+    context: &RequestContext,
+    conn: &SqliteConnection,
+    username: &str,
+    playlist_id: i32,
+) -> Result<Element, SubsonicError> {
+    let playlist: Playlist = playlists::table.find(playlist_id).get_result(conn)?;
+    let tracks = playlist.load_tracks(conn, username)?;
+    Ok(playlist_element(username, &playlist, &tracks).children(
+        "entry",
+        song_elements(context, conn, username, &tracks, "entry")?,
+    ))
+}
+
+fn get_playlist(
+    context: &RequestContext,
+    conn: &SqliteConnection,
+    username: &str,
+    params: &Params,
+) -> Result<Element, SubsonicError> {
+    let id = decode(params.require("id")?, EntityKind::Playlist)?;
+    playlist_with_entries(context, conn, username, id)
+}
+
+fn editable_playlist(conn: &SqliteConnection, playlist_id: i32) -> Result<(), SubsonicError> {
+    let rules: Option<String> = playlists::table
+        .find(playlist_id)
+        .select(playlists::rules)
+        .get_result(conn)?;
+    match rules {
+        Some(_) => Err(SubsonicError::new(
+            50,
+            "Tracks of smart playlists are determined by their rules",
+        )),
+        None => Ok(()),
+    }
+}
+
+fn replace_playlist_tracks(
+    conn: &SqliteConnection,
+    playlist_id: i32,
+    track_ids: &[i32],
+) -> Result<(), SubsonicError> {
+    diesel::delete(playlists_tracks::table.filter(playlists_tracks::playlist_id.eq(playlist_id)))
+        .execute(conn)?;
+    for (position, track_id) in track_ids.iter().enumerate() {
+        let new_playlist_track = NewPlaylistTrack {
+            track_id: *track_id,
+            position: Some(position as i32),
+        };
+        diesel::insert_into(playlists_tracks::table)
+            .values((
+                playlists_tracks::playlist_id.eq(playlist_id),
+                &new_playlist_track,
+            ))
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+fn song_ids(params: &Params, name: &str) -> Result<Vec<i32>, SubsonicError> {
+    params
+        .all(name)
+        .into_iter()
+        .map(|id| decode(id, EntityKind::Track))
+        .collect()
+}
+
+// Either creates a playlist or replaces the tracks of an existing one
+fn create_playlist(
+    context: &RequestContext,
+    conn: &SqliteConnection,
+    username: &str,
+    params: &Params,
+) -> Result<Element, SubsonicError> {
+    let track_ids = song_ids(params, "songId")?;
+    let playlist_id = conn.transaction::<_, SubsonicError, _>(|| {
+        let playlist_id = match params.get("playlistId") {
+            Some(playlist_id) => {
+                let playlist_id = decode(playlist_id, EntityKind::Playlist)?;
+                editable_playlist(conn, playlist_id)?;
+                if let Some(name) = params.get("name") {
+                    diesel::update(playlists::table.find(playlist_id))
+                        .set(playlists::name.eq(name))
+                        .execute(conn)?;
+                }
+                playlist_id
+            }
+            None => {
+                let mut new_playlist = NewPlaylist {
+                    id: 0,
+                    name: String::from(params.require("name")?),
+                    rules: None,
+                };
+                prng::insert_with_rand_i32(|id| {
+                    new_playlist.id = id;
+                    diesel::insert_into(playlists::table)
+                        .values(&new_playlist)
+                        .execute(conn)
+                })?;
+                new_playlist.id
+            }
+        };
+        replace_playlist_tracks(conn, playlist_id, &track_ids)?;
+        Ok(playlist_id)
+    })?;
+    context.events.publish(Event::PlaylistChanged(playlist_id));
+    playlist_with_entries(context, conn, username, playlist_id)
+}
+
+fn update_playlist(
+    context: &RequestContext,
+    conn: &SqliteConnection,
+    params: &Params,
+) -> Result<(), SubsonicError> {
+    let playlist_id = decode(params.require("playlistId")?, EntityKind::Playlist)?;
+    let track_ids_to_add = song_ids(params, "songIdToAdd")?;
+    let indexes_to_remove = params
+        .all("songIndexToRemove")
+        .into_iter()
+        .map(|index| {
+            index
+                .parse::<usize>()
+                .map_err(|_| SubsonicError::new(0, "songIndexToRemove must be a number"))
+        })
+        .collect::<Result<HashSet<usize>, SubsonicError>>()?;
+    conn.transaction::<_, SubsonicError, _>(|| {
+        playlists::table
+            .find(playlist_id)
+            .get_result::<Playlist>(conn)?;
+        if let Some(name) = params.get("name") {
+            diesel::update(playlists::table.find(playlist_id))
+                .set(playlists::name.eq(name))
+                .execute(conn)?;
+        }
+        if !track_ids_to_add.is_empty() || !indexes_to_remove.is_empty() {
+            editable_playlist(conn, playlist_id)?;
+            let mut track_ids: Vec<i32> = playlists_tracks::table
+                .filter(playlists_tracks::playlist_id.eq(playlist_id))
+                .order(playlists_tracks::position.asc())
+                .select(playlists_tracks::track_id)
+                .load(conn)?
+                .into_iter()
+                .enumerate()
+                .filter(|(index, _)| !indexes_to_remove.contains(index))
+                .map(|(_, track_id)| track_id)
+                .collect();
+            track_ids.extend(track_ids_to_add);
+            replace_playlist_tracks(conn, playlist_id, &track_ids)?;
+        }
+        Ok(())
+    })?;
+    context.events.publish(Event::PlaylistChanged(playlist_id));
+    Ok(())
+}
+
+fn delete_playlist(
+    context: &RequestContext,
+    conn: &SqliteConnection,
+    params: &Params,
+) -> Result<(), SubsonicError> {
+    let id = decode(params.require("id")?, EntityKind::Playlist)?;
+    if diesel::delete(playlists::table.find(id)).execute(conn)? == 0 {
+        Err(SubsonicError::not_found())?
+    }
+    context.events.publish(Event::PlaylistDeleted(id));
+    Ok(())
+}
+
+// Submissions are recorded as plays, "now playing" notifications are only passed on to the user's
+// scrobblers
+fn scrobble(conn: &SqliteConnection, username: &str, params: &Params) -> Result<(), SubsonicError> {
+    let now_playing = params.get("submission") == Some("false");
+    let times = params.all("time");
+    for (i, id) in params.all("id").into_iter().enumerate() {
+        let track_id = decode(id, EntityKind::Track)?;
+        let track: Track = tracks::table.find(track_id).get_result(conn)?;
+        let started_at = match times.get(i) {
+            Some(time) => time
+                .parse::<i64>()
+                .ok()
+                .and_then(|time| {
+                    NaiveDateTime::from_timestamp_opt(
+                        time.div_euclid(1000),
+                        (time.rem_euclid(1000) * 1_000_000) as u32,
+                    )
+                })
+                .ok_or_else(|| SubsonicError::new(10, "Invalid time"))?,
+            None => Utc::now().naive_utc(),
+        };
+        if !now_playing {
+            history::record_play(
+                conn,
+                &NewPlay {
+                    username: String::from(username),
+                    track_id,
+                    started_at,
+                    duration: track.duration,
+                    client: params.get("c").map(String::from),
+                },
+            )?;
+        }
+        scrobbling::enqueue(
+            conn,
+            username,
+            track_id,
+            started_at,
+            track.duration,
+            now_playing,
+        )?;
+    }
+    Ok(())
+}
+
+fn star(
+    conn: &SqliteConnection,
+    username: &str,
+    params: &Params,
+    starred: bool,
+) -> Result<(), SubsonicError> {
+    let mut entities = Vec::new();
+    for id in params.all("id") {
+        entities.push(decode_any(id)?);
+    }
+    for id in params.all("albumId") {
+        entities.push((EntityKind::Album, decode(id, EntityKind::Album)?));
+    }
+    for id in params.all("artistId") {
+        entities.push((EntityKind::Artist, decode(id, EntityKind::Artist)?));
+    }
+    for (kind, id) in entities {
+        if starred {
+            favorites::star(conn, username, kind, id)?;
+        } else {
+            favorites::unstar(conn, username, kind, id)?;
+        }
+    }
+    Ok(())
+}
+
+// Cover art is taken from the first embedded picture among the tracks of the requested entity
+fn get_cover_art(
+    context: &RequestContext,
+    conn: &SqliteConnection,
+    username: &str,
+    params: &Params,
+) -> Result<(String, Vec<u8>), SubsonicError> {
+    let track_ids: Vec<i32> = match decode_any(params.require("id")?)? {
+        (EntityKind::Track, id) => vec![id],
+        (EntityKind::Album, id) => album_tracks(conn, id)?
+            .iter()
+            .map(|track| track.id)
+            .collect(),
+        (EntityKind::Artist, id) => tracks::table
+            .filter(tracks::artist_id.eq(id))
+            .select(tracks::id)
+            .load(conn)?,
+        (EntityKind::Playlist, id) => playlists::table
+            .find(id)
+            .get_result::<Playlist>(conn)?
+            .load_tracks(conn, username)?
+            .iter()
+            .map(|track| track.id)
+            .collect(),
+        (EntityKind::Genre, _) => Vec::new(),
+    };
+    cover_art(&context.tracks_dir, &track_ids).ok_or_else(SubsonicError::not_found)
+}
@@ -1,22 +1,238 @@
 use std::{
-    io::{BufReader, BufWriter, Seek, SeekFrom, Write},
-    path::Path,
+    collections::HashMap,
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
 };
 
+use actix_files::NamedFile;
 use actix_multipart::Multipart;
-use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web::{
+    error::{self, BlockingError},
+    http::header::{
+        self, Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue,
+    },
+    web, Error, HttpRequest, HttpResponse,
+};
+use actix_web_httpauth::extractors::basic::BasicAuth;
 use diesel::prelude::*;
 use futures::{StreamExt, TryStreamExt};
 
 use crate::{
-    chunker::Chunker,
-    external_id::ExternalId,
+    events::Event,
+    external_id::{EntityKind, ExternalId},
+    fuzzy,
     graphql_schema::RequestContext,
-    models::{Album, Artist, Genre, NewAlbum, NewArtist, NewGenre, NewTrack},
-    prng,
+    history,
+    models::{self, Album, Artist, NewAlbum, NewArtist, NewGenre, NewTrack, Track},
+    playlist_files, prng,
     schema::{albums, artists, genres, tracks},
 };
 
+// Track files are named after untyped ids so that files uploaded before typed ids stay valid
+pub fn track_filepath(tracks_dir: &Path, id: i32) -> PathBuf {
+    let mut filepath = tracks_dir.to_path_buf();
+    filepath.push(&ExternalId::untyped(id).0[..]);
+    filepath.set_extension("mp3");
+    filepath
+}
+
+pub async fn get_track(
+    context: web::Data<RequestContext>,
+    credentials: BasicAuth,
+    req: HttpRequest,
+    web::Path(id): web::Path<String>,
+) -> Result<NamedFile, Error> {
+    let id = ExternalId(juniper::ID::from(id))
+        .decode(EntityKind::Track)
+        .map_err(error::ErrorNotFound)?;
+    let named_file = NamedFile::open(track_filepath(&context.tracks_dir, id))?;
+    if context.infer_plays {
+        let from_start = match req.headers().get(header::RANGE) {
+            Some(range) => matches!(range.to_str(), Ok(range) if range.starts_with("bytes=0-")),
+            None => true,
+        };
+        if from_start {
+            let username = String::from(credentials.user_id().as_ref());
+            let client = req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|user_agent| user_agent.to_str().ok())
+                .map(String::from);
+            let pool = context.pool.clone();
+            // failing to record the play must not keep the track from being played
+            if let Err(e) = web::block(move || {
+                let conn = pool.get()?;
+                history::infer_play(&conn, &username, id, client)
+            })
+            .await
+            {
+                eprintln!("Failed to record play of track {}: {}", id, e);
+            }
+        }
+    }
+    Ok(named_file)
+}
+
+// Parts of download names which end up empty are left out along with their separator
+pub const DEFAULT_DOWNLOAD_NAME: &str = "{number} - {artist} - {title}";
+
+// Saves tracks under a name like "01 - The Beatles - Come Together.mp3" instead of their id. The
+// name can be changed with the name query parameter, using the placeholders {artist}, {album},
+// {number} and {title}.
+#[get("/tracks/{id}/download")]
+async fn download_track(
+    context: web::Data<RequestContext>,
+    req: HttpRequest,
+    web::Path(id): web::Path<String>,
+    web::Query(params): web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, Error> {
+    let id = ExternalId(juniper::ID::from(id))
+        .decode(EntityKind::Track)
+        .map_err(error::ErrorNotFound)?;
+    let pool = context.pool.clone();
+    let (track, album, artist) = web::block(move || {
+        let conn = pool.get()?;
+        tracks::table
+            .find(id)
+            .left_join(albums::table)
+            .left_join(artists::table)
+            .get_result::<(Track, Option<Album>, Option<Artist>)>(&conn)
+            .map_err(anyhow::Error::from)
+    })
+    .await
+    .map_err(blocking_error)?;
+    let filepath = track_filepath(&context.tracks_dir, id);
+    let template = params
+        .get("name")
+        .map(|name| &name[..])
+        .unwrap_or(DEFAULT_DOWNLOAD_NAME);
+    let name = download_name(
+        template,
+        &track,
+        album.as_ref().map(|album| &album.name[..]),
+        artist.as_ref().map(|artist| &artist.name[..]),
+    );
+    // NamedFile takes care of the content type, ETag, Last-Modified and conditional and range
+    // requests
+    NamedFile::open(filepath)?
+        .set_content_disposition(attachment(&format!(
+            "{}.mp3",
+            playlist_files::sanitize(&name)
+        )))
+        .into_response(&req)
+}
+
+pub fn download_name(
+    template: &str,
+    track: &Track,
+    album: Option<&str>,
+    artist: Option<&str>,
+) -> String {
+    let number = track
+        .track_number
+        .map(|number| format!("{:02}", number))
+        .unwrap_or_default();
+    let name = template
+        .replace("{artist}", artist.unwrap_or_default())
+        .replace("{album}", album.unwrap_or_default())
+        .replace("{number}", &number)
+        .replace("{title}", &track.name);
+    let name = name
+        .split(" - ")
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(" - ");
+    if name.is_empty() {
+        track.name.clone()
+    } else {
+        name
+    }
+}
+
+// Only missing rows are reported as not found, other failures such as a locked database are
+// internal errors
+pub fn blocking_error(e: BlockingError<anyhow::Error>) -> Error {
+    match e {
+        BlockingError::Error(e) => match e.downcast::<diesel::result::Error>() {
+            Ok(diesel::result::Error::NotFound) => error::ErrorNotFound("Not found"),
+            Ok(e) => error::ErrorInternalServerError(e),
+            Err(e) => error::ErrorInternalServerError(e),
+        },
+        BlockingError::Canceled => error::ErrorInternalServerError("Request was canceled"),
+    }
+}
+
+// Browsers pick the UTF-8 file name while older clients fall back to an ASCII version of it
+pub fn attachment(filename: &str) -> ContentDisposition {
+    let ascii: String = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let mut parameters = vec![DispositionParam::Filename(ascii.clone())];
+    if ascii != filename {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext(String::from("UTF-8")),
+            language_tag: None,
+            value: filename.as_bytes().to_vec(),
+        }));
+    }
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters,
+    }
+}
+
+// Returns the first picture embedded in any of the tracks along with its mime type
+pub fn cover_art(tracks_dir: &Path, track_ids: &[i32]) -> Option<(String, Vec<u8>)> {
+    track_ids.iter().find_map(|track_id| {
+        let tag = id3::Tag::read_from_path(track_filepath(tracks_dir, *track_id)).ok()?;
+        let picture = tag.pictures().next()?;
+        Some((picture.mime_type.clone(), picture.data.clone()))
+    })
+}
+
+// Covers of albums, artists, genres and playlists are taken from their first track with a picture
+#[get("/covers/{id}")]
+async fn get_cover(
+    context: web::Data<RequestContext>,
+    credentials: BasicAuth,
+    web::Path(id): web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let (kind, id) = ExternalId(juniper::ID::from(id))
+        .decode_any()
+        .map_err(error::ErrorNotFound)?;
+    let username = String::from(credentials.user_id().as_ref());
+    let pool = context.pool.clone();
+    let tracks_dir = context.tracks_dir.clone();
+    let cover = web::block(move || {
+        let track_ids = match kind {
+            Some(EntityKind::Track) | None => vec![id],
+            Some(kind) => {
+                let conn = pool.get()?;
+                let (_, tracks) = models::collection(&conn, kind, id, &username)?;
+                tracks.iter().map(|track| track.id).collect()
+            }
+        };
+        Ok::<_, anyhow::Error>(cover_art(&tracks_dir, &track_ids))
+    })
+    .await
+    .map_err(blocking_error)?;
+    match cover {
+        Some((mime_type, data)) => Ok(HttpResponse::Ok()
+            .content_type(mime_type)
+            .header(header::CACHE_CONTROL, "max-age=86400")
+            .body(data)),
+        None => Err(error::ErrorNotFound("No cover art")),
+    }
+}
+
 #[post("/tracks")]
 async fn post_tracks(
     context: web::Data<RequestContext>,
@@ -25,22 +241,58 @@ async fn post_tracks(
 ) -> Result<HttpResponse, Error> {
     let mut tracks = Vec::new();
     let conn = context.pool.get().unwrap();
+    // names are loaded and normalized once for all uploaded files
+    let (mut albums, mut artists, mut genres) = web::block({
+        let pool = context.pool.clone();
+        move || {
+            let conn = pool.get()?;
+            Ok::<_, anyhow::Error>((
+                fuzzy::Names::new(
+                    albums::table
+                        .select((albums::id, albums::name))
+                        .load(&conn)?,
+                ),
+                fuzzy::Names::new(
+                    artists::table
+                        .select((artists::id, artists::name))
+                        .load(&conn)?,
+                ),
+                fuzzy::Names::new(
+                    genres::table
+                        .select((genres::id, genres::name))
+                        .load(&conn)?,
+                ),
+            ))
+        }
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)?;
     // iterate over multipart stream
     while let Ok(Some(mut field)) = payload.try_next().await {
+        // the upload is written next to its final path so that it can be renamed into place
+        let tracks_dir = context.tracks_dir.clone();
         // File::create is blocking operation, use threadpool
-        let mut tf = web::block(|| tempfile::tempfile()).await?;
+        let mut tf = web::block(move || {
+            tempfile::Builder::new()
+                .prefix(".upload")
+                .tempfile_in(tracks_dir)
+        })
+        .await?;
         // Field in turn is stream of *Bytes* object
         while let Some(chunk) = field.next().await {
             let data = chunk.unwrap();
             // filesystem operations are blocking, we have to use threadpool
             tf = web::block(move || tf.write_all(&data).map(|_| tf)).await?;
         }
+        tf = web::block(move || tf.as_file().sync_all().map(|_| tf)).await?;
         tf.seek(SeekFrom::Start(0))?;
-        let duration = mp3_duration::from_file(&tf);
+        let duration = mp3_duration::from_file(tf.as_file());
         tf.seek(SeekFrom::Start(0))?;
-        let tf2 = tf.try_clone()?;
-        if let Ok(external_id) = conn.transaction::<_, anyhow::Error, _>(|| {
-            let new_track = if let Ok(tag) = id3::Tag::read_from(tf2) {
+        let tf2 = tf.as_file().try_clone()?;
+        let mut persisted = None;
+        let mut created = Vec::new();
+        let result = conn.transaction::<_, anyhow::Error, _>(|| {
+            let mut new_track = if let Ok(tag) = id3::Tag::read_from(tf2) {
                 let track_name = tag.title().map(|t| String::from(t)).unwrap_or_else(|| {
                     let content_type = field.content_disposition().unwrap();
                     let filename = content_type.get_filename().unwrap();
@@ -54,57 +306,60 @@ async fn post_tracks(
                     tag.duration().unwrap() as i32
                 };
                 let track_album_id = if let Some(album_name) = tag.album() {
-                    if let Ok(album) = albums::table
-                        .filter(albums::name.eq(album_name))
-                        .first::<Album>(&conn)
-                    {
-                        Some(album.id)
+                    if let Some(album_id) = albums.find(album_name) {
+                        Some(album_id)
                     } else {
-                        let new_album = NewAlbum {
-                            id: prng::rand_i32(&conn)?,
+                        let mut new_album = NewAlbum {
+                            id: 0,
                             name: String::from(album_name),
                         };
-                        diesel::insert_into(albums::table)
-                            .values(&new_album)
-                            .execute(&conn)?;
+                        prng::insert_with_rand_i32(|id| {
+                            new_album.id = id;
+                            diesel::insert_into(albums::table)
+                                .values(&new_album)
+                                .execute(&conn)
+                        })?;
+                        created.push((EntityKind::Album, new_album.id, new_album.name));
                         Some(new_album.id)
                     }
                 } else {
                     None
                 };
                 let track_artist_id = if let Some(artist_name) = tag.artist() {
-                    if let Ok(artist) = artists::table
-                        .filter(artists::name.eq(artist_name))
-                        .first::<Artist>(&conn)
-                    {
-                        Some(artist.id)
+                    if let Some(artist_id) = artists.find(artist_name) {
+                        Some(artist_id)
                     } else {
-                        let new_artist = NewArtist {
-                            id: prng::rand_i32(&conn)?,
+                        let mut new_artist = NewArtist {
+                            id: 0,
                             name: String::from(artist_name),
                         };
-                        diesel::insert_into(artists::table)
-                            .values(&new_artist)
-                            .execute(&conn)?;
+                        prng::insert_with_rand_i32(|id| {
+                            new_artist.id = id;
+                            diesel::insert_into(artists::table)
+                                .values(&new_artist)
+                                .execute(&conn)
+                        })?;
+                        created.push((EntityKind::Artist, new_artist.id, new_artist.name));
                         Some(new_artist.id)
                     }
                 } else {
                     None
                 };
                 let track_genre_id = if let Some(genre_name) = tag.genre() {
-                    if let Ok(genre) = genres::table
-                        .filter(genres::name.eq(genre_name))
-                        .first::<Genre>(&conn)
-                    {
-                        Some(genre.id)
+                    if let Some(genre_id) = genres.find(genre_name) {
+                        Some(genre_id)
                     } else {
-                        let new_genre = NewGenre {
-                            id: prng::rand_i32(&conn)?,
+                        let mut new_genre = NewGenre {
+                            id: 0,
                             name: String::from(genre_name),
                         };
-                        diesel::insert_into(genres::table)
-                            .values(&new_genre)
-                            .execute(&conn)?;
+                        prng::insert_with_rand_i32(|id| {
+                            new_genre.id = id;
+                            diesel::insert_into(genres::table)
+                                .values(&new_genre)
+                                .execute(&conn)
+                        })?;
+                        created.push((EntityKind::Genre, new_genre.id, new_genre.name));
                         Some(new_genre.id)
                     }
                 } else {
@@ -112,7 +367,7 @@ async fn post_tracks(
                 };
                 let track_track_number = tag.track().map(|t| t as i32);
                 NewTrack {
-                    id: prng::rand_i32(&conn)?,
+                    id: 0,
                     name: track_name,
                     duration: track_duration,
                     album_id: track_album_id,
@@ -128,7 +383,7 @@ async fn post_tracks(
                 let track_name = String::from(file_stem.to_str().unwrap());
                 let track_duration = duration.unwrap().as_millis() as i32;
                 NewTrack {
-                    id: prng::rand_i32(&conn)?,
+                    id: 0,
                     name: track_name,
                     duration: track_duration,
                     album_id: None,
@@ -137,28 +392,70 @@ async fn post_tracks(
                     track_number: None,
                 }
             };
-            diesel::insert_into(tracks::table)
-                .values(&new_track)
-                .execute(&conn)?;
-            Ok(ExternalId::from(new_track.id))
-        }) {
-            let reader = BufReader::new(tf);
-            let mut chunker = Chunker::new(reader);
-            let filepath = {
-                let mut filepath = context.tracks_dir.clone();
-                filepath.push(&external_id.0[..]);
-                filepath.set_extension("mp3");
-                filepath
-            };
-            // File::create is blocking operation, use threadpool
-            let f = web::block(|| std::fs::File::create(filepath)).await?;
-            let mut writer = BufWriter::new(f);
-            while let Some(chunk) = chunker.next() {
-                // filesystem operations are blocking, we have to use threadpool
-                writer = web::block(move || writer.write_all(&chunk).map(|_| writer)).await?;
+            prng::insert_with_rand_i32(|id| {
+                new_track.id = id;
+                diesel::insert_into(tracks::table)
+                    .values(&new_track)
+                    .execute(&conn)
+            })?;
+            let filepath = track_filepath(&context.tracks_dir, new_track.id);
+            // the row must not be committed before its file is in place
+            tf.persist(&filepath)?;
+            persisted = Some(filepath);
+            std::fs::File::open(&context.tracks_dir)?.sync_all()?;
+            Ok(new_track.id)
+        });
+        match result {
+            Ok(id) => {
+                for (kind, id, name) in created {
+                    match kind {
+                        EntityKind::Album => albums.insert(id, name),
+                        EntityKind::Artist => artists.insert(id, name),
+                        _ => genres.insert(id, name),
+                    }
+                }
+                context.events.publish(Event::TrackAdded(id));
+                let external_id = ExternalId::new(EntityKind::Track, id);
+                tracks.push(req.url_for("get_track", &[&external_id.0[..]])?.to_string());
+            }
+            // tracks uploaded before this one are kept, the client learns about the failure
+            Err(e) => {
+                if let Some(filepath) = persisted {
+                    std::fs::remove_file(filepath)?;
+                }
+                return Err(error::ErrorInternalServerError(e));
             }
-            tracks.push(req.url_for("get_track", &[&external_id.0[..]])?.to_string());
         }
     }
     Ok(HttpResponse::Created().json(tracks))
 }
+
+#[test]
+fn it_renders_download_names() {
+    let track = Track {
+        id: 1,
+        created_at: chrono::NaiveDateTime::from_timestamp(0, 0),
+        name: String::from("Come Together"),
+        duration: 259_000,
+        album_id: None,
+        artist_id: None,
+        genre_id: None,
+        track_number: Some(1),
+    };
+    assert_eq!(
+        download_name(DEFAULT_DOWNLOAD_NAME, &track, None, Some("The Beatles")),
+        "01 - The Beatles - Come Together"
+    );
+    // parts which end up empty are left out
+    assert_eq!(
+        download_name("{artist} - {album} - {title}", &track, None, None),
+        "Come Together"
+    );
+    assert_eq!(
+        download_name("{album}", &track, None, None),
+        "Come Together"
+    );
+    let header = attachment("Sigur Rós.mp3").to_string();
+    assert!(header.contains("filename=\"Sigur R_s.mp3\""));
+    assert!(header.contains("filename*=UTF-8''Sigur%20R%C3%B3s.mp3"));
+}
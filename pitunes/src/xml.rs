@@ -0,0 +1,28 @@
+// Escaping of text and attribute values shared by the XML formats pitunes reads and writes
+
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+pub fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[test]
+fn it_escapes_and_unescapes() {
+    let s = r#"<a href="x">Tom & Jerry's</a>"#;
+    assert_eq!(
+        escape(s),
+        "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&apos;s&lt;/a&gt;"
+    );
+    assert_eq!(unescape(&escape(s)), s);
+    assert_eq!(unescape("&amp;lt;"), "&lt;");
+}
@@ -0,0 +1,331 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::PathBuf,
+};
+
+use actix_web::{web, Error};
+use chrono::{Datelike, NaiveDateTime, Timelike, Utc};
+use futures::{stream, Stream};
+
+// Files are read and sent in chunks of this many bytes
+const CHUNK_SIZE: usize = 64 * 1024;
+// Sizes and offsets which don't fit into the classic fields are stored in zip64 extra fields
+const ZIP64_LIMIT: u64 = 0xffff_ffff;
+// Entries followed by data descriptors are extracted with version 2.0, zip64 requires version 4.5
+const VERSION: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+// Checksums and sizes follow the data in data descriptors and file names are encoded as UTF-8
+const FLAGS: u16 = 1 << 3 | 1 << 11;
+
+pub enum Source {
+    File(PathBuf),
+    Data(Vec<u8>),
+}
+
+pub struct Entry {
+    pub name: String,
+    pub source: Source,
+}
+
+// A file which is being sent along with what its data descriptor needs
+struct Reading {
+    file: File,
+    name: String,
+    // of the local header
+    offset: u64,
+    hasher: crc32fast::Hasher,
+    size: u64,
+}
+
+struct Archive {
+    entries: std::vec::IntoIter<Entry>,
+    reading: Option<Reading>,
+    offset: u64,
+    central_directory: Vec<u8>,
+    count: u64,
+    modified: (u16, u16),
+    done: bool,
+}
+
+// Streams a ZIP archive of the entries without compressing them. Files are read once while they
+// are sent and their checksums and sizes follow in data descriptors, so that neither the archive
+// nor its files are held in memory.
+pub fn stream(entries: Vec<Entry>) -> impl Stream<Item = Result<web::Bytes, Error>> + Unpin {
+    let archive = Archive {
+        entries: entries.into_iter(),
+        reading: None,
+        offset: 0,
+        central_directory: Vec::new(),
+        count: 0,
+        modified: dos_date_time(Utc::now().naive_utc()),
+        done: false,
+    };
+    Box::pin(stream::unfold(archive, |mut archive| async move {
+        if archive.done {
+            return None;
+        }
+        match archive.next().await {
+            Ok(chunk) => Some((Ok(web::Bytes::from(chunk)), archive)),
+            Err(e) => {
+                archive.done = true;
+                Some((Err(e), archive))
+            }
+        }
+    }))
+}
+
+impl Archive {
+    async fn next(&mut self) -> Result<Vec<u8>, Error> {
+        if let Some(reading) = self.reading.take() {
+            let Reading {
+                file,
+                name,
+                offset,
+                mut hasher,
+                size,
+            } = reading;
+            let (file, chunk) = web::block(move || read_chunk(file)).await?;
+            let chunk = if chunk.is_empty() {
+                self.data_descriptor(&name, offset, hasher.finalize(), size)
+            } else {
+                hasher.update(&chunk);
+                self.reading = Some(Reading {
+                    file,
+                    name,
+                    offset,
+                    hasher,
+                    size: size + chunk.len() as u64,
+                });
+                chunk
+            };
+            self.offset += chunk.len() as u64;
+            return Ok(chunk);
+        }
+        let entry = match self.entries.next() {
+            Some(entry) => entry,
+            None => {
+                self.done = true;
+                return Ok(self.end_of_central_directory());
+            }
+        };
+        let offset = self.offset;
+        let mut chunk = self.local_header(&entry.name);
+        match entry.source {
+            Source::File(path) => {
+                let file = web::block(move || File::open(path)).await?;
+                self.reading = Some(Reading {
+                    file,
+                    name: entry.name,
+                    offset,
+                    hasher: crc32fast::Hasher::new(),
+                    size: 0,
+                });
+            }
+            Source::Data(data) => {
+                chunk.extend_from_slice(&data);
+                let descriptor =
+                    self.data_descriptor(&entry.name, offset, crc32(&data), data.len() as u64);
+                chunk.extend_from_slice(&descriptor);
+            }
+        }
+        self.offset += chunk.len() as u64;
+        Ok(chunk)
+    }
+
+    // The checksum and sizes are left empty since they follow in the data descriptor
+    fn local_header(&self, name: &str) -> Vec<u8> {
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&FLAGS.to_le_bytes());
+        // stored
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&self.modified.1.to_le_bytes());
+        header.extend_from_slice(&self.modified.0.to_le_bytes());
+        // checksum, compressed and uncompressed size
+        header.extend_from_slice(&[0; 12]);
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+        header
+    }
+
+    // Appends the entry's record to the central directory as well. Sizes are 64 bits wide in
+    // descriptors of zip64 entries.
+    fn data_descriptor(&mut self, name: &str, offset: u64, crc: u32, size: u64) -> Vec<u8> {
+        let zip64 = size >= ZIP64_LIMIT;
+        let mut descriptor = Vec::with_capacity(24);
+        descriptor.extend_from_slice(&0x0807_4b50u32.to_le_bytes());
+        descriptor.extend_from_slice(&crc.to_le_bytes());
+        if zip64 {
+            descriptor.extend_from_slice(&size.to_le_bytes());
+            descriptor.extend_from_slice(&size.to_le_bytes());
+        } else {
+            descriptor.extend_from_slice(&(size as u32).to_le_bytes());
+            descriptor.extend_from_slice(&(size as u32).to_le_bytes());
+        }
+        let classic_size = if zip64 { ZIP64_LIMIT } else { size } as u32;
+
+        let mut extra = Vec::new();
+        if zip64 {
+            extra.extend_from_slice(&size.to_le_bytes());
+            extra.extend_from_slice(&size.to_le_bytes());
+        }
+        let classic_offset = if offset >= ZIP64_LIMIT {
+            extra.extend_from_slice(&offset.to_le_bytes());
+            ZIP64_LIMIT
+        } else {
+            offset
+        } as u32;
+        let zip64 = !extra.is_empty();
+        let record = &mut self.central_directory;
+        record.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        // made by version 4.5 on unix
+        record.extend_from_slice(&((3u16 << 8) | VERSION_ZIP64).to_le_bytes());
+        record.extend_from_slice(&(if zip64 { VERSION_ZIP64 } else { VERSION }).to_le_bytes());
+        record.extend_from_slice(&FLAGS.to_le_bytes());
+        record.extend_from_slice(&0u16.to_le_bytes());
+        record.extend_from_slice(&self.modified.1.to_le_bytes());
+        record.extend_from_slice(&self.modified.0.to_le_bytes());
+        record.extend_from_slice(&crc.to_le_bytes());
+        record.extend_from_slice(&classic_size.to_le_bytes());
+        record.extend_from_slice(&classic_size.to_le_bytes());
+        record.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        let extra_length = if zip64 { 4 + extra.len() } else { 0 };
+        record.extend_from_slice(&(extra_length as u16).to_le_bytes());
+        // comment length, disk number, internal attributes
+        record.extend_from_slice(&[0; 6]);
+        // regular file readable by everyone
+        record.extend_from_slice(&(0o100_644u32 << 16).to_le_bytes());
+        record.extend_from_slice(&classic_offset.to_le_bytes());
+        record.extend_from_slice(name.as_bytes());
+        if zip64 {
+            record.extend_from_slice(&1u16.to_le_bytes());
+            record.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            record.extend_from_slice(&extra);
+        }
+        self.count += 1;
+        descriptor
+    }
+
+    fn end_of_central_directory(&mut self) -> Vec<u8> {
+        let mut end = std::mem::take(&mut self.central_directory);
+        let size = end.len() as u64;
+        let offset = self.offset;
+        let zip64 = offset >= ZIP64_LIMIT || size >= ZIP64_LIMIT || self.count >= 0xffff;
+        if zip64 {
+            let zip64_offset = offset + size;
+            end.extend_from_slice(&0x0606_4b50u32.to_le_bytes());
+            end.extend_from_slice(&44u64.to_le_bytes());
+            end.extend_from_slice(&((3u16 << 8) | VERSION_ZIP64).to_le_bytes());
+            end.extend_from_slice(&VERSION_ZIP64.to_le_bytes());
+            // disk numbers
+            end.extend_from_slice(&[0; 8]);
+            end.extend_from_slice(&self.count.to_le_bytes());
+            end.extend_from_slice(&self.count.to_le_bytes());
+            end.extend_from_slice(&size.to_le_bytes());
+            end.extend_from_slice(&offset.to_le_bytes());
+            // locator
+            end.extend_from_slice(&0x0706_4b50u32.to_le_bytes());
+            end.extend_from_slice(&0u32.to_le_bytes());
+            end.extend_from_slice(&zip64_offset.to_le_bytes());
+            end.extend_from_slice(&1u32.to_le_bytes());
+        }
+        let count = self.count.min(0xffff) as u16;
+        end.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        end.extend_from_slice(&[0; 4]);
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&(size.min(ZIP64_LIMIT) as u32).to_le_bytes());
+        end.extend_from_slice(&(offset.min(ZIP64_LIMIT) as u32).to_le_bytes());
+        // comment length
+        end.extend_from_slice(&0u16.to_le_bytes());
+        self.offset += end.len() as u64;
+        end
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+fn read_chunk(mut file: File) -> io::Result<(File, Vec<u8>)> {
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let n = file.read(&mut chunk)?;
+    chunk.truncate(n);
+    Ok((file, chunk))
+}
+
+// MS-DOS dates count years from 1980 and times have a resolution of two seconds
+fn dos_date_time(date_time: NaiveDateTime) -> (u16, u16) {
+    let year = (date_time.year() - 1980).clamp(0, 127) as u16;
+    let date = (year << 9) | ((date_time.month() as u16) << 5) | date_time.day() as u16;
+    let time = ((date_time.hour() as u16) << 11)
+        | ((date_time.minute() as u16) << 5)
+        | (date_time.second() as u16 / 2);
+    (date, time)
+}
+
+#[actix_rt::test]
+async fn it_writes_stored_archives() {
+    use std::io::Write;
+
+    use futures::StreamExt;
+
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(b"world").unwrap();
+    let entries = vec![
+        Entry {
+            name: String::from("a.txt"),
+            source: Source::Data(b"hello".to_vec()),
+        },
+        Entry {
+            name: String::from("\u{e4}.m3u"),
+            source: Source::Data(Vec::new()),
+        },
+        Entry {
+            name: String::from("b.mp3"),
+            source: Source::File(file.path().to_path_buf()),
+        },
+    ];
+    let chunks = stream(entries).collect::<Vec<_>>().await;
+    let archive: Vec<u8> = chunks
+        .into_iter()
+        .flat_map(|chunk| chunk.unwrap().to_vec())
+        .collect();
+    let u16_at = |i: usize| u16::from_le_bytes([archive[i], archive[i + 1]]);
+    let u32_at =
+        |i: usize| u32::from_le_bytes([archive[i], archive[i + 1], archive[i + 2], archive[i + 3]]);
+    assert_eq!(u32_at(0), 0x0403_4b50);
+    assert_eq!(u16_at(6), FLAGS);
+    // the checksum and sizes follow the data
+    assert_eq!(&archive[14..26], &[0; 12]);
+    assert_eq!(&archive[30..35], b"a.txt");
+    assert_eq!(&archive[35..40], b"hello");
+    assert_eq!(u32_at(40), 0x0807_4b50);
+    assert_eq!(u32_at(44), crc32(b"hello"));
+    assert_eq!((u32_at(48), u32_at(52)), (5, 5));
+    // the file is streamed between its header and its data descriptor
+    let third = 56 + 30 + "\u{e4}.m3u".len() + 16;
+    assert_eq!(&archive[third + 30..third + 35], b"b.mp3");
+    assert_eq!(&archive[third + 35..third + 40], b"world");
+    assert_eq!(u32_at(third + 44), crc32(b"world"));
+    let end = archive.len() - 22;
+    assert_eq!(u32_at(end), 0x0605_4b50);
+    assert_eq!(u16_at(end + 10), 3);
+    let central_directory = u32_at(end + 16) as usize;
+    assert_eq!(central_directory, third + 56);
+    assert_eq!(central_directory + u32_at(end + 12) as usize, end);
+    assert_eq!(u32_at(central_directory), 0x0201_4b50);
+    assert_eq!(u32_at(central_directory + 16), crc32(b"hello"));
+    // the second entry starts right after the first one's data descriptor
+    let second = central_directory + 46 + 5;
+    assert_eq!(u32_at(second + 42), 56);
+    assert_eq!(
+        dos_date_time(NaiveDateTime::from_timestamp(315_532_800, 0)),
+        (33, 0)
+    );
+}
@@ -0,0 +1,87 @@
+use actix_web::{error, web, Error, HttpResponse};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+
+use crate::{
+    external_id::{EntityKind, ExternalId},
+    graphql_schema::RequestContext,
+    models,
+    playlist_files::{self, ExportFormat, Item},
+    tracks_service::{attachment, blocking_error, cover_art, track_filepath},
+    zip::{self, Entry, Source},
+};
+
+// Albums and playlists are downloaded as archives of their tracks, named like
+// "01 - Artist - Title.mp3", along with an M3U playlist and a cover image if there is one
+#[get("/{kind:playlists|albums}/{id}.zip")]
+async fn get_archive(
+    ctx: web::Data<RequestContext>,
+    credentials: BasicAuth,
+    web::Path((kind, id)): web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let kind = match &kind[..] {
+        "albums" => EntityKind::Album,
+        _ => EntityKind::Playlist,
+    };
+    let id = ExternalId(juniper::ID::from(id))
+        .decode(kind)
+        .map_err(error::ErrorNotFound)?;
+    let username = String::from(credentials.user_id().as_ref());
+    let pool = ctx.pool.clone();
+    let tracks_dir = ctx.tracks_dir.clone();
+    let (name, entries) = web::block(move || {
+        let conn = pool.get()?;
+        let (name, tracks) = models::collection(&conn, kind, id, &username)?;
+        // the response can't fail anymore once the archive is being sent, so tracks whose files are
+        // missing, see `pitunes check`, are left out
+        let tracks: Vec<_> = tracks
+            .into_iter()
+            .filter(|track| track_filepath(&tracks_dir, track.id).is_file())
+            .collect();
+        let track_ids: Vec<i32> = tracks.iter().map(|track| track.id).collect();
+        let tracks = models::with_names(&conn, tracks)?;
+        let count = tracks.len();
+        let mut entries = Vec::new();
+        let mut items = Vec::new();
+        for (i, (track, album, artist)) in tracks.into_iter().enumerate() {
+            let path = playlist_files::relative_path(i + 1, count, &track, artist.as_deref());
+            entries.push(Entry {
+                name: path.clone(),
+                source: Source::File(track_filepath(&tracks_dir, track.id)),
+            });
+            items.push(Item {
+                url: path.clone(),
+                image: String::new(),
+                path,
+                track,
+                album,
+                artist,
+            });
+        }
+        let m3u = playlist_files::write(ExportFormat::M3u, "", &name, &items);
+        entries.push(Entry {
+            name: format!("{}.m3u", playlist_files::sanitize(&name)),
+            source: Source::Data(m3u.into_bytes()),
+        });
+        if let Some((mime_type, data)) = cover_art(&tracks_dir, &track_ids) {
+            let extension = match &mime_type[..] {
+                "image/png" => "png",
+                "image/gif" => "gif",
+                _ => "jpg",
+            };
+            entries.push(Entry {
+                name: format!("cover.{}", extension),
+                source: Source::Data(data),
+            });
+        }
+        Ok::<_, anyhow::Error>((name, entries))
+    })
+    .await
+    .map_err(blocking_error)?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .set(attachment(&format!(
+            "{}.zip",
+            playlist_files::sanitize(&name)
+        )))
+        .streaming(zip::stream(entries)))
+}